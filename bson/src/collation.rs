@@ -0,0 +1,197 @@
+/*
+    Copyright 2014-2016 Zumero, LLC
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+
+// sort keys for collation.  they live here instead of in elmo because
+// the index key encoding needs them.
+
+use std;
+
+// the options of a collation which decide its sort keys.  the sort key
+// of a string is another string, whose plain order is the collated order
+// of the original, so an index can store sort keys and stay byte ordered.
+//
+// a sort key is the primary level, the base letters, followed by the
+// levels for accents and case, each one after a separator which is lower
+// than anything in any level.  so two strings which differ in their base
+// letters are ordered by that, no matter their accents or case.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct CollationSpec {
+    // 1 is base letters only, 2 adds accents, 3 adds case, 4 and 5
+    // break any remaining ties with the string itself
+    pub strength: i32,
+    // case matters, even at strength 1 or 2
+    pub case_level: bool,
+    // runs of digits compare as numbers
+    pub numeric_ordering: bool,
+}
+
+const LEVEL_SEPARATOR: char = '\u{1}';
+
+impl CollationSpec {
+    pub fn sort_key(&self, s: &str) -> String {
+        let chars = s.chars().collect::<Vec<_>>();
+        let mut primary = String::new();
+        let mut secondary = String::new();
+        let mut case = String::new();
+        let mut i = 0;
+        while i < chars.len() {
+            let c = chars[i];
+            if self.numeric_ordering && is_digit(c) {
+                let start = i;
+                while i < chars.len() && is_digit(chars[i]) {
+                    i = i + 1;
+                }
+                let digits = chars[start .. i].iter().cloned().skip_while(|&c| c == '0').collect::<String>();
+                push_number(&mut primary, &digits);
+                continue;
+            }
+            let accent =
+                match fold(c) {
+                    Some((base, accent)) => {
+                        primary.push_str(base);
+                        accent
+                    },
+                    None => {
+                        primary.extend(c.to_lowercase());
+                        0
+                    },
+                };
+            secondary.push(std::char::from_u32(0x21 + accent).unwrap());
+            // lower case first
+            case.push(if c.is_uppercase() { 'B' } else { 'A' });
+            i = i + 1;
+        }
+        let mut key = primary;
+        if self.strength >= 2 {
+            key.push(LEVEL_SEPARATOR);
+            key.push_str(&secondary);
+        }
+        if self.case_level || self.strength >= 3 {
+            key.push(LEVEL_SEPARATOR);
+            key.push_str(&case);
+        }
+        if self.strength >= 4 {
+            key.push(LEVEL_SEPARATOR);
+            key.push_str(s);
+        }
+        key
+    }
+}
+
+fn is_digit(c: char) -> bool {
+    c >= '0' && c <= '9'
+}
+
+// a longer number is a bigger one, so the length goes first.  the length
+// is written in digits too, after one more digit for how long the length
+// is, so every char of it stays above the separator and below the
+// letters, no matter how long the run of digits.  a number with no
+// digits left, all zeros, is just the 0.
+fn push_number(key: &mut String, digits: &str) {
+    if digits.is_empty() {
+        key.push('0');
+        return;
+    }
+    let len = digits.len().to_string();
+    key.push(std::char::from_u32(0x30 + len.len() as u32).unwrap());
+    key.push_str(&len);
+    key.push_str(digits);
+}
+
+// the base letters, lower case, and which accent, for the latin letters
+// that have one.  everything else is its own base with no accent.
+fn fold(c: char) -> Option<(&'static str, u32)> {
+    const GRAVE: u32 = 1;
+    const ACUTE: u32 = 2;
+    const CIRCUMFLEX: u32 = 3;
+    const TILDE: u32 = 4;
+    const DIAERESIS: u32 = 5;
+    const RING: u32 = 6;
+    const CEDILLA: u32 = 7;
+    const STROKE: u32 = 8;
+    const CARON: u32 = 9;
+    const MACRON: u32 = 10;
+    const BREVE: u32 = 11;
+    const OGONEK: u32 = 12;
+    const DOT: u32 = 13;
+    const DOUBLE_ACUTE: u32 = 14;
+    let r =
+        match c {
+            'À' | 'à' => ("a", GRAVE),
+            'Á' | 'á' => ("a", ACUTE),
+            'Â' | 'â' => ("a", CIRCUMFLEX),
+            'Ã' | 'ã' => ("a", TILDE),
+            'Ä' | 'ä' => ("a", DIAERESIS),
+            'Å' | 'å' => ("a", RING),
+            'Ā' | 'ā' => ("a", MACRON),
+            'Ă' | 'ă' => ("a", BREVE),
+            'Ą' | 'ą' => ("a", OGONEK),
+            'Æ' | 'æ' => ("ae", 0),
+            'Ç' | 'ç' => ("c", CEDILLA),
+            'Ć' | 'ć' => ("c", ACUTE),
+            'Č' | 'č' => ("c", CARON),
+            'Ď' | 'ď' => ("d", CARON),
+            'Đ' | 'đ' => ("d", STROKE),
+            'È' | 'è' => ("e", GRAVE),
+            'É' | 'é' => ("e", ACUTE),
+            'Ê' | 'ê' => ("e", CIRCUMFLEX),
+            'Ë' | 'ë' => ("e", DIAERESIS),
+            'Ē' | 'ē' => ("e", MACRON),
+            'Ė' | 'ė' => ("e", DOT),
+            'Ę' | 'ę' => ("e", OGONEK),
+            'Ě' | 'ě' => ("e", CARON),
+            'Ğ' | 'ğ' => ("g", BREVE),
+            'Ì' | 'ì' => ("i", GRAVE),
+            'Í' | 'í' => ("i", ACUTE),
+            'Î' | 'î' => ("i", CIRCUMFLEX),
+            'Ï' | 'ï' => ("i", DIAERESIS),
+            'Ī' | 'ī' => ("i", MACRON),
+            'İ' => ("i", DOT),
+            'Ł' | 'ł' => ("l", STROKE),
+            'Ñ' | 'ñ' => ("n", TILDE),
+            'Ń' | 'ń' => ("n", ACUTE),
+            'Ň' | 'ň' => ("n", CARON),
+            'Ò' | 'ò' => ("o", GRAVE),
+            'Ó' | 'ó' => ("o", ACUTE),
+            'Ô' | 'ô' => ("o", CIRCUMFLEX),
+            'Õ' | 'õ' => ("o", TILDE),
+            'Ö' | 'ö' => ("o", DIAERESIS),
+            'Ø' | 'ø' => ("o", STROKE),
+            'Ō' | 'ō' => ("o", MACRON),
+            'Ő' | 'ő' => ("o", DOUBLE_ACUTE),
+            'Œ' | 'œ' => ("oe", 0),
+            'Ř' | 'ř' => ("r", CARON),
+            'Ś' | 'ś' => ("s", ACUTE),
+            'Ş' | 'ş' => ("s", CEDILLA),
+            'Š' | 'š' => ("s", CARON),
+            'ß' => ("ss", 0),
+            'Ť' | 'ť' => ("t", CARON),
+            'Ù' | 'ù' => ("u", GRAVE),
+            'Ú' | 'ú' => ("u", ACUTE),
+            'Û' | 'û' => ("u", CIRCUMFLEX),
+            'Ü' | 'ü' => ("u", DIAERESIS),
+            'Ū' | 'ū' => ("u", MACRON),
+            'Ů' | 'ů' => ("u", RING),
+            'Ű' | 'ű' => ("u", DOUBLE_ACUTE),
+            'Ý' | 'ý' => ("y", ACUTE),
+            'ÿ' | 'Ÿ' => ("y", DIAERESIS),
+            'Ź' | 'ź' => ("z", ACUTE),
+            'Ż' | 'ż' => ("z", DOT),
+            'Ž' | 'ž' => ("z", CARON),
+            _ => return None,
+        };
+    Some(r)
+}
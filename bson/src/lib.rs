@@ -27,6 +27,10 @@ use misc::bufndx;
 
 extern crate time;
 
+mod collation;
+
+pub use collation::CollationSpec;
+
 #[derive(Debug)]
 pub enum Error {
     // TODO remove Misc
@@ -886,6 +890,16 @@ impl Document {
         v
     }
 
+    // how many bytes to_bson would write, without writing them
+    pub fn bson_len(&self) -> usize {
+        let mut len = 4 + 1;
+        for t in self.pairs.iter() {
+            let (ref ksub, ref vsub) = *t;
+            len = len + 1 + ksub.len() + 1 + vsub.bson_len();
+        }
+        len
+    }
+
     pub fn find_all_strings<'a>(&'a self, dest: &mut Vec<&'a str>) {
         for t in &self.pairs {
             t.1.find_all_strings(dest);
@@ -1292,6 +1306,21 @@ impl Array {
         misc::bytes::copy_into(&i32_to_bytes_le(len as i32), &mut w[start .. start + 4]);
     }
 
+    fn bson_len(&self) -> usize {
+        let mut len = 4 + 1;
+        for (i, vsub) in self.items.iter().enumerate() {
+            // the key is the index in decimal
+            let mut digits = 1;
+            let mut rest = i / 10;
+            while rest > 0 {
+                digits = digits + 1;
+                rest = rest / 10;
+            }
+            len = len + 1 + digits + 1 + vsub.bson_len();
+        }
+        len
+    }
+
     fn find_all_strings<'a>(&'a self, dest: &mut Vec<&'a str>) {
         for v in &self.items {
             v.find_all_strings(dest);
@@ -2028,54 +2057,55 @@ impl Value {
 
     pub fn get_weight_from_index_entry(k: &[u8]) -> Result<i32> {
         let n = 1 + k.iter().rposition(|v| *v==0).expect("TODO");
+        let (w, _) = try!(Value::decode_weight_from_index_entry(&k[n ..]));
+        Ok(w)
+    }
+
+    // the weight at the start of k, which is the type order byte of a
+    // number encoded for an index, the way a text index entry has it.
+    // also returns how many bytes of k it took up, since whatever comes
+    // after it in the key isn't part of it.
+    pub fn decode_weight_from_index_entry(k: &[u8]) -> Result<(i32, usize)> {
         let ord_shouldbe = Value::BInt32(0).get_type_order() as u8;
-        if k[n] != ord_shouldbe {
+        if k.len() < 2 || k[0] != ord_shouldbe {
             return Err(Error::Misc(String::from("bad type order byte")));
         }
-        let e = (k[n+1] as i32) - 23;
+        let e = (k[1] as i32) - 23;
         // exponent is number of times the mantissa must be multiplied times 100
         // if we assume that all mantissa digits are to the right of the decimal point.
         if e <= 0 {
             return Err(Error::Misc(String::from("bad e")));
         }
         let e = e as usize;
-        let n = n + 2;
-        let a = &k[n .. ];
 
         // remaining bytes are mantissa, base 100
         // last byte of mantissa is 2*x
         // previous bytes are 2*x+1
-
-        //printfn "mantissa: %A" a
-        //printfn "e: %d" e
-
-        // we have an array of centimal digits here, all of
-        // which appear to the right of the decimal point.
         //
         // we know from the context that this
-        // SHOULD be an integer.
-
-        let a =
-            if a.len() > e {
-                &a[0 .. e]
-            } else {
-                a
-            };
-
-        let mut v = a.iter().fold(0, |v,d| {
-            let b = (d >> 1) as i32;
-            v * 100 + b
-        });
-
-        let need = e - a.len();
-        if need > 0 {
-            for _ in 0 .. need {
-                v = v * 100;
+        // SHOULD be an integer, so only the first e digits count.
+        let mut n = 2;
+        let mut digits = 0;
+        let mut v = 0;
+        loop {
+            if n >= k.len() {
+                return Err(Error::Misc(String::from("weight runs past the end of the key")));
+            }
+            let d = k[n];
+            n = n + 1;
+            if digits < e {
+                v = v * 100 + ((d >> 1) as i32);
+                digits = digits + 1;
+            }
+            if (d & 1) == 0 {
+                break;
             }
         }
+        for _ in digits .. e {
+            v = v * 100;
+        }
 
-        //printfn "weight: %d" v
-        Ok(v)
+        Ok((v, n))
     }
 
     pub fn get_type_order(&self) -> i32 {
@@ -2109,6 +2139,12 @@ impl Value {
     }
 
     pub fn encode_for_index_into(&self, w: &mut Vec<u8>) {
+        self.encode_for_index_into_collated(w, None)
+    }
+
+    // with a collation, a string is encoded as its sort key, so that the
+    // bytes sort in collated order.  nothing else is any different.
+    pub fn encode_for_index_into_collated(&self, w: &mut Vec<u8>, c: Option<&CollationSpec>) {
         w.push(self.get_type_order() as u8);
         match self {
             &Value::BBoolean(b) => if b { w.push(1u8) } else { w.push(0u8) },
@@ -2117,7 +2153,12 @@ impl Value {
             &Value::BMaxKey => (),
             &Value::BUndefined => (),
             &Value::BObjectID(ref a) => w.push_all(a),
-            &Value::BString(ref s) => vec_push_c_string(w, &s),
+            &Value::BString(ref s) => {
+                match c {
+                    Some(c) => vec_push_c_string(w, &c.sort_key(s)),
+                    None => vec_push_c_string(w, &s),
+                }
+            },
             &Value::BDouble(f) => misc::Sqlite4Num::from_f64(f).encode_for_index(w),
             &Value::BInt64(n) => misc::Sqlite4Num::from_i64(n).encode_for_index(w),
             &Value::BInt32(n) => misc::Sqlite4Num::from_i64(n as i64).encode_for_index(w),
@@ -2132,7 +2173,7 @@ impl Value {
                 w.push_all(&i32_to_bytes_be(bd.pairs.len() as i32));
                 for t in &bd.pairs {
                     vec_push_c_string(w, &t.0);;
-                    t.1.encode_for_index_into(w);
+                    t.1.encode_for_index_into_collated(w, c);
                 }
             },
             &Value::BArray(ref ba) => {
@@ -2141,7 +2182,7 @@ impl Value {
 
                 w.push_all(&i32_to_bytes_be(ba.items.len() as i32));
                 for v in &ba.items {
-                    v.encode_for_index_into(w);
+                    v.encode_for_index_into_collated(w, c);
                 }
             },
             &Value::BRegex(ref expr, ref opt) => {
@@ -2167,11 +2208,15 @@ impl Value {
 
     // TODO dislike neg as a bool param
     pub fn encode_one_for_index(v: &Value, neg: bool) -> Vec<u8> {
+        Self::encode_one_for_index_collated(v, neg, None)
+    }
+
+    pub fn encode_one_for_index_collated(v: &Value, neg: bool, c: Option<&CollationSpec>) -> Vec<u8> {
         // TODO
         // I hate that this requires an alloc just so we can do neg
         // as a second step.
         let mut a = Vec::new();
-        v.encode_for_index_into(&mut a);
+        v.encode_for_index_into_collated(&mut a, c);
         if neg {
             for i in 0 .. a.len() {
                 let b = a[i];
@@ -2182,14 +2227,18 @@ impl Value {
     }
 
     pub fn push_encode_multi_for_index(r: &mut Vec<u8>, vals: &Vec<(&Value, bool)>, extra: Option<&Vec<(&Value, bool)>>) {
+        Self::push_encode_multi_for_index_collated(r, vals, extra, None)
+    }
+
+    pub fn push_encode_multi_for_index_collated(r: &mut Vec<u8>, vals: &Vec<(&Value, bool)>, extra: Option<&Vec<(&Value, bool)>>, c: Option<&CollationSpec>) {
         for &(v, neg) in vals {
-            let a = Self::encode_one_for_index(v, neg);
+            let a = Self::encode_one_for_index_collated(v, neg, c);
             r.push_all(&a);
         }
         match extra {
             Some(extra) => {
                 for &(v, neg) in extra {
-                    let a = Self::encode_one_for_index(v, neg);
+                    let a = Self::encode_one_for_index_collated(v, neg, c);
                     r.push_all(&a);
                 }
             },
@@ -2199,8 +2248,12 @@ impl Value {
     }
 
     pub fn encode_multi_for_index(vals: &Vec<(&Value, bool)>, extra: Option<&Vec<(&Value, bool)>>) -> Vec<u8> {
+        Self::encode_multi_for_index_collated(vals, extra, None)
+    }
+
+    pub fn encode_multi_for_index_collated(vals: &Vec<(&Value, bool)>, extra: Option<&Vec<(&Value, bool)>>, c: Option<&CollationSpec>) -> Vec<u8> {
         let mut r = Vec::new();
-        Self::push_encode_multi_for_index(&mut r, vals, extra);
+        Self::push_encode_multi_for_index_collated(&mut r, vals, extra, c);
         r
     }
 
@@ -2272,5 +2325,29 @@ impl Value {
         }
     }
 
+    // how many bytes to_bson would write, without writing them
+    pub fn bson_len(&self) -> usize {
+        match self {
+            &Value::BDouble(_) => 8,
+            &Value::BInt32(_) => 4,
+            &Value::BDateTime(_) => 8,
+            &Value::BTimeStamp(_) => 8,
+            &Value::BInt64(_) => 8,
+            &Value::BString(ref s) => 4 + s.len() + 1,
+            &Value::BObjectID(_) => 12,
+            &Value::BBoolean(_) => 1,
+            &Value::BNull => 0,
+            &Value::BMinKey => 0,
+            &Value::BMaxKey => 0,
+            &Value::BRegex(ref expr, ref opt) => expr.len() + 1 + opt.len() + 1,
+            &Value::BUndefined => 0,
+            &Value::BJSCode(ref s) => 4 + s.len() + 1,
+            &Value::BJSCodeWithScope(ref s, ref scope) => 4 + 4 + s.len() + 1 + scope.bson_len(),
+            &Value::BBinary(_, ref ba) => 4 + 1 + ba.len(),
+            &Value::BArray(ref ba) => ba.bson_len(),
+            &Value::BDocument(ref bd) => bd.bson_len(),
+        }
+    }
+
 }
 
@@ -52,3 +52,87 @@ fn bson_simple() {
 }
 
 
+
+fn spec(strength: i32, numeric: bool) -> bson::CollationSpec {
+    bson::CollationSpec {
+        strength: strength,
+        case_level: false,
+        numeric_ordering: numeric,
+    }
+}
+
+// the sort keys of a, in the order they sort
+fn sorted_by_key(c: &bson::CollationSpec, a: &[&str]) -> Vec<String> {
+    let mut a = a.iter().map(|s| (c.sort_key(s), String::from(*s))).collect::<Vec<_>>();
+    a.sort();
+    a.into_iter().map(|(_, s)| s).collect()
+}
+
+#[test]
+fn sort_key_strength() {
+    let c = spec(1, false);
+    assert_eq!(c.sort_key("resume"), c.sort_key("Résumé"));
+    assert_eq!(sorted_by_key(&c, &["c", "B", "a"]), vec!["a", "B", "c"]);
+
+    let c = spec(2, false);
+    assert!(c.sort_key("resume") < c.sort_key("résumé"));
+    assert_eq!(c.sort_key("resume"), c.sort_key("RESUME"));
+    // base letters win over accents
+    assert!(c.sort_key("résumé") < c.sort_key("resumf"));
+
+    let c = spec(3, false);
+    // lower case first
+    assert!(c.sort_key("a") < c.sort_key("A"));
+    assert!(c.sort_key("A") < c.sort_key("b"));
+
+    let mut c = spec(1, false);
+    c.case_level = true;
+    assert!(c.sort_key("a") < c.sort_key("A"));
+    assert_eq!(c.sort_key("a"), c.sort_key("á"));
+}
+
+#[test]
+fn sort_key_numeric() {
+    let c = spec(1, false);
+    assert!(c.sort_key("item10") < c.sort_key("item2"));
+
+    let c = spec(1, true);
+    assert_eq!(sorted_by_key(&c, &["item10", "item2", "item1"]), vec!["item1", "item2", "item10"]);
+    assert_eq!(c.sort_key("7"), c.sort_key("007"));
+    assert_eq!(c.sort_key("0"), c.sort_key("000"));
+    assert!(c.sort_key("0") < c.sort_key("1"));
+    assert!(c.sort_key("x0y") < c.sort_key("x1"));
+    // digits sort below letters
+    assert!(c.sort_key("9") < c.sort_key("a"));
+}
+
+#[test]
+fn sort_key_long_digit_runs() {
+    let c = spec(1, true);
+    let nines = |n: usize| (0 .. n).map(|_| '9').collect::<String>();
+    let one_then_zeros = |n: usize| format!("1{}", (0 .. n - 1).map(|_| '0').collect::<String>());
+    for &n in &[9, 10, 47, 48, 49, 60, 100, 1000] {
+        // still below the letters, no matter how long
+        assert!(c.sort_key(&nines(n)) < c.sort_key("a"), "{}", n);
+        assert!(c.sort_key(&format!("x{}", nines(n))) < c.sort_key("xa"), "{}", n);
+        // and a longer number is still a bigger one
+        assert!(c.sort_key(&nines(n)) < c.sort_key(&one_then_zeros(n + 1)), "{}", n);
+        assert!(c.sort_key(&one_then_zeros(n)) < c.sort_key(&nines(n)), "{}", n);
+        // the rest of the string only matters after the number
+        assert!(c.sort_key(&format!("{}z", nines(n))) < c.sort_key(&format!("{}a", one_then_zeros(n + 1))), "{}", n);
+    }
+}
+
+#[test]
+fn sort_key_index_encoding() {
+    // collated index keys sort the same as the sort keys themselves
+    let c = spec(1, true);
+    let words = ["b", "A", "a10", "a9", "á", "B2"];
+    let mut encoded = words.iter().map(|s| {
+        let v = bson::Value::BString(String::from(*s));
+        (bson::Value::encode_one_for_index_collated(&v, false, Some(&c)), String::from(*s))
+    }).collect::<Vec<_>>();
+    encoded.sort();
+    let by_index = encoded.into_iter().map(|(_, s)| s).collect::<Vec<_>>();
+    assert_eq!(by_index, sorted_by_key(&c, &words));
+}
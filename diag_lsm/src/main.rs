@@ -467,6 +467,22 @@ fn add_random(name: &str, seed: usize, count_groups: usize, count_per_group: usi
     }
 }
 
+fn dump(name: &str, file: String) -> Result<(),lsm::Error> {
+    let db = try!(lsm::DatabaseFile::new(String::from(name), lsm::DEFAULT_SETTINGS));
+    let mut fs = try!(std::fs::File::create(&file));
+    let count = try!(lsm::dump(&db, &mut fs));
+    println!("dumped {} pairs", count);
+    Ok(())
+}
+
+fn load(name: &str, file: String) -> Result<(),lsm::Error> {
+    let mut fs = try!(std::fs::File::open(&file));
+    let db = try!(lsm::load(&mut fs, String::from(name), lsm::DEFAULT_SETTINGS));
+    let (incoming, waiting, regular) = try!(db.list_segments());
+    println!("loaded into {} segment(s)", incoming.len() + waiting.len() + regular.len());
+    Ok(())
+}
+
 fn result_main() -> Result<(),lsm::Error> {
     let args: Vec<_> = std::env::args().collect();
     println!("args: {:?}", args);
@@ -616,6 +632,22 @@ fn result_main() -> Result<(),lsm::Error> {
         "list_free_blocks" => {
             list_free_blocks(name)
         },
+        "dump" => {
+            println!("usage: dump file");
+            if args.len() < 4 {
+                return Err(lsm::Error::Misc(String::from("too few args")));
+            }
+            let file = args[3].clone();
+            dump(name, file)
+        },
+        "load" => {
+            println!("usage: load file");
+            if args.len() < 4 {
+                return Err(lsm::Error::Misc(String::from("too few args")));
+            }
+            let file = args[3].clone();
+            load(name, file)
+        },
         _ => {
             Err(lsm::Error::Misc(String::from("unknown command")))
         },
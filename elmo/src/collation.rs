@@ -0,0 +1,126 @@
+/*
+    Copyright 2014-2016 Zumero, LLC
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+
+// collation, for comparing strings the way people expect instead of by
+// their bytes.  there is no ICU here, so every locale gets the same
+// ordering, which folds case and the accents of the latin alphabets.
+// the locale is kept only so that two collations can be compared.
+//
+// everything works through sort keys, which come from bson, since the
+// storage engines need them to encode the keys of a collated index.
+// here, the matcher, the sorts and $group just compare sort keys instead
+// of the strings.
+
+use std::borrow::Cow;
+
+use super::Result;
+use super::Error;
+
+extern crate bson;
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct Collation {
+    pub locale: String,
+    pub spec: bson::CollationSpec,
+}
+
+impl Collation {
+    // None means the simple collation, which is the same as none at all
+    pub fn from_document(d: &bson::Document) -> Result<Option<Collation>> {
+        let mut locale = None;
+        let mut strength = 3;
+        let mut case_level = false;
+        let mut numeric_ordering = false;
+        for &(ref k, ref v) in &d.pairs {
+            match (k.as_str(), v) {
+                ("locale", &bson::Value::BString(ref s)) => locale = Some(s.clone()),
+                ("strength", v) if v.is_numeric() => strength = try!(v.numeric_to_i32()),
+                ("caseLevel", &bson::Value::BBoolean(b)) => case_level = b,
+                ("numericOrdering", &bson::Value::BBoolean(b)) => numeric_ordering = b,
+                _ => return Err(Error::MongoCode(2, format!("invalid collation field: {}", k))),
+            }
+        }
+        let locale = match locale {
+            Some(s) => s,
+            None => return Err(Error::MongoCode(2, String::from("collation requires a locale"))),
+        };
+        if locale == "simple" {
+            if d.pairs.len() > 1 {
+                return Err(Error::MongoCode(2, String::from("the simple collation cannot have other options")));
+            }
+            return Ok(None);
+        }
+        if locale.is_empty() {
+            return Err(Error::MongoCode(2, String::from("collation locale cannot be empty")));
+        }
+        if strength < 1 || strength > 5 {
+            return Err(Error::MongoCode(2, format!("collation strength must be 1 to 5: {}", strength)));
+        }
+        Ok(Some(Collation {
+            locale: locale,
+            spec: bson::CollationSpec {
+                strength: strength,
+                case_level: case_level,
+                numeric_ordering: numeric_ordering,
+            },
+        }))
+    }
+
+    // the collation option of a collection or an index
+    pub fn from_options(options: &bson::Document) -> Result<Option<Collation>> {
+        match options.get("collation") {
+            None => Ok(None),
+            Some(&bson::Value::BDocument(ref d)) => Collation::from_document(d),
+            Some(_) => Err(Error::MongoCode(2, String::from("collation must be a document"))),
+        }
+    }
+
+    pub fn sort_key(&self, s: &str) -> String {
+        self.spec.sort_key(s)
+    }
+
+    // the strings in v, including the ones inside arrays and documents,
+    // replaced by their sort keys
+    pub fn key_value(&self, v: &bson::Value) -> bson::Value {
+        match v {
+            &bson::Value::BString(ref s) => bson::Value::BString(self.sort_key(s)),
+            &bson::Value::BArray(ref a) => {
+                bson::Value::BArray(bson::Array {items: a.items.iter().map(|v| self.key_value(v)).collect()})
+            },
+            &bson::Value::BDocument(ref d) => {
+                bson::Value::BDocument(bson::Document {pairs: d.pairs.iter().map(|&(ref k, ref v)| (k.clone(), self.key_value(v))).collect()})
+            },
+            _ => v.clone(),
+        }
+    }
+}
+
+fn has_strings(v: &bson::Value) -> bool {
+    match v {
+        &bson::Value::BString(_) => true,
+        &bson::Value::BArray(ref a) => a.items.iter().any(has_strings),
+        &bson::Value::BDocument(ref d) => d.pairs.iter().any(|&(_, ref v)| has_strings(v)),
+        _ => false,
+    }
+}
+
+// v as it should be compared under the collation, if any
+pub fn collate<'a>(c: Option<&Collation>, v: &'a bson::Value) -> Cow<'a, bson::Value> {
+    match c {
+        Some(c) if has_strings(v) => Cow::Owned(c.key_value(v)),
+        _ => Cow::Borrowed(v),
+    }
+}
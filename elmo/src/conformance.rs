@@ -0,0 +1,2204 @@
+/*
+    Copyright 2014-2016 Zumero, LLC
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+
+// tests that every storage engine should pass.  each engine's tests/lib.rs
+// runs all of them with conformance_tests!, which gives each one a factory
+// for a fresh, empty database.  each one uses its own db name, so they
+// don't step on each other if they happen to share a database.
+//
+// mostly these go through Connection, the way the server does.  the ones
+// that need to poke at the StorageConnection directly (rollback, index
+// scans with specific bounds) can do so because this module lives inside
+// the elmo crate.
+
+// one #[test] for each of the tests below.  $name is the name of the
+// test, for an engine which wants it in the file name, and $factory makes
+// the factory.  it goes inside a fn which returns Result, so it can use
+// try!.  a new test gets added here, and every engine picks it up.
+#[macro_export]
+macro_rules! conformance_tests {
+    ($name:ident => $factory:expr) => {
+        conformance_tests!(@each $name => $factory;
+            collections,
+            indexes,
+            rename,
+            unique_indexes,
+            index_bounds,
+            rollback,
+            savepoints,
+            writes_in_one_tx,
+            bulk_load,
+            aggregate_out,
+            background_index,
+            background_index_resume,
+            capped,
+            capped_totals,
+            validation,
+            partial_index,
+            collation,
+            multikey,
+            id_index,
+            index_plans,
+            query_stats,
+            index_intersection,
+            index_sort,
+            sort_spill,
+            text_search,
+            geo,
+            geo_sphere
+            );
+    };
+    (@each $name:ident => $factory:expr; $($test:ident),*) => {
+        $(
+            #[test]
+            fn $test() {
+                fn f() -> $crate::Result<()> {
+                    let $name = stringify!($test);
+                    let factory = $factory;
+                    $crate::conformance::$test(&factory)
+                }
+                let r = f();
+                println!("{:?}", r);
+                assert!(r.is_ok());
+            }
+        )*
+    };
+}
+
+extern crate bson;
+
+use super::Result;
+use super::Error;
+use super::Connection;
+use super::ConnectionFactory;
+use super::IndexInfo;
+use super::QueryBounds;
+use super::QueryPlan;
+use super::CachedPlan;
+use super::PlanCacheEntry;
+use super::SORT_BUFFER_BYTES;
+use super::Row;
+use super::matcher;
+use super::stats;
+use misc::sort;
+
+fn index(db: &str, coll: &str, name: &str, spec: bson::Document, options: bson::Document) -> IndexInfo {
+    IndexInfo {
+        db: String::from(db),
+        coll: String::from(coll),
+        name: String::from(name),
+        spec: spec,
+        options: options,
+    }
+}
+
+fn ascending(k: &str) -> bson::Document {
+    let mut spec = bson::Document::new();
+    spec.set_i32(k, 1);
+    spec
+}
+
+fn unique() -> bson::Document {
+    let mut options = bson::Document::new();
+    options.set_bool("unique", true);
+    options
+}
+
+fn int(n: i32) -> bson::Value {
+    bson::Value::BInt32(n)
+}
+
+fn ints(a: &[i32]) -> bson::Value {
+    bson::Value::BArray(bson::Array {items: a.iter().map(|&n| int(n)).collect()})
+}
+
+fn string(s: &str) -> bson::Value {
+    bson::Value::BString(String::from(s))
+}
+
+fn boolean(b: bool) -> bson::Value {
+    bson::Value::BBoolean(b)
+}
+
+// a doc with the _id and then whatever other fields
+fn doc_with(id: i32, fields: Vec<(&str, bson::Value)>) -> bson::Document {
+    let mut d = bson::Document::new();
+    d.set_i32("_id", id);
+    for (k, v) in fields {
+        d.set(k, v);
+    }
+    d
+}
+
+fn doc(id: i32, k: &str, n: i32) -> bson::Document {
+    doc_with(id, vec![(k, int(n))])
+}
+
+// the docs some of the planner tests share
+fn ab_doc(id: i32) -> bson::Document {
+    doc_with(id, vec![("a", int(id % 5)), ("b", int(id % 4)), ("c", int(id % 3))])
+}
+
+fn flag_doc(id: i32) -> bson::Document {
+    doc_with(id, vec![("flag", int(id % 2)), ("user", int(id))])
+}
+
+fn time_doc(id: i32) -> bson::Document {
+    doc_with(id, vec![("t", int(id)), ("g", int(id % 3)), ("z", int((id * 7) % 50))])
+}
+
+// { k: v }
+fn field_query(k: &str, v: bson::Value) -> bson::Document {
+    let mut q = bson::Document::new();
+    q.set(k, v);
+    q
+}
+
+// { op: n, ... }, for the comparisons of a field_query
+fn ops(a: &[(&str, i32)]) -> bson::Value {
+    let mut d = bson::Document::new();
+    for &(op, n) in a {
+        d.set_i32(op, n);
+    }
+    d.into_value()
+}
+
+fn eq_query(k: &str, n: i32) -> bson::Document {
+    field_query(k, int(n))
+}
+
+fn op_query(k: &str, op: &str, n: i32) -> bson::Document {
+    field_query(k, ops(&[(op, n)]))
+}
+
+fn or_query(a: bson::Document, b: bson::Document) -> bson::Document {
+    let mut q = bson::Document::new();
+    q.set_array("$or", bson::Array {items: vec![a.into_value(), b.into_value()]});
+    q
+}
+
+fn row_id(r: &Row) -> i32 {
+    match r.doc.as_document().unwrap().get("_id") {
+        Some(&bson::Value::BInt32(n)) => n,
+        _ => panic!(),
+    }
+}
+
+fn sorted(mut a: Vec<i32>) -> Vec<i32> {
+    a.sort();
+    a
+}
+
+fn insert_all(conn: &Connection, db: &str, coll: &str, mut docs: Vec<bson::Document>) -> Result<()> {
+    for r in try!(conn.insert(db, coll, &mut docs, true)) {
+        try!(r);
+    }
+    Ok(())
+}
+
+// the ids in the order they come, which for a tail is up to the point
+// where it runs out, for now
+fn row_ids(seq: &mut Iterator<Item=Result<Row>>) -> Result<Vec<i32>> {
+    let rows = try!(seq.collect::<Result<Vec<_>>>());
+    Ok(rows.iter().map(row_id).collect())
+}
+
+// the ids in the order find gives them
+fn find_ids(conn: &Connection, db: &str, coll: &str, q: bson::Document, orderby: Option<bson::Value>, collation: Option<bson::Document>) -> Result<Vec<i32>> {
+    let mut seq = try!(conn.find(db, coll, q, orderby, None, None, None, None, None, collation));
+    row_ids(&mut seq)
+}
+
+// the ids of the docs which match, sorted
+fn query_ids(conn: &Connection, db: &str, coll: &str, q: bson::Document) -> Result<Vec<i32>> {
+    Ok(sorted(try!(find_ids(conn, db, coll, q, None, None))))
+}
+
+fn all_ids(conn: &Connection, db: &str, coll: &str) -> Result<Vec<i32>> {
+    query_ids(conn, db, coll, bson::Document::new())
+}
+
+// in the order they were inserted, not sorted
+fn natural_ids(conn: &Connection, db: &str, coll: &str) -> Result<Vec<i32>> {
+    let mut hint = bson::Document::new();
+    hint.set_i32("$natural", 1);
+    let mut seq = try!(conn.find(db, coll, bson::Document::new(), None, None, None, None, Some(hint.into_value()), None, None));
+    row_ids(&mut seq)
+}
+
+fn has_collection(conn: &Connection, db: &str, coll: &str) -> Result<bool> {
+    let a = try!(conn.list_all_collections());
+    Ok(a.iter().any(|c| c.db == db && c.coll == coll))
+}
+
+fn index_names(conn: &Connection, db: &str, coll: &str) -> Result<Vec<String>> {
+    let a = try!(conn.list_indexes());
+    let mut a = a.into_iter().filter(|ndx| ndx.db == db && ndx.coll == coll).map(|ndx| ndx.name).collect::<Vec<_>>();
+    a.sort();
+    Ok(a)
+}
+
+fn is_duplicate_key(r: &Result<()>) -> bool {
+    match r {
+        &Err(Error::MongoCode(11000, _)) => true,
+        _ => false,
+    }
+}
+
+fn has_code<T>(r: &Result<T>, code: i32) -> bool {
+    match r {
+        &Err(Error::MongoCode(n, _)) => n == code,
+        _ => false,
+    }
+}
+
+pub fn collections(factory: &ConnectionFactory) -> Result<()> {
+    let db = "conf_collections";
+    let conn = try!(factory.open());
+
+    assert!(try!(conn.create_collection(db, "a", bson::Document::new())));
+    assert!(!try!(conn.create_collection(db, "a", bson::Document::new())));
+    assert!(try!(has_collection(&conn, db, "a")));
+    assert_eq!(try!(index_names(&conn, db, "a")), vec![String::from("_id_")]);
+
+    // no _id index if asked not to
+    let mut options = bson::Document::new();
+    options.set_bool("autoIndexId", false);
+    assert!(try!(conn.create_collection(db, "b", options)));
+    assert_eq!(try!(index_names(&conn, db, "b")), Vec::<String>::new());
+
+    // inserting creates the collection implicitly
+    try!(insert_all(&conn, db, "c", vec![doc(1, "x", 1)]));
+    assert!(try!(has_collection(&conn, db, "c")));
+    assert_eq!(try!(index_names(&conn, db, "c")), vec![String::from("_id_")]);
+
+    assert!(try!(conn.drop_collection(db, "a")));
+    assert!(!try!(conn.drop_collection(db, "a")));
+    assert!(!try!(has_collection(&conn, db, "a")));
+    assert_eq!(try!(index_names(&conn, db, "a")), Vec::<String>::new());
+
+    // and a collection can come back after being dropped, empty
+    try!(insert_all(&conn, db, "c", vec![doc(2, "x", 2)]));
+    assert!(try!(conn.drop_collection(db, "c")));
+    assert!(try!(conn.create_collection(db, "c", bson::Document::new())));
+    assert_eq!(try!(all_ids(&conn, db, "c")), Vec::<i32>::new());
+
+    assert!(try!(conn.drop_database(db)));
+    assert!(!try!(has_collection(&conn, db, "b")));
+    assert!(!try!(conn.drop_database(db)));
+
+    Ok(())
+}
+
+pub fn indexes(factory: &ConnectionFactory) -> Result<()> {
+    let db = "conf_indexes";
+    let conn = try!(factory.open());
+
+    // two indexes on a collection that doesn't exist yet, in one call.
+    // the collection gets created along the way.
+    let a = vec![
+        index(db, "c", "x_1", ascending("x"), bson::Document::new()),
+        index(db, "c", "y_1", ascending("y"), bson::Document::new()),
+        ];
+    assert_eq!(try!(conn.create_indexes(a.clone())), vec![true, true]);
+    assert_eq!(try!(conn.create_indexes(a)), vec![false, false]);
+    assert_eq!(try!(index_names(&conn, db, "c")), vec![String::from("_id_"), String::from("x_1"), String::from("y_1")]);
+    let found = try!(conn.list_all_collections()).into_iter().filter(|c| c.db == db).count();
+    assert_eq!(found, 1);
+
+    // same name, different keys
+    let r = conn.create_indexes(vec![index(db, "c", "x_1", ascending("z"), bson::Document::new())]);
+    assert!(r.is_err());
+
+    // the docs get index entries whether they were there first or not
+    try!(insert_all(&conn, db, "c", vec![doc(1, "x", 5)]));
+    assert_eq!(try!(conn.create_indexes(vec![index(db, "c", "w_1", ascending("w"), bson::Document::new())])), vec![true]);
+    let mut d = doc(2, "x", 5);
+    d.set_i32("w", 3);
+    try!(insert_all(&conn, db, "c", vec![d]));
+    let mut q = bson::Document::new();
+    q.set_i32("x", 5);
+    let seq = try!(conn.find(db, "c", q, None, None, None, None, None, None, None));
+    let rows = try!(seq.collect::<Result<Vec<_>>>());
+    assert_eq!(sorted(rows.iter().map(row_id).collect()), vec![1, 2]);
+
+    let (before, deleted) = try!(conn.delete_indexes(db, "c", &bson::Value::BString(String::from("x_1"))));
+    assert_eq!((before, deleted), (4, 1));
+    assert_eq!(try!(index_names(&conn, db, "c")), vec![String::from("_id_"), String::from("w_1"), String::from("y_1")]);
+    let (_, deleted) = try!(conn.delete_indexes(db, "c", &bson::Value::BString(String::from("x_1"))));
+    assert_eq!(deleted, 0);
+
+    // all but _id_
+    let (_, deleted) = try!(conn.delete_indexes(db, "c", &bson::Value::BString(String::from("*"))));
+    assert_eq!(deleted, 2);
+    assert_eq!(try!(index_names(&conn, db, "c")), vec![String::from("_id_")]);
+    assert_eq!(try!(all_ids(&conn, db, "c")), vec![1, 2]);
+
+    Ok(())
+}
+
+pub fn rename(factory: &ConnectionFactory) -> Result<()> {
+    let db = "conf_rename";
+    let conn = try!(factory.open());
+
+    try!(insert_all(&conn, db, "a", vec![doc(1, "x", 1), doc(2, "x", 2)]));
+    try!(conn.create_indexes(vec![index(db, "a", "x_1", ascending("x"), bson::Document::new())]));
+    try!(insert_all(&conn, db, "t", vec![doc(9, "x", 9)]));
+
+    // the data and the indexes go with it
+    assert!(!try!(conn.rename_collection("conf_rename.a", "conf_rename.b", false)));
+    assert!(!try!(has_collection(&conn, db, "a")));
+    assert_eq!(try!(index_names(&conn, db, "a")), Vec::<String>::new());
+    assert_eq!(try!(all_ids(&conn, db, "b")), vec![1, 2]);
+    assert_eq!(try!(index_names(&conn, db, "b")), vec![String::from("_id_"), String::from("x_1")]);
+    let mut q = bson::Document::new();
+    q.set_i32("x", 2);
+    let seq = try!(conn.find(db, "b", q, None, None, None, None, None, None, None));
+    let rows = try!(seq.collect::<Result<Vec<_>>>());
+    assert_eq!(rows.iter().map(row_id).collect::<Vec<_>>(), vec![2]);
+
+    // the target exists and we weren't told to drop it
+    assert!(conn.rename_collection("conf_rename.b", "conf_rename.t", false).is_err());
+    assert_eq!(try!(all_ids(&conn, db, "b")), vec![1, 2]);
+    assert_eq!(try!(all_ids(&conn, db, "t")), vec![9]);
+
+    // and now we were
+    try!(conn.rename_collection("conf_rename.b", "conf_rename.t", true));
+    assert!(!try!(has_collection(&conn, db, "b")));
+    assert_eq!(try!(all_ids(&conn, db, "t")), vec![1, 2]);
+    assert_eq!(try!(index_names(&conn, db, "t")), vec![String::from("_id_"), String::from("x_1")]);
+
+    // the old name is free to use again
+    try!(insert_all(&conn, db, "b", vec![doc(3, "x", 3)]));
+    assert_eq!(try!(all_ids(&conn, db, "b")), vec![3]);
+
+    assert!(conn.rename_collection("conf_rename.t", "conf_rename.system.foo", false).is_err());
+
+    Ok(())
+}
+
+pub fn unique_indexes(factory: &ConnectionFactory) -> Result<()> {
+    let db = "conf_unique";
+    let conn = try!(factory.open());
+
+    try!(conn.create_indexes(vec![index(db, "c", "x_1", ascending("x"), unique())]));
+
+    // the second one in the same batch collides with the first.  this
+    // batch is not ordered, so the third one still goes in.
+    let mut docs = vec![doc(1, "x", 1), doc(2, "x", 1), doc(3, "x", 3)];
+    let results = try!(conn.insert(db, "c", &mut docs, false));
+    assert_eq!(results.len(), 3);
+    assert!(results[0].is_ok());
+    assert!(is_duplicate_key(&results[1]));
+    assert!(results[2].is_ok());
+    assert_eq!(try!(all_ids(&conn, db, "c")), vec![1, 3]);
+
+    // and across transactions
+    let mut docs = vec![doc(4, "x", 3)];
+    let results = try!(conn.insert(db, "c", &mut docs, true));
+    assert!(is_duplicate_key(&results[0]));
+    assert_eq!(try!(all_ids(&conn, db, "c")), vec![1, 3]);
+
+    // the failed doc left nothing behind in the index
+    let mut q = bson::Document::new();
+    q.set_i32("x", 3);
+    let seq = try!(conn.find(db, "c", q, None, None, None, None, None, None, None));
+    let rows = try!(seq.collect::<Result<Vec<_>>>());
+    assert_eq!(rows.iter().map(row_id).collect::<Vec<_>>(), vec![3]);
+
+    // _id is unique too
+    let mut docs = vec![doc(1, "x", 7)];
+    let results = try!(conn.insert(db, "c", &mut docs, true));
+    assert!(is_duplicate_key(&results[0]));
+    assert_eq!(try!(all_ids(&conn, db, "c")), vec![1, 3]);
+
+    // deleting a doc frees up its key
+    let mut q = bson::Document::new();
+    q.set_i32("_id", 3);
+    let mut d = bson::Document::new();
+    d.set_document("q", q);
+    d.set_i32("limit", 1);
+    assert_eq!(try!(conn.delete(db, "c", vec![d])), 1);
+    try!(insert_all(&conn, db, "c", vec![doc(5, "x", 3)]));
+    assert_eq!(try!(all_ids(&conn, db, "c")), vec![1, 5]);
+
+    // an ordered batch stops at the first failure, but keeps what came
+    // before it
+    let mut docs = vec![doc(6, "x", 6), doc(7, "x", 1), doc(8, "x", 8)];
+    let results = try!(conn.insert(db, "c", &mut docs, true));
+    assert_eq!(results.len(), 2);
+    assert!(results[0].is_ok());
+    assert!(is_duplicate_key(&results[1]));
+    assert_eq!(try!(all_ids(&conn, db, "c")), vec![1, 5, 6]);
+
+    // a unique index can't be built over docs that already collide
+    try!(insert_all(&conn, db, "d", vec![doc(1, "y", 1), doc(2, "y", 1)]));
+    let r = conn.create_indexes(vec![index(db, "d", "y_1", ascending("y"), unique())]);
+    assert!(r.is_err());
+    assert_eq!(try!(index_names(&conn, db, "d")), vec![String::from("_id_")]);
+    assert_eq!(try!(all_ids(&conn, db, "d")), vec![1, 2]);
+
+    Ok(())
+}
+
+pub fn index_bounds(factory: &ConnectionFactory) -> Result<()> {
+    let db = "conf_bounds";
+    let conn = try!(factory.open());
+
+    try!(conn.create_indexes(vec![index(db, "c", "n_1", ascending("n"), bson::Document::new())]));
+    let mut docs = vec![];
+    for i in 0 .. 10 {
+        // _id and n run in opposite directions, so a scan that
+        // accidentally used the wrong index would notice
+        docs.push(doc(i, "n", 9 - i));
+    }
+    try!(insert_all(&conn, db, "c", docs));
+
+    let reader = try!(conn.conn.begin_read());
+    let ndx = try!(reader.list_indexes(Some((db, "c")))).into_iter().filter(|ndx| ndx.name == "n_1").next().unwrap();
+    let scan = |bounds: QueryBounds| -> Result<Vec<i32>> {
+        let seq = try!(reader.get_reader_regular_index_scan(&ndx, bounds));
+        let rows = try!(seq.collect::<Result<Vec<_>>>());
+        Ok(sorted(rows.iter().map(row_id).collect()))
+    };
+
+    let two = bson::Value::BInt32(2);
+    let three = bson::Value::BInt32(3);
+    let six = bson::Value::BInt32(6);
+    let seven = bson::Value::BInt32(7);
+    let nine = bson::Value::BInt32(9);
+    let ten = bson::Value::BInt32(10);
+
+    assert_eq!(try!(scan(QueryBounds::EQ(vec![(&three, false)]))), vec![6]);
+    assert_eq!(try!(scan(QueryBounds::EQ(vec![(&ten, false)]))), Vec::<i32>::new());
+    assert_eq!(try!(scan(QueryBounds::GT(vec![(&seven, false)]))), vec![0, 1]);
+    assert_eq!(try!(scan(QueryBounds::GTE(vec![(&seven, false)]))), vec![0, 1, 2]);
+    assert_eq!(try!(scan(QueryBounds::GT(vec![(&nine, false)]))), Vec::<i32>::new());
+    assert_eq!(try!(scan(QueryBounds::LT(vec![(&two, false)]))), vec![8, 9]);
+    assert_eq!(try!(scan(QueryBounds::LTE(vec![(&two, false)]))), vec![7, 8, 9]);
+    assert_eq!(try!(scan(QueryBounds::GT_LT(vec![], vec![(&three, false)], vec![(&six, false)]))), vec![4, 5]);
+    assert_eq!(try!(scan(QueryBounds::GT_LTE(vec![], vec![(&three, false)], vec![(&six, false)]))), vec![3, 4, 5]);
+    assert_eq!(try!(scan(QueryBounds::GTE_LT(vec![], vec![(&three, false)], vec![(&six, false)]))), vec![4, 5, 6]);
+    assert_eq!(try!(scan(QueryBounds::GTE_LTE(vec![], vec![(&three, false)], vec![(&six, false)]))), vec![3, 4, 5, 6]);
+
+    // and a descending index, where the storage engine has to flip
+    // things around
+    let mut spec = bson::Document::new();
+    spec.set_i32("n", -1);
+    try!(conn.create_indexes(vec![index(db, "d", "n_-1", spec, bson::Document::new())]));
+    try!(insert_all(&conn, db, "d", (0 .. 10).map(|i| doc(i, "n", 9 - i)).collect()));
+    let reader = try!(conn.conn.begin_read());
+    let ndx = try!(reader.list_indexes(Some((db, "d")))).into_iter().filter(|ndx| ndx.name == "n_-1").next().unwrap();
+    let seq = try!(reader.get_reader_regular_index_scan(&ndx, QueryBounds::GTE_LT(vec![], vec![(&three, true)], vec![(&six, true)])));
+    let rows = try!(seq.collect::<Result<Vec<_>>>());
+    assert_eq!(sorted(rows.iter().map(row_id).collect()), vec![4, 5, 6]);
+
+    Ok(())
+}
+
+pub fn rollback(factory: &ConnectionFactory) -> Result<()> {
+    let db = "conf_rollback";
+    let conn = try!(factory.open());
+
+    try!(insert_all(&conn, db, "c", vec![doc(1, "x", 1)]));
+
+    {
+        let mut writer = try!(conn.conn.begin_write());
+        try!(writer.create_collection(db, "new", bson::Document::new()));
+        try!(writer.insert(db, "new", &doc(1, "x", 1)));
+        try!(writer.insert(db, "c", &doc(2, "x", 2)));
+        assert!(try!(writer.delete(db, "c", &bson::Value::BInt32(1))));
+        try!(writer.create_indexes(vec![index(db, "c", "x_1", ascending("x"), bson::Document::new())]));
+        try!(writer.rollback());
+    }
+    assert!(!try!(has_collection(&conn, db, "new")));
+    assert_eq!(try!(all_ids(&conn, db, "c")), vec![1]);
+    assert_eq!(try!(index_names(&conn, db, "c")), vec![String::from("_id_")]);
+
+    // a writer that just goes away without commit rolls back too
+    {
+        let mut writer = try!(conn.conn.begin_write());
+        try!(writer.insert(db, "c", &doc(3, "x", 3)));
+        assert!(try!(writer.drop_collection(db, "c")));
+    }
+    assert_eq!(try!(all_ids(&conn, db, "c")), vec![1]);
+
+    // and the connection is still fine for writing afterward
+    try!(insert_all(&conn, db, "c", vec![doc(4, "x", 4)]));
+    assert_eq!(try!(all_ids(&conn, db, "c")), vec![1, 4]);
+
+    // including from a different connection
+    let conn2 = try!(factory.open());
+    assert_eq!(try!(all_ids(&conn2, db, "c")), vec![1, 4]);
+    assert!(!try!(has_collection(&conn2, db, "new")));
+
+    Ok(())
+}
+
+pub fn savepoints(factory: &ConnectionFactory) -> Result<()> {
+    let db = "conf_savepoints";
+    let conn = try!(factory.open());
+
+    {
+        let mut writer = try!(conn.conn.begin_write());
+        try!(writer.insert(db, "c", &doc(1, "x", 1)));
+        let sp1 = try!(writer.savepoint());
+        try!(writer.insert(db, "c", &doc(2, "x", 2)));
+        let sp2 = try!(writer.savepoint());
+        try!(writer.insert(db, "c", &doc(3, "x", 3)));
+        try!(writer.create_indexes(vec![index(db, "c", "x_1", ascending("x"), unique())]));
+
+        // the index went away with doc 3, so this is not a duplicate
+        try!(writer.rollback_to(sp2));
+        try!(writer.insert(db, "c", &doc(4, "x", 3)));
+
+        // sp2 is still there after rolling back to it
+        try!(writer.rollback_to(sp2));
+
+        // but rolling back to sp1 takes sp2 with it
+        try!(writer.insert(db, "c", &doc(5, "x", 5)));
+        try!(writer.rollback_to(sp1));
+        assert!(writer.rollback_to(sp2).is_err());
+        try!(writer.insert(db, "c", &doc(6, "x", 6)));
+
+        // release keeps the changes, but not the savepoint
+        let sp = try!(writer.savepoint());
+        try!(writer.insert(db, "c", &doc(7, "x", 7)));
+        try!(writer.release(sp));
+        assert!(writer.rollback_to(sp).is_err());
+
+        try!(writer.commit());
+    }
+    assert_eq!(try!(all_ids(&conn, db, "c")), vec![1, 6, 7]);
+    assert_eq!(try!(index_names(&conn, db, "c")), vec![String::from("_id_")]);
+
+    // a collection created after the savepoint goes away with it
+    {
+        let mut writer = try!(conn.conn.begin_write());
+        let sp = try!(writer.savepoint());
+        try!(writer.create_collection(db, "new", bson::Document::new()));
+        try!(writer.insert(db, "new", &doc(1, "x", 1)));
+        try!(writer.rollback_to(sp));
+        try!(writer.insert(db, "c", &doc(8, "x", 8)));
+        try!(writer.release(sp));
+        try!(writer.commit());
+    }
+    assert!(!try!(has_collection(&conn, db, "new")));
+    assert_eq!(try!(all_ids(&conn, db, "c")), vec![1, 6, 7, 8]);
+
+    Ok(())
+}
+
+// a transaction has to see its own writes when it looks a doc up by _id,
+// both for a regular collection and for a clustered one (which only lsm
+// does anything with, since the others just keep the option).
+pub fn writes_in_one_tx(factory: &ConnectionFactory) -> Result<()> {
+    let db = "conf_writes_in_one_tx";
+    let conn = try!(factory.open());
+
+    let mut clustered = bson::Document::new();
+    clustered.set_bool("clustered", true);
+    for &(coll, ref options) in &[("plain", bson::Document::new()), ("clustered", clustered)] {
+        assert!(try!(conn.create_collection(db, coll, options.clone())));
+        try!(conn.create_indexes(vec![index(db, coll, "x_1", ascending("x"), bson::Document::new())]));
+
+        {
+            let mut writer = try!(conn.conn.begin_write());
+            try!(writer.insert(db, coll, &doc(1, "x", 1)));
+
+            // a duplicate _id inserted in the same tx
+            let sp = try!(writer.savepoint());
+            let r = writer.insert(db, coll, &doc(1, "x", 2));
+            assert!(is_duplicate_key(&r));
+            try!(writer.rollback_to(sp));
+
+            // delete and reinsert
+            assert!(try!(writer.delete(db, coll, &bson::Value::BInt32(1))));
+            try!(writer.insert(db, coll, &doc(1, "x", 3)));
+
+            // update a doc inserted in this tx
+            try!(writer.insert(db, coll, &doc(2, "x", 4)));
+            try!(writer.update(db, coll, &doc(2, "x", 5)));
+
+            try!(writer.commit());
+        }
+
+        assert_eq!(try!(all_ids(&conn, db, coll)), vec![1, 2]);
+        // and no index entries were left behind for the old versions
+        for &(x, ref ids) in &[(1, vec![]), (2, vec![]), (3, vec![1]), (4, vec![]), (5, vec![2])] {
+            assert_eq!(&try!(query_ids(&conn, db, coll, eq_query("x", x))), ids);
+        }
+    }
+
+    Ok(())
+}
+
+// what the server does for $out: run the pipeline, empty the target and
+// load it with what came out.  the target keeps its indexes.
+pub fn aggregate_out(factory: &ConnectionFactory) -> Result<()> {
+    let db = "conf_aggregate_out";
+    let conn = try!(factory.open());
+
+    try!(insert_all(&conn, db, "src", (1 .. 6).map(|i| doc(i, "x", i * 10)).collect()));
+    try!(insert_all(&conn, db, "dest", vec![doc(100, "x", 40)]));
+    try!(conn.create_indexes(vec![index(db, "dest", "x_1", ascending("x"), unique())]));
+
+    let mut m = bson::Document::new();
+    m.set_document("$match", op_query("x", "$gte", 30));
+    let mut out = bson::Document::new();
+    out.set_str("$out", "dest");
+    let pipeline = bson::Array {items: vec![bson::Value::BDocument(m), bson::Value::BDocument(out)]};
+    let (target, seq) = try!(conn.aggregate(db, "src", pipeline, None));
+    assert_eq!(target, Some(String::from("dest")));
+
+    try!(conn.clear_collection(db, "dest"));
+    for r in try!(conn.insert_seq(db, "dest", seq)) {
+        try!(r);
+    }
+    assert_eq!(try!(all_ids(&conn, db, "dest")), vec![3, 4, 5]);
+    assert_eq!(try!(index_names(&conn, db, "dest")), vec![String::from("_id_"), String::from("x_1")]);
+    // x:40 was doc 100's, but that went away with the clear
+    assert_eq!(try!(query_ids(&conn, db, "dest", eq_query("x", 40))), vec![4]);
+    assert_eq!(try!(all_ids(&conn, db, "src")), vec![1, 2, 3, 4, 5]);
+
+    Ok(())
+}
+
+fn text_search_ids(conn: &Connection, search: &str) -> Result<Vec<i32>> {
+    let rows = try!(text_search_rows(conn, search));
+    Ok(sorted(rows.iter().map(row_id).collect()))
+}
+
+fn text_search_rows(conn: &Connection, search: &str) -> Result<Vec<Row>> {
+    let mut t = bson::Document::new();
+    t.set_str("$search", search);
+    let mut q = bson::Document::new();
+    q.set_document("$text", t);
+    let seq = try!(conn.find("conf_text", "c", q, None, None, None, None, None, None, None));
+    let rows = try!(seq.collect::<Result<Vec<_>>>());
+    Ok(rows)
+}
+
+fn text_index() -> IndexInfo {
+    let mut spec = bson::Document::new();
+    spec.set_str("a", "text");
+    spec.set_str("b", "text");
+    let mut weights = bson::Document::new();
+    weights.set_i32("a", 10);
+    let mut options = bson::Document::new();
+    options.set_document("weights", weights);
+    IndexInfo {
+        db: String::from("conf_text"),
+        coll: String::from("c"),
+        name: String::from("a_text_b_text"),
+        spec: spec,
+        options: options,
+    }
+}
+
+fn rows(docs: Vec<bson::Document>) -> Box<Iterator<Item=Result<Row>>> {
+    let rows = docs.into_iter().map(|d| Ok(Row {doc: bson::Value::BDocument(d), pos: None, score: None})).collect::<Vec<_>>();
+    box rows.into_iter()
+}
+
+pub fn bulk_load(factory: &ConnectionFactory) -> Result<()> {
+    let db = "conf_bulk";
+    let conn = try!(factory.open());
+
+    // big enough to be worth it, in reverse order so the engine has to
+    // do some sorting
+    try!(conn.create_indexes(vec![index(db, "c", "x_1", ascending("x"), unique())]));
+    let docs = (0 .. 2000).rev().map(|i| doc(i, "x", i * 3)).collect::<Vec<_>>();
+    let results = try!(conn.insert_seq(db, "c", rows(docs)));
+    assert_eq!(results.len(), 2000);
+    assert!(results.iter().all(|r| r.is_ok()));
+    assert_eq!(try!(all_ids(&conn, db, "c")), (0 .. 2000).collect::<Vec<_>>());
+
+    // the index entries went in too
+    let ndx = index(db, "c", "x_1", ascending("x"), unique());
+    let reader = try!(conn.conn.begin_read());
+    let lo = bson::Value::BInt32(300);
+    let hi = bson::Value::BInt32(330);
+    let bounds = QueryBounds::GTE_LT(vec![], vec![(&lo, false)], vec![(&hi, false)]);
+    let seq = try!(reader.get_reader_regular_index_scan(&ndx, bounds));
+    let found = try!(seq.collect::<Result<Vec<_>>>());
+    assert_eq!(sorted(found.iter().map(row_id).collect()), (100 .. 110).collect::<Vec<_>>());
+    drop(reader);
+
+    // the collection isn't empty anymore, and the unique index still
+    // works
+    let results = try!(conn.insert_seq(db, "c", rows(vec![doc(5000, "x", 3), doc(5001, "x", 5001)])));
+    assert!(is_duplicate_key(&results[0]));
+    assert!(results[1].is_ok());
+    assert_eq!(try!(all_ids(&conn, db, "c")).len(), 2001);
+
+    // a duplicate inside one load into an empty collection fails just
+    // that doc, even on the fast path
+    let docs = vec![doc(1, "y", 1), doc(2, "y", 2), doc(1, "y", 3)];
+    let results = try!(conn.insert_seq(db, "d", rows(docs)));
+    assert_eq!(results.len(), 3);
+    assert!(results[0].is_ok());
+    assert!(results[1].is_ok());
+    assert!(is_duplicate_key(&results[2]));
+    assert_eq!(try!(all_ids(&conn, db, "d")), vec![1, 2]);
+    assert_eq!(try!(query_ids(&conn, db, "d", eq_query("y", 3))), Vec::<i32>::new());
+
+    // the same as one at a time: doc 2 loses y:1 to doc 1, so doc 3 can
+    // have _id 2
+    try!(conn.create_indexes(vec![index(db, "e", "y_1", ascending("y"), unique())]));
+    let docs = vec![doc(1, "y", 1), doc(2, "y", 1), doc(2, "y", 2)];
+    let results = try!(conn.insert_seq(db, "e", rows(docs)));
+    assert!(results[0].is_ok());
+    assert!(is_duplicate_key(&results[1]));
+    assert!(results[2].is_ok());
+    assert_eq!(try!(query_ids(&conn, db, "e", eq_query("y", 2))), vec![2]);
+    assert_eq!(try!(query_ids(&conn, db, "e", eq_query("y", 1))), vec![1]);
+
+    // more writes in the same transaction as a load see what it loaded
+    {
+        let mut writer = try!(conn.conn.begin_write());
+        let mut docs = (1 .. 4).map(|i| -> Result<bson::Document> { Ok(doc(i, "y", i)) });
+        let results = try!(writer.insert_bulk(db, "f", &mut docs));
+        assert!(results.iter().all(|r| r.is_ok()));
+        let docs: Vec<Result<bson::Document>> = vec![Ok(doc(3, "y", 30)), Ok(doc(4, "y", 4))];
+        let mut docs = docs.into_iter();
+        let results = try!(writer.insert_bulk(db, "f", &mut docs));
+        assert!(is_duplicate_key(&results[0]));
+        assert!(results[1].is_ok());
+        assert!(is_duplicate_key(&writer.insert(db, "f", &doc(2, "y", 20))));
+        try!(writer.update(db, "f", &doc(1, "y", 10)));
+        assert!(try!(writer.delete(db, "f", &bson::Value::BInt32(2))));
+        try!(writer.commit());
+    }
+    assert_eq!(try!(all_ids(&conn, db, "f")), vec![1, 3, 4]);
+    assert_eq!(try!(query_ids(&conn, db, "f", eq_query("y", 10))), vec![1]);
+    assert_eq!(try!(query_ids(&conn, db, "f", eq_query("y", 1))), Vec::<i32>::new());
+
+    // and a drop in the same transaction takes the loaded rows with it
+    {
+        let mut writer = try!(conn.conn.begin_write());
+        let mut docs = (1 .. 4).map(|i| -> Result<bson::Document> { Ok(doc(i, "y", i)) });
+        try!(writer.insert_bulk(db, "g", &mut docs));
+        assert!(try!(writer.drop_collection(db, "g")));
+        try!(writer.insert(db, "g", &doc(5, "y", 5)));
+        try!(writer.commit());
+    }
+    assert_eq!(try!(all_ids(&conn, db, "g")), vec![5]);
+
+    Ok(())
+}
+
+pub fn background_index(factory: &ConnectionFactory) -> Result<()> {
+    let db = "conf_background";
+    let conn = try!(factory.open());
+    try!(insert_all(&conn, db, "c", (1 .. 11).map(|i| doc(i, "x", i)).collect()));
+
+    let ndx = index(db, "c", "x_1", ascending("x"), bson::Document::new());
+    let scan = |conn: &Connection| -> Result<Vec<i32>> {
+        let reader = try!(conn.conn.begin_read());
+        let lo = bson::Value::BInt32(0);
+        let hi = bson::Value::BInt32(1000);
+        let bounds = QueryBounds::GTE_LT(vec![], vec![(&lo, false)], vec![(&hi, false)]);
+        let seq = try!(reader.get_reader_regular_index_scan(&ndx, bounds));
+        let rows = try!(seq.collect::<Result<Vec<_>>>());
+        Ok(sorted(rows.iter().map(row_id).collect()))
+    };
+
+    // do the steps by hand, with other writes in between
+    {
+        let mut writer = try!(conn.conn.begin_write());
+        assert!(try!(writer.begin_index_build(ndx.clone())));
+        try!(writer.commit());
+    }
+    // nobody can see it yet
+    assert_eq!(try!(index_names(&conn, db, "c")), vec![String::from("_id_")]);
+
+    // but writers keep it up to date
+    try!(insert_all(&conn, db, "c", vec![doc(11, "x", 11)]));
+    let mut q = bson::Document::new();
+    q.set_i32("_id", 2);
+    let mut d = bson::Document::new();
+    d.set_document("q", q);
+    d.set_i32("limit", 1);
+    assert_eq!(try!(conn.delete(db, "c", vec![d])), 1);
+
+    {
+        // 2 is gone, so it gets skipped
+        let ids = (1 .. 11).map(|i| bson::Value::BInt32(i)).collect::<Vec<_>>();
+        let mut writer = try!(conn.conn.begin_write());
+        try!(writer.index_build_add(&ndx, &ids[0 .. 5]));
+        try!(writer.commit());
+        let mut writer = try!(conn.conn.begin_write());
+        try!(writer.index_build_add(&ndx, &ids[5 ..]));
+        try!(writer.finish_index_build(&ndx));
+        try!(writer.commit());
+    }
+    assert_eq!(try!(index_names(&conn, db, "c")), vec![String::from("_id_"), String::from("x_1")]);
+    assert_eq!(try!(scan(&conn)), vec![1, 3, 4, 5, 6, 7, 8, 9, 10, 11]);
+
+    // and all at once through Connection
+    let ndx2 = index(db, "c", "y_1", ascending("y"), bson::Document::new());
+    let mut calls = vec![];
+    assert!(try!(conn.create_index_background(ndx2.clone(), factory, &mut |done, total| calls.push((done, total)))));
+    assert_eq!(calls.last(), Some(&(10, 10)));
+    assert_eq!(try!(index_names(&conn, db, "c")).len(), 3);
+    assert!(!try!(conn.create_index_background(ndx2, factory, &mut |_, _| ())));
+
+    // a unique index over docs that collide doesn't get built
+    try!(insert_all(&conn, db, "d", vec![doc(1, "y", 1), doc(2, "y", 1)]));
+    let r = conn.create_index_background(index(db, "d", "y_1", ascending("y"), unique()), factory, &mut |_, _| ());
+    assert!(is_duplicate_key(&r.map(|_| ())));
+    assert_eq!(try!(index_names(&conn, db, "d")), vec![String::from("_id_")]);
+
+    Ok(())
+}
+
+pub fn background_index_resume(factory: &ConnectionFactory) -> Result<()> {
+    let db = "conf_background_resume";
+    let conn = try!(factory.open());
+    try!(insert_all(&conn, db, "c", (1 .. 11).map(|i| doc(i, "x", i)).collect()));
+
+    // a build which got begun and then cut off, as if by a crash
+    let begin = |ndx: &IndexInfo| -> Result<()> {
+        let mut writer = try!(conn.conn.begin_write());
+        assert!(try!(writer.begin_index_build(ndx.clone())));
+        try!(writer.commit());
+        Ok(())
+    };
+    let ndx = index(db, "c", "x_1", ascending("x"), bson::Document::new());
+    try!(begin(&ndx));
+    try!(insert_all(&conn, db, "c", vec![doc(11, "x", 11)]));
+    assert_eq!(try!(index_names(&conn, db, "c")), vec![String::from("_id_")]);
+
+    // trying again picks it up instead of saying it's already there
+    assert!(try!(conn.create_index_background(ndx.clone(), factory, &mut |_, _| ())));
+    assert_eq!(try!(index_names(&conn, db, "c")), vec![String::from("_id_"), String::from("x_1")]);
+    assert_eq!(try!(chosen_index(&conn, db, "c", op_query("x", "$gte", 5))), Some(String::from("x_1")));
+    assert_eq!(try!(query_ids(&conn, db, "c", op_query("x", "$gte", 5))), vec![5, 6, 7, 8, 9, 10, 11]);
+    assert!(!try!(conn.create_index_background(ndx, factory, &mut |_, _| ())));
+
+    // or all of them get finished at once, which is what happens on open
+    try!(begin(&index(db, "c", "y_1", ascending("y"), bson::Document::new())));
+    assert_eq!(try!(conn.resume_index_builds(factory)), 1);
+    assert_eq!(try!(index_names(&conn, db, "c")).len(), 3);
+    assert_eq!(try!(conn.resume_index_builds(factory)), 0);
+
+    // a doc without _id can't be added back by _id, so the build fails
+    // instead of leaving it out, and the index goes away
+    let mut options = bson::Document::new();
+    options.set_bool("autoIndexId", false);
+    assert!(try!(conn.create_collection(db, "d", options)));
+    {
+        let mut d = bson::Document::new();
+        d.set_i32("x", 1);
+        let mut writer = try!(conn.conn.begin_write());
+        try!(writer.insert(db, "d", &d));
+        try!(writer.commit());
+    }
+    let r = conn.create_index_background(index(db, "d", "x_1", ascending("x"), bson::Document::new()), factory, &mut |_, _| ());
+    assert!(r.is_err());
+    assert_eq!(try!(index_names(&conn, db, "d")), Vec::<String>::new());
+    assert_eq!(try!(conn.resume_index_builds(factory)), 0);
+
+    Ok(())
+}
+
+fn capped_options(size: i64, max: Option<i64>) -> bson::Document {
+    let mut options = bson::Document::new();
+    options.set_bool("capped", true);
+    options.set_i64("size", size);
+    if let Some(max) = max {
+        options.set_i64("max", max);
+    }
+    options
+}
+
+pub fn capped(factory: &ConnectionFactory) -> Result<()> {
+    let db = "conf_capped";
+    let conn = try!(factory.open());
+
+    let mut no_size = bson::Document::new();
+    no_size.set_bool("capped", true);
+    assert!(has_code(&conn.create_collection(db, "bad", no_size), 72));
+
+    // max kicks out the oldest, and its index entries go with it
+    assert!(try!(conn.create_collection(db, "c", capped_options(100000, Some(3)))));
+    try!(insert_all(&conn, db, "c", (1 .. 6).map(|i| doc(i, "x", i)).collect()));
+    assert_eq!(try!(natural_ids(&conn, db, "c")), vec![3, 4, 5]);
+    try!(insert_all(&conn, db, "c", vec![doc(1, "x", 1)]));
+    assert_eq!(try!(natural_ids(&conn, db, "c")), vec![4, 5, 1]);
+
+    let mut q = bson::Document::new();
+    q.set_i32("_id", 4);
+    let mut d = bson::Document::new();
+    d.set_document("q", q);
+    d.set_i32("limit", 1);
+    assert!(has_code(&conn.delete(db, "c", vec![d]), 20));
+
+    {
+        let mut writer = try!(conn.conn.begin_write());
+        // same size is fine
+        try!(writer.update(db, "c", &doc(4, "x", 40)));
+        let mut bigger = doc(5, "x", 5);
+        bigger.set_str("s", "this does not fit where the old one was");
+        assert!(has_code(&writer.update(db, "c", &bigger), 10003));
+        try!(writer.commit());
+    }
+    assert_eq!(try!(natural_ids(&conn, db, "c")), vec![4, 5, 1]);
+
+    // size too.  each of these docs is 21 bytes of bson.
+    assert!(try!(conn.create_collection(db, "s", capped_options(50, None))));
+    try!(insert_all(&conn, db, "s", (1 .. 5).map(|i| doc(i, "x", i)).collect()));
+    assert_eq!(try!(natural_ids(&conn, db, "s")), vec![3, 4]);
+    let mut huge = doc(9, "x", 9);
+    huge.set_string("s", (0 .. 100).map(|_| 'a').collect());
+    assert!(has_code(&conn.insert(db, "s", &mut vec![huge], true).map(|mut a| a.remove(0)).and_then(|r| r), 10128));
+    assert_eq!(try!(natural_ids(&conn, db, "s")), vec![3, 4]);
+
+    // eviction has to undo along with everything else
+    {
+        let mut writer = try!(conn.conn.begin_write());
+        let sp = try!(writer.savepoint());
+        try!(writer.insert(db, "s", &doc(5, "x", 5)));
+        try!(writer.rollback_to(sp));
+        try!(writer.release(sp));
+        try!(writer.commit());
+    }
+    assert_eq!(try!(natural_ids(&conn, db, "s")), vec![3, 4]);
+
+    // a tail runs out, then picks up what gets inserted later
+    assert!(try!(conn.create_collection(db, "t", capped_options(100000, Some(3)))));
+    try!(insert_all(&conn, db, "t", vec![doc(1, "x", 1), doc(2, "x", 2)]));
+    let mut tail = try!(try!(factory.open()).into_tailable_find(db, "t", bson::Document::new(), None));
+    assert_eq!(try!(row_ids(&mut tail)), vec![1, 2]);
+    assert_eq!(try!(row_ids(&mut tail)), vec![]);
+    try!(insert_all(&conn, db, "t", vec![doc(3, "x", 3)]));
+    assert_eq!(try!(row_ids(&mut tail)), vec![3]);
+
+    // the query applies, and docs that don't match still move it along
+    let mut q = bson::Document::new();
+    q.set_i32("x", 5);
+    let mut matching = try!(try!(factory.open()).into_tailable_find(db, "t", q, None));
+    assert_eq!(try!(row_ids(&mut matching)), vec![]);
+    try!(insert_all(&conn, db, "t", vec![doc(4, "x", 4), doc(5, "x", 5)]));
+    assert_eq!(try!(row_ids(&mut matching)), vec![5]);
+
+    // 3 is gone now, so the first tail has lost its place
+    try!(insert_all(&conn, db, "t", vec![doc(6, "x", 6), doc(7, "x", 7)]));
+    assert!(has_code(&row_ids(&mut tail), 136));
+    assert_eq!(try!(row_ids(&mut matching)), vec![]);
+
+    // only a capped collection can be tailed
+    try!(insert_all(&conn, db, "plain", vec![doc(1, "x", 1)]));
+    assert!(has_code(&try!(factory.open()).into_tailable_find(db, "plain", bson::Document::new(), None).map(|_| ()), 13051));
+
+    Ok(())
+}
+
+// the count and bytes of a capped collection are kept with it, not
+// counted again by each transaction, so they have to stay right across
+// transactions, connections, updates and clears.
+pub fn capped_totals(factory: &ConnectionFactory) -> Result<()> {
+    let db = "conf_capped_totals";
+    let conn = try!(factory.open());
+
+    assert!(try!(conn.create_collection(db, "c", capped_options(100000, Some(10)))));
+    for i in 1 .. 31 {
+        try!(insert_all(&conn, db, "c", vec![doc(i, "x", i)]));
+    }
+    assert_eq!(try!(natural_ids(&conn, db, "c")), (21 .. 31).collect::<Vec<_>>());
+    // several evictions in one transaction
+    try!(insert_all(&conn, db, "c", (31 .. 46).map(|i| doc(i, "x", i)).collect()));
+    assert_eq!(try!(natural_ids(&conn, db, "c")), (36 .. 46).collect::<Vec<_>>());
+    let other = try!(factory.open());
+    try!(insert_all(&other, db, "c", vec![doc(46, "x", 46)]));
+    assert_eq!(try!(natural_ids(&conn, db, "c")), (37 .. 47).collect::<Vec<_>>());
+
+    // each of these docs is 21 bytes of bson, and 14 with only an _id
+    assert!(try!(conn.create_collection(db, "s", capped_options(50, None))));
+    try!(insert_all(&conn, db, "s", (1 .. 4).map(|i| doc(i, "x", i)).collect()));
+    assert_eq!(try!(natural_ids(&conn, db, "s")), vec![2, 3]);
+    {
+        let mut writer = try!(conn.conn.begin_write());
+        for i in 2 .. 4 {
+            let mut d = bson::Document::new();
+            d.set_i32("_id", i);
+            try!(writer.update(db, "s", &d));
+        }
+        try!(writer.commit());
+    }
+    // 14 + 14 + 21 fits
+    try!(insert_all(&conn, db, "s", vec![doc(4, "x", 4)]));
+    assert_eq!(try!(natural_ids(&conn, db, "s")), vec![2, 3, 4]);
+    try!(insert_all(&conn, db, "s", vec![doc(5, "x", 5)]));
+    assert_eq!(try!(natural_ids(&conn, db, "s")), vec![3, 4, 5]);
+
+    // clearing starts the count over
+    assert!(!try!(conn.clear_collection(db, "s")));
+    try!(insert_all(&conn, db, "s", vec![doc(6, "x", 6), doc(7, "x", 7)]));
+    assert_eq!(try!(natural_ids(&conn, db, "s")), vec![6, 7]);
+
+    // and a rename takes it along
+    assert!(try!(conn.rename_collection(&format!("{}.c", db), &format!("{}.r", db), false)));
+    try!(insert_all(&conn, db, "r", vec![doc(47, "x", 47)]));
+    assert_eq!(try!(natural_ids(&conn, db, "r")), (38 .. 48).collect::<Vec<_>>());
+
+    Ok(())
+}
+
+fn validated_options(level: Option<&str>, action: Option<&str>) -> bson::Document {
+    let mut gte = bson::Document::new();
+    gte.set_i32("$gte", 0);
+    let mut validator = bson::Document::new();
+    validator.set_document("x", gte);
+    let mut options = bson::Document::new();
+    options.set_document("validator", validator);
+    if let Some(level) = level {
+        options.set_str("validationLevel", level);
+    }
+    if let Some(action) = action {
+        options.set_str("validationAction", action);
+    }
+    options
+}
+
+fn set_x(conn: &Connection, factory: &ConnectionFactory, db: &str, coll: &str, id: i32, x: i32) -> Result<(i32, i32, Option<bson::Value>)> {
+    let mut q = bson::Document::new();
+    q.set_i32("_id", id);
+    let mut set = bson::Document::new();
+    set.set_i32("x", x);
+    let mut u = bson::Document::new();
+    u.set_document("$set", set);
+    let mut upd = bson::Document::new();
+    upd.set_document("q", q);
+    upd.set_document("u", u);
+    upd.set_bool("multi", false);
+    upd.set_bool("upsert", false);
+    let mut results = try!(conn.update(db, coll, &mut vec![upd], true, factory));
+    results.remove(0)
+}
+
+fn insert_one(conn: &Connection, db: &str, coll: &str, d: bson::Document) -> Result<()> {
+    let mut results = try!(conn.insert(db, coll, &mut vec![d], true));
+    results.remove(0)
+}
+
+pub fn validation(factory: &ConnectionFactory) -> Result<()> {
+    let db = "conf_validation";
+    let conn = try!(factory.open());
+
+    let mut bad = validated_options(Some("sometimes"), None);
+    assert!(has_code(&conn.create_collection(db, "bad", bad), 2));
+    bad = validated_options(None, Some("complain"));
+    assert!(has_code(&conn.create_collection(db, "bad", bad), 2));
+    bad = bson::Document::new();
+    let mut validator = bson::Document::new();
+    validator.set_str("$where", "true");
+    bad.set_document("validator", validator);
+    assert!(has_code(&conn.create_collection(db, "bad", bad), 2));
+    assert!(!try!(has_collection(&conn, db, "bad")));
+
+    assert!(try!(conn.create_collection(db, "c", validated_options(None, None))));
+    try!(insert_one(&conn, db, "c", doc(1, "x", 1)));
+    assert!(has_code(&insert_one(&conn, db, "c", doc(2, "x", -2)), 121));
+    // a missing field doesn't match either
+    assert!(has_code(&insert_one(&conn, db, "c", doc(2, "y", 2)), 121));
+
+    // an ordered insert stops at the first bad doc
+    let results = try!(conn.insert(db, "c", &mut vec![doc(3, "x", 3), doc(4, "x", -4), doc(5, "x", 5)], true));
+    assert_eq!(results.len(), 2);
+    assert!(has_code(&results[1], 121));
+    assert_eq!(try!(all_ids(&conn, db, "c")), vec![1, 3]);
+
+    assert!(has_code(&set_x(&conn, factory, db, "c", 1, -1), 121));
+    assert_eq!(try!(set_x(&conn, factory, db, "c", 1, 10)), (1, 1, None));
+
+    let mut q = bson::Document::new();
+    q.set_i32("_id", 3);
+    let r = try!(conn.find_and_modify(db, "c", Some(bson::Value::BDocument(q.clone())), None, None, Some(bson::Value::BDocument(doc(3, "x", -3))), false, false));
+    assert!(has_code(&r.1.map_or(Ok(()), Err), 121));
+    let r = try!(conn.find_and_modify(db, "c", Some(bson::Value::BDocument(q)), None, None, Some(bson::Value::BDocument(doc(3, "x", 30))), false, false));
+    assert!(r.1.is_none());
+    assert!(r.2);
+
+    // with validation off, anything goes in
+    let mut level = bson::Document::new();
+    level.set_str("validationLevel", "off");
+    try!(conn.coll_mod(db, "c", level));
+    try!(insert_one(&conn, db, "c", doc(6, "x", -6)));
+
+    // moderate leaves alone the docs which were already bad
+    let mut level = bson::Document::new();
+    level.set_str("validationLevel", "moderate");
+    try!(conn.coll_mod(db, "c", level));
+    assert_eq!(try!(set_x(&conn, factory, db, "c", 6, -60)), (1, 1, None));
+    assert!(has_code(&set_x(&conn, factory, db, "c", 1, -1), 121));
+    assert!(has_code(&insert_one(&conn, db, "c", doc(7, "x", -7)), 121));
+
+    // warn lets it through
+    let mut action = bson::Document::new();
+    action.set_str("validationAction", "warn");
+    try!(conn.coll_mod(db, "c", action));
+    try!(insert_one(&conn, db, "c", doc(7, "x", -7)));
+
+    // an empty validator takes it away
+    let mut none = bson::Document::new();
+    none.set_document("validator", bson::Document::new());
+    none.set_str("validationAction", "error");
+    try!(conn.coll_mod(db, "c", none));
+    try!(insert_one(&conn, db, "c", doc(8, "x", -8)));
+    assert_eq!(try!(all_ids(&conn, db, "c")), vec![1, 3, 6, 7, 8]);
+
+    let mut other = bson::Document::new();
+    other.set_bool("capped", true);
+    assert!(has_code(&conn.coll_mod(db, "c", other), 2));
+    assert!(has_code(&conn.coll_mod(db, "nope", validated_options(None, None)), 26));
+
+    // collMod adds a validator to a collection which didn't have one
+    try!(insert_one(&conn, db, "later", doc(1, "x", -1)));
+    try!(conn.coll_mod(db, "later", validated_options(None, None)));
+    assert!(has_code(&insert_one(&conn, db, "later", doc(2, "x", -2)), 121));
+
+    // a load, like $out, checks each doc too, even into an empty
+    // collection where an engine may take a faster path
+    assert!(try!(conn.create_collection(db, "loaded", validated_options(None, None))));
+    let results = try!(conn.insert_seq(db, "loaded", rows(vec![doc(1, "x", 1), doc(2, "x", -2), doc(3, "x", 3)])));
+    assert!(results[0].is_ok());
+    assert!(has_code(&results[1], 121));
+    assert!(results[2].is_ok());
+    assert_eq!(try!(all_ids(&conn, db, "loaded")), vec![1, 3]);
+
+    // and warn lets them all through
+    assert!(try!(conn.create_collection(db, "warned", validated_options(None, Some("warn")))));
+    let results = try!(conn.insert_seq(db, "warned", rows(vec![doc(1, "x", 1), doc(2, "x", -2)])));
+    assert!(results.iter().all(|r| r.is_ok()));
+    assert_eq!(try!(all_ids(&conn, db, "warned")), vec![1, 2]);
+
+    Ok(())
+}
+
+fn partial(filter: bson::Document) -> bson::Document {
+    let mut options = bson::Document::new();
+    options.set_document("partialFilterExpression", filter);
+    options
+}
+
+fn pending_filter() -> bson::Document {
+    let mut filter = bson::Document::new();
+    filter.set_bool("pending", true);
+    filter
+}
+
+// the name of the index the planner would use, if any
+fn chosen_index(conn: &Connection, db: &str, coll: &str, q: bson::Document) -> Result<Option<String>> {
+    let reader = try!(conn.conn.begin_read());
+    let indexes = try!(reader.list_indexes(Some((db, coll))));
+    let m = try!(matcher::parse_query(q));
+    let plan = try!(Connection::choose_index(&indexes, &m, None, None));
+    Ok(plan.map(|p| p.get_ndx().name.clone()))
+}
+
+pub fn partial_index(factory: &ConnectionFactory) -> Result<()> {
+    let db = "conf_partial";
+    let conn = try!(factory.open());
+
+    // only the pending ones go in the index
+    try!(conn.create_indexes(vec![index(db, "c", "x_1", ascending("x"), partial(pending_filter()))]));
+    try!(insert_all(&conn, db, "c", vec![
+        doc_with(1, vec![("x", int(1)), ("pending", boolean(true))]),
+        doc_with(2, vec![("x", int(2)), ("pending", boolean(false))]),
+        doc_with(3, vec![("x", int(3)), ("pending", boolean(true))]),
+        doc(4, "x", 4),
+        ]));
+    let scan = |conn: &Connection, name: &str| -> Result<Vec<i32>> {
+        let reader = try!(conn.conn.begin_read());
+        let ndx = try!(reader.list_indexes(Some((db, "c")))).into_iter().filter(|ndx| ndx.name == name).next().unwrap();
+        let lo = bson::Value::BInt32(0);
+        let hi = bson::Value::BInt32(1000);
+        let bounds = QueryBounds::GTE_LT(vec![], vec![(&lo, false)], vec![(&hi, false)]);
+        let seq = try!(reader.get_reader_regular_index_scan(&ndx, bounds));
+        let rows = try!(seq.collect::<Result<Vec<_>>>());
+        Ok(sorted(rows.iter().map(row_id).collect()))
+    };
+    assert_eq!(try!(scan(&conn, "x_1")), vec![1, 3]);
+
+    // docs move in and out of it when they get updated
+    {
+        let mut writer = try!(conn.conn.begin_write());
+        try!(writer.update(db, "c", &doc_with(1, vec![("x", int(1)), ("pending", boolean(false))])));
+        try!(writer.update(db, "c", &doc_with(2, vec![("x", int(2)), ("pending", boolean(true))])));
+        try!(writer.commit());
+    }
+    assert_eq!(try!(scan(&conn, "x_1")), vec![2, 3]);
+
+    // and an index built over docs which are already there
+    let ndx = index(db, "c", "x_bg", ascending("x"), partial(pending_filter()));
+    assert!(try!(conn.create_index_background(ndx, factory, &mut |_, _| ())));
+    assert_eq!(try!(scan(&conn, "x_bg")), vec![2, 3]);
+
+    // it only gets used when the query can't match anything outside it
+    let mut q = op_query("x", "$gt", 1);
+    assert_eq!(try!(chosen_index(&conn, db, "c", q.clone())), None);
+    assert_eq!(try!(query_ids(&conn, db, "c", q.clone())), vec![2, 3, 4]);
+    q.set_bool("pending", true);
+    assert!(try!(chosen_index(&conn, db, "c", q.clone())).is_some());
+    assert_eq!(try!(query_ids(&conn, db, "c", q)), vec![2, 3]);
+    let mut q = op_query("x", "$gt", 1);
+    q.set_bool("pending", false);
+    assert_eq!(try!(chosen_index(&conn, db, "c", q)), None);
+
+    // a range in the filter is implied by a narrower range in the query
+    try!(conn.create_indexes(vec![index(db, "r", "x_1", ascending("x"), partial(op_query("x", "$gte", 5)))]));
+    try!(insert_all(&conn, db, "r", (1 .. 10).map(|i| doc(i, "x", i)).collect()));
+    assert_eq!(try!(chosen_index(&conn, db, "r", op_query("x", "$gt", 7))), Some(String::from("x_1")));
+    assert_eq!(try!(chosen_index(&conn, db, "r", op_query("x", "$gte", 5))), Some(String::from("x_1")));
+    assert_eq!(try!(chosen_index(&conn, db, "r", op_query("x", "$gt", 3))), None);
+    assert_eq!(try!(chosen_index(&conn, db, "r", doc(7, "x", 7))), Some(String::from("_id_")));
+    let mut q = bson::Document::new();
+    q.set_i32("x", 6);
+    assert_eq!(try!(chosen_index(&conn, db, "r", q)), Some(String::from("x_1")));
+    let mut q = bson::Document::new();
+    q.set_str("x", "6");
+    assert_eq!(try!(chosen_index(&conn, db, "r", q)), None);
+    assert_eq!(try!(query_ids(&conn, db, "r", op_query("x", "$gt", 3))), vec![4, 5, 6, 7, 8, 9]);
+    assert_eq!(try!(query_ids(&conn, db, "r", op_query("x", "$gt", 7))), vec![8, 9]);
+
+    // bad ones
+    let mut options = partial(pending_filter());
+    options.set_bool("sparse", true);
+    assert!(has_code(&conn.create_indexes(vec![index(db, "bad", "x_1", ascending("x"), options)]), 67));
+    let mut ne = bson::Document::new();
+    ne.set_i32("$ne", 3);
+    let mut filter = bson::Document::new();
+    filter.set_document("x", ne);
+    assert!(has_code(&conn.create_indexes(vec![index(db, "bad", "x_1", ascending("x"), partial(filter))]), 67));
+    let mut options = bson::Document::new();
+    options.set_i32("partialFilterExpression", 1);
+    assert!(has_code(&conn.create_indexes(vec![index(db, "bad", "x_1", ascending("x"), options)]), 67));
+    assert!(!try!(has_collection(&conn, db, "bad")));
+
+    Ok(())
+}
+
+fn name_docs() -> Vec<bson::Document> {
+    let names = ["apple", "Apple", "äpple", "banana", "APPLE", "item10", "item9"];
+    names.iter().enumerate().map(|(i, s)| doc_with((i + 1) as i32, vec![("name", string(s))])).collect()
+}
+
+fn collation_doc(strength: i32, numeric: bool) -> bson::Document {
+    let mut c = bson::Document::new();
+    c.set_str("locale", "en");
+    c.set_i32("strength", strength);
+    if numeric {
+        c.set_bool("numericOrdering", true);
+    }
+    c
+}
+
+fn with_collation(c: bson::Document) -> bson::Document {
+    let mut options = bson::Document::new();
+    options.set_document("collation", c);
+    options
+}
+
+// like chosen_index, but planned the way find() does it
+fn collated_index(conn: &Connection, db: &str, coll: &str, q: bson::Document, collation: Option<bson::Document>) -> Result<Option<String>> {
+    let reader = try!(conn.conn.begin_read());
+    let indexes = try!(reader.list_indexes(Some((db, coll))));
+    let collation = try!(Connection::resolve_collation(&try!(reader.list_collections()), db, coll, collation));
+    let m = try!(matcher::parse_query(q));
+    let plan = try!(Connection::choose_index(&indexes, &m, None, collation.as_ref()));
+    Ok(plan.map(|p| p.get_ndx().name.clone()))
+}
+
+pub fn collation(factory: &ConnectionFactory) -> Result<()> {
+    let db = "conf_collation";
+    let conn = try!(factory.open());
+
+    assert!(has_code(&conn.create_collection(db, "bad", with_collation(collation_doc(7, false))), 2));
+    let mut c = collation_doc(1, false);
+    c.remove("locale");
+    assert!(has_code(&conn.create_collection(db, "bad", with_collation(c)), 2));
+    let mut c = bson::Document::new();
+    c.set_str("locale", "simple");
+    c.set_i32("strength", 1);
+    assert!(has_code(&conn.create_collection(db, "bad", with_collation(c)), 2));
+    assert!(!try!(has_collection(&conn, db, "bad")));
+
+    try!(insert_all(&conn, db, "c", name_docs()));
+
+    // strength 1 ignores case and accents.  2 ignores only case.
+    assert_eq!(sorted(try!(find_ids(&conn, db, "c", field_query("name", string("apple")), None, Some(collation_doc(1, false))))), vec![1, 2, 3, 5]);
+    assert_eq!(sorted(try!(find_ids(&conn, db, "c", field_query("name", string("apple")), None, Some(collation_doc(2, false))))), vec![1, 2, 5]);
+    assert_eq!(try!(find_ids(&conn, db, "c", field_query("name", string("apple")), None, None)), vec![1]);
+    let mut lt = bson::Document::new();
+    lt.set_str("$lt", "b");
+    let mut q = bson::Document::new();
+    q.set_document("name", lt);
+    assert_eq!(sorted(try!(find_ids(&conn, db, "c", q.clone(), None, Some(collation_doc(1, false))))), vec![1, 2, 3, 5]);
+    assert_eq!(sorted(try!(find_ids(&conn, db, "c", q.clone(), None, None))), vec![1, 2, 5]);
+
+    // base letters first, then accents, then lower case before upper
+    let mut orderby = bson::Document::new();
+    orderby.set_i32("name", 1);
+    let orderby = bson::Value::BDocument(orderby);
+    assert_eq!(try!(find_ids(&conn, db, "c", bson::Document::new(), Some(orderby.clone()), Some(collation_doc(3, false)))), vec![1, 2, 5, 3, 4, 6, 7]);
+    assert_eq!(try!(find_ids(&conn, db, "c", bson::Document::new(), Some(orderby.clone()), Some(collation_doc(3, true)))), vec![1, 2, 5, 3, 4, 7, 6]);
+    assert_eq!(try!(find_ids(&conn, db, "c", bson::Document::new(), Some(orderby.clone()), None)), vec![5, 2, 1, 4, 6, 7, 3]);
+
+    // values which collate the same are one value
+    assert_eq!(try!(conn.distinct(db, "c", "name", q.clone(), Some(collation_doc(1, false)))).items.len(), 1);
+    assert_eq!(try!(conn.distinct(db, "c", "name", q, None)).items.len(), 3);
+
+    let mut sum = bson::Document::new();
+    sum.set_i32("$sum", 1);
+    let mut group = bson::Document::new();
+    group.set_str("_id", "$name");
+    group.set_document("n", sum);
+    let mut stage = bson::Document::new();
+    stage.set_document("$group", group);
+    let mut pipeline = bson::Array::new();
+    pipeline.push(bson::Value::BDocument(stage));
+    let (_, seq) = try!(conn.aggregate(db, "c", pipeline.clone(), Some(collation_doc(2, false))));
+    assert_eq!(try!(seq.collect::<Result<Vec<_>>>()).len(), 5);
+    let (_, seq) = try!(conn.aggregate(db, "c", pipeline, None));
+    assert_eq!(try!(seq.collect::<Result<Vec<_>>>()).len(), 7);
+
+    // a plain index is no good for a collated query on strings, and a
+    // collated one is no good for a plain query
+    try!(conn.create_indexes(vec![index(db, "c", "name_1", ascending("name"), bson::Document::new())]));
+    try!(conn.create_indexes(vec![index(db, "c", "name_ci", ascending("name"), with_collation(collation_doc(2, false)))]));
+    assert_eq!(try!(collated_index(&conn, db, "c", field_query("name", string("apple")), None)), Some(String::from("name_1")));
+    assert_eq!(try!(collated_index(&conn, db, "c", field_query("name", string("apple")), Some(collation_doc(2, false)))), Some(String::from("name_ci")));
+    assert_eq!(try!(collated_index(&conn, db, "c", field_query("name", string("apple")), Some(collation_doc(1, false)))), None);
+    assert_eq!(sorted(try!(find_ids(&conn, db, "c", field_query("name", string("APPLE")), None, Some(collation_doc(2, false))))), vec![1, 2, 5]);
+    assert_eq!(try!(find_ids(&conn, db, "c", field_query("name", string("APPLE")), None, None)), vec![5]);
+    // but a query which doesn't compare strings can use either
+    let mut q = bson::Document::new();
+    q.set_i32("name", 3);
+    assert!(try!(collated_index(&conn, db, "c", q, Some(collation_doc(1, false)))).is_some());
+
+    // the collection default applies to everything, and its indexes get it
+    assert!(try!(conn.create_collection(db, "d", with_collation(collation_doc(2, false)))));
+    try!(insert_all(&conn, db, "d", name_docs()));
+    try!(conn.create_indexes(vec![index(db, "d", "name_1", ascending("name"), bson::Document::new())]));
+    assert_eq!(try!(collated_index(&conn, db, "d", field_query("name", string("apple")), None)), Some(String::from("name_1")));
+    assert_eq!(sorted(try!(find_ids(&conn, db, "d", field_query("name", string("Apple")), None, None))), vec![1, 2, 5]);
+    let mut simple = bson::Document::new();
+    simple.set_str("locale", "simple");
+    assert_eq!(try!(collated_index(&conn, db, "d", field_query("name", string("apple")), Some(simple.clone()))), None);
+    assert_eq!(try!(find_ids(&conn, db, "d", field_query("name", string("Apple")), None, Some(simple))), vec![2]);
+
+    let mut set = bson::Document::new();
+    set.set_i32("x", 1);
+    let mut u = bson::Document::new();
+    u.set_document("$set", set);
+    let mut upd = bson::Document::new();
+    upd.set_document("q", field_query("name", string("APPLE")));
+    upd.set_document("u", u);
+    upd.set_bool("multi", true);
+    upd.set_bool("upsert", false);
+    let mut results = try!(conn.update(db, "d", &mut vec![upd], true, factory));
+    assert_eq!(try!(results.remove(0)), (3, 3, None));
+
+    let mut del = bson::Document::new();
+    del.set_document("q", field_query("name", string("BANANA")));
+    del.set_i32("limit", 0);
+    assert_eq!(try!(conn.delete(db, "d", vec![del])), 1);
+    assert_eq!(try!(all_ids(&conn, db, "d")), vec![1, 2, 3, 5, 6, 7]);
+
+    // the engine stores the sort keys, so for a unique index, strings
+    // which collate the same are duplicates
+    let mut options = with_collation(collation_doc(2, false));
+    options.set_bool("unique", true);
+    try!(conn.create_indexes(vec![index(db, "u", "name_1", ascending("name"), options)]));
+    let mut docs = name_docs();
+    try!(insert_one(&conn, db, "u", docs.remove(0)));
+    assert!(is_duplicate_key(&insert_one(&conn, db, "u", docs.remove(0))));
+    try!(insert_one(&conn, db, "u", docs.remove(0)));
+    let mut range = bson::Document::new();
+    range.set_str("$gte", "A");
+    range.set_str("$lt", "B");
+    let mut q = bson::Document::new();
+    q.set_document("name", range);
+    assert_eq!(try!(collated_index(&conn, db, "u", q.clone(), Some(collation_doc(2, false)))), Some(String::from("name_1")));
+    assert_eq!(sorted(try!(find_ids(&conn, db, "u", q, None, Some(collation_doc(2, false))))), vec![1, 3]);
+
+    Ok(())
+}
+
+fn index_is_multikey(conn: &Connection, db: &str, coll: &str, name: &str) -> Result<bool> {
+    let a = try!(conn.list_indexes());
+    let ndx = a.into_iter().filter(|ndx| ndx.db == db && ndx.coll == coll && ndx.name == name).next().unwrap();
+    Ok(ndx.is_multikey())
+}
+
+// whether the planner would use both ends of a range
+fn uses_both_bounds(conn: &Connection, db: &str, coll: &str, q: bson::Document) -> Result<bool> {
+    let reader = try!(conn.conn.begin_read());
+    let indexes = try!(reader.list_indexes(Some((db, coll))));
+    let m = try!(matcher::parse_query(q));
+    let plan = try!(Connection::choose_index(&indexes, &m, None, None));
+    let both =
+        match plan {
+            Some(QueryPlan::Regular(_, QueryBounds::GT_LT(..))) => true,
+            Some(QueryPlan::Regular(_, QueryBounds::GT_LTE(..))) => true,
+            Some(QueryPlan::Regular(_, QueryBounds::GTE_LT(..))) => true,
+            Some(QueryPlan::Regular(_, QueryBounds::GTE_LTE(..))) => true,
+            _ => false,
+        };
+    Ok(both)
+}
+
+pub fn multikey(factory: &ConnectionFactory) -> Result<()> {
+    let db = "conf_multikey";
+    let conn = try!(factory.open());
+    let between = field_query("x", ops(&[("$gt", 2), ("$lt", 5)]));
+
+    // as long as there are no arrays, a range can use both bounds
+    try!(conn.create_indexes(vec![index(db, "c", "x_1", ascending("x"), bson::Document::new())]));
+    try!(insert_all(&conn, db, "c", vec![doc(1, "x", 1), doc(2, "x", 4), doc(3, "x", 7)]));
+    assert!(!try!(index_is_multikey(&conn, db, "c", "x_1")));
+    assert!(try!(uses_both_bounds(&conn, db, "c", between.clone())));
+    assert_eq!(try!(query_ids(&conn, db, "c", between.clone())), vec![2]);
+
+    // the 7 is more than 2 and the 1 is less than 5, so this one matches,
+    // even though none of its entries is between them
+    try!(insert_all(&conn, db, "c", vec![doc_with(4, vec![("x", ints(&[1, 7]))])]));
+    assert!(try!(index_is_multikey(&conn, db, "c", "x_1")));
+    assert!(!try!(uses_both_bounds(&conn, db, "c", between.clone())));
+    assert_eq!(try!(query_ids(&conn, db, "c", between.clone())), vec![2, 4]);
+
+    // it stays that way, even after the array is gone
+    {
+        let mut writer = try!(conn.conn.begin_write());
+        try!(writer.update(db, "c", &doc(4, "x", 3)));
+        try!(writer.commit());
+    }
+    assert!(try!(index_is_multikey(&conn, db, "c", "x_1")));
+
+    // an index built over docs which are already there
+    try!(insert_all(&conn, db, "d", vec![doc(1, "x", 4), doc_with(2, vec![("x", ints(&[1, 7]))])]));
+    try!(conn.create_indexes(vec![index(db, "d", "x_1", ascending("x"), bson::Document::new())]));
+    assert!(try!(index_is_multikey(&conn, db, "d", "x_1")));
+    assert_eq!(try!(query_ids(&conn, db, "d", between.clone())), vec![1, 2]);
+    try!(insert_all(&conn, db, "e", vec![doc(1, "x", 4), doc_with(2, vec![("x", ints(&[1, 7]))])]));
+    let ndx = index(db, "e", "x_1", ascending("x"), bson::Document::new());
+    assert!(try!(conn.create_index_background(ndx, factory, &mut |_, _| ())));
+    assert!(try!(index_is_multikey(&conn, db, "e", "x_1")));
+    assert_eq!(try!(query_ids(&conn, db, "e", between.clone())), vec![1, 2]);
+
+    // a compound index can have one array in it
+    let mut spec = ascending("x");
+    spec.set_i32("y", 1);
+    try!(conn.create_indexes(vec![index(db, "p", "x_1_y_1", spec.clone(), bson::Document::new())]));
+    let mut a = vec![doc_with(1, vec![("x", ints(&[1, 7]))]), doc(2, "x", 4), doc(3, "x", 9)];
+    for d in a.iter_mut() {
+        d.set_i32("y", 3);
+    }
+    try!(insert_all(&conn, db, "p", a));
+    assert!(try!(index_is_multikey(&conn, db, "p", "x_1_y_1")));
+    assert_eq!(try!(query_ids(&conn, db, "p", between.clone())), vec![1, 2]);
+    let mut q = between.clone();
+    q.set_i32("y", 3);
+    assert_eq!(try!(query_ids(&conn, db, "p", q)), vec![1, 2]);
+
+    // but not two
+    let mut d = doc_with(4, vec![("x", ints(&[1, 2]))]);
+    d.set("y", ints(&[3, 4]));
+    assert!(has_code(&insert_one(&conn, db, "p", d.clone()), 171));
+    assert_eq!(try!(all_ids(&conn, db, "p")), vec![1, 2, 3]);
+    {
+        let mut writer = try!(conn.conn.begin_write());
+        let mut u = doc_with(1, vec![("x", ints(&[1, 2]))]);
+        u.set("y", ints(&[3, 4]));
+        assert!(has_code(&writer.update(db, "p", &u), 171));
+        try!(writer.rollback());
+    }
+    try!(insert_one(&conn, db, "q", d));
+    assert!(has_code(&conn.create_indexes(vec![index(db, "q", "x_1_y_1", spec, bson::Document::new())]), 171));
+
+    // two keys inside the same array are fine
+    let mut spec = ascending("a.x");
+    spec.set_i32("a.y", 1);
+    try!(conn.create_indexes(vec![index(db, "s", "a.x_1_a.y_1", spec, bson::Document::new())]));
+    let mut e1 = bson::Document::new();
+    e1.set_i32("x", 1);
+    e1.set_i32("y", 2);
+    let mut e2 = bson::Document::new();
+    e2.set_i32("x", 3);
+    e2.set_i32("y", 4);
+    let d = doc_with(1, vec![("a", bson::Value::BArray(bson::Array {items: vec![e1.into_value(), e2.into_value()]}))]);
+    try!(insert_one(&conn, db, "s", d));
+    let mut q = bson::Document::new();
+    q.set_i32("a.x", 3);
+    q.set_i32("a.y", 2);
+    assert_eq!(try!(query_ids(&conn, db, "s", q)), vec![1]);
+
+    Ok(())
+}
+
+// an _id can't be an array, so the _id index an engine makes by itself
+// is never multikey, and the planner can use it for everything.
+pub fn id_index(factory: &ConnectionFactory) -> Result<()> {
+    let db = "conf_id_index";
+    let conn = try!(factory.open());
+    try!(insert_all(&conn, db, "c", (1 .. 11).map(|i| doc(i, "x", i)).collect()));
+    assert!(!try!(index_is_multikey(&conn, db, "c", "_id_")));
+
+    let between = field_query("_id", ops(&[("$gt", 2), ("$lt", 5)]));
+    assert!(try!(uses_both_bounds(&conn, db, "c", between.clone())));
+    assert_eq!(try!(query_ids(&conn, db, "c", between)), vec![3, 4]);
+
+    assert_eq!(try!(sort_plan(&conn, db, "c", bson::Document::new(), orderby("_id", 1))), Some((String::from("_id_"), false)));
+    assert_eq!(try!(sort_plan(&conn, db, "c", bson::Document::new(), orderby("_id", -1))), Some((String::from("_id_"), true)));
+
+    Ok(())
+}
+
+// what sort of plan the planner comes up with
+fn plan_kind(conn: &Connection, db: &str, coll: &str, q: bson::Document) -> Result<&'static str> {
+    let reader = try!(conn.conn.begin_read());
+    let indexes = try!(reader.list_indexes(Some((db, coll))));
+    let m = try!(matcher::parse_query(q));
+    let plan = try!(Connection::choose_index(&indexes, &m, None, None));
+    let kind =
+        match plan {
+            None => "scan",
+            Some(QueryPlan::Union(_)) => "union",
+            Some(QueryPlan::Intersection(_, _)) => "intersection",
+            Some(_) => "index",
+        };
+    Ok(kind)
+}
+
+pub fn index_plans(factory: &ConnectionFactory) -> Result<()> {
+    let db = "conf_index_plans";
+    let conn = try!(factory.open());
+
+    try!(conn.create_indexes(vec![
+        index(db, "c", "a_1", ascending("a"), bson::Document::new()),
+        index(db, "c", "b_1", ascending("b"), bson::Document::new()),
+        ]));
+    try!(insert_all(&conn, db, "c", (1 .. 41).map(ab_doc).collect()));
+    let expect = |f: &Fn(i32) -> bool| -> Vec<i32> { (1 .. 41).filter(|&i| f(i)).collect() };
+
+    // one scan for each branch, and a doc which both of them find comes
+    // back only once
+    let q = or_query(eq_query("a", 1), eq_query("b", 2));
+    assert_eq!(try!(plan_kind(&conn, db, "c", q.clone())), "union");
+    let seq = try!(conn.find(db, "c", q, None, None, None, None, None, None, None));
+    let ids = try!(seq.collect::<Result<Vec<_>>>()).iter().map(row_id).collect::<Vec<_>>();
+    assert_eq!(sorted(ids.clone()), expect(&|i| i % 5 == 1 || i % 4 == 2));
+    assert_eq!(ids.len(), expect(&|i| i % 5 == 1 || i % 4 == 2).len());
+
+    // the rest of the query still gets checked
+    let mut q = or_query(eq_query("a", 1), eq_query("b", 2));
+    q.set_i32("c", 0);
+    assert_eq!(try!(plan_kind(&conn, db, "c", q.clone())), "union");
+    assert_eq!(try!(query_ids(&conn, db, "c", q)), expect(&|i| (i % 5 == 1 || i % 4 == 2) && i % 3 == 0));
+
+    // a branch without an index means a collection scan
+    let q = or_query(eq_query("a", 1), eq_query("c", 2));
+    assert_eq!(try!(plan_kind(&conn, db, "c", q.clone())), "scan");
+    assert_eq!(try!(query_ids(&conn, db, "c", q)), expect(&|i| i % 5 == 1 || i % 3 == 2));
+
+    // and an index for the rest of the query is good enough by itself
+    let mut q = or_query(eq_query("c", 1), eq_query("c", 2));
+    q.set_i32("a", 3);
+    assert_eq!(try!(plan_kind(&conn, db, "c", q.clone())), "index");
+    assert_eq!(try!(query_ids(&conn, db, "c", q)), expect(&|i| i % 3 != 0 && i % 5 == 3));
+
+    // two indexes for two parts of the query.  without stats, there is no
+    // telling whether reading both pays off, so it's just one.
+    let mut gt = bson::Document::new();
+    gt.set_i32("$gt", 1);
+    let mut q = eq_query("a", 2);
+    q.set_document("b", gt);
+    assert_eq!(try!(plan_kind(&conn, db, "c", q.clone())), "index");
+    assert_eq!(try!(query_ids(&conn, db, "c", q)), expect(&|i| i % 5 == 2 && i % 4 > 1));
+
+    // but not when one index takes care of everything the other does
+    let mut spec = ascending("a");
+    spec.set_i32("b", 1);
+    try!(conn.create_indexes(vec![
+        index(db, "d", "a_1", ascending("a"), bson::Document::new()),
+        index(db, "d", "a_1_b_1", spec, bson::Document::new()),
+        ]));
+    try!(insert_all(&conn, db, "d", (1 .. 41).map(ab_doc).collect()));
+    let mut q = eq_query("a", 2);
+    q.set_i32("b", 3);
+    assert_eq!(try!(plan_kind(&conn, db, "d", q.clone())), "index");
+    assert_eq!(try!(chosen_index(&conn, db, "d", q.clone())), Some(String::from("a_1_b_1")));
+    assert_eq!(try!(query_ids(&conn, db, "d", q)), expect(&|i| i % 5 == 2 && i % 4 == 3));
+
+    // writers plan the same way
+    let mut upd = bson::Document::new();
+    upd.set_document("q", or_query(eq_query("a", 1), eq_query("b", 2)));
+    let mut set = bson::Document::new();
+    set.set_i32("c", 7);
+    let mut u = bson::Document::new();
+    u.set_document("$set", set);
+    upd.set_document("u", u);
+    upd.set_bool("multi", true);
+    upd.set_bool("upsert", false);
+    for r in try!(conn.update(db, "c", &mut vec![upd], true, factory)) {
+        try!(r);
+    }
+    assert_eq!(try!(query_ids(&conn, db, "c", eq_query("c", 7))), expect(&|i| i % 5 == 1 || i % 4 == 2));
+
+    Ok(())
+}
+
+fn index_stats(conn: &Connection, db: &str, coll: &str, name: &str) -> Result<stats::IndexStats> {
+    let a = try!(conn.list_indexes());
+    let ndx = a.into_iter().filter(|ndx| ndx.db == db && ndx.coll == coll && ndx.name == name).next().unwrap();
+    Ok(try!(stats::IndexStats::from_options(&ndx.options)).unwrap())
+}
+
+// the index of the plan cached for queries shaped like this one
+fn cached_index(conn: &Connection, db: &str, coll: &str, q: bson::Document) -> Result<Option<String>> {
+    let m = try!(matcher::parse_query(q));
+    let key = Connection::plan_cache_key(db, coll, &m, None);
+    let planning = try!(conn.planner.lock());
+    let name =
+        match planning.plans.get(&key) {
+            Some(&PlanCacheEntry {plan: CachedPlan::Index(ref name), ..}) => Some(name.clone()),
+            _ => None,
+        };
+    Ok(name)
+}
+
+pub fn query_stats(factory: &ConnectionFactory) -> Result<()> {
+    let db = "conf_query_stats";
+    let conn = try!(factory.open());
+
+    // half the docs have each flag, but every user is different
+    try!(insert_all(&conn, db, "c", (1 .. 401).map(flag_doc).collect()));
+    try!(conn.create_indexes(vec![
+        index(db, "c", "flag_1", ascending("flag"), bson::Document::new()),
+        index(db, "c", "user_1", ascending("user"), bson::Document::new()),
+        ]));
+    // the stats for new indexes get gathered later, by whoever keeps
+    // them fresh
+    assert!(try!(stats::IndexStats::from_options(&try!(conn.list_indexes()).into_iter().find(|ndx| ndx.db == db && ndx.name == "flag_1").unwrap().options)).is_none());
+    assert_eq!(try!(conn.analyze_stale()), 1);
+    assert_eq!(try!(conn.analyze_stale()), 0);
+    let flag = try!(index_stats(&conn, db, "c", "flag_1"));
+    let user = try!(index_stats(&conn, db, "c", "user_1"));
+    assert_eq!(flag.keys, 400);
+    assert_eq!(flag.distinct, 2);
+    assert_eq!(user.keys, 400);
+    assert!(user.distinct > 100);
+    assert!(!try!(index_is_multikey(&conn, db, "c", "flag_1")));
+
+    // counting fields would pick the first index and intersect it with the
+    // other.  the stats say the user is all it takes.
+    let mut q = eq_query("flag", 1);
+    q.set_i32("user", 7);
+    assert_eq!(try!(plan_kind(&conn, db, "c", q.clone())), "index");
+    assert_eq!(try!(chosen_index(&conn, db, "c", q.clone())), Some(String::from("user_1")));
+    assert_eq!(try!(query_ids(&conn, db, "c", q)), vec![7]);
+    let mut q = op_query("user", "$gt", 390);
+    q.set_i32("flag", 0);
+    assert_eq!(try!(chosen_index(&conn, db, "c", q.clone())), Some(String::from("user_1")));
+    assert_eq!(try!(query_ids(&conn, db, "c", q)), vec![392, 394, 396, 398, 400]);
+
+    // a union which reads more entries than there are docs is worse than
+    // a scan
+    let q = or_query(op_query("flag", "$gte", 0), op_query("user", "$gt", 0));
+    assert_eq!(try!(plan_kind(&conn, db, "c", q)), "scan");
+    let q = or_query(eq_query("user", 3), eq_query("user", 9));
+    assert_eq!(try!(plan_kind(&conn, db, "c", q.clone())), "union");
+    assert_eq!(try!(query_ids(&conn, db, "c", q)), vec![3, 9]);
+
+    // a query of the same shape uses the same plan, even from another
+    // connection
+    let mut q = eq_query("flag", 1);
+    q.set_i32("user", 7);
+    assert_eq!(try!(query_ids(&conn, db, "c", q.clone())), vec![7]);
+    assert_eq!(try!(cached_index(&conn, db, "c", q)), Some(String::from("user_1")));
+    let other = try!(factory.open());
+    let mut q = eq_query("flag", 0);
+    q.set_i32("user", 8);
+    assert_eq!(try!(cached_index(&other, db, "c", q.clone())), Some(String::from("user_1")));
+    assert_eq!(try!(query_ids(&other, db, "c", q.clone())), vec![8]);
+
+    // enough writes, from any connection, and the stats are stale.  they
+    // don't get gathered again until somebody asks.
+    try!(insert_all(&conn, db, "c", (401 .. 451).map(flag_doc).collect()));
+    try!(insert_all(&other, db, "c", (451 .. 501).map(flag_doc).collect()));
+    assert_eq!(try!(index_stats(&conn, db, "c", "flag_1")).keys, 400);
+    assert!(try!(other.wait_for_stale_stats(0)));
+    assert_eq!(try!(other.analyze_stale()), 1);
+    assert!(!try!(conn.wait_for_stale_stats(0)));
+    assert_eq!(try!(index_stats(&conn, db, "c", "flag_1")).keys, 500);
+    assert_eq!(try!(index_stats(&conn, db, "c", "user_1")).keys, 500);
+
+    // new stats mean the plan gets chosen again
+    assert_eq!(try!(cached_index(&conn, db, "c", q)), None);
+
+    // for a collated index, the samples are the keys the way the index
+    // has them, so a bound finds the ones which only differ in case too
+    let names = ["apple", "APPLE", "pear"];
+    let docs = (1 .. 401).map(|i| doc_with(i, vec![("name", string(names[(i % 3) as usize]))])).collect();
+    try!(insert_all(&conn, db, "k", docs));
+    try!(conn.create_indexes(vec![index(db, "k", "name_ci", ascending("name"), with_collation(collation_doc(2, false)))]));
+    try!(conn.analyze_stale());
+    let ndx = try!(conn.list_indexes()).into_iter().find(|ndx| ndx.db == db && ndx.name == "name_ci").unwrap();
+    let st = try!(stats::IndexStats::from_options(&ndx.options)).unwrap();
+    let apple = bson::Value::BString(String::from("apple"));
+    let n = st.estimate(&QueryBounds::EQ(vec![(&apple, false)]), try!(ndx.collation_spec()).as_ref()).unwrap();
+    assert!(n > 250.0 && n < 285.0);
+
+    // and once the index is gone, so is the plan which used it
+    try!(conn.delete_indexes(db, "c", &bson::Value::BString(String::from("user_1"))));
+    let mut q = eq_query("flag", 1);
+    q.set_i32("user", 7);
+    assert_eq!(try!(query_ids(&conn, db, "c", q.clone())), vec![7]);
+    assert_eq!(try!(cached_index(&conn, db, "c", q)), Some(String::from("flag_1")));
+
+    Ok(())
+}
+
+pub fn index_intersection(factory: &ConnectionFactory) -> Result<()> {
+    let db = "conf_index_intersection";
+    let conn = try!(factory.open());
+
+    // each value of a or b is in about 1 doc out of 20, so together they
+    // are in about 1 out of 400
+    let docs = (1 .. 401).map(|i| doc_with(i, vec![("a", int(i % 21)), ("b", int(i % 23))])).collect();
+    try!(insert_all(&conn, db, "c", docs));
+    try!(conn.create_indexes(vec![
+        index(db, "c", "a_1", ascending("a"), bson::Document::new()),
+        index(db, "c", "b_1", ascending("b"), bson::Document::new()),
+        ]));
+    try!(conn.analyze_stale());
+
+    // the stats say reading the record ids from both indexes beats
+    // looking up every doc either one finds
+    let mut q = eq_query("a", 3);
+    q.set_i32("b", 1);
+    assert_eq!(try!(plan_kind(&conn, db, "c", q.clone())), "intersection");
+    assert_eq!(try!(query_ids(&conn, db, "c", q)), vec![24]);
+
+    // but not when one of them finds almost nothing by itself
+    let mut q = op_query("a", "$gte", 0);
+    q.set_i32("_id", 7);
+    assert_eq!(try!(plan_kind(&conn, db, "c", q.clone())), "index");
+    assert_eq!(try!(query_ids(&conn, db, "c", q)), vec![7]);
+
+    // a union puts its branches together by record id, so a doc without
+    // an _id which both branches find still comes back just once
+    let mut options = bson::Document::new();
+    options.set_bool("autoIndexId", false);
+    assert!(try!(conn.create_collection(db, "u", options)));
+    {
+        let mut writer = try!(conn.conn.begin_write());
+        for i in 1 .. 41 {
+            let mut d = bson::Document::new();
+            d.set_i32("n", i);
+            d.set_i32("a", i % 5);
+            d.set_i32("b", i % 4);
+            try!(writer.insert(db, "u", &d));
+        }
+        try!(writer.commit());
+    }
+    try!(conn.create_indexes(vec![
+        index(db, "u", "a_1", ascending("a"), bson::Document::new()),
+        index(db, "u", "b_1", ascending("b"), bson::Document::new()),
+        ]));
+    try!(conn.analyze_stale());
+    let q = or_query(eq_query("a", 1), eq_query("b", 2));
+    assert_eq!(try!(plan_kind(&conn, db, "u", q.clone())), "union");
+    let seq = try!(conn.find(db, "u", q, None, None, None, None, None, None, None));
+    let rows = try!(seq.collect::<Result<Vec<_>>>());
+    let mut ns = rows.iter().map(|r| match r.doc.as_document().unwrap().get("n") {
+        Some(&bson::Value::BInt32(n)) => n,
+        _ => panic!(),
+    }).collect::<Vec<_>>();
+    ns.sort();
+    assert_eq!(ns, (1 .. 41).filter(|&i| i % 5 == 1 || i % 4 == 2).collect::<Vec<_>>());
+
+    Ok(())
+}
+
+fn orderby(k: &str, dir: i32) -> bson::Value {
+    let mut d = bson::Document::new();
+    d.set_i32(k, dir);
+    d.into_value()
+}
+
+// the index which gives the order of the sort, and whether it gets
+// scanned backwards.  None means the rows get sorted.
+fn sort_plan(conn: &Connection, db: &str, coll: &str, q: bson::Document, orderby: bson::Value) -> Result<Option<(String, bool)>> {
+    let reader = try!(conn.conn.begin_read());
+    let indexes = try!(reader.list_indexes(Some((db, coll))));
+    let m = try!(matcher::parse_query(q));
+    let plan = try!(Connection::choose_index(&indexes, &m, None, None));
+    let keys = try!(Connection::parse_sort_keys(&orderby));
+    let (plan, sorted) = try!(Connection::plan_for_sort(&indexes, plan, &keys, None, true));
+    let found =
+        match plan {
+            Some(QueryPlan::Regular(ndx, _)) if sorted => Some((ndx.name.clone(), false)),
+            Some(QueryPlan::Reversed(ndx, _)) => Some((ndx.name.clone(), true)),
+            _ => None,
+        };
+    Ok(found)
+}
+
+pub fn index_sort(factory: &ConnectionFactory) -> Result<()> {
+    let db = "conf_index_sort";
+    let conn = try!(factory.open());
+
+    let mut spec = ascending("g");
+    spec.set_i32("t", -1);
+    try!(conn.create_indexes(vec![
+        index(db, "c", "t_1", ascending("t"), bson::Document::new()),
+        index(db, "c", "g_1_t_-1", spec, bson::Document::new()),
+        ]));
+    try!(insert_all(&conn, db, "c", (1 .. 41).map(time_doc).collect()));
+    let up = (1 .. 41).collect::<Vec<_>>();
+    let down = (1 .. 41).rev().collect::<Vec<_>>();
+
+    // a whole index, either way
+    let q = bson::Document::new();
+    assert_eq!(try!(sort_plan(&conn, db, "c", q.clone(), orderby("t", 1))), Some((String::from("t_1"), false)));
+    assert_eq!(try!(find_ids(&conn, db, "c", q.clone(), Some(orderby("t", 1)), None)), up);
+    assert_eq!(try!(sort_plan(&conn, db, "c", q.clone(), orderby("t", -1))), Some((String::from("t_1"), true)));
+    assert_eq!(try!(find_ids(&conn, db, "c", q.clone(), Some(orderby("t", -1)), None)), down);
+
+    // the latest few, from the end of the index
+    let q = op_query("t", "$gt", 30);
+    assert_eq!(try!(sort_plan(&conn, db, "c", q.clone(), orderby("t", -1))), Some((String::from("t_1"), true)));
+    assert_eq!(try!(find_ids(&conn, db, "c", q.clone(), Some(orderby("t", -1)), None)), (31 .. 41).rev().collect::<Vec<_>>());
+    let seq = try!(conn.find(db, "c", q, Some(orderby("t", -1)), None, None, None, None, None, None));
+    assert_eq!(try!(seq.take(3).collect::<Result<Vec<_>>>()).iter().map(row_id).collect::<Vec<_>>(), vec![40, 39, 38]);
+
+    // the sort can start after the fields which are all the same
+    let q = eq_query("g", 1);
+    let latest = (1 .. 41).rev().filter(|i| i % 3 == 1).collect::<Vec<_>>();
+    assert_eq!(try!(sort_plan(&conn, db, "c", q.clone(), orderby("t", -1))), Some((String::from("g_1_t_-1"), false)));
+    assert_eq!(try!(find_ids(&conn, db, "c", q.clone(), Some(orderby("t", -1)), None)), latest);
+    assert_eq!(try!(sort_plan(&conn, db, "c", q.clone(), orderby("t", 1))), Some((String::from("g_1_t_-1"), true)));
+    assert_eq!(try!(find_ids(&conn, db, "c", q, Some(orderby("t", 1)), None)), latest.into_iter().rev().collect::<Vec<_>>());
+
+    // no index for it, so the rows get sorted
+    let q = bson::Document::new();
+    let mut by_z = up.clone();
+    by_z.sort_by(|a, b| ((b * 7) % 50).cmp(&((a * 7) % 50)));
+    assert_eq!(try!(sort_plan(&conn, db, "c", q.clone(), orderby("z", -1))), None);
+    assert_eq!(try!(find_ids(&conn, db, "c", q.clone(), Some(orderby("z", -1)), None)), by_z);
+
+    // and too many of them go to disk in runs, which get merged back
+    let keys = try!(Connection::parse_sort_keys(&orderby("z", -1)));
+    let mut sorter = sort::Sorter::new(200, |a: &Row, b: &Row| Connection::compare_rows(&keys, a, b, None));
+    let seq = try!(conn.find(db, "c", q.clone(), None, None, None, None, None, None, None));
+    for row in seq {
+        try!(sorter.push(try!(row)));
+    }
+    assert!(sorter.spilled() > 1);
+    let rows = try!(try!(sorter.finish()).collect::<Result<Vec<_>>>());
+    assert_eq!(rows.iter().map(row_id).collect::<Vec<_>>(), by_z);
+
+    // an index with an array in it doesn't have the docs in order
+    try!(conn.create_indexes(vec![index(db, "m", "x_1", ascending("x"), bson::Document::new())]));
+    try!(insert_one(&conn, db, "m", doc_with(1, vec![("x", ints(&[5, 1]))])));
+    try!(insert_one(&conn, db, "m", doc_with(2, vec![("x", ints(&[3]))])));
+    assert_eq!(try!(sort_plan(&conn, db, "m", q.clone(), orderby("x", 1))), None);
+    assert_eq!(sorted(try!(find_ids(&conn, db, "m", q, Some(orderby("x", 1)), None))), vec![1, 2]);
+
+    Ok(())
+}
+
+// a sort with the buffer the finds really use.  the docs are big, so it
+// doesn't take many of them to fill it.
+pub fn sort_spill(factory: &ConnectionFactory) -> Result<()> {
+    let db = "conf_sort_spill";
+    let conn = try!(factory.open());
+
+    let filler = (0 .. 1024 * 1024).map(|_| 'x').collect::<String>();
+    let docs = (1 .. 41).map(|i| {
+        let mut d = time_doc(i);
+        d.set_str("filler", &filler);
+        d
+    }).collect::<Vec<_>>();
+    let bytes = docs.iter().map(|d| d.bson_len()).fold(0, |a, b| a + b);
+    assert!(bytes > SORT_BUFFER_BYTES);
+    try!(insert_all(&conn, db, "c", docs));
+
+    let mut by_z = (1 .. 41).collect::<Vec<_>>();
+    by_z.sort_by(|a, b| ((a * 7) % 50).cmp(&((b * 7) % 50)));
+    let q = bson::Document::new();
+    assert_eq!(try!(sort_plan(&conn, db, "c", q.clone(), orderby("z", 1))), None);
+    let seq = try!(conn.find(db, "c", q, Some(orderby("z", 1)), None, None, None, None, None, None));
+    let rows = try!(seq.collect::<Result<Vec<_>>>());
+    assert_eq!(rows.iter().map(row_id).collect::<Vec<_>>(), by_z);
+    for row in rows.iter() {
+        assert_eq!(try!(try!(row.doc.as_document()).must_get_str("filler")).len(), filler.len());
+    }
+
+    Ok(())
+}
+
+pub fn text_search(factory: &ConnectionFactory) -> Result<()> {
+    let conn = try!(factory.open());
+
+    // one doc before the index, so create_index has to build its entries
+    let mut docs = vec![doc_with(1, vec![("a", string("the quick brown fox")), ("b", string("jumps"))])];
+    for r in try!(conn.insert("conf_text", "c", &mut docs, true)) {
+        try!(r);
+    }
+    try!(conn.create_indexes(vec![text_index()]));
+    let mut docs = vec![
+        doc_with(2, vec![("a", string("a brown dog")), ("b", string("sleeps"))]),
+        doc_with(3, vec![("a", string("a red fox")), ("b", string("the quick one"))]),
+        doc_with(4, vec![("a", string("nothing")), ("b", string("here"))]),
+        ];
+    for r in try!(conn.insert("conf_text", "c", &mut docs, true)) {
+        try!(r);
+    }
+
+    assert_eq!(try!(text_search_ids(&conn, "fox")), vec![1, 3]);
+    assert_eq!(try!(text_search_ids(&conn, "brown fox")), vec![1, 2, 3]);
+    assert_eq!(try!(text_search_ids(&conn, "fox -red")), vec![1]);
+    assert_eq!(try!(text_search_ids(&conn, "\"quick brown\"")), vec![1]);
+    assert_eq!(try!(text_search_ids(&conn, "cat")), Vec::<i32>::new());
+
+    // a match in a gets its weight of 10, in b the default of 1
+    for r in try!(text_search_rows(&conn, "quick")) {
+        let id = match r.doc.as_document().unwrap().get("_id") {
+            Some(&bson::Value::BInt32(n)) => n,
+            _ => panic!(),
+        };
+        match id {
+            1 => assert_eq!(r.score, Some(10.0)),
+            3 => assert_eq!(r.score, Some(1.0)),
+            _ => panic!(),
+        }
+    }
+
+    // index entries follow deletes
+    let mut q = bson::Document::new();
+    q.set_i32("_id", 3);
+    let mut d = bson::Document::new();
+    d.set_document("q", q);
+    d.set_i32("limit", 1);
+    assert_eq!(try!(conn.delete("conf_text", "c", vec![d])), 1);
+    assert_eq!(try!(text_search_ids(&conn, "fox")), vec![1]);
+
+    Ok(())
+}
+
+fn geo_query(op: &str, v: bson::Value) -> bson::Document {
+    let mut preds = bson::Document::new();
+    preds.set(op, v);
+    field_query("loc", preds.into_value())
+}
+
+fn geo_point(x: f64, y: f64) -> bson::Value {
+    let mut a = bson::Array::new();
+    a.push(bson::Value::BDouble(x));
+    a.push(bson::Value::BDouble(y));
+    bson::Value::BArray(a)
+}
+
+pub fn geo(factory: &ConnectionFactory) -> Result<()> {
+    let conn = try!(factory.open());
+
+    let mut docs = vec![doc_with(1, vec![("loc", geo_point(0.0, 0.0))])];
+    for r in try!(conn.insert("conf_geo", "g", &mut docs, true)) {
+        try!(r);
+    }
+    let mut spec = bson::Document::new();
+    spec.set_str("loc", "2d");
+    let ndx = IndexInfo {
+        db: String::from("conf_geo"),
+        coll: String::from("g"),
+        name: String::from("loc_2d"),
+        spec: spec,
+        options: bson::Document::new(),
+    };
+    try!(conn.create_indexes(vec![ndx]));
+    let mut nowhere = bson::Document::new();
+    nowhere.set_i32("_id", 5);
+    let mut docs = vec![
+        doc_with(2, vec![("loc", geo_point(1.0, 1.0))]),
+        doc_with(3, vec![("loc", geo_point(5.0, 5.0))]),
+        doc_with(4, vec![("loc", geo_point(-3.0, 2.0))]),
+        nowhere,
+        ];
+    for r in try!(conn.insert("conf_geo", "g", &mut docs, true)) {
+        try!(r);
+    }
+
+    // $near comes back sorted by distance
+    let q = geo_query("$near", geo_point(5.0, 4.0));
+    assert_eq!(try!(find_ids(&conn, "conf_geo", "g", q, None, None)), vec![3, 2, 1, 4]);
+
+    let mut preds = bson::Document::new();
+    preds.set("$near", geo_point(0.0, 0.0));
+    preds.set_f64("$maxDistance", 2.0);
+    let mut q = bson::Document::new();
+    q.set_document("loc", preds);
+    assert_eq!(try!(find_ids(&conn, "conf_geo", "g", q, None, None)), vec![1, 2]);
+
+    let mut a = bson::Array::new();
+    a.push(geo_point(-1.0, -1.0));
+    a.push(geo_point(2.0, 2.0));
+    let mut shape = bson::Document::new();
+    shape.set_array("$box", a);
+    let mut ids = try!(find_ids(&conn, "conf_geo", "g", geo_query("$geoWithin", bson::Value::BDocument(shape)), None, None));
+    ids.sort();
+    assert_eq!(ids, vec![1, 2]);
+
+    let mut a = bson::Array::new();
+    a.push(geo_point(0.0, 0.0));
+    a.push(bson::Value::BDouble(4.0));
+    let mut shape = bson::Document::new();
+    shape.set_array("$center", a);
+    let mut ids = try!(find_ids(&conn, "conf_geo", "g", geo_query("$geoWithin", bson::Value::BDocument(shape)), None, None));
+    ids.sort();
+    assert_eq!(ids, vec![1, 2, 4]);
+
+    let mut stage = bson::Document::new();
+    stage.set("near", geo_point(0.0, 0.0));
+    stage.set_str("distanceField", "dist");
+    stage.set_i32("num", 2);
+    let mut op = bson::Document::new();
+    op.set_document("$geoNear", stage);
+    let mut pipeline = bson::Array::new();
+    pipeline.push(bson::Value::BDocument(op));
+    let (_, seq) = try!(conn.aggregate("conf_geo", "g", pipeline, None));
+    let rows = try!(seq.collect::<Result<Vec<_>>>());
+    assert_eq!(rows.len(), 2);
+    let d = rows[1].doc.as_document().unwrap();
+    assert_eq!(d.get("_id"), Some(&bson::Value::BInt32(2)));
+    assert_eq!(d.get("dist"), Some(&bson::Value::BDouble(2.0f64.sqrt())));
+
+    Ok(())
+}
+
+fn geojson(typ: &str, coords: bson::Value) -> bson::Document {
+    let mut d = bson::Document::new();
+    d.set_str("type", typ);
+    d.set("coordinates", coords);
+    d
+}
+
+fn lnglats(a: &[(f64, f64)]) -> bson::Value {
+    let mut r = bson::Array::new();
+    for &(x, y) in a {
+        r.push(geo_point(x, y));
+    }
+    bson::Value::BArray(r)
+}
+
+fn dollar_geometry(g: bson::Document) -> bson::Value {
+    let mut d = bson::Document::new();
+    d.set_document("$geometry", g);
+    bson::Value::BDocument(d)
+}
+
+pub fn geo_sphere(factory: &ConnectionFactory) -> Result<()> {
+    let conn = try!(factory.open());
+
+    let square = lnglats(&[(-74.0, 40.7), (-73.9, 40.7), (-73.9, 40.8), (-74.0, 40.8), (-74.0, 40.7)]);
+    let mut ring = bson::Array::new();
+    ring.push(square);
+    let mut docs = vec![
+        doc_with(1, vec![("loc", geojson("Point", geo_point(-73.97, 40.77)).into_value())]),
+        doc_with(2, vec![("loc", geojson("Point", geo_point(-73.99, 40.75)).into_value())]),
+        doc_with(3, vec![("loc", geojson("Point", geo_point(-122.42, 37.77)).into_value())]),
+        doc_with(4, vec![("loc", geojson("LineString", lnglats(&[(-73.0, 41.0), (-72.0, 41.5)])).into_value())]),
+        doc_with(5, vec![("loc", geojson("Polygon", bson::Value::BArray(ring)).into_value())]),
+        ];
+    for r in try!(conn.insert("conf_sphere", "s", &mut docs, true)) {
+        try!(r);
+    }
+    let mut spec = bson::Document::new();
+    spec.set_str("loc", "2dsphere");
+    let ndx = IndexInfo {
+        db: String::from("conf_sphere"),
+        coll: String::from("s"),
+        name: String::from("loc_2dsphere"),
+        spec: spec,
+        options: bson::Document::new(),
+    };
+    try!(conn.create_indexes(vec![ndx]));
+
+    let find = |q: bson::Document| -> Result<Vec<i32>> {
+        let seq = try!(conn.find("conf_sphere", "s", q, None, None, None, None, None, None, None));
+        let rows = try!(seq.collect::<Result<Vec<_>>>());
+        let a = rows.iter().map(|r| match r.doc.as_document().unwrap().get("_id") {
+            Some(&bson::Value::BInt32(n)) => n,
+            _ => panic!(),
+        }).collect::<Vec<_>>();
+        Ok(a)
+    };
+
+    // the closest one comes first
+    let q = geo_query("$near", dollar_geometry(geojson("Point", geo_point(-122.0, 37.5))));
+    let ids = try!(find(q));
+    assert_eq!(ids.len(), 5);
+    assert_eq!(ids[0], 3);
+
+    // $maxDistance is in meters for GeoJSON
+    let mut near = bson::Document::new();
+    near.set_document("$geometry", geojson("Point", geo_point(-73.97, 40.77)));
+    near.set_f64("$maxDistance", 5000.0);
+    let q = geo_query("$near", bson::Value::BDocument(near));
+    assert_eq!(sorted(try!(find(q))), vec![1, 2, 5]);
+
+    // and in radians for a legacy point
+    let mut preds = bson::Document::new();
+    preds.set("$nearSphere", geo_point(-73.97, 40.77));
+    preds.set_f64("$maxDistance", 5000.0 / 6378100.0);
+    let mut q = bson::Document::new();
+    q.set_document("loc", preds);
+    assert_eq!(sorted(try!(find(q))), vec![1, 2, 5]);
+
+    let big = lnglats(&[(-74.1, 40.6), (-73.8, 40.6), (-73.8, 40.9), (-74.1, 40.9), (-74.1, 40.6)]);
+    let mut ring = bson::Array::new();
+    ring.push(big);
+    let q = geo_query("$geoWithin", dollar_geometry(geojson("Polygon", bson::Value::BArray(ring))));
+    assert_eq!(sorted(try!(find(q))), vec![1, 2, 5]);
+
+    let line = lnglats(&[(-74.05, 40.75), (-73.95, 40.75)]);
+    let q = geo_query("$geoIntersects", dollar_geometry(geojson("LineString", line)));
+    assert_eq!(sorted(try!(find(q))), vec![2, 5]);
+
+    let mut stage = bson::Document::new();
+    stage.set_document("near", geojson("Point", geo_point(-122.0, 37.5)));
+    stage.set_bool("spherical", true);
+    stage.set_str("distanceField", "dist");
+    stage.set_i32("num", 1);
+    let mut op = bson::Document::new();
+    op.set_document("$geoNear", stage);
+    let mut pipeline = bson::Array::new();
+    pipeline.push(bson::Value::BDocument(op));
+    let (_, seq) = try!(conn.aggregate("conf_sphere", "s", pipeline, None));
+    let rows = try!(seq.collect::<Result<Vec<_>>>());
+    assert_eq!(rows.len(), 1);
+    let d = rows[0].doc.as_document().unwrap();
+    assert_eq!(d.get("_id"), Some(&bson::Value::BInt32(3)));
+    match d.get("dist") {
+        Some(&bson::Value::BDouble(f)) => assert!(f > 30000.0 && f < 60000.0),
+        _ => panic!(),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,847 @@
+/*
+    Copyright 2014-2016 Zumero, LLC
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+
+// geo support.  a location in a doc is either a legacy coordinate pair,
+// an array [x, y] or a document whose first two values are x and y, or
+// a GeoJSON Point, LineString or Polygon.
+//
+// geo indexes don't need anything special from the storage engines.
+// an index entry is just a number, a cell in a grid.  the cell is found
+// by cutting the bounds of the index in half, over and over, alternating
+// between x and y, `bits` times for each axis.  the bits are interleaved
+// (a z-order curve, like a geohash), so any cell at a coarser level is one
+// contiguous range of the finest cells.  a query becomes a handful of
+// those ranges, which are regular index scans.
+//
+// a 2d index only holds points, so every entry is a cell at the finest
+// level.  a 2dsphere index also holds lines and polygons, which get
+// covered by a few cells at whatever level fits them.  so the level goes
+// into the high bits of the entry, and a query does its ranges once
+// for each level.
+//
+// for 2dsphere, x is longitude and y is latitude, in degrees.  distances
+// are great circle distances, but the edges of lines and polygons are
+// treated as straight lines in lng/lat, not as great circles.
+
+use std;
+
+use super::Result;
+use super::Error;
+
+extern crate bson;
+
+pub const DEFAULT_BITS: u32 = 26;
+pub const DEFAULT_MIN: f64 = -180.0;
+pub const DEFAULT_MAX: f64 = 180.0;
+
+// same as mongo
+pub const EARTH_RADIUS_METERS: f64 = 6378100.0;
+
+// two bits per level have to fit in a positive i64
+const MAX_BITS: u32 = 31;
+
+// for 2dsphere entries, the level goes above the cell
+const LEVEL_SHIFT: u32 = 56;
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct Point {
+    pub x: f64,
+    pub y: f64,
+}
+
+#[derive(Debug,Clone)]
+pub enum Geometry {
+    Point(Point),
+    LineString(Vec<Point>),
+    // the first ring is the outside.  any others are holes.
+    Polygon(Vec<Vec<Point>>),
+}
+
+fn num(v: &bson::Value) -> Option<f64> {
+    match v {
+        &bson::Value::BInt32(n) => Some(n as f64),
+        &bson::Value::BInt64(n) => Some(n as f64),
+        &bson::Value::BDouble(f) => Some(f),
+        _ => None,
+    }
+}
+
+pub fn point_from_value(v: &bson::Value) -> Option<Point> {
+    let (x, y) =
+        match v {
+            &bson::Value::BArray(ref ba) => {
+                if ba.items.len() < 2 {
+                    return None;
+                }
+                (&ba.items[0], &ba.items[1])
+            },
+            &bson::Value::BDocument(ref bd) => {
+                if bd.pairs.len() < 2 {
+                    return None;
+                }
+                (&bd.pairs[0].1, &bd.pairs[1].1)
+            },
+            _ => return None,
+        };
+    match (num(x), num(y)) {
+        (Some(x), Some(y)) => Some(Point {x: x, y: y}),
+        _ => None,
+    }
+}
+
+// a location in a doc can be one point, or an array of them
+pub fn points_from_value(v: &bson::Value) -> Option<Vec<Point>> {
+    if let Some(p) = point_from_value(v) {
+        return Some(vec![p]);
+    }
+    match v {
+        &bson::Value::BArray(ref ba) if ba.items.len() > 0 => {
+            ba.items.iter().map(|v| point_from_value(v)).collect::<Option<Vec<_>>>()
+        },
+        _ => None,
+    }
+}
+
+pub fn parse_point(v: &bson::Value) -> Result<Point> {
+    match point_from_value(v) {
+        Some(p) => Ok(p),
+        None => Err(Error::Misc(format!("invalid point: {:?}", v))),
+    }
+}
+
+fn is_geojson(v: &bson::Value) -> bool {
+    match v {
+        &bson::Value::BDocument(ref bd) => bd.get("type").is_some() && bd.get("coordinates").is_some(),
+        _ => false,
+    }
+}
+
+fn parse_lnglat(v: &bson::Value) -> Result<Point> {
+    let p = try!(parse_point(v));
+    if p.x < -180.0 || p.x > 180.0 || p.y < -90.0 || p.y > 90.0 {
+        return Err(Error::Misc(format!("longitude/latitude is out of bounds: {:?}", p)));
+    }
+    Ok(p)
+}
+
+fn parse_lnglats(v: &bson::Value) -> Result<Vec<Point>> {
+    let a = try!(v.as_array());
+    a.items.iter().map(|v| parse_lnglat(v)).collect::<Result<Vec<_>>>()
+}
+
+pub fn parse_geojson(v: &bson::Value) -> Result<Geometry> {
+    let bd = try!(v.as_document());
+    let coords =
+        match bd.get("coordinates") {
+            Some(v) => v,
+            None => return Err(Error::Misc(String::from("GeoJSON needs coordinates"))),
+        };
+    match try!(bd.must_get_str("type")) {
+        "Point" => {
+            Ok(Geometry::Point(try!(parse_lnglat(coords))))
+        },
+        "LineString" => {
+            let a = try!(parse_lnglats(coords));
+            if a.len() < 2 {
+                return Err(Error::Misc(String::from("GeoJSON LineString must have at least 2 vertices")));
+            }
+            Ok(Geometry::LineString(a))
+        },
+        "Polygon" => {
+            let rings = try!(try!(coords.as_array()).items.iter().map(|v| parse_lnglats(v)).collect::<Result<Vec<_>>>());
+            if rings.len() == 0 {
+                return Err(Error::Misc(String::from("GeoJSON Polygon needs a ring")));
+            }
+            for ring in rings.iter() {
+                if ring.len() < 4 {
+                    return Err(Error::Misc(String::from("GeoJSON Polygon ring must have at least 4 vertices")));
+                }
+                if ring[0] != ring[ring.len() - 1] {
+                    return Err(Error::Misc(String::from("GeoJSON Polygon ring is not closed")));
+                }
+            }
+            Ok(Geometry::Polygon(rings))
+        },
+        t => {
+            Err(Error::Misc(format!("unsupported GeoJSON type: {}", t)))
+        },
+    }
+}
+
+// {$geometry: {type: ..., coordinates: ...}}
+pub fn parse_dollar_geometry(v: &bson::Value) -> Result<Geometry> {
+    let bd = try!(v.as_document());
+    match bd.get("$geometry") {
+        Some(v) => parse_geojson(v),
+        None => Err(Error::Misc(format!("expected $geometry: {:?}", v))),
+    }
+}
+
+pub fn geometry_from_value(v: &bson::Value) -> Option<Geometry> {
+    if is_geojson(v) {
+        parse_geojson(v).ok()
+    } else {
+        point_from_value(v).map(|p| Geometry::Point(p))
+    }
+}
+
+// like points_from_value
+pub fn geometries_from_value(v: &bson::Value) -> Option<Vec<Geometry>> {
+    if let Some(g) = geometry_from_value(v) {
+        return Some(vec![g]);
+    }
+    match v {
+        &bson::Value::BArray(ref ba) if ba.items.len() > 0 => {
+            ba.items.iter().map(|v| geometry_from_value(v)).collect::<Option<Vec<_>>>()
+        },
+        _ => None,
+    }
+}
+
+// everything found at the path, since the matcher has to
+// consider every leaf.
+pub fn geometries_at_path(doc: &bson::Value, path: &str) -> Vec<Geometry> {
+    doc.walk_path(path).leaves().filter_map(
+        |leaf|
+        match leaf.v {
+            Some(v) => geometry_from_value(v),
+            None => None,
+        }
+        ).collect::<Vec<_>>()
+}
+
+pub fn distance(a: &Point, b: &Point) -> f64 {
+    let dx = a.x - b.x;
+    let dy = a.y - b.y;
+    (dx * dx + dy * dy).sqrt()
+}
+
+// great circle distance in radians, with haversine
+pub fn sphere_distance(a: &Point, b: &Point) -> f64 {
+    let lat1 = a.y.to_radians();
+    let lat2 = b.y.to_radians();
+    let dlat = lat2 - lat1;
+    let dlng = (b.x - a.x).to_radians();
+    let h = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlng / 2.0).sin().powi(2);
+    2.0 * h.sqrt().min(1.0).asin()
+}
+
+// the lng/lat box around a circle on the sphere.  if the circle gets
+// near a pole or wraps around the antimeridian, it just gets every
+// longitude.
+fn sphere_box(c: &Point, r: f64) -> (Point, Point) {
+    let dlat = r.to_degrees();
+    let lat0 = c.y - dlat;
+    let lat1 = c.y + dlat;
+    let everywhere = (Point {x: -180.0, y: lat0.max(-90.0)}, Point {x: 180.0, y: lat1.min(90.0)});
+    if lat0 <= -90.0 || lat1 >= 90.0 {
+        return everywhere;
+    }
+    let s = r.sin() / c.y.to_radians().cos();
+    if s >= 1.0 {
+        return everywhere;
+    }
+    let dlng = s.asin().to_degrees();
+    if c.x - dlng < -180.0 || c.x + dlng > 180.0 {
+        return everywhere;
+    }
+    (Point {x: c.x - dlng, y: lat0}, Point {x: c.x + dlng, y: lat1})
+}
+
+fn bounding_box_of(a: &[Point]) -> (Point, Point) {
+    let mut lo = a[0];
+    let mut hi = a[0];
+    for p in a {
+        lo.x = lo.x.min(p.x);
+        lo.y = lo.y.min(p.y);
+        hi.x = hi.x.max(p.x);
+        hi.y = hi.y.max(p.y);
+    }
+    (lo, hi)
+}
+
+fn cross(a: &Point, b: &Point, p: &Point) -> f64 {
+    (b.x - a.x) * (p.y - a.y) - (b.y - a.y) * (p.x - a.x)
+}
+
+fn on_segment(a: &Point, b: &Point, p: &Point) -> bool {
+    cross(a, b, p) == 0.0
+        && p.x >= a.x.min(b.x) && p.x <= a.x.max(b.x)
+        && p.y >= a.y.min(b.y) && p.y <= a.y.max(b.y)
+}
+
+fn segments_intersect(a: &Point, b: &Point, c: &Point, d: &Point) -> bool {
+    let d1 = cross(c, d, a);
+    let d2 = cross(c, d, b);
+    let d3 = cross(a, b, c);
+    let d4 = cross(a, b, d);
+    if ((d1 > 0.0 && d2 < 0.0) || (d1 < 0.0 && d2 > 0.0))
+        && ((d3 > 0.0 && d4 < 0.0) || (d3 < 0.0 && d4 > 0.0)) {
+        return true;
+    }
+    on_segment(c, d, a) || on_segment(c, d, b) || on_segment(a, b, c) || on_segment(a, b, d)
+}
+
+// only a crossing where each segment goes through the interior of the
+// other.  touching doesn't count.
+fn segments_cross(a: &Point, b: &Point, c: &Point, d: &Point) -> bool {
+    let d1 = cross(c, d, a);
+    let d2 = cross(c, d, b);
+    let d3 = cross(a, b, c);
+    let d4 = cross(a, b, d);
+    ((d1 > 0.0 && d2 < 0.0) || (d1 < 0.0 && d2 > 0.0))
+        && ((d3 > 0.0 && d4 < 0.0) || (d3 < 0.0 && d4 > 0.0))
+}
+
+fn on_ring(a: &[Point], p: &Point) -> bool {
+    let mut j = a.len() - 1;
+    for i in 0 .. a.len() {
+        if on_segment(&a[i], &a[j], p) {
+            return true;
+        }
+        j = i;
+    }
+    false
+}
+
+// ray casting.  points on an edge count as inside, like mongo.
+fn polygon_contains(a: &[Point], p: &Point) -> bool {
+    if on_ring(a, p) {
+        return true;
+    }
+    let mut inside = false;
+    let mut j = a.len() - 1;
+    for i in 0 .. a.len() {
+        if (a[i].y > p.y) != (a[j].y > p.y) {
+            let x = (a[j].x - a[i].x) * (p.y - a[i].y) / (a[j].y - a[i].y) + a[i].x;
+            if p.x < x {
+                inside = !inside;
+            }
+        }
+        j = i;
+    }
+    inside
+}
+
+impl Geometry {
+    pub fn vertices(&self) -> Vec<Point> {
+        match self {
+            &Geometry::Point(p) => vec![p],
+            &Geometry::LineString(ref a) => a.clone(),
+            &Geometry::Polygon(ref rings) => rings.iter().flat_map(|r| r.iter().cloned()).collect(),
+        }
+    }
+
+    pub fn edges(&self) -> Vec<(Point, Point)> {
+        match self {
+            &Geometry::Point(_) => vec![],
+            &Geometry::LineString(ref a) => a.windows(2).map(|w| (w[0], w[1])).collect(),
+            &Geometry::Polygon(ref rings) => rings.iter().flat_map(|r| r.windows(2).map(|w| (w[0], w[1]))).collect(),
+        }
+    }
+
+    pub fn bounding_box(&self) -> (Point, Point) {
+        bounding_box_of(&self.vertices())
+    }
+
+    pub fn contains_point(&self, p: &Point) -> bool {
+        match self {
+            &Geometry::Point(ref q) => p == q,
+            &Geometry::LineString(ref a) => a.windows(2).any(|w| on_segment(&w[0], &w[1], p)),
+            &Geometry::Polygon(ref rings) => {
+                polygon_contains(&rings[0], p)
+                    && !rings[1 ..].iter().any(|h| polygon_contains(h, p) && !on_ring(h, p))
+            },
+        }
+    }
+
+    pub fn intersects(&self, other: &Geometry) -> bool {
+        if self.vertices().iter().any(|p| other.contains_point(p)) {
+            return true;
+        }
+        if other.vertices().iter().any(|p| self.contains_point(p)) {
+            return true;
+        }
+        let e2 = other.edges();
+        self.edges().iter().any(|&(a, b)| e2.iter().any(|&(c, d)| segments_intersect(&a, &b, &c, &d)))
+    }
+
+    pub fn within(&self, other: &Geometry) -> bool {
+        if !self.vertices().iter().all(|p| other.contains_point(p)) {
+            return false;
+        }
+        let e2 = other.edges();
+        !self.edges().iter().any(|&(a, b)| e2.iter().any(|&(c, d)| segments_cross(&a, &b, &c, &d)))
+    }
+}
+
+#[derive(Debug,Clone)]
+pub enum Shape {
+    Box(Point, Point),
+    Center(Point, f64),
+    Polygon(Vec<Point>),
+    // radius in radians
+    CenterSphere(Point, f64),
+    Geometry(Geometry),
+}
+
+fn parse_points(v: &bson::Value) -> Result<Vec<Point>> {
+    match v {
+        &bson::Value::BArray(ref ba) => {
+            ba.items.iter().map(|v| parse_point(v)).collect::<Result<Vec<_>>>()
+        },
+        _ => Err(Error::Misc(format!("expected an array of points: {:?}", v))),
+    }
+}
+
+fn parse_center(v: &bson::Value) -> Result<(Point, f64)> {
+    let a = try!(v.as_array());
+    if a.items.len() != 2 {
+        return Err(Error::Misc(String::from("a center needs a point and a radius")));
+    }
+    let p = try!(parse_point(&a.items[0]));
+    let r = try!(a.items[1].numeric_to_f64());
+    if r < 0.0 {
+        return Err(Error::Misc(String::from("radius must be positive")));
+    }
+    Ok((p, r))
+}
+
+impl Shape {
+    pub fn parse(v: bson::Value) -> Result<Shape> {
+        let bd = try!(v.into_document());
+        if bd.pairs.len() != 1 {
+            return Err(Error::Misc(format!("$geoWithin wants one shape: {:?}", bd)));
+        }
+        let (ref k, ref v) = bd.pairs[0];
+        match k.as_str() {
+            "$box" => {
+                let a = try!(parse_points(v));
+                if a.len() != 2 {
+                    return Err(Error::Misc(String::from("$box needs two points")));
+                }
+                let lo = Point {x: a[0].x.min(a[1].x), y: a[0].y.min(a[1].y)};
+                let hi = Point {x: a[0].x.max(a[1].x), y: a[0].y.max(a[1].y)};
+                Ok(Shape::Box(lo, hi))
+            },
+            "$center" => {
+                let (p, r) = try!(parse_center(v));
+                Ok(Shape::Center(p, r))
+            },
+            "$centerSphere" => {
+                let (p, r) = try!(parse_center(v));
+                Ok(Shape::CenterSphere(p, r))
+            },
+            "$polygon" => {
+                let a = try!(parse_points(v));
+                if a.len() < 3 {
+                    return Err(Error::Misc(String::from("$polygon needs at least three points")));
+                }
+                Ok(Shape::Polygon(a))
+            },
+            "$geometry" => {
+                match try!(parse_geojson(v)) {
+                    g @ Geometry::Polygon(_) => Ok(Shape::Geometry(g)),
+                    _ => Err(Error::Misc(String::from("$geoWithin $geometry must be a Polygon"))),
+                }
+            },
+            _ => {
+                Err(Error::Misc(format!("unknown shape for $geoWithin: {}", k)))
+            },
+        }
+    }
+
+    pub fn contains(&self, g: &Geometry) -> bool {
+        match self {
+            &Shape::Box(ref lo, ref hi) => {
+                g.vertices().iter().all(|p| p.x >= lo.x && p.x <= hi.x && p.y >= lo.y && p.y <= hi.y)
+            },
+            &Shape::Center(ref c, r) => {
+                g.vertices().iter().all(|p| distance(c, p) <= r)
+            },
+            &Shape::Polygon(ref a) => {
+                g.vertices().iter().all(|p| polygon_contains(a, p))
+            },
+            &Shape::CenterSphere(ref c, r) => {
+                g.vertices().iter().all(|p| sphere_distance(c, p) <= r)
+            },
+            &Shape::Geometry(ref poly) => {
+                g.within(poly)
+            },
+        }
+    }
+
+    pub fn bounding_box(&self) -> (Point, Point) {
+        match self {
+            &Shape::Box(lo, hi) => (lo, hi),
+            &Shape::Center(c, r) => (Point {x: c.x - r, y: c.y - r}, Point {x: c.x + r, y: c.y + r}),
+            &Shape::Polygon(ref a) => bounding_box_of(a),
+            &Shape::CenterSphere(c, r) => sphere_box(&c, r),
+            &Shape::Geometry(ref g) => g.bounding_box(),
+        }
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct Near {
+    pub point: Point,
+    pub max_distance: Option<f64>,
+    // distance on the sphere instead of in the plane
+    pub spherical: bool,
+    // a GeoJSON point means distances are in meters.  a legacy point
+    // with spherical means radians.
+    pub meters: bool,
+}
+
+impl Near {
+    pub fn parse(v: bson::Value, spherical: bool) -> Result<Near> {
+        // {$geometry: <GeoJSON point>, $maxDistance: ...}
+        if let bson::Value::BDocument(ref bd) = v {
+            if bd.get("$geometry").is_some() {
+                let p =
+                    match try!(parse_dollar_geometry(&v)) {
+                        Geometry::Point(p) => p,
+                        _ => return Err(Error::Misc(String::from("$near needs a GeoJSON Point"))),
+                    };
+                let max_distance =
+                    match bd.get("$maxDistance") {
+                        Some(v) => Some(try!(v.numeric_to_f64())),
+                        None => None,
+                    };
+                let near = Near {
+                    point: p,
+                    max_distance: max_distance,
+                    spherical: true,
+                    meters: true,
+                };
+                return Ok(near);
+            }
+        }
+        let meters = is_geojson(&v);
+        let p =
+            if meters {
+                match try!(parse_geojson(&v)) {
+                    Geometry::Point(p) => p,
+                    _ => return Err(Error::Misc(String::from("$near needs a GeoJSON Point"))),
+                }
+            } else {
+                try!(parse_point(&v))
+            };
+        let near = Near {
+            point: p,
+            max_distance: None,
+            spherical: spherical || meters,
+            meters: meters,
+        };
+        Ok(near)
+    }
+
+    pub fn distance(&self, p: &Point) -> f64 {
+        if self.spherical {
+            let d = sphere_distance(&self.point, p);
+            if self.meters {
+                d * EARTH_RADIUS_METERS
+            } else {
+                d
+            }
+        } else {
+            distance(&self.point, p)
+        }
+    }
+
+    // TODO this is the distance to the closest vertex, not to the
+    // closest point on an edge.
+    pub fn distance_to(&self, g: &Geometry) -> f64 {
+        if g.contains_point(&self.point) {
+            return 0.0;
+        }
+        g.vertices().iter().map(|p| self.distance(p)).fold(std::f64::INFINITY, |a, d| a.min(d))
+    }
+
+    pub fn contains(&self, g: &Geometry) -> bool {
+        match self.max_distance {
+            Some(d) => self.distance_to(g) <= d,
+            None => true,
+        }
+    }
+}
+
+#[derive(Debug,Clone)]
+pub enum GeoQuery {
+    Near(Near),
+    Within(Shape),
+    Intersects(Geometry),
+}
+
+impl GeoQuery {
+    pub fn matches(&self, g: &Geometry) -> bool {
+        match self {
+            &GeoQuery::Near(ref near) => near.contains(g),
+            &GeoQuery::Within(ref shape) => shape.contains(g),
+            &GeoQuery::Intersects(ref q) => g.intersects(q),
+        }
+    }
+
+    // None means the query could be anywhere
+    pub fn bounding_box(&self) -> Option<(Point, Point)> {
+        match self {
+            &GeoQuery::Near(ref near) => {
+                match near.max_distance {
+                    Some(d) => {
+                        let p = near.point;
+                        if near.spherical {
+                            let r = if near.meters { d / EARTH_RADIUS_METERS } else { d };
+                            Some(sphere_box(&p, r))
+                        } else {
+                            Some((Point {x: p.x - d, y: p.y - d}, Point {x: p.x + d, y: p.y + d}))
+                        }
+                    },
+                    None => None,
+                }
+            },
+            &GeoQuery::Within(ref shape) => Some(shape.bounding_box()),
+            &GeoQuery::Intersects(ref g) => Some(g.bounding_box()),
+        }
+    }
+
+    pub fn is_near(&self) -> bool {
+        match self {
+            &GeoQuery::Near(_) => true,
+            _ => false,
+        }
+    }
+
+    // a 2d index only has points, so it can't help with GeoJSON shapes
+    pub fn needs_2dsphere(&self) -> bool {
+        match self {
+            &GeoQuery::Near(ref near) => near.meters,
+            &GeoQuery::Within(Shape::Geometry(_)) => true,
+            &GeoQuery::Within(_) => false,
+            &GeoQuery::Intersects(_) => true,
+        }
+    }
+}
+
+#[derive(Debug,Clone,Copy)]
+pub struct Grid {
+    min: f64,
+    max: f64,
+    bits: u32,
+    // whether max itself is in bounds
+    closed: bool,
+}
+
+fn interleave(ix: u64, iy: u64, bits: u32) -> u64 {
+    let mut cell = 0u64;
+    for i in (0 .. bits).rev() {
+        cell = (cell << 1) | ((ix >> i) & 1);
+        cell = (cell << 1) | ((iy >> i) & 1);
+    }
+    cell
+}
+
+impl Grid {
+    pub fn from_options(options: &bson::Document) -> Result<Grid> {
+        let min =
+            match options.get("min") {
+                Some(v) => try!(v.numeric_to_f64()),
+                None => DEFAULT_MIN,
+            };
+        let max =
+            match options.get("max") {
+                Some(v) => try!(v.numeric_to_f64()),
+                None => DEFAULT_MAX,
+            };
+        let bits =
+            match options.get("bits") {
+                Some(v) => try!(v.numeric_to_i32()),
+                None => DEFAULT_BITS as i32,
+            };
+        if bits < 1 || bits > (MAX_BITS as i32) {
+            return Err(Error::MongoCode(13028, format!("bits in geo index must be between 1 and {}", MAX_BITS)));
+        }
+        if !(min < max) {
+            return Err(Error::MongoCode(13067, String::from("geo field bounds need max > min")));
+        }
+        let g = Grid {
+            min: min,
+            max: max,
+            bits: bits as u32,
+            closed: false,
+        };
+        Ok(g)
+    }
+
+    // latitude only uses the middle half of this, but a square grid
+    // keeps things simple.
+    pub fn sphere() -> Grid {
+        Grid {
+            min: -180.0,
+            max: 180.0,
+            bits: DEFAULT_BITS,
+            closed: true,
+        }
+    }
+
+    fn scale(&self, f: f64) -> u64 {
+        let n = 1u64 << self.bits;
+        let f = (f - self.min) / (self.max - self.min) * (n as f64);
+        if f <= 0.0 {
+            0
+        } else if f >= (n - 1) as f64 {
+            n - 1
+        } else {
+            f as u64
+        }
+    }
+
+    fn in_bounds(&self, f: f64) -> bool {
+        f >= self.min && (f < self.max || (self.closed && f == self.max))
+    }
+
+    pub fn cell(&self, p: &Point) -> Result<i64> {
+        if !self.in_bounds(p.x) || !self.in_bounds(p.y) {
+            return Err(Error::MongoCode(13027, format!("point not in interval of [ {}, {} ): {:?}", self.min, self.max, p)));
+        }
+        Ok(interleave(self.scale(p.x), self.scale(p.y), self.bits) as i64)
+    }
+
+    pub fn everything(&self) -> Vec<(i64, i64)> {
+        vec![(0, (1u64 << (2 * self.bits)) as i64)]
+    }
+
+    fn scale_box(&self, lo: &Point, hi: &Point) -> (u64, u64, u64, u64) {
+        (self.scale(lo.x), self.scale(lo.y), self.scale(hi.x), self.scale(hi.y))
+    }
+
+    // the deepest level where a cell is still about a quarter of the
+    // box.  going deeper than that just makes more ranges.
+    fn depth_for_box(&self, q: (u64, u64, u64, u64)) -> u32 {
+        let span = std::cmp::max(q.2 - q.0, q.3 - q.1) + 1;
+        let mut depth = 0;
+        while depth < self.bits && (1u64 << (self.bits - depth - 1)) * 4 >= span {
+            depth = depth + 1;
+        }
+        depth
+    }
+
+    // returns ranges of cells, [start, end), in order, which cover the
+    // box.  the ranges can include cells which are only partly inside, so
+    // the caller still has to check the points.
+    pub fn ranges_for_box(&self, lo: &Point, hi: &Point) -> Vec<(i64, i64)> {
+        let q = self.scale_box(lo, hi);
+        let max_depth = self.depth_for_box(q);
+        let mut a = Vec::new();
+        self.add_ranges(0, max_depth, 0, 0, 0, q, &mut a);
+        a
+    }
+
+    fn add_ranges(&self, depth: u32, max_depth: u32, cell: u64, x0: u64, y0: u64, q: (u64, u64, u64, u64), a: &mut Vec<(i64, i64)>) {
+        let (qx0, qy0, qx1, qy1) = q;
+        let side = 1u64 << (self.bits - depth);
+        let x1 = x0 + side - 1;
+        let y1 = y0 + side - 1;
+        if x1 < qx0 || x0 > qx1 || y1 < qy0 || y0 > qy1 {
+            return;
+        }
+        let inside = x0 >= qx0 && x1 <= qx1 && y0 >= qy0 && y1 <= qy1;
+        if inside || depth == max_depth {
+            let shift = 2 * (self.bits - depth);
+            let start = (cell << shift) as i64;
+            let end = ((cell + 1) << shift) as i64;
+            // children are visited in order, so merge with the previous
+            // range when they touch.
+            if let Some(last) = a.last_mut() {
+                if last.1 == start {
+                    last.1 = end;
+                    return;
+                }
+            }
+            a.push((start, end));
+            return;
+        }
+        let half = side / 2;
+        // the x bit comes before the y bit, same as in cell()
+        for xb in 0 .. 2 {
+            for yb in 0 .. 2 {
+                self.add_ranges(depth + 1, max_depth, (cell << 2) | (xb << 1) | yb, x0 + xb * half, y0 + yb * half, q, a);
+            }
+        }
+    }
+
+    fn level_key(level: u32, cell: u64) -> i64 {
+        (((level as u64) << LEVEL_SHIFT) | cell) as i64
+    }
+
+    // the entries for a geometry in a 2dsphere index.  a point gets its
+    // cell at the finest level.  anything else gets the cells, at one
+    // level, which cover its bounding box.
+    pub fn sphere_keys(&self, g: &Geometry) -> Result<Vec<i64>> {
+        for p in g.vertices() {
+            // just for the bounds check
+            try!(self.cell(&p));
+        }
+        match g {
+            &Geometry::Point(ref p) => {
+                let cell = try!(self.cell(p)) as u64;
+                Ok(vec![Self::level_key(self.bits, cell)])
+            },
+            _ => {
+                let (lo, hi) = g.bounding_box();
+                let q = self.scale_box(&lo, &hi);
+                let depth = self.depth_for_box(q);
+                let shift = self.bits - depth;
+                let mut a = vec![];
+                for ix in (q.0 >> shift) .. (q.2 >> shift) + 1 {
+                    for iy in (q.1 >> shift) .. (q.3 >> shift) + 1 {
+                        a.push(Self::level_key(depth, interleave(ix, iy, depth)));
+                    }
+                }
+                a.sort();
+                Ok(a)
+            },
+        }
+    }
+
+    // the ranges of 2dsphere entries which might be in the box.  a
+    // cell at a coarser level is found by shifting the finest ranges.
+    pub fn sphere_ranges_for_box(&self, lo: &Point, hi: &Point) -> Vec<(i64, i64)> {
+        let fine = self.ranges_for_box(lo, hi);
+        let mut a: Vec<(i64, i64)> = vec![];
+        for level in 0 .. self.bits + 1 {
+            let shift = 2 * (self.bits - level);
+            let first = a.len();
+            for &(start, end) in fine.iter() {
+                let start = Self::level_key(level, (start as u64) >> shift);
+                let end = Self::level_key(level, (((end - 1) as u64) >> shift) + 1);
+                if a.len() > first {
+                    let last = a.last_mut().expect("len > first");
+                    if last.1 >= start {
+                        last.1 = std::cmp::max(last.1, end);
+                        continue;
+                    }
+                }
+                a.push((start, end));
+            }
+        }
+        a
+    }
+
+    pub fn sphere_everything(&self) -> Vec<(i64, i64)> {
+        vec![(0, Self::level_key(self.bits + 1, 0))]
+    }
+}
+
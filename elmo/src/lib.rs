@@ -22,6 +22,8 @@
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::cmp::Ordering;
+use std::sync::Condvar;
+use std::sync::Mutex;
 
 extern crate time;
 
@@ -175,6 +177,13 @@ pub fn mongo_strftime(fmt: &str, tm: &time::Tm) -> Result<String> {
 }
 
 mod matcher;
+mod geo;
+mod collation;
+mod stats;
+mod sort;
+
+use collation::Collation;
+pub mod conformance;
 
 pub struct CollectionInfo {
     pub db: String,
@@ -183,7 +192,7 @@ pub struct CollectionInfo {
 }
 
 // TODO remove derive Clone later
-#[derive(Clone,Debug)]
+#[derive(Clone,Debug,PartialEq)]
 pub struct IndexInfo {
     pub db: String,
     pub coll: String,
@@ -203,6 +212,57 @@ impl IndexInfo {
             _ => false,
         }
     }
+
+    // one document can have several entries in a multikey index, so two
+    // bounds on the same key might be satisfied by two different entries.
+    // an index without the flag at all is from before we kept track, so
+    // we have to assume the worst.  except for _id_, because an _id can't
+    // be an array.
+    pub fn is_multikey(&self) -> bool {
+        self.name != "_id_" && is_multikey(&self.options)
+    }
+
+    fn partial_filter(&self) -> Result<Option<matcher::QueryDoc>> {
+        get_partial_filter(&self.options)
+    }
+
+    pub fn collation_spec(&self) -> Result<Option<bson::CollationSpec>> {
+        let (normspec, weights) = try!(get_normalized_spec(&self.spec, &self.options));
+        get_index_collation(&normspec, &weights, &self.options)
+    }
+
+    // catch a bad partialFilterExpression when the index gets created,
+    // not on the first insert
+    fn check_partial_filter(&self) -> Result<()> {
+        match try!(self.partial_filter()) {
+            Some(filter) => {
+                if self.is_sparse() {
+                    Err(Error::MongoCode(67, String::from("cannot mix partialFilterExpression and sparse")))
+                } else if !matcher::is_partial_filter(&filter) {
+                    Err(Error::MongoCode(67, String::from("unsupported expression in partial index")))
+                } else {
+                    Ok(())
+                }
+            },
+            None => Ok(()),
+        }
+    }
+}
+
+pub fn is_multikey(options: &bson::Document) -> bool {
+    match options.get("multikey") {
+        Some(&bson::Value::BBoolean(b)) => b,
+        _ => true,
+    }
+}
+
+// a partial index only has entries for the docs which match this
+fn get_partial_filter(options: &bson::Document) -> Result<Option<matcher::QueryDoc>> {
+    match options.get("partialFilterExpression") {
+        None => Ok(None),
+        Some(&bson::Value::BDocument(ref d)) => Ok(Some(try!(matcher::parse_query(d.clone())))),
+        Some(_) => Err(Error::MongoCode(67, String::from("partialFilterExpression must be a document"))),
+    }
 }
 
 // TODO should be called IndexKey?
@@ -228,9 +288,152 @@ pub enum QueryBounds<'a> {
     GTE_LTE(QueryKey<'a>, QueryKey<'a>, QueryKey<'a>),
 }
 
+// how many docs a background index build adds per write transaction
+const INDEX_BUILD_BATCH: usize = 1000;
+
+// a savepoint is just how deep it is.  the first one in a transaction is 1.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct Savepoint(pub usize);
+
+// the limits on a capped collection.  size is the total bytes of bson
+// and max, if there is one, is the number of docs.  when an insert goes
+// over either one, the oldest docs get evicted until it doesn't.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct Capped {
+    pub size: u64,
+    pub max: Option<u64>,
+}
+
+impl Capped {
+    pub fn from_options(options: &bson::Document) -> Result<Option<Capped>> {
+        fn get_i64(options: &bson::Document, k: &str) -> Option<i64> {
+            match options.get(k) {
+                Some(&bson::Value::BInt32(n)) => Some(n as i64),
+                Some(&bson::Value::BInt64(n)) => Some(n),
+                Some(&bson::Value::BDouble(n)) => Some(n as i64),
+                _ => None,
+            }
+        }
+        match options.get("capped") {
+            Some(&bson::Value::BBoolean(true)) => {
+                let size = match get_i64(options, "size") {
+                    Some(n) if n > 0 => n as u64,
+                    _ => return Err(Error::MongoCode(72, String::from("capped collection requires a positive size"))),
+                };
+                // mongo treats a max of zero or less as no max at all
+                let max = match get_i64(options, "max") {
+                    Some(n) if n > 0 => Some(n as u64),
+                    _ => None,
+                };
+                Ok(Some(Capped {
+                    size: size,
+                    max: max,
+                }))
+            },
+            _ => Ok(None),
+        }
+    }
+
+    pub fn is_over(&self, count: u64, bytes: u64) -> bool {
+        bytes > self.size || self.max.map_or(false, |max| count > max)
+    }
+
+    // a doc which wouldn't fit even by itself
+    pub fn check_doc_size(&self, len: usize) -> Result<()> {
+        if (len as u64) > self.size {
+            Err(Error::MongoCode(10128, String::from("document is larger than the capped collection")))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+pub fn capped_cannot_grow() -> Error {
+    Error::MongoCode(10003, String::from("objects in a capped ns cannot grow"))
+}
+
+pub fn capped_cannot_delete() -> Error {
+    Error::MongoCode(20, String::from("cannot remove from a capped collection"))
+}
+
+// the validator option of a collection.  a write which leaves behind a doc
+// that doesn't match it fails, unless the action is warn.  at the moderate
+// level, updating a doc which already didn't match is allowed.
+pub struct Validator {
+    m: matcher::QueryDoc,
+    moderate: bool,
+    warn: bool,
+}
+
+impl Validator {
+    // None if there is no validator or the level is off.  bad options are
+    // an error even then.
+    pub fn from_options(options: &bson::Document) -> Result<Option<Validator>> {
+        let (off, moderate) = match options.get("validationLevel") {
+            None => (false, false),
+            Some(&bson::Value::BString(ref s)) if s == "off" => (true, false),
+            Some(&bson::Value::BString(ref s)) if s == "strict" => (false, false),
+            Some(&bson::Value::BString(ref s)) if s == "moderate" => (false, true),
+            Some(v) => return Err(Error::MongoCode(2, format!("invalid validationLevel: {:?}", v))),
+        };
+        let warn = match options.get("validationAction") {
+            None => false,
+            Some(&bson::Value::BString(ref s)) if s == "error" => false,
+            Some(&bson::Value::BString(ref s)) if s == "warn" => true,
+            Some(v) => return Err(Error::MongoCode(2, format!("invalid validationAction: {:?}", v))),
+        };
+        let m = match options.get("validator") {
+            None => return Ok(None),
+            Some(&bson::Value::BDocument(ref d)) => {
+                if d.pairs.is_empty() {
+                    // an empty validator is how collMod takes one away
+                    return Ok(None);
+                }
+                try!(matcher::parse_query(d.clone()))
+            },
+            Some(_) => return Err(Error::MongoCode(2, String::from("validator must be a document"))),
+        };
+        if matcher::uses_where(&m) || matcher::uses_near(&m) {
+            return Err(Error::MongoCode(2, String::from("$where and $near are not allowed in a validator")));
+        }
+        if off {
+            Ok(None)
+        } else {
+            Ok(Some(Validator {
+                m: m,
+                moderate: moderate,
+                warn: warn,
+            }))
+        }
+    }
+
+    fn matches(&self, d: &bson::Document) -> bool {
+        // TODO the clone
+        let (b, _) = matcher::match_query(&self.m, &bson::Value::BDocument(d.clone()));
+        b
+    }
+
+    // old is the doc being replaced, None for an insert
+    pub fn check(&self, old: Option<&bson::Document>, new: &bson::Document) -> Result<()> {
+        if self.warn {
+            // there is no log to warn in, so the write just happens
+            return Ok(());
+        }
+        if self.moderate && old.map_or(false, |d| !self.matches(d)) {
+            return Ok(());
+        }
+        if self.matches(new) {
+            Ok(())
+        } else {
+            Err(Error::MongoCode(121, String::from("Document failed validation")))
+        }
+    }
+}
+
 struct Comps<'a> {
     eq: HashMap<&'a str, &'a bson::Value>,
     ineq: HashMap<&'a str, (Option<(OpGt, &'a bson::Value)>, Option<(OpLt, &'a bson::Value)>)>,
+    geo: HashMap<&'a str, geo::GeoQuery>,
 }
 
 // TODO the IndexInfos below should be references
@@ -238,18 +441,163 @@ struct Comps<'a> {
 #[derive(Debug)]
 enum QueryPlan<'a,'i> {
     Regular(&'i IndexInfo, QueryBounds<'a>),
+    // the same as Regular, but from the other end of the index, for a sort
+    // which goes the opposite way of it.
+    Reversed(&'i IndexInfo, QueryBounds<'a>),
     Text(&'i IndexInfo, QueryKey<'a>, Vec<TextQueryTerm>),
+    Geo(&'i IndexInfo, geo::GeoQuery),
+    // one plan for each branch of an $or.  a doc can match more than one
+    // branch, so the results get deduplicated by record id.
+    Union(Vec<QueryPlan<'a,'i>>),
+    // two plans for different parts of the query.  only the docs which
+    // both of them find can match.
+    Intersection(Box<QueryPlan<'a,'i>>, Box<QueryPlan<'a,'i>>),
 }
 
 impl<'a,'i> QueryPlan<'a,'i> {
+    // a plan with more than one index gives the first one
     fn get_ndx(&self) -> &'i IndexInfo {
         match self {
             &QueryPlan::Regular(ndx, _) => ndx,
+            &QueryPlan::Reversed(ndx, _) => ndx,
             &QueryPlan::Text(ndx,_,_) => ndx,
+            &QueryPlan::Geo(ndx,_) => ndx,
+            &QueryPlan::Union(ref plans) => plans[0].get_ndx(),
+            &QueryPlan::Intersection(ref a, _) => a.get_ndx(),
+        }
+    }
+
+    // whether the plan can give just record ids.  a union or an
+    // intersection only works with those.
+    fn has_record_ids(&self) -> bool {
+        match self {
+            &QueryPlan::Regular(_, _) => true,
+            &QueryPlan::Reversed(_, _) => true,
+            &QueryPlan::Text(_, _, _) => false,
+            &QueryPlan::Geo(_, _) => false,
+            &QueryPlan::Union(ref plans) => plans.iter().all(|plan| plan.has_record_ids()),
+            &QueryPlan::Intersection(ref a, ref b) => a.has_record_ids() && b.has_record_ids(),
         }
     }
 }
 
+// a plan the planner chose before, for the next query of the same shape.
+// only the names of the indexes are kept, since the bounds come from the
+// values in each query.
+#[derive(Debug,Clone)]
+enum CachedPlan {
+    Index(String),
+    Union(Vec<CachedPlan>),
+    Intersection(Box<CachedPlan>, Box<CachedPlan>),
+}
+
+impl CachedPlan {
+    fn from_plan(plan: &QueryPlan) -> CachedPlan {
+        match plan {
+            &QueryPlan::Union(ref plans) => CachedPlan::Union(plans.iter().map(CachedPlan::from_plan).collect()),
+            &QueryPlan::Intersection(ref a, ref b) => CachedPlan::Intersection(box CachedPlan::from_plan(a), box CachedPlan::from_plan(b)),
+            _ => CachedPlan::Index(plan.get_ndx().name.clone()),
+        }
+    }
+}
+
+// reading a record id out of an index is cheaper than looking up the
+// doc it points at.  this is about how much.
+const RECORD_ID_COST: f64 = 0.25;
+
+// what the stats say about a plan
+struct PlanEstimate {
+    // index entries read
+    entries: f64,
+    // docs found
+    docs: f64,
+    // docs in the collection
+    total: f64,
+}
+
+impl PlanEstimate {
+    // the docs both plans find.  assuming the two have nothing to do with
+    // each other, the fraction of these docs which the other one finds
+    // too is the fraction of all docs which it finds.
+    fn intersect(&self, other: &PlanEstimate) -> PlanEstimate {
+        let docs =
+            if other.total > 0.0 {
+                self.docs * (other.docs / other.total).min(1.0)
+            } else {
+                0.0
+            };
+        PlanEstimate {
+            entries: self.entries + other.entries,
+            docs: docs,
+            total: self.total.max(other.total),
+        }
+    }
+
+    // for a plan which reads only record ids from its indexes
+    fn record_ids_cost(&self) -> f64 {
+        self.entries * RECORD_ID_COST + self.docs
+    }
+}
+
+struct PlanCacheEntry {
+    // the names of the indexes the plan was chosen from, and the version
+    // of the collection then.  if either has changed, the plan gets
+    // chosen again.
+    indexes: Vec<String>,
+    version: u64,
+    plan: CachedPlan,
+}
+
+// the plan cache gets thrown out when it gets this big
+const PLAN_CACHE_SIZE: usize = 1000;
+
+#[derive(Default)]
+struct Planning {
+    // keyed by db, collection and the shape of the query
+    plans: HashMap<(String, String, String), PlanCacheEntry>,
+    // for each collection, how many docs have changed since the stats
+    // were gathered, and how many docs there were then
+    writes: HashMap<(String, String), (u64, u64)>,
+    // for each collection, goes up whenever its indexes or their stats
+    // change
+    versions: HashMap<(String, String), u64>,
+    // the collections whose stats need gathering again
+    stale: HashSet<(String, String)>,
+}
+
+// what the planner knows, shared by every connection from the same
+// factory.  the server opens a connection for each request, so anything
+// kept in the connection itself would be forgotten right away.
+pub struct PlannerState {
+    planning: Mutex<Planning>,
+    stale_changed: Condvar,
+}
+
+impl PlannerState {
+    pub fn new() -> PlannerState {
+        PlannerState {
+            planning: Mutex::new(Planning::default()),
+            stale_changed: Condvar::new(),
+        }
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<Planning>> {
+        self.planning.lock().map_err(|_| Error::Misc(String::from("planner state poisoned")))
+    }
+}
+
+// how a find sorts on each key of its orderby
+#[derive(Copy,Clone,Debug)]
+enum SortType {
+    // true for backward
+    Compare(bool),
+    TextScore,
+}
+
+// a sort which no index can give keeps this many bytes of docs in memory
+// before it spills them to a file
+const SORT_BUFFER_BYTES: usize = 32 * 1024 * 1024;
+
 #[derive(PartialEq,Copy,Clone)]
 enum OpIneq {
     LT,
@@ -883,7 +1231,17 @@ pub trait StorageBase {
     fn get_reader_collection_scan(&self, db: &str, coll: &str) -> Result<Box<Iterator<Item=Result<Row>> + 'static>>;
     // TODO QueryKey in text_index_scan should be an option
     fn get_reader_text_index_scan(&self, ndx: &IndexInfo, eq: QueryKey, terms: Vec<TextQueryTerm>) -> Result<Box<Iterator<Item=Result<Row>> + 'static>>;
+    // the rows come in the order of the index, each doc once, where it
+    // first shows up.  reversed is the same thing from the other end.
     fn get_reader_regular_index_scan(&self, ndx: &IndexInfo, bounds: QueryBounds) -> Result<Box<Iterator<Item=Result<Row>> + 'static>>;
+    fn get_reader_reversed_index_scan(&self, ndx: &IndexInfo, bounds: QueryBounds) -> Result<Box<Iterator<Item=Result<Row>> + 'static>>;
+
+    // the same scan as get_reader_regular_index_scan, but just the record
+    // ids, without looking up the docs.  what a record id is belongs to
+    // the storage.  elmo only compares them and hands them back to
+    // get_reader_records, which gives the docs in the order of the ids.
+    fn get_record_ids_regular_index_scan(&self, ndx: &IndexInfo, bounds: QueryBounds) -> Result<Box<Iterator<Item=Result<Box<[u8]>>> + 'static>>;
+    fn get_reader_records(&self, db: &str, coll: &str, ids: Vec<Box<[u8]>>) -> Result<Box<Iterator<Item=Result<Row>> + 'static>>;
 }
 
 // TODO should implement Drop = rollback
@@ -894,16 +1252,40 @@ pub trait StorageReader : StorageBase {
     fn into_reader_collection_scan(self: Box<Self>, db: &str, coll: &str) -> Result<Box<Iterator<Item=Result<Row>> + 'static>>;
     fn into_reader_text_index_scan(&self, ndx: &IndexInfo, eq: QueryKey, terms: Vec<TextQueryTerm>) -> Result<Box<Iterator<Item=Result<Row>> + 'static>>;
     fn into_reader_regular_index_scan(&self, ndx: &IndexInfo, bounds: QueryBounds) -> Result<Box<Iterator<Item=Result<Row>> + 'static>>;
+    fn into_reader_reversed_index_scan(&self, ndx: &IndexInfo, bounds: QueryBounds) -> Result<Box<Iterator<Item=Result<Row>> + 'static>>;
+
+    // for tailing a capped collection.  the docs which were inserted after
+    // the one with this _id, in the order they were inserted.  None means
+    // that doc isn't there anymore, so we have lost our place.
+    fn into_reader_collection_scan_after(self: Box<Self>, db: &str, coll: &str, id: &bson::Value) -> Result<Option<Box<Iterator<Item=Result<Row>> + 'static>>>;
 }
 
 pub trait StorageWriter : StorageBase {
     fn create_collection(&mut self, db: &str, coll: &str, options: bson::Document) -> Result<bool>;
+    // replaces the options the collection was created with, for collMod.
+    // false if there is no such collection.
+    fn set_collection_options(&mut self, db: &str, coll: &str, options: bson::Document) -> Result<bool>;
     fn rename_collection(&mut self, old_name: &str, new_name: &str, drop_target: bool) -> Result<bool>;
     fn clear_collection(&mut self, db: &str, coll: &str) -> Result<bool>;
     fn drop_collection(&mut self, db: &str, coll: &str) -> Result<bool>;
 
     fn create_indexes(&mut self, Vec<IndexInfo>) -> Result<Vec<bool>>;
     fn drop_index(&mut self, db: &str, coll: &str, name: &str) -> Result<bool>;
+    // replaces the options of an index, like when its stats are gathered.
+    // false if there is no such index.
+    fn set_index_options(&mut self, db: &str, coll: &str, name: &str, options: bson::Document) -> Result<bool>;
+
+    // building an index in the background.  begin registers the index so
+    // that every writer keeps it up to date from then on, but nobody else
+    // can see it yet.  then the docs which were already there get added,
+    // by _id, as many at a time as the caller likes.  finish makes it
+    // visible.
+    fn begin_index_build(&mut self, info: IndexInfo) -> Result<bool>;
+    fn index_build_add(&mut self, ndx: &IndexInfo, ids: &[bson::Value]) -> Result<()>;
+    fn finish_index_build(&mut self, ndx: &IndexInfo) -> Result<()>;
+    // the builds which were begun and never finished, like when the
+    // process went away in the middle of one.
+    fn list_index_builds(&mut self) -> Result<Vec<IndexInfo>>;
 
     fn drop_database(&mut self, db: &str) -> Result<bool>;
 
@@ -911,9 +1293,42 @@ pub trait StorageWriter : StorageBase {
     fn update(&mut self, db: &str, coll: &str, v: &bson::Document) -> Result<()>;
     fn delete(&mut self, db: &str, coll: &str, v: &bson::Value) -> Result<bool>;
 
+    // for loading a lot of docs at once, like $out.  there is one result
+    // for each doc, and a doc which fails leaves the others alone, the
+    // same as inserting them one at a time.  an engine with a faster way
+    // to do this may use it when the collection is empty.
+    fn insert_bulk(&mut self, db: &str, coll: &str, docs: &mut Iterator<Item=Result<bson::Document>>) -> Result<Vec<Result<()>>> {
+        let mut results = Vec::new();
+        for r in docs {
+            match r {
+                Ok(d) => {
+                    // an insert which fails partway may have left some of
+                    // its index entries behind
+                    let sp = try!(self.savepoint());
+                    let r = self.insert(db, coll, &d);
+                    if r.is_err() {
+                        try!(self.rollback_to(sp));
+                    }
+                    try!(self.release(sp));
+                    results.push(r);
+                },
+                Err(e) => results.push(Err(e)),
+            }
+        }
+        Ok(results)
+    }
+
     fn commit(self: Box<Self>) -> Result<()>;
     fn rollback(self: Box<Self>) -> Result<()>;
 
+    // savepoints nest.  rollback_to() undoes everything since the savepoint,
+    // including any savepoints after it, and leaves the savepoint in place.
+    // release() forgets the savepoint and any after it, but keeps the
+    // changes.
+    fn savepoint(&mut self) -> Result<Savepoint>;
+    fn rollback_to(&mut self, sp: Savepoint) -> Result<()>;
+    fn release(&mut self, sp: Savepoint) -> Result<()>;
+
 }
 
 // TODO I'm not sure this type is worth the trouble anymore.
@@ -924,6 +1339,17 @@ pub enum IndexType {
     Forward,
     Backward,
     Geo2d,
+    Geo2dSphere,
+}
+
+impl IndexType {
+    fn is_geo(self) -> bool {
+        match self {
+            IndexType::Geo2d => true,
+            IndexType::Geo2dSphere => true,
+            _ => false,
+        }
+    }
 }
 
 fn decode_index_type(v: &bson::Value) -> Result<IndexType> {
@@ -934,6 +1360,8 @@ fn decode_index_type(v: &bson::Value) -> Result<IndexType> {
         &bson::Value::BString(ref s) => 
             if s == "2d" { 
                 Ok(IndexType::Geo2d)
+            } else if s == "2dsphere" {
+                Ok(IndexType::Geo2dSphere)
             } else {
                 Err(Error::Misc(format!("invalid index type: {:?}", v)))
             },
@@ -1049,6 +1477,7 @@ pub trait StorageConnection {
 
 pub struct Connection {
     conn: Box<StorageConnection>,
+    planner: std::sync::Arc<PlannerState>,
 }
 
 pub trait ConnectionFactory {
@@ -1158,9 +1587,10 @@ enum Expr {
 }
 
 impl Connection {
-    pub fn new(conn: Box<StorageConnection>) -> Connection {
+    pub fn new(conn: Box<StorageConnection>, planner: std::sync::Arc<PlannerState>) -> Connection {
         Connection {
             conn: conn,
+            planner: planner,
         }
     }
 
@@ -1861,10 +2291,26 @@ impl Connection {
                         let rdr = try!(r.into_reader_text_index_scan(ndx, eq, terms));
                         return Ok(rdr);
                     },
+                    QueryPlan::Geo(ndx, q) => {
+                        let rdr = try!(Self::geo_index_scan(|bounds| r.get_reader_regular_index_scan(ndx, bounds), ndx, &q));
+                        return Ok(rdr);
+                    },
                     QueryPlan::Regular(ndx, bounds) => {
                         let rdr = try!(r.into_reader_regular_index_scan(ndx, bounds));
                         return Ok(rdr);
                     },
+                    QueryPlan::Reversed(ndx, bounds) => {
+                        let rdr = try!(r.into_reader_reversed_index_scan(ndx, bounds));
+                        return Ok(rdr);
+                    },
+                    QueryPlan::Union(plans) => {
+                        let rdr = try!(Self::record_ids_scan(&*r, db, coll, QueryPlan::Union(plans)));
+                        return Ok(rdr);
+                    },
+                    QueryPlan::Intersection(a, b) => {
+                        let rdr = try!(Self::record_ids_scan(&*r, db, coll, QueryPlan::Intersection(a, b)));
+                        return Ok(rdr);
+                    },
                 }
             },
             None => {
@@ -1882,10 +2328,26 @@ impl Connection {
                         let rdr = try!(r.get_reader_text_index_scan(ndx, eq, terms));
                         return Ok(rdr);
                     },
+                    QueryPlan::Geo(ndx, q) => {
+                        let rdr = try!(Self::geo_index_scan(|bounds| r.get_reader_regular_index_scan(ndx, bounds), ndx, &q));
+                        return Ok(rdr);
+                    },
                     QueryPlan::Regular(ndx, bounds) => {
                         let rdr = try!(r.get_reader_regular_index_scan(ndx, bounds));
                         return Ok(rdr);
                     },
+                    QueryPlan::Reversed(ndx, bounds) => {
+                        let rdr = try!(r.get_reader_reversed_index_scan(ndx, bounds));
+                        return Ok(rdr);
+                    },
+                    QueryPlan::Union(plans) => {
+                        let rdr = try!(Self::record_ids_scan(r, db, coll, QueryPlan::Union(plans)));
+                        return Ok(rdr);
+                    },
+                    QueryPlan::Intersection(a, b) => {
+                        let rdr = try!(Self::record_ids_scan(r, db, coll, QueryPlan::Intersection(a, b)));
+                        return Ok(rdr);
+                    },
                 }
             },
             None => {
@@ -1903,10 +2365,26 @@ impl Connection {
                         let rdr = try!(w.get_reader_text_index_scan(ndx, eq, terms));
                         return Ok(rdr);
                     },
+                    QueryPlan::Geo(ndx, q) => {
+                        let rdr = try!(Self::geo_index_scan(|bounds| w.get_reader_regular_index_scan(ndx, bounds), ndx, &q));
+                        return Ok(rdr);
+                    },
                     QueryPlan::Regular(ndx, bounds) => {
                         let rdr = try!(w.get_reader_regular_index_scan(ndx, bounds));
                         return Ok(rdr);
                     },
+                    QueryPlan::Reversed(ndx, bounds) => {
+                        let rdr = try!(w.get_reader_reversed_index_scan(ndx, bounds));
+                        return Ok(rdr);
+                    },
+                    QueryPlan::Union(plans) => {
+                        let rdr = try!(Self::record_ids_scan(w, db, coll, QueryPlan::Union(plans)));
+                        return Ok(rdr);
+                    },
+                    QueryPlan::Intersection(a, b) => {
+                        let rdr = try!(Self::record_ids_scan(w, db, coll, QueryPlan::Intersection(a, b)));
+                        return Ok(rdr);
+                    },
                 }
             },
             None => {
@@ -1916,21 +2394,224 @@ impl Connection {
         };
     }
 
-    fn get_one_match(db: &str, coll: &str, w: &StorageWriter, m: &matcher::QueryDoc, orderby: Option<&bson::Value>) -> Result<Option<Row>> {
+    // a plan with several indexes works on record ids.  only the docs it
+    // ends up with get looked up, in the order of the ids.
+    fn record_ids_scan<S: StorageBase + ?Sized>(r: &S, db: &str, coll: &str, plan: QueryPlan) -> Result<Box<Iterator<Item=Result<Row>>>> {
+        let ids = try!(Self::plan_record_ids(&|ndx, bounds| r.get_record_ids_regular_index_scan(ndx, bounds), plan));
+        r.get_reader_records(db, coll, ids)
+    }
+
+    // the record ids for the plan, each once.  these are much smaller
+    // than the docs, so it's ok to keep them around.
+    fn plan_record_ids<'a,'i>(scan: &Fn(&'i IndexInfo, QueryBounds<'a>) -> Result<Box<Iterator<Item=Result<Box<[u8]>>>>>, plan: QueryPlan<'a,'i>) -> Result<Vec<Box<[u8]>>> {
+        match plan {
+            QueryPlan::Regular(ndx, bounds) | QueryPlan::Reversed(ndx, bounds) => {
+                let mut ids = vec![];
+                for id in try!(scan(ndx, bounds)) {
+                    ids.push(try!(id));
+                }
+                Ok(ids)
+            },
+            QueryPlan::Union(plans) => {
+                // a doc that matches more than one branch only goes once,
+                // whether it has an _id or not
+                let mut seen = HashSet::new();
+                let mut ids = vec![];
+                for plan in plans {
+                    for id in try!(Self::plan_record_ids(scan, plan)) {
+                        if seen.insert(id.clone()) {
+                            ids.push(id);
+                        }
+                    }
+                }
+                Ok(ids)
+            },
+            QueryPlan::Intersection(a, b) => {
+                let in_b = try!(Self::plan_record_ids(scan, *b)).into_iter().collect::<HashSet<_>>();
+                let ids = try!(Self::plan_record_ids(scan, *a)).into_iter().filter(|id| in_b.contains(id)).collect();
+                Ok(ids)
+            },
+            QueryPlan::Text(_, _, _) | QueryPlan::Geo(_, _) => {
+                Err(Error::Misc(String::from("this plan has no record ids")))
+            },
+        }
+    }
+
+    // a geo index has entries which are grid cells.  the query gets
+    // turned into ranges of cells, each range is a regular index scan,
+    // and then the locations are checked for real.  results come back
+    // sorted by distance for $near.
+    fn geo_index_scan<F>(scan: F, ndx: &IndexInfo, q: &geo::GeoQuery) -> Result<Box<Iterator<Item=Result<Row>>>>
+        where F: Fn(QueryBounds) -> Result<Box<Iterator<Item=Result<Row>>>>
+    {
+        let found = try!(Self::geo_index_find(scan, ndx, q));
+        let rows = found.into_iter().map(|(_, row)| Ok(row)).collect::<Vec<_>>();
+        Ok(box rows.into_iter())
+    }
+
+    fn geo_index_find<F>(scan: F, ndx: &IndexInfo, q: &geo::GeoQuery) -> Result<Vec<(f64, Row)>>
+        where F: Fn(QueryBounds) -> Result<Box<Iterator<Item=Result<Row>>>>
+    {
+        let (normspec, _) = try!(get_normalized_spec(&ndx.spec, &ndx.options));
+        let path = &normspec[0].0;
+        let ranges =
+            if IndexType::Geo2d == normspec[0].1 {
+                let grid = try!(geo::Grid::from_options(&ndx.options));
+                match q.bounding_box() {
+                    Some((lo, hi)) => grid.ranges_for_box(&lo, &hi),
+                    None => grid.everything(),
+                }
+            } else {
+                let grid = geo::Grid::sphere();
+                match q.bounding_box() {
+                    Some((lo, hi)) => grid.sphere_ranges_for_box(&lo, &hi),
+                    None => grid.sphere_everything(),
+                }
+            };
+        let mut seen = HashSet::new();
+        let mut found = vec![];
+        for (lo, hi) in ranges {
+            let lo = bson::Value::BInt64(lo);
+            let hi = bson::Value::BInt64(hi);
+            let bounds = QueryBounds::GTE_LT(vec![], vec![(&lo, false)], vec![(&hi, false)]);
+            for row in try!(scan(bounds)) {
+                let row = try!(row);
+                // a doc with several cells can show up more than once
+                let id = try!(row.doc.as_document()).get("_id").cloned();
+                if let Some(id) = id {
+                    if !seen.insert(id) {
+                        continue;
+                    }
+                }
+                let geoms = geo::geometries_at_path(&row.doc, path);
+                let dist =
+                    match q {
+                        &geo::GeoQuery::Near(ref near) => {
+                            // the closest location is the one that counts
+                            let mut best = None;
+                            for g in geoms.iter().filter(|g| near.contains(g)) {
+                                let d = near.distance_to(g);
+                                if best.map_or(true, |b| d < b) {
+                                    best = Some(d);
+                                }
+                            }
+                            best
+                        },
+                        _ => {
+                            if geoms.iter().any(|g| q.matches(g)) {
+                                Some(0.0)
+                            } else {
+                                None
+                            }
+                        },
+                    };
+                if let Some(d) = dist {
+                    found.push((d, row));
+                }
+            }
+        }
+        if q.is_near() {
+            found.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+        }
+        Ok(found)
+    }
+
+    fn agg_geo_near(reader: &StorageReader, db: &str, coll: &str, v: bson::Value) -> Result<Box<Iterator<Item=Result<Row>>>> {
+        let mut bd = try!(v.into_document());
+        let spherical =
+            match bd.remove("spherical") {
+                Some(v) => try!(v.to_bool()),
+                None => false,
+            };
+        let mut near =
+            match bd.remove("near") {
+                Some(v) => try!(geo::Near::parse(v, spherical)),
+                None => return Err(Error::MongoCode(16605, String::from("$geoNear requires a 'near' option as an Array"))),
+            };
+        let distance_field =
+            match bd.remove("distanceField") {
+                Some(bson::Value::BString(s)) => s,
+                _ => return Err(Error::MongoCode(16606, String::from("$geoNear requires a 'distanceField' option as a String"))),
+            };
+        near.max_distance =
+            match bd.remove("maxDistance") {
+                Some(v) => Some(try!(v.numeric_to_f64())),
+                None => None,
+            };
+        let multiplier =
+            match bd.remove("distanceMultiplier") {
+                Some(v) => try!(v.numeric_to_f64()),
+                None => 1.0,
+            };
+        let m =
+            match bd.remove("query") {
+                Some(v) => Some(try!(matcher::parse_query(try!(v.into_document())))),
+                None => None,
+            };
+        let num =
+            match bd.remove("num").or(bd.remove("limit")) {
+                Some(v) => try!(v.numeric_to_i64()) as usize,
+                None => 100,
+            };
+
+        let q = geo::GeoQuery::Near(near);
+
+        // prefer the kind of index that matches the kind of distance,
+        // but a 2d index can still do spherical distance for points.
+        let indexes = try!(reader.list_indexes(Some((db, coll))));
+        let mut ndx = None;
+        for info in indexes.iter() {
+            let (normspec, _) = try!(get_normalized_spec(&info.spec, &info.options));
+            if normspec.len() == 0 || !normspec[0].1.is_geo() {
+                continue;
+            }
+            let typ = normspec[0].1;
+            if typ == IndexType::Geo2d && q.needs_2dsphere() {
+                continue;
+            }
+            let preferred = if spherical { IndexType::Geo2dSphere } else { IndexType::Geo2d };
+            if ndx.is_none() || typ == preferred {
+                ndx = Some(info);
+            }
+        }
+        let ndx =
+            match ndx {
+                Some(ndx) => ndx,
+                None => return Err(Error::MongoCode(2, String::from("unable to find index for $geoNear query"))),
+            };
+
+        let found = try!(Self::geo_index_find(|bounds| reader.get_reader_regular_index_scan(ndx, bounds), ndx, &q));
+        let mut rows = vec![];
+        for (d, mut row) in found {
+            if rows.len() >= num {
+                break;
+            }
+            if let Some(ref m) = m {
+                if !matcher::match_query(m, &row.doc).0 {
+                    continue;
+                }
+            }
+            try!(row.doc.set_path(&distance_field, bson::Value::BDouble(d * multiplier)));
+            rows.push(Ok(row));
+        }
+        Ok(box rows.into_iter())
+    }
+
+    fn get_one_match(db: &str, coll: &str, w: &StorageWriter, m: &matcher::QueryDoc, collation: Option<&Collation>, orderby: Option<&bson::Value>) -> Result<Option<Row>> {
         // TODO dry
         let indexes = try!(w.list_indexes(Some((db, coll))));
         //println!("indexes: {:?}", indexes);
-        let plan = try!(Self::choose_index(&indexes, &m, None));
+        let plan = try!(Self::choose_index(&indexes, &m, None, collation));
         //println!("plan: {:?}", plan);
         let seq: Box<Iterator<Item=Result<Row>>> = try!(Self::get_collection_reader_w(w, db, coll, plan));
         // TODO we shadow-let here because the type from seq_match_ref() doesn't match the original
         // type because of its explicit lifetime.
-        let mut seq = Self::seq_match_ref(seq, &m);
+        let mut seq = Self::seq_match_ref(seq, &m, collation);
         match orderby {
             None => (),
             Some(orderby) => {
                 let mut a = try!(seq.collect::<Result<Vec<_>>>());
-                try!(Self::do_sort(&mut a, orderby));
+                try!(Self::do_sort(&mut a, orderby, collation));
                 seq = box a.into_iter().map(|d| Ok(d));
             },
         }
@@ -2013,6 +2694,35 @@ impl Connection {
         Ok(id)
     }
 
+    fn get_validator(w: &StorageWriter, db: &str, coll: &str) -> Result<Option<Validator>> {
+        let collections = try!(w.list_collections());
+        match collections.iter().find(|c| c.db == db && c.coll == coll) {
+            Some(c) => Validator::from_options(&c.options),
+            None => Ok(None),
+        }
+    }
+
+    fn check_validator(validator: &Option<Validator>, old: Option<&bson::Document>, new: &bson::Document) -> Result<()> {
+        match *validator {
+            Some(ref v) => v.check(old, new),
+            None => Ok(()),
+        }
+    }
+
+    // the collation given with the operation, or else the default for the
+    // collection
+    fn resolve_collation(collections: &Vec<CollectionInfo>, db: &str, coll: &str, collation: Option<bson::Document>) -> Result<Option<Collation>> {
+        match collation {
+            Some(d) => Collation::from_document(&d),
+            None => {
+                match collections.iter().find(|c| c.db == db && c.coll == coll) {
+                    Some(c) => Collation::from_options(&c.options),
+                    None => Ok(None),
+                }
+            },
+        }
+    }
+
     fn id_changed(d1: &bson::Document, d2: &bson::Document) -> Result<bool> {
         let id1 = try!(d1.must_get("_id"));
         let id2 = try!(d2.must_get("_id"));
@@ -2020,7 +2730,7 @@ impl Connection {
         Ok(b)
     }
 
-    pub fn update(&self, db: &str, coll: &str, updates: &mut Vec<bson::Document>, factory: &ConnectionFactory) -> Result<Vec<Result<(i32, i32, Option<bson::Value>)>>> {
+    pub fn update(&self, db: &str, coll: &str, updates: &mut Vec<bson::Document>, ordered: bool, factory: &ConnectionFactory) -> Result<Vec<Result<(i32, i32, Option<bson::Value>)>>> {
         let mut results = Vec::new();
         {
             // TODO the following is sqlite-specific.
@@ -2035,9 +2745,12 @@ impl Connection {
             // ourself.
             let rconn = try!(factory.open());
             let mut writer = try!(self.conn.begin_write());
+            let validator = try!(Self::get_validator(&*writer, db, coll));
+            let collation = try!(Self::resolve_collation(&try!(writer.list_collections()), db, coll, None));
             {
-                // TODO why does this closure need to be mut?
-                let mut one_update_or_upsert = |upd: &mut bson::Document| -> Result<(i32, i32, Option<bson::Value>)> {
+                // the writer is passed in, rather than captured, so that
+                // the loop below can set savepoints around each call.
+                let one_update_or_upsert = |writer: &mut StorageWriter, upd: &mut bson::Document| -> Result<(i32, i32, Option<bson::Value>)> {
                     //println!("in closure: {:?}", upd);
                     let q = try!(upd.must_remove_document("q"));
                     let u = try!(upd.must_remove_document("u"));
@@ -2058,11 +2771,11 @@ impl Connection {
                             if multi {
                                 let reader = try!(rconn.conn.begin_read());
                                 let indexes = try!(reader.list_indexes(Some((db, coll))));
-                                let plan = try!(Self::choose_index(&indexes, &m, None));
+                                let plan = try!(Self::choose_index(&indexes, &m, None, collation.as_ref()));
                                 let seq: Box<Iterator<Item=Result<Row>>> = try!(Self::into_collection_reader(reader, db, coll, plan));
                                 // TODO we shadow-let here because the type from seq_match_ref() doesn't match the original
                                 // type because of its explicit lifetime.
-                                let seq = Self::seq_match_ref(seq, &m);
+                                let seq = Self::seq_match_ref(seq, &m, collation.as_ref());
                                 let mut mods = 0;
                                 let mut matches = 0;
                                 for rr in seq {
@@ -2077,13 +2790,14 @@ impl Connection {
                                     matches = matches + 1;
                                     if new_doc != old_doc {
                                         let id = try!(Self::validate_for_storage(&mut new_doc));
+                                        try!(Self::check_validator(&validator, Some(&old_doc), &new_doc));
                                         try!(writer.update(db, coll, &new_doc));
                                         mods = mods + 1;
                                     }
                                 }
                                 (matches, mods)
                             } else {
-                                match try!(Self::get_one_match(db, coll, &*writer, &m, None)) {
+                                match try!(Self::get_one_match(db, coll, &*writer, &m, collation.as_ref(), None)) {
                                     Some(row) => {
                                         //println!("row found for update: {:?}", row);
                                         let old_doc = try!(row.doc.into_document());
@@ -2094,6 +2808,7 @@ impl Connection {
                                         }
                                         if new_doc != old_doc {
                                             let id = try!(Self::validate_for_storage(&mut new_doc));
+                                            try!(Self::check_validator(&validator, Some(&old_doc), &new_doc));
                                             try!(writer.update(db, coll, &new_doc));
                                             (1, 1)
                                         } else {
@@ -2110,6 +2825,7 @@ impl Connection {
                             if upsert {
                                 let mut doc = try!(Self::build_upsert_with_update_operators(&m, &ops));
                                 let id = try!(Self::validate_for_storage(&mut doc));
+                                try!(Self::check_validator(&validator, None, &doc));
                                 try!(writer.insert(db, coll, &doc));
                                 Ok((0,0,Some(id)))
                             } else {
@@ -2124,7 +2840,7 @@ impl Connection {
                         if multi {
                             return Err(Error::Misc(String::from("multi update requires $ update operators")));
                         }
-                        match try!(Self::get_one_match(db, coll, &*writer, &m, None)) {
+                        match try!(Self::get_one_match(db, coll, &*writer, &m, collation.as_ref(), None)) {
                             Some(row) => {
                                 //println!("get_one_match found");
                                 let old_doc = try!(row.doc.into_document());
@@ -2148,6 +2864,7 @@ impl Connection {
                                 }
                                 if new_doc != old_doc {
                                     let id = try!(Self::validate_for_storage(&mut new_doc));
+                                    try!(Self::check_validator(&validator, Some(&old_doc), &new_doc));
                                     try!(writer.update(db, coll, &new_doc));
                                     Ok((1,1,None))
                                 } else {
@@ -2161,6 +2878,7 @@ impl Connection {
                                     try!(Self::build_simple_upsert(q_id, &mut new_doc));
                                     // TODO what if this doesn't have an id yet?
                                     let id = try!(Self::validate_for_storage(&mut new_doc));
+                                    try!(Self::check_validator(&validator, None, &new_doc));
                                     try!(writer.insert(db, coll, &new_doc));
                                     Ok((0, 0, Some(id)))
                                 } else {
@@ -2172,37 +2890,56 @@ impl Connection {
                 };
 
                 for upd in updates {
-                    let r = one_update_or_upsert(upd);
+                    // a multi update can fail partway through, so each one
+                    // gets a savepoint.
+                    let sp = try!(writer.savepoint());
+                    let r = one_update_or_upsert(&mut *writer, upd);
+                    let failed = r.is_err();
+                    if failed {
+                        try!(writer.rollback_to(sp));
+                    }
+                    try!(writer.release(sp));
                     results.push(r);
+                    if failed && ordered {
+                        break;
+                    }
                 }
             }
+            let n = results.iter().map(
+                |r| match r {
+                    &Ok((_, modified, ref upserted)) => modified as usize + if upserted.is_some() { 1 } else { 0 },
+                    &Err(_) => 0,
+                }).sum::<usize>();
+            try!(self.note_writes(&*writer, db, coll, n));
             try!(writer.commit());
         }
         Ok(results)
     }
 
     pub fn find_and_modify(&self, db: &str, coll: &str, filter: Option<bson::Value>, sort: Option<bson::Value>, remove: Option<bson::Value>, update: Option<bson::Value>, new: bool, upsert: bool) -> Result<(bool,Option<Error>,bool,Option<bson::Value>,Option<bson::Document>)> {
-        let (m, q_id) = {
-            let (q, id) =
-                match filter {
-                    Some(q) => {
-                        let q = try!(q.into_document());
-                        let id =
-                            match q.get("_id") {
-                                Some(id) => Some(id.clone()),
-                                None => None,
-                            };
-                        (q,id)
-                    },
-                    None => {
-                        (bson::Document::new(), None)
-                    },
-                };
-            let m = try!(matcher::parse_query(q));
-            (m, id)
-        };
+        let (q, q_id) =
+            match filter {
+                Some(q) => {
+                    let q = try!(q.into_document());
+                    let id =
+                        match q.get("_id") {
+                            Some(id) => Some(id.clone()),
+                            None => None,
+                        };
+                    (q,id)
+                },
+                None => {
+                    (bson::Document::new(), None)
+                },
+            };
         let mut writer = try!(self.conn.begin_write());
-        let found = match Self::get_one_match(db, coll, &*writer, &m, sort.as_ref()) {
+        let validator = match Self::get_validator(&*writer, db, coll) {
+            Ok(v) => v,
+            Err(e) => return Ok((false,Some(e),false,None,None)),
+        };
+        let collation = try!(Self::resolve_collation(&try!(writer.list_collections()), db, coll, None));
+        let m = try!(matcher::parse_query(q));
+        let found = match Self::get_one_match(db, coll, &*writer, &m, collation.as_ref(), sort.as_ref()) {
             Ok(v) => v,
             Err(e) => return Ok((false,Some(e),false,None,None)),
         };
@@ -2237,6 +2974,7 @@ impl Connection {
                             }
                             if old_doc != new_doc {
                                 let id = try!(Self::validate_for_storage(&mut new_doc));
+                                try!(Self::check_validator(&validator, Some(&old_doc), &new_doc));
                                 try!(writer.update(db, coll, &new_doc));
                                 changed = true;
                             }
@@ -2262,6 +3000,7 @@ impl Connection {
                             new_doc.set("_id", old_id);
                             if old_doc != new_doc {
                                 let id = try!(Self::validate_for_storage(&mut new_doc));
+                                try!(Self::check_validator(&validator, Some(&old_doc), &new_doc));
                                 try!(writer.update(db, coll, &new_doc));
                                 changed = true;
                             }
@@ -2282,6 +3021,7 @@ impl Connection {
                                 let ops = try!(Self::parse_update_doc(u));
                                 let mut new_doc = try!(Self::build_upsert_with_update_operators(&m, &ops));
                                 let id = try!(Self::validate_for_storage(&mut new_doc));
+                                try!(Self::check_validator(&validator, None, &new_doc));
                                 try!(writer.insert(db, coll, &new_doc));
                                  changed = true;
                                 upserted = Some(id);
@@ -2292,6 +3032,7 @@ impl Connection {
                                 let mut new_doc = u;
                                 try!(Self::build_simple_upsert(q_id, &mut new_doc));
                                 let id = try!(Self::validate_for_storage(&mut new_doc));
+                                try!(Self::check_validator(&validator, None, &new_doc));
                                 try!(writer.insert(db, coll, &new_doc));
                                 changed = true;
                                 upserted = Some(id);
@@ -2333,38 +3074,28 @@ impl Connection {
     }
 
     pub fn insert_seq(&self, db: &str, coll: &str, docs: Box<Iterator<Item=Result<Row>>>) -> Result<Vec<Result<()>>> {
-        let mut results = Vec::new();
-        {
-            let mut writer = try!(self.conn.begin_write());
-            {
-                for rr in docs {
-                    match rr {
-                        Ok(row) => {
-                            let doc = row.doc;
-                            match doc.into_document() {
-                                Ok(mut doc) => {
-                                    doc.ensure_id();
-                                    let id = try!(Self::validate_for_storage(&mut doc));
-                                    let r = writer.insert(db, coll, &doc);
-                                    results.push(r);
-                                },
-                                Err(e) => {
-                                    results.push(Err(wrap_err(e)));
-                                },
-                            }
-                        },
-                        Err(e) => {
-                            results.push(Err(e));
-                        },
-                    }
-                }
-            }
-            try!(writer.commit());
-        }
+        let mut writer = try!(self.conn.begin_write());
+        // a doc the validator turns away is an error for just that doc,
+        // the same as in insert()
+        let validator = try!(Self::get_validator(&*writer, db, coll));
+        let mut docs = docs.map(|rr| -> Result<bson::Document> {
+            let row = try!(rr);
+            let mut doc = try!(row.doc.into_document().map_err(wrap_err));
+            doc.ensure_id();
+            try!(Self::validate_for_storage(&mut doc));
+            try!(Self::check_validator(&validator, None, &doc));
+            Ok(doc)
+        });
+        let results = try!(writer.insert_bulk(db, coll, &mut docs));
+        let n = results.iter().filter(|r| r.is_ok()).count();
+        try!(self.note_writes(&*writer, db, coll, n));
+        try!(writer.commit());
         Ok(results)
     }
 
-    pub fn insert(&self, db: &str, coll: &str, docs: &mut Vec<bson::Document>) -> Result<Vec<Result<()>>> {
+    // if ordered, the first doc that fails is the last one tried.  the
+    // ones before it stay inserted either way.
+    pub fn insert(&self, db: &str, coll: &str, docs: &mut Vec<bson::Document>, ordered: bool) -> Result<Vec<Result<()>>> {
         // make sure every doc has an _id
         for d in docs.iter_mut() {
             d.ensure_id();
@@ -2372,13 +3103,29 @@ impl Connection {
         let mut results = Vec::new();
         {
             let mut writer = try!(self.conn.begin_write());
+            let validator = try!(Self::get_validator(&*writer, db, coll));
             {
                 for mut doc in docs {
                     let id = try!(Self::validate_for_storage(&mut doc));
-                    let r = writer.insert(db, coll, doc);
+                    let sp = try!(writer.savepoint());
+                    let r =
+                        match Self::check_validator(&validator, None, doc) {
+                            Ok(()) => writer.insert(db, coll, doc),
+                            Err(e) => Err(e),
+                        };
+                    let failed = r.is_err();
+                    if failed {
+                        try!(writer.rollback_to(sp));
+                    }
+                    try!(writer.release(sp));
                     results.push(r);
+                    if failed && ordered {
+                        break;
+                    }
                 }
             }
+            let n = results.iter().filter(|r| r.is_ok()).count();
+            try!(self.note_writes(&*writer, db, coll, n));
             try!(writer.commit());
         }
         Ok(results)
@@ -2422,7 +3169,7 @@ impl Connection {
         match filter {
             Some(q) => {
                 let m = try!(matcher::parse_query(q));
-                seq = Self::seq_match(seq, m);
+                seq = Self::seq_match(seq, m, None);
                 Ok(box seq)
             },
             None => {
@@ -2480,16 +3227,292 @@ impl Connection {
             }
         }
         try!(writer.commit());
+        try!(self.indexes_changed(db, Some(coll)));
         Ok((count_before, count_deleted))
     }
 
-    pub fn create_indexes(&self, indexes: Vec<IndexInfo>) -> Result<Vec<bool>> {
+    // an index without a collation of its own gets the one for the
+    // collection, if any.  the simple collation, asked for explicitly,
+    // stays.
+    fn inherit_collation(w: &StorageWriter, ndx: &mut IndexInfo) -> Result<()> {
+        let _ = try!(Collation::from_options(&ndx.options));
+        if ndx.options.get("collation").is_some() {
+            return Ok(());
+        }
+        let collections = try!(w.list_collections());
+        if let Some(c) = collections.iter().find(|c| c.db == ndx.db && c.coll == ndx.coll) {
+            if let Some(v) = c.options.get("collation") {
+                ndx.options.set("collation", v.clone());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn create_indexes(&self, mut indexes: Vec<IndexInfo>) -> Result<Vec<bool>> {
+        for ndx in &indexes {
+            try!(ndx.check_partial_filter());
+        }
         let mut writer = try!(self.conn.begin_write());
+        for ndx in indexes.iter_mut() {
+            try!(Self::inherit_collation(&*writer, ndx));
+            // the storage layer sets this when a doc has an array for a key
+            ndx.options.set_bool("multikey", false);
+        }
+        let colls = indexes.iter().map(|ndx| (ndx.db.clone(), ndx.coll.clone())).collect::<HashSet<_>>();
         let results = try!(writer.create_indexes(indexes));
         try!(writer.commit());
+        for (db, coll) in colls {
+            try!(self.indexes_changed(&db, Some(&coll)));
+            try!(self.stats_stale(&db, &coll));
+        }
         Ok(results)
     }
 
+    // like create_indexes, for one index, but the write lock is only held
+    // for a batch of docs at a time.  progress gets (done, total) after
+    // each batch.
+    pub fn create_index_background(&self, mut info: IndexInfo, factory: &ConnectionFactory, progress: &mut FnMut(usize, usize)) -> Result<bool> {
+        try!(info.check_partial_filter());
+        let (created, unfinished) = {
+            let mut writer = try!(self.conn.begin_write());
+            try!(Self::inherit_collation(&*writer, &mut info));
+            info.options.set_bool("multikey", false);
+            let created = try!(writer.begin_index_build(info.clone()));
+            let unfinished =
+                if created {
+                    None
+                } else {
+                    try!(writer.list_index_builds()).into_iter().find(|ndx| ndx.db == info.db && ndx.coll == info.coll && ndx.name == info.name)
+                };
+            try!(writer.commit());
+            (created, unfinished)
+        };
+        if created {
+            try!(self.complete_index_build(&info, factory, progress));
+            Ok(true)
+        } else if let Some(ndx) = unfinished {
+            // an earlier try got this far and then went away.  pick up
+            // where it left off.
+            try!(self.complete_index_build(&ndx, factory, progress));
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    // an index build which never finished is invisible, but every writer
+    // still keeps it up to date, so it can't just be left there.  this
+    // finishes all of them.  call it after opening a database which might
+    // have been closed in the middle of a build.  returns how many there
+    // were.
+    pub fn resume_index_builds(&self, factory: &ConnectionFactory) -> Result<usize> {
+        let builds = {
+            let mut writer = try!(self.conn.begin_write());
+            let builds = try!(writer.list_index_builds());
+            try!(writer.rollback());
+            builds
+        };
+        for ndx in &builds {
+            try!(self.complete_index_build(ndx, factory, &mut |_, _| ()));
+        }
+        Ok(builds.len())
+    }
+
+    fn complete_index_build(&self, info: &IndexInfo, factory: &ConnectionFactory, progress: &mut FnMut(usize, usize)) -> Result<()> {
+        match self.index_build_catch_up(info, factory, progress) {
+            Ok(()) => {
+                try!(self.indexes_changed(&info.db, Some(&info.coll)));
+                try!(self.stats_stale(&info.db, &info.coll));
+                Ok(())
+            },
+            Err(e) => {
+                // take the half built index back out
+                let mut writer = try!(self.conn.begin_write());
+                try!(writer.drop_index(&info.db, &info.coll, &info.name));
+                try!(writer.commit());
+                Err(e)
+            },
+        }
+    }
+
+    fn index_build_catch_up(&self, info: &IndexInfo, factory: &ConnectionFactory, progress: &mut FnMut(usize, usize)) -> Result<()> {
+        // the index is registered, so anything written from here on takes
+        // care of itself.  what's left is everything in this snapshot.  it
+        // has to come from another connection, since this one is about to
+        // be used for writing.  docs get added back by _id, so a doc
+        // without one would be left out of the index, and the build
+        // fails instead.
+        let ids = {
+            let rconn = try!(factory.open());
+            let reader = try!(rconn.conn.begin_read());
+            let seq = try!(reader.into_reader_collection_scan(&info.db, &info.coll));
+            let mut ids = vec![];
+            for rr in seq {
+                let row = try!(rr);
+                match try!(row.doc.as_document()).get("_id") {
+                    Some(id) => ids.push(id.clone()),
+                    None => return Err(Error::Misc(String::from("cannot build an index in the background over a doc without _id"))),
+                }
+            }
+            ids
+        };
+
+        let total = ids.len();
+        let mut done = 0;
+        for batch in ids.chunks(INDEX_BUILD_BATCH) {
+            let mut writer = try!(self.conn.begin_write());
+            try!(writer.index_build_add(info, batch));
+            try!(writer.commit());
+            done = done + batch.len();
+            progress(done, total);
+        }
+
+        let mut writer = try!(self.conn.begin_write());
+        try!(writer.finish_index_build(info));
+        try!(writer.commit());
+        Ok(())
+    }
+
+    // gathers the stats the planner uses to guess what each index of the
+    // collection would cost.  text and geo indexes don't get any, since a
+    // query which can use one of those has to.  the pass over the
+    // collection reads a snapshot, so writers don't wait for it.  only
+    // storing the stats takes the write lock.
+    pub fn analyze(&self, db: &str, coll: &str) -> Result<()> {
+        let mut gathering = vec![];
+        let mut docs = 0;
+        {
+            let reader = try!(self.conn.begin_read());
+            for ndx in try!(reader.list_indexes(Some((db, coll)))) {
+                let (normspec, weights) = try!(get_normalized_spec(&ndx.spec, &ndx.options));
+                if weights.is_none() && !normspec.iter().any(|&(_, t)| t.is_geo()) {
+                    let collation = try!(ndx.collation_spec());
+                    gathering.push((ndx, normspec, collation, stats::Gatherer::new()));
+                }
+            }
+            let seq = try!(reader.into_reader_collection_scan(db, coll));
+            for rr in seq {
+                let row = try!(rr);
+                let d = try!(row.doc.as_document());
+                for &mut (ref ndx, ref normspec, ref collation, ref mut g) in gathering.iter_mut() {
+                    for vals in try!(get_index_entries(d, normspec, &None, &ndx.options)) {
+                        let vals = vals.iter().map(|&(ref v, neg)| (v, neg)).collect::<Vec<_>>();
+                        g.add(bson::Value::encode_multi_for_index_collated(&vals, None, collation.as_ref()));
+                    }
+                }
+                docs = docs + 1;
+            }
+        }
+        let mut writer = try!(self.conn.begin_write());
+        // an index which was dropped or replaced in the meantime doesn't
+        // get the stats, and anything else which changed in its options
+        // stays changed.
+        let current = try!(writer.list_indexes(Some((db, coll))));
+        for (ndx, _, _, g) in gathering {
+            if let Some(cur) = current.iter().find(|cur| cur.name == ndx.name && cur.spec == ndx.spec) {
+                let mut options = cur.options.clone();
+                options.set_document("stats", g.finish().to_document());
+                try!(writer.set_index_options(db, coll, &ndx.name, options));
+            }
+        }
+        try!(writer.commit());
+        {
+            let key = (String::from(db), String::from(coll));
+            let mut planning = try!(self.planner.lock());
+            planning.writes.insert(key.clone(), (0, docs));
+            planning.stale.remove(&key);
+        }
+        try!(self.indexes_changed(db, Some(coll)));
+        Ok(())
+    }
+
+    // gathers the stats again for every collection which needs it.
+    // returns how many there were.
+    pub fn analyze_stale(&self) -> Result<usize> {
+        let stale = {
+            let mut planning = try!(self.planner.lock());
+            planning.stale.drain().collect::<Vec<_>>()
+        };
+        for &(ref db, ref coll) in &stale {
+            try!(self.analyze(db, coll));
+        }
+        Ok(stale.len())
+    }
+
+    // waits until some collection needs its stats gathered again, or the
+    // timeout is up.  this is for a thread which keeps them fresh in the
+    // background with analyze_stale.
+    pub fn wait_for_stale_stats(&self, timeout_ms: u64) -> Result<bool> {
+        let planning = try!(self.planner.lock());
+        if !planning.stale.is_empty() {
+            return Ok(true);
+        }
+        let timeout = std::time::Duration::from_millis(timeout_ms);
+        let (planning, _) = try!(self.planner.stale_changed.wait_timeout(planning, timeout));
+        Ok(!planning.stale.is_empty())
+    }
+
+    fn stats_stale(&self, db: &str, coll: &str) -> Result<()> {
+        let mut planning = try!(self.planner.lock());
+        planning.stale.insert((String::from(db), String::from(coll)));
+        self.planner.stale_changed.notify_all();
+        Ok(())
+    }
+
+    // the plans chosen for the collection, or for every collection in the
+    // db, can't be trusted anymore
+    fn indexes_changed(&self, db: &str, coll: Option<&str>) -> Result<()> {
+        let in_scope = |kdb: &str, kcoll: &str| kdb == db && coll.map_or(true, |c| kcoll == c);
+        let mut planning = try!(self.planner.lock());
+        let planning = &mut *planning;
+        if let Some(coll) = coll {
+            // so a plan cached from here on has a version to check
+            planning.versions.entry((String::from(db), String::from(coll))).or_insert(0);
+        }
+        for (k, v) in planning.versions.iter_mut() {
+            if in_scope(&k.0, &k.1) {
+                *v = *v + 1;
+            }
+        }
+        let gone = planning.plans.keys().filter(|k| in_scope(&k.0, &k.1)).cloned().collect::<Vec<_>>();
+        for k in gone {
+            planning.plans.remove(&k);
+        }
+        Ok(())
+    }
+
+    // counts the docs changed in the collection, and when it's time to
+    // gather the stats again, says so.  like the autovacuum of postgres,
+    // that's after 50 plus a tenth of the docs there were.  nothing waits
+    // for the stats to be gathered.  whoever calls analyze_stale does it.
+    fn note_writes(&self, w: &StorageWriter, db: &str, coll: &str, n: usize) -> Result<()> {
+        if n == 0 {
+            return Ok(());
+        }
+        let key = (String::from(db), String::from(coll));
+        let known = try!(self.planner.lock()).writes.contains_key(&key);
+        let docs =
+            if known {
+                0
+            } else {
+                // the _id_ index has an entry for every doc
+                match try!(w.list_indexes(Some((db, coll)))).iter().find(|ndx| ndx.name == "_id_") {
+                    Some(ndx) => try!(stats::IndexStats::from_options(&ndx.options)).map(|st| st.keys).unwrap_or(0),
+                    None => 0,
+                }
+            };
+        let stale = {
+            let mut planning = try!(self.planner.lock());
+            let e = planning.writes.entry(key).or_insert((0, docs));
+            e.0 = e.0 + n as u64;
+            e.0 > 50 + e.1 / 10
+        };
+        if stale {
+            try!(self.stats_stale(db, coll));
+        }
+        Ok(())
+    }
+
     pub fn drop_collection(&self, db: &str, coll: &str) -> Result<bool> {
         let deleted = {
             let mut writer = try!(self.conn.begin_write());
@@ -2497,6 +3520,7 @@ impl Connection {
             try!(writer.commit());
             deleted
         };
+        try!(self.indexes_changed(db, Some(coll)));
         Ok(deleted)
     }
 
@@ -2507,6 +3531,7 @@ impl Connection {
             try!(writer.commit());
             b
         };
+        try!(self.stats_stale(db, coll));
         Ok(b)
     }
 
@@ -2517,6 +3542,10 @@ impl Connection {
             try!(writer.commit());
             done
         };
+        // the names are full, so it's easiest to forget both dbs
+        for name in &[old_name, new_name] {
+            try!(self.indexes_changed(name.split('.').next().unwrap_or(""), None));
+        }
         Ok(done)
     }
 
@@ -2527,6 +3556,7 @@ impl Connection {
             try!(writer.commit());
             deleted
         };
+        try!(self.indexes_changed(db, None));
         Ok(deleted)
     }
 
@@ -2534,6 +3564,7 @@ impl Connection {
         let mut count = 0;
         {
             let mut writer = try!(self.conn.begin_write());
+            let collation = try!(Self::resolve_collation(&try!(writer.list_collections()), db, coll, None));
             {
                 for mut del in items {
                     let q = try!(del.must_remove_document("q"));
@@ -2542,13 +3573,13 @@ impl Connection {
                     let indexes = try!(writer.list_indexes(Some((db, coll))));
                     //println!("indexes: {:?}", indexes);
                     let mut seq = {
-                        let plan = try!(Self::choose_index(&indexes, &m, None));
+                        let plan = try!(Self::choose_index(&indexes, &m, None, collation.as_ref()));
                         //println!("plan: {:?}", plan);
                         // TODO is this safe?  or do we need two-conn isolation like update?
                         let seq: Box<Iterator<Item=Result<Row>>> = try!(Self::get_collection_reader_w(&*writer, db, coll, plan));
                         seq
                     };
-                    seq = Self::seq_match(seq, m);
+                    seq = Self::seq_match(seq, m, collation.clone());
                     for rr in seq {
                         let row = try!(rr);
                         let d = try!(row.doc.into_document());
@@ -2565,18 +3596,49 @@ impl Connection {
                     }
                 }
             }
+            try!(self.note_writes(&*writer, db, coll, count));
             try!(writer.commit());
         }
         Ok(count)
     }
 
     pub fn create_collection(&self, db: &str, coll: &str, options: bson::Document) -> Result<bool> {
+        // catch bad capped, validator or collation options now, not on the
+        // first insert
+        let _ = try!(Capped::from_options(&options));
+        let _ = try!(Validator::from_options(&options));
+        let _ = try!(Collation::from_options(&options));
         let mut writer = try!(self.conn.begin_write());
         let result = try!(writer.create_collection(db, coll, options));
         try!(writer.commit());
         Ok(result)
     }
 
+    // only the validation options can be changed.  the docs already in
+    // the collection are not checked.
+    pub fn coll_mod(&self, db: &str, coll: &str, changes: bson::Document) -> Result<()> {
+        let mut writer = try!(self.conn.begin_write());
+        let mut options = {
+            let collections = try!(writer.list_collections());
+            match collections.into_iter().find(|c| c.db == db && c.coll == coll) {
+                Some(c) => c.options,
+                None => return Err(Error::MongoCode(26, String::from("ns does not exist"))),
+            }
+        };
+        for (k, v) in changes.pairs {
+            match k.as_str() {
+                "validator" | "validationLevel" | "validationAction" => {
+                    options.set(&k, v);
+                },
+                _ => return Err(Error::MongoCode(2, format!("unknown option to collMod: {}", k))),
+            }
+        }
+        let _ = try!(Validator::from_options(&options));
+        try!(writer.set_collection_options(db, coll, options));
+        try!(writer.commit());
+        Ok(())
+    }
+
     fn parse_index_min_max<'m>(m: &'m matcher::QueryDoc) -> Result<Vec<(&'m str, &'m bson::Value)>> {
         let &matcher::QueryDoc::QueryDoc(ref items) = m;
         items.iter().map(
@@ -2663,7 +3725,50 @@ impl Connection {
                     ).collect::<Result<HashMap<_,_>>>()
                 );
 
-        Ok(mc)
+        Ok(mc)
+    }
+
+    fn find_compares_geo(m: &matcher::QueryDoc) -> Result<HashMap<&str, geo::GeoQuery>> {
+        fn find<'a>(m: &'a matcher::QueryDoc, dest: &mut HashMap<&'a str, geo::GeoQuery>) {
+            let &matcher::QueryDoc::QueryDoc(ref a) = m;
+            for it in a {
+                match it {
+                    &matcher::QueryItem::Compare(ref k, ref preds) => {
+                        for p in preds {
+                            // if there is more than one, any of them will do for the
+                            // index, and the matcher checks the rest.
+                            match p {
+                                &matcher::Pred::Near(ref near) | &matcher::Pred::NearSphere(ref near) => {
+                                    dest.insert(k, geo::GeoQuery::Near(near.clone()));
+                                },
+                                &matcher::Pred::GeoWithin(ref shape) => {
+                                    if !dest.contains_key(k.as_str()) {
+                                        dest.insert(k, geo::GeoQuery::Within(shape.clone()));
+                                    }
+                                },
+                                &matcher::Pred::GeoIntersects(ref g) => {
+                                    if !dest.contains_key(k.as_str()) {
+                                        dest.insert(k, geo::GeoQuery::Intersects(g.clone()));
+                                    }
+                                },
+                                _ => (),
+                            }
+                        }
+                    },
+                    &matcher::QueryItem::AND(ref docs) => {
+                        for d in docs {
+                            find(d, dest);
+                        }
+                    },
+                    _ => {
+                    },
+                }
+            }
+        }
+
+        let mut comps = HashMap::new();
+        find(m, &mut comps);
+        Ok(comps)
     }
 
     fn find_compares_ineq(m: &matcher::QueryDoc) -> Result<HashMap<&str, (Option<(OpGt, &bson::Value)>, Option<(OpLt, &bson::Value)>)>> {
@@ -2779,44 +3884,9 @@ impl Connection {
             // where we would do it, but mongo allows this according to test case
             // find8.js
 
-            // TODO issue here of diving into elemMatch or not:
-            // TODO we cannot do a query with both bounds unless the two
-            // comparisons came from the same elemMatch.
-            // for example:
-            // {x:{$gt:2,$lt:5}}
-            // this query has to match the following document:
-            // {x:[1,7]}
-            // because the array x has
-            // something that matches x>2 (the 7)
-            // AND
-            // something that matches x<5 (the 1)
-            // even those two somethings are not the same thing,
-            // they came from the same x.
-            // we can choose x>2 or x<5 as our index, but we can't choose both.
-            // unless elemMatch.
-            //
-            // note that if we have to satisfy two gt on x, such as:
-            // x>5
-            // x>9
-            // it doesn't really matter which one we choose for the index.
-            // both will be correct.  but choosing x>9 will result in us reviewing
-            // fewer documents.
-
-            // suppose there are three records:
-            // {a: [1,2]}
-            // {a: [1,2,6]}
-            // {a: [1,4,6]}
-            // this query:
-            // find( {a:{$gte:3, $lte: 5}} )
-            // cannot use either comparison for the bounds of the index
-            // arrayfind3.js
-
-            // TODO can't do this unless same elemMatch: m2.insert(k, (gt, lt));
-            if gt.is_some() {
-                m2.insert(k, (gt, None));
-            } else {
-                m2.insert(k, (None, lt));
-            }
+            // both bounds are kept here.  whether the index can use both
+            // depends on whether it is multikey, see fit_index_to_query.
+            m2.insert(k, (gt, lt));
         }
 
 
@@ -2831,7 +3901,25 @@ impl Connection {
         -> Result<Option<QueryPlan<'a,'i>>> 
     {
         let (scalar_keys, weights) = try!(get_normalized_spec(&ndx.spec, &ndx.options));
-        if weights.is_none() && text_query.is_some() {
+        if scalar_keys.len() > 0 && scalar_keys[0].1.is_geo() {
+            // a geo index is only good for a geo query on its first key.
+            // any other keys in it are just along for the ride.
+            if text_query.is_some() {
+                return Ok(None);
+            }
+            match comps.geo.get(scalar_keys[0].0.as_str()) {
+                Some(q) if scalar_keys[0].1 == IndexType::Geo2d && q.needs_2dsphere() => {
+                    Ok(None)
+                },
+                Some(q) => {
+                    let plan = QueryPlan::Geo(&ndx, q.clone());
+                    Ok(Some(plan))
+                },
+                None => {
+                    Ok(None)
+                },
+            }
+        } else if weights.is_none() && text_query.is_some() {
             // if there is a textQuery but this is not a text index, give up now
             Ok(None)
         } else {
@@ -2862,11 +3950,25 @@ impl Connection {
             } else {
                 // we have some scalar keys, and maybe a text index after it.
                 // for every scalar key, find comparisons from the query.
+                let multikey = ndx.is_multikey();
                 let matching_ineqs = 
                     scalar_keys.iter().map(
                         |&(ref k, ndx_type)| {
+                            if ndx_type.is_geo() {
+                                return None;
+                            }
                             match comps.ineq.get(k.as_str()) {
                                 Some(&(gt, lt)) => {
+                                    // {x:{$gt:2,$lt:5}} has to match the
+                                    // document {x:[1,7]}, because the array x
+                                    // has something that matches x>2 (the 7)
+                                    // and something that matches x<5 (the 1),
+                                    // even though they are not the same thing.
+                                    // in a multikey index, those are two
+                                    // different entries, so we can choose
+                                    // x>2 or x<5 for the bounds, but not both.
+                                    // arrayfind3.js
+                                    let lt = if gt.is_some() && multikey { None } else { lt };
                                     let gt = gt.map(|(c,v)| (c,(v,ndx_type == IndexType::Backward)));
                                     let lt = lt.map(|(c,v)| (c,(v,ndx_type == IndexType::Backward)));
                                     Some((gt, lt))
@@ -2878,6 +3980,12 @@ impl Connection {
                 let mut first_no_eqs = None;
                 let mut matching_eqs = vec![];
                 for (i, &(ref k, ndx_type)) in scalar_keys.iter().enumerate() {
+                    if ndx_type.is_geo() {
+                        // the entries for this key are grid cells, which
+                        // can't be compared with anything in the query
+                        first_no_eqs = Some(i);
+                        break;
+                    }
                     match comps.eq.get(k.as_str()) {
                         Some(a) => {
                             // TODO not sure this check belongs here.  might want to do it later so
@@ -2967,8 +4075,7 @@ impl Connection {
                                         }
                                     },
                                     Some((Some(min),Some(max))) => {
-                                        // this can probably only happen when the comps came
-                                        // from an elemMatch
+                                        // only when the index is not multikey
                                         let (op_gt, vmin) = min;
                                         let (op_lt, vmax) = max;
 
@@ -3102,7 +4209,7 @@ impl Connection {
         }
     }
 
-    fn find_fit_indexes<'a, 'm>(indexes: &'a Vec<IndexInfo>, m: &'m matcher::QueryDoc) -> Result<(Vec<QueryPlan<'m,'a>>, Option<Vec<TextQueryTerm>>)> {
+    fn find_fit_indexes<'a, 'm>(indexes: &'a Vec<IndexInfo>, m: &'m matcher::QueryDoc, collation: Option<&Collation>) -> Result<(Vec<QueryPlan<'m,'a>>, Option<Vec<TextQueryTerm>>)> {
         let text_query = if let Some(s) = try!(Self::find_text_query(m)) {
             let v = s.chars().collect::<Vec<char>>();
             Some(try!(Self::parse_text_query(&v)))
@@ -3113,12 +4220,32 @@ impl Connection {
         // predicate.  like $exists
         let comps_eq = try!(Self::find_compares_eq(m));
         let comps_ineq = try!(Self::find_compares_ineq(m));
+        let comps_geo = try!(Self::find_compares_geo(m));
         let comps = Comps {
             eq: comps_eq,
             ineq: comps_ineq,
+            geo: comps_geo,
         };
+        let strings = matcher::compares_strings(m);
         let mut fits = Vec::new();
         for ndx in indexes {
+            // a collated index has sort keys instead of strings, so a query
+            // for strings can only use it if the collations are the same.
+            if strings && try!(ndx.collation_spec()) != collation.map(|c| c.spec) {
+                continue;
+            }
+            // a partial index is only any good if every doc the query could
+            // match is in it
+            if let Some(filter) = try!(ndx.partial_filter()) {
+                // implies() compares strings as they are, but a collated
+                // query can match strings which it wouldn't.
+                if strings && collation.is_some() {
+                    continue;
+                }
+                if !matcher::implies(m, &filter) {
+                    continue;
+                }
+            }
             if let Some(x) = try!(Self::fit_index_to_query(ndx, &comps, &text_query)) {
                 fits.push(x);
             }
@@ -3127,32 +4254,311 @@ impl Connection {
         Ok((fits, text_query))
     }
 
-    fn choose_from_possibles<'a,'b>(m: &matcher::QueryDoc, possibles: Vec<QueryPlan<'a,'b>>) -> Option<QueryPlan<'a,'b>> {
+    // the fields of the query which the bounds of the plan take care of
+    fn bounded_fields(plan: &QueryPlan) -> Result<Vec<String>> {
+        match plan {
+            &QueryPlan::Regular(ndx, ref bounds) | &QueryPlan::Reversed(ndx, ref bounds) => {
+                let n =
+                    match bounds {
+                        &QueryBounds::EQ(ref k) => k.len(),
+                        &QueryBounds::GT(ref k) => k.len(),
+                        &QueryBounds::GTE(ref k) => k.len(),
+                        &QueryBounds::LT(ref k) => k.len(),
+                        &QueryBounds::LTE(ref k) => k.len(),
+                        &QueryBounds::GT_LT(ref eqs, _, _) => eqs.len() + 1,
+                        &QueryBounds::GT_LTE(ref eqs, _, _) => eqs.len() + 1,
+                        &QueryBounds::GTE_LT(ref eqs, _, _) => eqs.len() + 1,
+                        &QueryBounds::GTE_LTE(ref eqs, _, _) => eqs.len() + 1,
+                    };
+                let (normspec, _) = try!(get_normalized_spec(&ndx.spec, &ndx.options));
+                Ok(normspec.into_iter().take(n).map(|(k, _)| k).collect())
+            },
+            _ => Ok(vec![]),
+        }
+    }
+
+    // about how many index entries the plan reads, how many docs it ends
+    // up with, and how many docs there are, going by the stats of its
+    // indexes.  None if any of them doesn't have any.
+    fn estimate_plan(plan: &QueryPlan) -> Result<Option<PlanEstimate>> {
+        match plan {
+            &QueryPlan::Regular(ndx, ref bounds) | &QueryPlan::Reversed(ndx, ref bounds) => {
+                match try!(stats::IndexStats::from_options(&ndx.options)) {
+                    Some(st) => {
+                        let e = st.estimate(bounds, try!(ndx.collation_spec()).as_ref()).map(
+                            |n| PlanEstimate {
+                                entries: n,
+                                docs: n,
+                                total: st.keys as f64,
+                            });
+                        Ok(e)
+                    },
+                    None => Ok(None),
+                }
+            },
+            &QueryPlan::Union(ref plans) => {
+                let mut e = PlanEstimate { entries: 0.0, docs: 0.0, total: 0.0 };
+                for plan in plans {
+                    match try!(Self::estimate_plan(plan)) {
+                        Some(p) => {
+                            e.entries = e.entries + p.entries;
+                            e.docs = e.docs + p.docs;
+                            e.total = e.total.max(p.total);
+                        },
+                        None => return Ok(None),
+                    }
+                }
+                e.docs = e.docs.min(e.total);
+                Ok(Some(e))
+            },
+            &QueryPlan::Intersection(ref a, ref b) => {
+                match (try!(Self::estimate_plan(a)), try!(Self::estimate_plan(b))) {
+                    (Some(a), Some(b)) => Ok(Some(a.intersect(&b))),
+                    _ => Ok(None),
+                }
+            },
+            _ => Ok(None),
+        }
+    }
+
+    // about how much work the plan is, in docs looked up.  a plan with one
+    // index looks up the doc for every entry it reads.  a plan with more
+    // than one reads only record ids from its indexes, which is cheaper,
+    // and then looks up just the docs it ends up with.
+    fn estimate_cost(plan: &QueryPlan) -> Result<Option<f64>> {
+        let e =
+            match try!(Self::estimate_plan(plan)) {
+                Some(e) => e,
+                None => return Ok(None),
+            };
+        match plan {
+            &QueryPlan::Union(_) | &QueryPlan::Intersection(_, _) => Ok(Some(e.record_ids_cost())),
+            _ => Ok(Some(e.entries)),
+        }
+    }
+
+    // how many of the fields at the front of the index have the same
+    // value for every entry within the bounds
+    fn eq_fields(bounds: &QueryBounds) -> usize {
+        match bounds {
+            &QueryBounds::EQ(ref k) => k.len(),
+            // a one-sided bound is an EQ on all but its last value
+            &QueryBounds::GT(ref k) => k.len().saturating_sub(1),
+            &QueryBounds::GTE(ref k) => k.len().saturating_sub(1),
+            &QueryBounds::LT(ref k) => k.len().saturating_sub(1),
+            &QueryBounds::LTE(ref k) => k.len().saturating_sub(1),
+            &QueryBounds::GT_LT(ref eqs, _, _) => eqs.len(),
+            &QueryBounds::GT_LTE(ref eqs, _, _) => eqs.len(),
+            &QueryBounds::GTE_LT(ref eqs, _, _) => eqs.len(),
+            &QueryBounds::GTE_LTE(ref eqs, _, _) => eqs.len(),
+        }
+    }
+
+    // whether scanning the index gives the rows in the order of the sort,
+    // and if so, whether it has to go backwards.  the first eqs fields of
+    // the index are the same for every row, so the sort can start after
+    // any of them.  an index with an array in it has more than one entry
+    // for a doc, and the doc shows up at the first one, which isn't always
+    // where the sort wants it.
+    fn index_sort_direction(ndx: &IndexInfo, eqs: usize, keys: &[(String, SortType)], collation: Option<&Collation>) -> Result<Option<bool>> {
+        if keys.is_empty() || ndx.is_multikey() {
+            return Ok(None);
+        }
+        if try!(ndx.collation_spec()) != collation.map(|c| c.spec) {
+            return Ok(None);
+        }
+        let (normspec, _) = try!(get_normalized_spec(&ndx.spec, &ndx.options));
+        let mut skip = 0;
+        while skip <= eqs && skip + keys.len() <= normspec.len() {
+            let mut reverse = None;
+            for (&(ref path, dir), &(ref k, typ)) in keys.iter().zip(normspec[skip ..].iter()) {
+                let backward =
+                    match dir {
+                        SortType::Compare(b) => b,
+                        SortType::TextScore => return Ok(None),
+                    };
+                let ndx_backward =
+                    match typ {
+                        IndexType::Forward => false,
+                        IndexType::Backward => true,
+                        _ => return Ok(None),
+                    };
+                if path != k || reverse.map_or(false, |r| r != (backward != ndx_backward)) {
+                    reverse = None;
+                    break;
+                }
+                reverse = Some(backward != ndx_backward);
+            }
+            if reverse.is_some() {
+                return Ok(reverse);
+            }
+            skip = skip + 1;
+        }
+        Ok(None)
+    }
+
+    // the plan, changed to give the rows in the order of the sort if it
+    // can be, and whether it does.  the index the query chose is the first
+    // choice, in whichever direction the sort needs.  a query which would
+    // have scanned the whole collection can scan a whole index instead, as
+    // long as every doc is in it.
+    fn plan_for_sort<'a,'i>(indexes: &'i Vec<IndexInfo>, plan: Option<QueryPlan<'a,'i>>, keys: &[(String, SortType)], collation: Option<&Collation>, any_index: bool) -> Result<(Option<QueryPlan<'a,'i>>, bool)> {
+        match plan {
+            Some(QueryPlan::Regular(ndx, bounds)) => {
+                match try!(Self::index_sort_direction(ndx, Self::eq_fields(&bounds), keys, collation)) {
+                    Some(false) => Ok((Some(QueryPlan::Regular(ndx, bounds)), true)),
+                    Some(true) => Ok((Some(QueryPlan::Reversed(ndx, bounds)), true)),
+                    None => Ok((Some(QueryPlan::Regular(ndx, bounds)), false)),
+                }
+            },
+            None if any_index => {
+                for ndx in indexes {
+                    if ndx.is_sparse() || try!(ndx.partial_filter()).is_some() {
+                        continue;
+                    }
+                    match try!(Self::index_sort_direction(ndx, 0, keys, collation)) {
+                        Some(false) => return Ok((Some(QueryPlan::Regular(ndx, QueryBounds::GTE(vec![]))), true)),
+                        Some(true) => return Ok((Some(QueryPlan::Reversed(ndx, QueryBounds::GTE(vec![]))), true)),
+                        None => (),
+                    }
+                }
+                Ok((None, false))
+            },
+            plan => Ok((plan, false)),
+        }
+    }
+
+    // the rows in the order of the sort.  they go to disk once there are
+    // more than limit bytes of them.
+    fn sort_rows(seq: Box<Iterator<Item=Result<Row>>>, orderby: &bson::Value, collation: Option<Collation>, limit: usize) -> Result<Box<Iterator<Item=Result<Row>>>> {
+        let keys = try!(Self::parse_sort_keys(orderby));
+        let mut sorter = misc::sort::Sorter::new(limit, move |a: &Row, b: &Row| Self::compare_rows(&keys, a, b, collation.as_ref()));
+        for row in seq {
+            try!(sorter.push(try!(row)));
+        }
+        let merged = try!(sorter.finish());
+        Ok(box merged)
+    }
+
+    fn choose_from_possibles<'a,'b>(mut possibles: Vec<QueryPlan<'a,'b>>) -> Result<Option<QueryPlan<'a,'b>>> {
         if possibles.len() == 0 {
-            None
-        } else {
-            // prefer the _id_ index if we can use it
-            // TODO otherwise prefer any unique index
-            // TODO otherwise prefer any EQ index
-            // TODO or any index which has both min_max bounds
-            // otherwise any index at all.  just take the first one.
-            let mut winner = None;
-            for plan in possibles {
-                if winner.is_none() || plan.get_ndx().name == "_id_" {
-                    //if matcher::uses_exists_false(m) && plan.get_ndx().is_sparse() {
-                        // ineligible
-                        //println!("INELIGIBLE");
-                    //} else {
-                        winner = Some(plan);
-                    //}
+            return Ok(None);
+        }
+        let costs = try!(possibles.iter().map(|plan| Self::estimate_cost(plan)).collect::<Result<Vec<_>>>());
+        if costs.iter().all(|c| c.is_some()) {
+            // when there are stats for all of them, the one which does
+            // the least work
+            let mut best = 0;
+            for i in 1 .. possibles.len() {
+                if costs[i] < costs[best] {
+                    best = i;
+                }
+            }
+            // another index with bounds on something the best one doesn't
+            // cover can narrow things down further.  both of them get
+            // read, so it's only worth it if the stats say they agree on
+            // few enough docs.
+            let fields = try!(possibles.iter().map(|plan| Self::bounded_fields(plan)).collect::<Result<Vec<_>>>());
+            let mut pair = None;
+            let mut pair_cost = costs[best];
+            for other in 0 .. possibles.len() {
+                if other == best || !possibles[other].has_record_ids() || !possibles[best].has_record_ids() {
+                    continue;
+                }
+                if !fields[other].iter().any(|f| !fields[best].contains(f)) {
+                    continue;
+                }
+                let (a, b) = (try!(Self::estimate_plan(&possibles[best])), try!(Self::estimate_plan(&possibles[other])));
+                if let (Some(a), Some(b)) = (a, b) {
+                    let cost = Some(a.intersect(&b).record_ids_cost());
+                    if cost < pair_cost {
+                        pair = Some(other);
+                        pair_cost = cost;
+                    }
+                }
+            }
+            return match pair {
+                Some(other) => {
+                    // remove the later one first, so the other index stays put
+                    let (a, b) =
+                        if other > best {
+                            let b = possibles.remove(other);
+                            (possibles.remove(best), b)
+                        } else {
+                            let a = possibles.remove(best);
+                            (a, possibles.remove(other))
+                        };
+                    Ok(Some(QueryPlan::Intersection(box a, box b)))
+                },
+                None => Ok(Some(possibles.remove(best))),
+            };
+        }
+        // without stats, there is no telling whether reading a second
+        // index would pay off, so it's just one.  prefer the _id_ index
+        // if we can use it.
+        // TODO otherwise prefer any unique index
+        if let Some(i) = possibles.iter().position(|plan| plan.get_ndx().name == "_id_") {
+            return Ok(Some(possibles.remove(i)));
+        }
+        // otherwise the one whose bounds take care of the most fields.
+        // on a tie, the first one.
+        let fields = try!(possibles.iter().map(|plan| Self::bounded_fields(plan)).collect::<Result<Vec<_>>>());
+        let mut best = 0;
+        for i in 1 .. possibles.len() {
+            if fields[i].len() > fields[best].len() {
+                best = i;
+            }
+        }
+        Ok(Some(possibles.remove(best)))
+    }
+
+    // when no one index can do the whole query, an $or can still use one
+    // for each of its branches.  every doc the query matches has to match
+    // one of them, but if any branch can't use an index, it's no good.
+    // the branches get put together by record id, so a text or geo plan
+    // for one of them is no good either.
+    fn choose_union<'a, 'm>(indexes: &'a Vec<IndexInfo>, m: &'m matcher::QueryDoc, collation: Option<&Collation>) -> Result<Option<QueryPlan<'m,'a>>> {
+        let &matcher::QueryDoc::QueryDoc(ref items) = m;
+        for it in items {
+            if let &matcher::QueryItem::OR(ref branches) = it {
+                let mut plans = vec![];
+                for b in branches {
+                    match try!(Self::choose_index(indexes, b, None, collation)) {
+                        Some(ref plan) if !plan.has_record_ids() => break,
+                        Some(plan) => plans.push(plan),
+                        None => break,
+                    }
+                }
+                if plans.len() > 0 && plans.len() == branches.len() {
+                    let plan = QueryPlan::Union(plans);
+                    // reading more entries than there are docs is worse
+                    // than just scanning them
+                    let docs =
+                        match indexes.iter().find(|ndx| ndx.name == "_id_") {
+                            Some(ndx) => try!(stats::IndexStats::from_options(&ndx.options)).map(|st| st.keys as f64),
+                            None => None,
+                        };
+                    if let (Some(cost), Some(docs)) = (try!(Self::estimate_cost(&plan)), docs) {
+                        if cost > docs {
+                            continue;
+                        }
+                    }
+                    return Ok(Some(plan));
                 }
             }
-            winner
         }
+        Ok(None)
     }
 
-    fn choose_index<'a, 'm>(indexes: &'a Vec<IndexInfo>, m: &'m matcher::QueryDoc, hint: Option<&IndexInfo>) -> Result<Option<QueryPlan<'m,'a>>> {
-        let (mut fits, text_query) = try!(Self::find_fit_indexes(indexes, m));
+    fn choose_index<'a, 'm>(indexes: &'a Vec<IndexInfo>, m: &'m matcher::QueryDoc, hint: Option<&IndexInfo>, collation: Option<&Collation>) -> Result<Option<QueryPlan<'m,'a>>> {
+        let (mut fits, text_query) = try!(Self::find_fit_indexes(indexes, m, collation));
+        if matcher::uses_near(m) {
+            // only the geo index can sort by distance, so it has to be used
+            return match fits.iter().position(|plan| if let &QueryPlan::Geo(_, ref q) = plan { q.is_near() } else { false }) {
+                Some(i) => Ok(Some(fits.remove(i))),
+                None => Err(Error::MongoCode(2, String::from("unable to find index for $geoNear query"))),
+            };
+        }
         match text_query {
             Some(_) => {
                 // TODO if there is a $text query, disallow hint
@@ -3168,25 +4574,115 @@ impl Connection {
                 // even if it does not fit the query.  how does this work?
                 // what bounds are used?
 
-                match hint {
-                    Some(hint) => {
-                        match fits.iter().position(|plan| plan.get_ndx().spec == hint.spec) {
-                            Some(i) => {
-                                Ok(Some(fits.remove(i)))
-                            },
-                            None => Ok(Self::choose_from_possibles(m, fits))
+                if let Some(hint) = hint {
+                    if let Some(i) = fits.iter().position(|plan| plan.get_ndx().spec == hint.spec) {
+                        return Ok(Some(fits.remove(i)));
+                    }
+                }
+                if fits.len() == 0 {
+                    Self::choose_union(indexes, m, collation)
+                } else {
+                    Self::choose_from_possibles(fits)
+                }
+            },
+        }
+    }
+
+    // the same plan again, for a query of the same shape.  None if its
+    // indexes can't do this one, like a partial index whose filter isn't
+    // implied by these values.
+    fn replan<'a, 'm>(indexes: &'a Vec<IndexInfo>, m: &'m matcher::QueryDoc, collation: Option<&Collation>, cached: &CachedPlan) -> Result<Option<QueryPlan<'m,'a>>> {
+        match cached {
+            &CachedPlan::Index(ref name) => {
+                let (mut fits, _) = try!(Self::find_fit_indexes(indexes, m, collation));
+                match fits.iter().position(|plan| plan.get_ndx().name == *name) {
+                    Some(i) => Ok(Some(fits.remove(i))),
+                    None => Ok(None),
+                }
+            },
+            &CachedPlan::Intersection(ref a, ref b) => {
+                match (try!(Self::replan(indexes, m, collation, a)), try!(Self::replan(indexes, m, collation, b))) {
+                    (Some(a), Some(b)) => Ok(Some(QueryPlan::Intersection(box a, box b))),
+                    _ => Ok(None),
+                }
+            },
+            &CachedPlan::Union(ref cached) => {
+                let &matcher::QueryDoc::QueryDoc(ref items) = m;
+                for it in items {
+                    if let &matcher::QueryItem::OR(ref branches) = it {
+                        if branches.len() != cached.len() {
+                            continue;
                         }
-                    },
-                    None => {
-                        Ok(Self::choose_from_possibles(m, fits))
-                    },
+                        let mut plans = vec![];
+                        for (b, c) in branches.iter().zip(cached.iter()) {
+                            match try!(Self::replan(indexes, b, collation, c)) {
+                                Some(plan) => plans.push(plan),
+                                None => break,
+                            }
+                        }
+                        if plans.len() == branches.len() {
+                            return Ok(Some(QueryPlan::Union(plans)));
+                        }
+                    }
+                }
+                Ok(None)
+            },
+        }
+    }
+
+    // like choose_index, without a hint, but the choice is remembered for
+    // the next query of the same shape.  a collection scan isn't, since
+    // different values might let a partial index be used.
+    fn plan_cache_key(db: &str, coll: &str, m: &matcher::QueryDoc, collation: Option<&Collation>) -> (String, String, String) {
+        (String::from(db), String::from(coll), format!("{} {:?}", matcher::query_shape(m), collation.map(|c| c.spec)))
+    }
+
+    fn choose_index_cached<'a, 'm>(&self, db: &str, coll: &str, indexes: &'a Vec<IndexInfo>, m: &'m matcher::QueryDoc, collation: Option<&Collation>) -> Result<Option<QueryPlan<'m,'a>>> {
+        let key = Self::plan_cache_key(db, coll, m, collation);
+        let names = indexes.iter().map(|ndx| ndx.name.clone()).collect::<Vec<_>>();
+        let (cached, version) = {
+            let planning = try!(self.planner.lock());
+            let version = planning.versions.get(&(String::from(db), String::from(coll))).cloned().unwrap_or(0);
+            let cached =
+                match planning.plans.get(&key) {
+                    Some(e) if e.version == version && e.indexes == names => Some(e.plan.clone()),
+                    _ => None,
+                };
+            (cached, version)
+        };
+        if let Some(cached) = cached {
+            if let Some(plan) = try!(Self::replan(indexes, m, collation, &cached)) {
+                return Ok(Some(plan));
+            }
+        }
+        let plan = try!(Self::choose_index(indexes, m, None, collation));
+        let mut planning = try!(self.planner.lock());
+        let plans = &mut planning.plans;
+        match plan {
+            Some(ref plan) => {
+                if plans.len() >= PLAN_CACHE_SIZE {
+                    plans.clear();
                 }
+                let e = PlanCacheEntry {
+                    indexes: names,
+                    version: version,
+                    plan: CachedPlan::from_plan(plan),
+                };
+                plans.insert(key, e);
+            },
+            None => {
+                plans.remove(&key);
             },
         }
+        Ok(plan)
     }
 
     fn find_index_for_min_max<'a>(indexes: &'a Vec<IndexInfo>, keys: &Vec<&str>) -> Result<Option<&'a IndexInfo>> {
         for ndx in indexes {
+            // min and max don't come with a query to prove anything about
+            if ndx.options.get("partialFilterExpression").is_some() {
+                continue;
+            }
             let (normspec, _) = try!(get_normalized_spec(&ndx.spec, &ndx.options));
             let a = normspec.iter().map(|&(ref k,_)| k).collect::<Vec<_>>();
             if a.len() != keys.len() {
@@ -4292,7 +5788,7 @@ impl Connection {
                             Ok(AggOp::Redact(e))
                         },
                         "$geoNear" => {
-                            Err(Error::Misc(format!("agg pipeline TODO: {}", k)))
+                            Ok(AggOp::GeoNear(v))
                         },
                         _ => Err(Error::MongoCode(16436, format!("invalid agg pipeline stage name: {}", k)))
                     }
@@ -4467,7 +5963,7 @@ impl Connection {
         ctx
     }
 
-    fn do_group(seq: Box<Iterator<Item=Result<Row>>>, id: Expr, ops: Vec<(String,GroupAccum)>) -> Result<HashMap<bson::Value,bson::Document>> {
+    fn do_group(seq: Box<Iterator<Item=Result<Row>>>, id: Expr, ops: Vec<(String,GroupAccum)>, c: Option<&Collation>) -> Result<HashMap<bson::Value,bson::Document>> {
         let mut mapa = HashMap::new();
         for rr in seq {
             let row = try!(rr);
@@ -4499,8 +5995,9 @@ impl Connection {
                         idval
                     },
                 };
-            // TODO avoid clone() in following line
-            let acc = match mapa.entry(idval.clone()) {
+            // with a collation, ids which collate the same are the same
+            // group, which keeps the first of them as its _id.
+            let acc = match mapa.entry(collation::collate(c, &idval).into_owned()) {
                 std::collections::hash_map::Entry::Vacant(e) => {
                     let mut d = bson::Document::new();
                     d.set("_id", idval);
@@ -4534,7 +6031,7 @@ impl Connection {
                         let v = try!(Self::eval(&ctx, &e));
                         match try!(acc.entry(k)) {
                             bson::Entry::Found(e) => {
-                                if Ordering::Greater == matcher::cmp(&v, e.get()) {
+                                if Ordering::Greater == matcher::cmp(&collation::collate(c, &v), &collation::collate(c, e.get())) {
                                     e.replace(v);
                                 }
                             },
@@ -4547,7 +6044,7 @@ impl Connection {
                         let v = try!(Self::eval(&ctx, &e));
                         match try!(acc.entry(k)) {
                             bson::Entry::Found(e) => {
-                                if Ordering::Less == matcher::cmp(&v, e.get()) {
+                                if Ordering::Less == matcher::cmp(&collation::collate(c, &v), &collation::collate(c, e.get())) {
                                     e.replace(v);
                                 }
                             },
@@ -4651,7 +6148,7 @@ impl Connection {
         Ok(mapa)
     }
 
-    fn compare_values_at_path(a: &bson::Value, b: &bson::Value, path: &str, backward: bool) -> Ordering {
+    fn compare_values_at_path(a: &bson::Value, b: &bson::Value, path: &str, backward: bool, collation: Option<&Collation>) -> Ordering {
         let null = bson::Value::BNull;
 
         // TODO what is this supposed to do if the path yields multiple values?  error?
@@ -4668,7 +6165,7 @@ impl Connection {
         let va = a.walk_path(path).leaves().map(|leaf| leaf.v.unwrap_or(&null)).next().unwrap_or(&null);
         let vb = b.walk_path(path).leaves().map(|leaf| leaf.v.unwrap_or(&null)).next().unwrap_or(&null);
 
-        let c = matcher::cmpdir(va, vb, backward);
+        let c = matcher::cmpdir(&collation::collate(collation, va), &collation::collate(collation, vb), backward);
         c
 /*
 For server6125 (agg sort), the following code seems a little
@@ -4711,7 +6208,7 @@ looking EXACTLY like a plain objectid entry.
             };
         a.sort_by(|a,b| -> Ordering {
             for &(ref path, dir) in keys.iter() {
-                let c = Self::compare_values_at_path(a, b, path, dir);
+                let c = Self::compare_values_at_path(a, b, path, dir, None);
                 if c != Ordering::Equal {
                     return c;
                 }
@@ -4721,13 +6218,7 @@ looking EXACTLY like a plain objectid entry.
         Ok(())
     }
 
-    fn do_sort(a: &mut Vec<Row>, orderby: &bson::Value) -> Result<()> {
-        #[derive(Copy,Clone)]
-        enum SortType {
-            Compare(bool),
-            TextScore,
-        };
-
+    fn parse_sort_keys(orderby: &bson::Value) -> Result<Vec<(String, SortType)>> {
         let keys =
             match orderby {
                 &bson::Value::BDocument(ref bd) => {
@@ -4739,7 +6230,7 @@ looking EXACTLY like a plain objectid entry.
                             if n == 0 {
                                 return Err(Error::Misc(String::from("sort dir cannot be 0")));
                             }
-                            a.push((path, SortType::Compare(n < 0)));
+                            a.push((path.clone(), SortType::Compare(n < 0)));
                         } else if dir.is_document() {
                             let dir = dir.as_document().unwrap();
                             if dir.len() != 1 {
@@ -4751,7 +6242,7 @@ looking EXACTLY like a plain objectid entry.
                             } else if dir.pairs[0].1.as_str().unwrap() != "textScore" {
                                 return Err(Error::Misc(format!("sort dir invalid: {:?}", dir)));
                             } else {
-                                a.push((path, SortType::TextScore));
+                                a.push((path.clone(), SortType::TextScore));
                             }
                         } else {
                             return Err(Error::Misc(format!("sort dir invalid: {:?}", dir)));
@@ -4764,39 +6255,46 @@ looking EXACTLY like a plain objectid entry.
                 },
             };
 
-        a.sort_by(|a,b| -> Ordering {
-            for &(ref path, dir) in keys.iter() {
-                match dir {
-                    SortType::Compare(backward) => {
-                        let c = Self::compare_values_at_path(&a.doc, &b.doc, path, backward);
-                        if c != Ordering::Equal {
-                            return c;
-                        }
-                    },
-                    SortType::TextScore => {
-                        if a.score.is_none() || b.score.is_none() {
-                            println!("TODO score is missing");
+        Ok(keys)
+    }
+
+    fn compare_rows(keys: &[(String, SortType)], a: &Row, b: &Row, collation: Option<&Collation>) -> Ordering {
+        for &(ref path, dir) in keys.iter() {
+            match dir {
+                SortType::Compare(backward) => {
+                    let c = Self::compare_values_at_path(&a.doc, &b.doc, path, backward, collation);
+                    if c != Ordering::Equal {
+                        return c;
+                    }
+                },
+                SortType::TextScore => {
+                    if a.score.is_none() || b.score.is_none() {
+                        println!("TODO score is missing");
+                    } else {
+                        let sa = a.score.unwrap();
+                        let sb = b.score.unwrap();
+                        if sb < sa {
+                            return Ordering::Less;
+                        } else if sb > sa {
+                            return Ordering::Greater;
                         } else {
-                            let sa = a.score.unwrap();
-                            let sb = b.score.unwrap();
-                            if sb < sa {
-                                return Ordering::Less;
-                            } else if sb > sa {
-                                return Ordering::Greater;
-                            } else {
-                                // Ordering::Equal
-                            }
+                            // Ordering::Equal
                         }
-                    },
-                }
+                    }
+                },
             }
-            Ordering::Equal
-        });
+        }
+        Ordering::Equal
+    }
+
+    fn do_sort(a: &mut Vec<Row>, orderby: &bson::Value, collation: Option<&Collation>) -> Result<()> {
+        let keys = try!(Self::parse_sort_keys(orderby));
+        a.sort_by(|a,b| Self::compare_rows(&keys, a, b, collation));
         Ok(())
     }
 
-    fn agg_group(seq: Box<Iterator<Item=Result<Row>>>, id: Expr, ops: Vec<(String,GroupAccum)>) -> Box<Iterator<Item=Result<Row>>> {
-        match Self::do_group(seq, id, ops) {
+    fn agg_group(seq: Box<Iterator<Item=Result<Row>>>, id: Expr, ops: Vec<(String,GroupAccum)>, c: Option<&Collation>) -> Box<Iterator<Item=Result<Row>>> {
+        match Self::do_group(seq, id, ops, c) {
             Ok(mapa) => {
                 box mapa.into_iter().map(|(_,v)| {
                     let row = Row {
@@ -4813,12 +6311,12 @@ looking EXACTLY like a plain objectid entry.
         }
     }
 
-    fn guts_matcher_filter_map(rr: Result<Row>, m: &matcher::QueryDoc) -> Option<Result<Row>> {
+    fn guts_matcher_filter_map(rr: Result<Row>, m: &matcher::QueryDoc, c: Option<&Collation>) -> Option<Result<Row>> {
         match rr {
             Ok(row) => {
                 //println!("looking at row: {:?}", row);
                 //println!("matcher is: {:?}", m);
-                let (b, pos) = matcher::match_query(&m, &row.doc);
+                let (b, pos) = matcher::match_query_collated(&m, &row.doc, c);
                 if b {
                     //println!("    matched");
                     let r = Row {
@@ -4851,12 +6349,21 @@ looking EXACTLY like a plain objectid entry.
             )
     }
 
-    fn seq_match(seq: Box<Iterator<Item=Result<Row>>>, m: matcher::QueryDoc) -> Box<Iterator<Item=Result<Row>>> {
-        box seq.filter_map(move |r| Self::guts_matcher_filter_map(r, &m))
+    // with a collation, the literals of the query get keyed once, here,
+    // instead of for every doc.
+    fn seq_match(seq: Box<Iterator<Item=Result<Row>>>, m: matcher::QueryDoc, c: Option<Collation>) -> Box<Iterator<Item=Result<Row>>> {
+        let m = matcher::key_literals(m, c.as_ref());
+        box seq.filter_map(move |r| Self::guts_matcher_filter_map(r, &m, c.as_ref()))
     }
 
-    fn seq_match_ref<'a>(seq: Box<Iterator<Item=Result<Row>>>, m: &'a matcher::QueryDoc) -> Box<Iterator<Item=Result<Row>> + 'a> {
-        box seq.filter_map(move |r| Self::guts_matcher_filter_map(r, m))
+    fn seq_match_ref<'a>(seq: Box<Iterator<Item=Result<Row>>>, m: &'a matcher::QueryDoc, c: Option<&'a Collation>) -> Box<Iterator<Item=Result<Row>> + 'a> {
+        match c {
+            Some(_) => {
+                let m = matcher::key_literals(m.clone(), c);
+                box seq.filter_map(move |r| Self::guts_matcher_filter_map(r, &m, c))
+            },
+            None => box seq.filter_map(move |r| Self::guts_matcher_filter_map(r, m, c)),
+        }
     }
 
     fn agg_project(seq: Box<Iterator<Item=Result<Row>>>, expressions: Vec<(String,AggProj)>) -> Box<Iterator<Item=Result<Row>>> {
@@ -4906,15 +6413,28 @@ looking EXACTLY like a plain objectid entry.
     pub fn aggregate(&self,
                 db: &str,
                 coll: &str,
-                pipeline: bson::Array
+                pipeline: bson::Array,
+                collation: Option<bson::Document>
                 ) 
         -> Result<(Option<String>, Box<Iterator<Item=Result<Row>> + 'static>)>
     {
-        let ops = try!(Self::parse_agg(pipeline));
-        // TODO check for plan
-        let plan = None;
+        let mut ops = try!(Self::parse_agg(pipeline));
+        if ops.iter().skip(1).any(|op| if let &AggOp::GeoNear(_) = op { true } else { false }) {
+            return Err(Error::MongoCode(28837, String::from("$geoNear is only valid as the first stage in a pipeline")));
+        }
         let reader = try!(self.conn.begin_read());
-        let mut seq: Box<Iterator<Item=Result<Row>>> = try!(Self::into_collection_reader(reader, db, coll, plan));
+        let collation = try!(Self::resolve_collation(&try!(reader.list_collections()), db, coll, collation));
+        let mut seq: Box<Iterator<Item=Result<Row>>> =
+            if let Some(&AggOp::GeoNear(_)) = ops.first() {
+                match ops.remove(0) {
+                    AggOp::GeoNear(v) => try!(Self::agg_geo_near(&*reader, db, coll, v)),
+                    _ => unreachable!(),
+                }
+            } else {
+                // TODO check for plan
+                let plan = None;
+                try!(Self::into_collection_reader(reader, db, coll, plan))
+            };
         let mut out = None;
         for op in ops {
             match op {
@@ -4925,18 +6445,16 @@ looking EXACTLY like a plain objectid entry.
                     seq = box seq.take(n as usize);
                 },
                 AggOp::Match(m) => {
-                    seq = Self::seq_match(seq, m);
+                    seq = Self::seq_match(seq, m, collation.clone());
                 },
                 AggOp::Sort(ref orderby) => {
-                    let mut a = try!(seq.collect::<Result<Vec<_>>>());
-                    try!(Self::do_sort(&mut a, orderby));
-                    seq = box a.into_iter().map(|d| Ok(d))
+                    seq = try!(Self::sort_rows(seq, orderby, collation.clone(), SORT_BUFFER_BYTES));
                 },
                 AggOp::Project(expressions) => {
                     seq = Self::agg_project(seq, expressions);
                 },
                 AggOp::Group(id, ops) => {
-                    seq = Self::agg_group(seq, id, ops);
+                    seq = Self::agg_group(seq, id, ops, collation.as_ref());
                 },
                 AggOp::Unwind(path) => {
                     seq = Self::agg_unwind(seq, path);
@@ -4948,41 +6466,44 @@ looking EXACTLY like a plain objectid entry.
                     out = Some(s);
                 },
                 AggOp::GeoNear(_) => {
-                    return Err(Error::Misc(format!("agg pipeline TODO: {:?}", op)))
+                    // already checked above
+                    unreachable!();
                 },
             }
         }
         Ok((out, seq))
     }
 
-    pub fn distinct(&self, db: &str, coll: &str, key: &str, query: bson::Document) -> Result<bson::Array> {
+    pub fn distinct(&self, db: &str, coll: &str, key: &str, query: bson::Document, collation: Option<bson::Document>) -> Result<bson::Array> {
         let reader = try!(self.conn.begin_read());
         let indexes = try!(reader.list_indexes(Some((db, coll))));
         //println!("indexes: {:?}", indexes);
+        let collation = try!(Self::resolve_collation(&try!(reader.list_collections()), db, coll, collation));
         let m = try!(matcher::parse_query(query));
-        let plan = try!(Self::choose_index(&indexes, &m, None));
+        let plan = try!(self.choose_index_cached(db, coll, &indexes, &m, collation.as_ref()));
         //println!("plan: {:?}", plan);
         let seq: Box<Iterator<Item=Result<Row>>> = try!(Self::get_collection_reader_r(&*reader, db, coll, plan));
         // TODO we shadow-let here because the type from seq_match_ref() doesn't match the original
         // type because of its explicit lifetime.
-        let seq = Self::seq_match_ref(seq, &m);
-        let mut results = HashSet::new();
+        let seq = Self::seq_match_ref(seq, &m, collation.as_ref());
+        // values which collate the same are the same value, and the
+        // first one seen is the one returned.
+        let mut seen = HashSet::new();
+        let mut a = bson::Array::new();
         for rr in seq {
             let row = try!(rr);
             for leaf in row.doc.walk_path(key).leaves() {
                 match leaf.v {
                     Some(v) => {
-                        results.insert(v.clone());
+                        if seen.insert(collation::collate(collation.as_ref(), v).into_owned()) {
+                            a.push(v.clone());
+                        }
                     },
                     None => {
                     },
                 }
             }
         }
-        let mut a = bson::Array::new();
-        for v in results {
-            a.push(v);
-        }
         Ok(a)
     }
 
@@ -4996,13 +6517,15 @@ looking EXACTLY like a plain objectid entry.
                 max: Option<bson::Value>,
                 hint: Option<bson::Value>,
                 // TODO explain is never used in latest wire protocol
-                explain: Option<bson::Value>
+                explain: Option<bson::Value>,
+                collation: Option<bson::Document>
                 ) 
         -> Result<Box<Iterator<Item=Result<Row>> + 'static>>
     {
         let reader = try!(self.conn.begin_read());
         let indexes = try!(reader.list_indexes(Some((db, coll))));
         // TODO maybe we should get normalized index specs for all the indexes now.
+        let collation = try!(Self::resolve_collation(&try!(reader.list_collections()), db, coll, collation));
         let m = try!(matcher::parse_query(query));
         fn is_hint_natural(v: &bson::Value) -> bool {
             match v {
@@ -5049,7 +6572,7 @@ looking EXACTLY like a plain objectid entry.
                 None
             },
         };
-        let mut seq = {
+        let (mut seq, sorted) = {
             let plan =
                 // unless we're going to add comparisons to the query,
                 // the bounds for min/max need to be precise, since the matcher isn't
@@ -5059,8 +6582,10 @@ looking EXACTLY like a plain objectid entry.
                     (&None, &None) => {
                         if natural {
                             None
+                        } else if hint.is_some() {
+                            try!(Self::choose_index(&indexes, &m, hint, collation.as_ref()))
                         } else {
-                            try!(Self::choose_index(&indexes, &m, hint))
+                            try!(self.choose_index_cached(db, coll, &indexes, &m, collation.as_ref()))
                         }
                     },
                     (min, max) => {
@@ -5150,17 +6675,26 @@ looking EXACTLY like a plain objectid entry.
                     }
                 };
 
+            // when the index gives the order of the sort, the rows can
+            // just stream out of it
+            let (plan, sorted) =
+                match (&orderby, &min, &max) {
+                    (&Some(ref orderby), &None, &None) if !natural => {
+                        let keys = try!(Self::parse_sort_keys(orderby));
+                        try!(Self::plan_for_sort(&indexes, plan, &keys, collation.as_ref(), hint.is_none()))
+                    },
+                    _ => (plan, false),
+                };
+
             let seq: Box<Iterator<Item=Result<Row>>> = try!(Self::into_collection_reader(reader, db, coll, plan));
-            seq
+            (seq, sorted)
         };
-        seq = Self::seq_match(seq, m);
+        seq = Self::seq_match(seq, m, collation.clone());
         match orderby {
-            Some(ref orderby) => {
-                let mut a = try!(seq.collect::<Result<Vec<_>>>());
-                try!(Self::do_sort(&mut a, orderby));
-                seq = box a.into_iter().map(|d| Ok(d))
+            Some(ref orderby) if !sorted => {
+                seq = try!(Self::sort_rows(seq, orderby, collation.clone(), SORT_BUFFER_BYTES));
             },
-            None => {
+            _ => {
             },
         }
         match projection {
@@ -5173,17 +6707,187 @@ looking EXACTLY like a plain objectid entry.
         }
         Ok(seq)
     }
+
+    // a find on a capped collection which doesn't end.  when it runs out
+    // of docs, it returns None, but the next call picks up with whatever
+    // has been inserted since.  it only ever goes in natural order.
+    pub fn into_tailable_find(self, db: &str, coll: &str, query: bson::Document, projection: Option<bson::Document>) -> Result<Box<Iterator<Item=Result<Row>> + 'static>> {
+        let (capped, collation) = {
+            let reader = try!(self.conn.begin_read());
+            let colls = try!(reader.list_collections());
+            let capped =
+                match colls.iter().find(|c| c.db == db && c.coll == coll) {
+                    Some(c) => try!(Capped::from_options(&c.options)).is_some(),
+                    // mongo just returns nothing
+                    None => true,
+                };
+            (capped, try!(Self::resolve_collation(&colls, db, coll, None)))
+        };
+        if !capped {
+            return Err(Error::MongoCode(13051, format!("tailable cursor requested on non capped collection: {}.{}", db, coll)));
+        }
+        let m = try!(matcher::parse_query(query));
+        let projection = match projection {
+            Some(projection) => Some(try!(Projection::parse(projection))),
+            None => None,
+        };
+        let tail = Tail {
+            conn: self,
+            db: String::from(db),
+            coll: String::from(coll),
+            m: matcher::key_literals(m, collation.as_ref()),
+            collation: collation,
+            projection: projection,
+            last: None,
+            seq: None,
+        };
+        Ok(box tail)
+    }
+}
+
+struct Tail {
+    conn: Connection,
+    db: String,
+    coll: String,
+    m: matcher::QueryDoc,
+    collation: Option<Collation>,
+    projection: Option<Projection>,
+    // the _id of the last doc we looked at, whether it matched or not
+    last: Option<bson::Value>,
+    // only open while there are docs to return.  holding a reader
+    // between calls would keep us from ever seeing anything new.
+    seq: Option<Box<Iterator<Item=Result<Row>>>>,
+}
+
+impl Tail {
+    fn iter_next(&mut self) -> Result<Option<Row>> {
+        loop {
+            if self.seq.is_none() {
+                let reader = try!(self.conn.conn.begin_read());
+                let seq =
+                    match self.last {
+                        None => try!(reader.into_reader_collection_scan(&self.db, &self.coll)),
+                        Some(ref id) => {
+                            match try!(reader.into_reader_collection_scan_after(&self.db, &self.coll, id)) {
+                                Some(seq) => seq,
+                                None => return Err(Error::MongoCode(136, String::from("capped position lost"))),
+                            }
+                        },
+                    };
+                self.seq = Some(seq);
+            }
+            let row =
+                match self.seq.as_mut().unwrap().next() {
+                    None => {
+                        self.seq = None;
+                        return Ok(None);
+                    },
+                    Some(rr) => try!(rr),
+                };
+            match row.doc.as_document().ok().and_then(|d| d.get("_id")) {
+                Some(id) => self.last = Some(id.clone()),
+                None => return Err(Error::Misc(String::from("tailable cursor needs _id"))),
+            }
+            match Connection::guts_matcher_filter_map(Ok(row), &self.m, self.collation.as_ref()) {
+                None => {
+                },
+                Some(rr) => {
+                    let row = try!(rr);
+                    let row =
+                        match self.projection {
+                            Some(ref proj) => try!(proj.project(row)),
+                            None => row,
+                        };
+                    return Ok(Some(row));
+                },
+            }
+        }
+    }
+}
+
+impl Iterator for Tail {
+    type Item = Result<Row>;
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.iter_next() {
+            Err(e) => Some(Err(e)),
+            Ok(v) => v.map(|row| Ok(row)),
+        }
+    }
+}
+
+// how the storage engine should encode the strings in the keys of an
+// index.  the words in a text index stay as they are, and there are no
+// strings in the keys of a geo index.
+pub fn get_index_collation(normspec: &Vec<(String, IndexType)>, weights: &Option<HashMap<String,i32>>, options: &bson::Document) -> Result<Option<bson::CollationSpec>> {
+    if weights.is_some() || normspec.iter().any(|&(_, typ)| typ.is_geo()) {
+        return Ok(None);
+    }
+    let collation = try!(Collation::from_options(options));
+    Ok(collation.map(|c| c.spec))
 }
 
 pub fn get_index_entries(new_doc: &bson::Document, normspec: &Vec<(String, IndexType)>, weights: &Option<HashMap<String,i32>>, options: &bson::Document) -> Result<HashSet<Vec<(bson::Value,bool)>>> {
-    fn find_index_entry_vals(normspec: &Vec<(String, IndexType)>, new_doc: &bson::Document, sparse: bool) -> Vec<(bson::Value,bool)> {
+    fn find_index_entry_vals(normspec: &Vec<(String, IndexType)>, new_doc: &bson::Document, sparse: bool, options: &bson::Document) -> Result<Option<Vec<(bson::Value,bool)>>> {
         //println!("find_index_entry_vals: sparse = {:?}", sparse);
         let mut r = Vec::new();
+        let mut array_path: Option<String> = None;
         for t in normspec {
             let k = &t.0;
             let typ = t.1;
             let mut v = new_doc.walk_path(k).hack_like_find_path();
 
+            // maybe_array makes an entry for every item of an array.  with
+            // two different arrays, that would be every combination of
+            // items from both, which mongo refuses to do.  two keys down
+            // inside the same array, like a.x and a.y, are okay.
+            if !typ.is_geo() && v.is_array() {
+                let path = find_array_path(new_doc, k);
+                match array_path {
+                    Some(ref other) if *other != path => {
+                        return Err(Error::MongoCode(171, format!("cannot index parallel arrays [{}] [{}]", other, path)));
+                    },
+                    _ => array_path = Some(path),
+                }
+            }
+
+            if typ.is_geo() {
+                // a doc without a location does not go in a geo index at all
+                if v.is_undefined() || v.is_null() {
+                    return Ok(None);
+                }
+                // the entry is the grid cell, not the location.  if there
+                // are several cells, they go in an array, so that
+                // maybe_array will make an entry for each of them.
+                let mut cells =
+                    if IndexType::Geo2d == typ {
+                        let grid = try!(geo::Grid::from_options(options));
+                        let points = match geo::points_from_value(&v) {
+                            Some(points) => points,
+                            None => return Err(Error::MongoCode(16755, format!("Can't extract geo keys: {:?}", v))),
+                        };
+                        try!(points.iter().map(|p| grid.cell(p)).collect::<Result<Vec<_>>>())
+                    } else {
+                        let grid = geo::Grid::sphere();
+                        let geoms = match geo::geometries_from_value(&v) {
+                            Some(geoms) => geoms,
+                            None => return Err(Error::MongoCode(16755, format!("Can't extract geo keys: {:?}", v))),
+                        };
+                        let mut cells = vec![];
+                        for g in geoms.iter() {
+                            cells.extend(try!(grid.sphere_keys(g)));
+                        }
+                        cells
+                    };
+                cells.sort();
+                cells.dedup();
+                v =
+                    if cells.len() == 1 {
+                        bson::Value::BInt64(cells[0])
+                    } else {
+                        bson::Value::BArray(bson::Array {items: cells.into_iter().map(|c| bson::Value::BInt64(c)).collect()})
+                    };
+            }
+
             // now we replace any BUndefined with BNull.  this seems, well,
             // kinda wrong, as it effectively encodes the index entries to
             // contain information that is slightly incorrect, since BNull
@@ -5208,7 +6912,19 @@ pub fn get_index_entries(new_doc: &bson::Document, normspec: &Vec<(String, Index
                 r.push((v,neg));
             }
         }
-        r
+        Ok(Some(r))
+    }
+
+    // the shortest prefix of the path which is an array
+    fn find_array_path(new_doc: &bson::Document, k: &str) -> String {
+        let parts = k.split('.').collect::<Vec<_>>();
+        for i in 1 .. parts.len() {
+            let prefix = parts[0 .. i].join(".");
+            if new_doc.walk_path(&prefix).hack_like_find_path().is_array() {
+                return prefix;
+            }
+        }
+        String::from(k)
     }
 
     // TODO what should the name of this func actually be?
@@ -5304,14 +7020,23 @@ pub fn get_index_entries(new_doc: &bson::Document, normspec: &Vec<(String, Index
         }
     }
 
+    if let Some(filter) = try!(get_partial_filter(options)) {
+        // TODO the clone
+        let (b, _) = matcher::match_query(&filter, &bson::Value::BDocument(new_doc.clone()));
+        if !b {
+            return Ok(HashSet::new());
+        }
+    }
+
     let sparse = match options.get("sparse") {
         Some(&bson::Value::BBoolean(b)) => b,
         _ => false,
     };
 
-    let vals = find_index_entry_vals(normspec, new_doc, sparse);
     let mut entries = Vec::new();
-    maybe_array(&vals, new_doc, weights, &mut entries);
+    if let Some(vals) = try!(find_index_entry_vals(normspec, new_doc, sparse, options)) {
+        maybe_array(&vals, new_doc, weights, &mut entries);
+    }
 
     //println!("entries: {:?}", entries);
 
@@ -5320,3 +7045,57 @@ pub fn get_index_entries(new_doc: &bson::Document, normspec: &Vec<(String, Index
     Ok(entries)
 }
 
+// an index is multikey once any document has an array for one of its
+// keys.  the storage layer keeps track of this in the index options,
+// because the planner has to know.  see IndexInfo::is_multikey().
+pub fn is_multikey_doc(new_doc: &bson::Document, normspec: &Vec<(String, IndexType)>) -> bool {
+    normspec.iter().any(
+        |&(ref k, typ)|
+        !typ.is_geo() && new_doc.walk_path(k).hack_like_find_path().is_array()
+        )
+}
+
+
+// the storage layer finds the candidates for a $text query by looking
+// up each word in the index, but the index can't tell whether a phrase
+// appears as a whole.  so each candidate gets checked here.
+pub fn check_text_phrases(terms: &Vec<TextQueryTerm>, weights: &HashMap<String, i32>, doc: &bson::Value) -> bool {
+    fn contains_phrase(weights: &HashMap<String, i32>, doc: &bson::Value, p: &str) -> bool {
+        weights.keys().any(
+            |k|
+            doc.walk_path(k).leaves().any(
+                |leaf|
+                match leaf.v {
+                    Some(v) => {
+                        match v {
+                            &bson::Value::BString(ref s) => s.find(p).is_some(),
+                            _ => false,
+                        }
+                    },
+                    None => {
+                        false
+                    },
+                }
+                )
+            )
+    }
+
+    for term in terms {
+        let b = 
+            match term {
+                &TextQueryTerm::Word(_, _) => true,
+                &TextQueryTerm::Phrase(neg, ref s) => {
+                    let has = contains_phrase(weights, doc, s);
+                    if neg {
+                        !has
+                    } else {
+                        has
+                    }
+                },
+            };
+        if !b {
+            return false;
+        }
+    }
+    return true;
+}
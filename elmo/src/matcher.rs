@@ -3,17 +3,19 @@ use std;
 use std::cmp::Ordering;
 
 use super::Result;
+use super::collation::Collation;
+use super::collation::collate;
 
 extern crate misc;
 extern crate bson;
 extern crate regex;
 
-#[derive(Debug)]
+#[derive(Debug,Clone)]
 pub enum QueryDoc {
     QueryDoc(Vec<QueryItem>),
 }
 
-#[derive(Debug)]
+#[derive(Debug,Clone)]
 pub enum QueryItem {
     Compare(String, Vec<Pred>),
     AND(Vec<QueryDoc>),
@@ -24,7 +26,7 @@ pub enum QueryItem {
 }
 
 // TODO does this need to be public?  index min/max code is using it.
-#[derive(Debug)]
+#[derive(Debug,Clone)]
 pub enum Pred {
     Exists(bool),
     Size(i32),
@@ -44,11 +46,10 @@ pub enum Pred {
     GTE(bson::Value),
     LTE(bson::Value),
     REGEX(regex::Regex),
-    Near(bson::Value),
-    NearSphere(bson::Value),
-    GeoWithin(bson::Value),
-    GeoIntersects(bson::Value),
-    // TODO $within?
+    Near(super::geo::Near),
+    NearSphere(super::geo::Near),
+    GeoWithin(super::geo::Shape),
+    GeoIntersects(super::geo::Geometry),
 }
 
 fn cmp_f64(m: f64, litv: f64) -> Ordering {
@@ -337,13 +338,13 @@ fn cmp_gte(d: &bson::Value, lit: &bson::Value) -> bool {
     cmp_lte_gte(d, lit, Ordering::Greater)
 }
 
-fn do_elem_match_objects<F: Fn(usize)>(doc: &QueryDoc, v: &bson::Value, cb_array_pos: &F) -> bool {
+fn do_elem_match_objects<F: Fn(usize)>(doc: &QueryDoc, v: &bson::Value, cb_array_pos: &F, c: Option<&Collation>) -> bool {
     match v {
         &bson::Value::BArray(ref ba) => {
             let found = 
                 ba.items.iter().position(|vsub| {
                     match vsub {
-                        &bson::Value::BDocument(_) | &bson::Value::BArray(_) => match_query_doc(doc, vsub, cb_array_pos),
+                        &bson::Value::BDocument(_) | &bson::Value::BArray(_) => match_query_doc(doc, vsub, cb_array_pos, c),
                         _ => false,
                     }
                 });
@@ -390,7 +391,11 @@ fn cmp_with_array<F: Fn(&bson::Value) -> bool, G: Fn(usize)>(func: F, cb_array_p
 // TODO rather than call cb_array_pos, it would be better if this function simply returned
 // the actual path that matched.
 
-fn match_walk<'v, 'p, F: Fn(usize)>(pred: &Pred, walk: &bson::WalkRoot<'v, 'p>, cb_array_pos: &F) -> bool {
+// with a collation, values get compared by their sort keys.  the
+// literals already are sort keys, from key_literals(), so only the values
+// from the doc get keyed here.  a regex always sees the strings
+// themselves.
+fn match_walk<'v, 'p, F: Fn(usize)>(pred: &Pred, walk: &bson::WalkRoot<'v, 'p>, cb_array_pos: &F, c: Option<&Collation>) -> bool {
     let null = bson::Value::BNull;
 
     let eq = 
@@ -398,7 +403,7 @@ fn match_walk<'v, 'p, F: Fn(usize)>(pred: &Pred, walk: &bson::WalkRoot<'v, 'p>,
         walk.leaves().any(
             |leaf| {
                 let pos = leaf.path.last_array_index();
-                cmp_with_array(|v| cmp_eq(v, lit), cb_array_pos, pos, leaf.v.unwrap_or(&null))
+                cmp_with_array(|v| cmp_eq(&collate(c, v), lit), cb_array_pos, pos, leaf.v.unwrap_or(&null))
             }
         );
 
@@ -408,7 +413,7 @@ fn match_walk<'v, 'p, F: Fn(usize)>(pred: &Pred, walk: &bson::WalkRoot<'v, 'p>,
             |leaf| {
                 match leaf.v {
                     Some(v) => {
-                        do_elem_match_objects(doc, v, cb_array_pos)
+                        do_elem_match_objects(doc, v, cb_array_pos, c)
                     },
                     None => {
                         false
@@ -428,7 +433,7 @@ fn match_walk<'v, 'p, F: Fn(usize)>(pred: &Pred, walk: &bson::WalkRoot<'v, 'p>,
             walk.leaves().any(
                 |leaf| {
                     let pos = leaf.path.last_array_index();
-                    cmp_with_array(|v| cmp_lt(v,lit), cb_array_pos, pos, leaf.v.unwrap_or(&null))
+                    cmp_with_array(|v| cmp_lt(&collate(c, v), lit), cb_array_pos, pos, leaf.v.unwrap_or(&null))
                 }
             )
         },
@@ -436,7 +441,7 @@ fn match_walk<'v, 'p, F: Fn(usize)>(pred: &Pred, walk: &bson::WalkRoot<'v, 'p>,
             walk.leaves().any(
                 |leaf| {
                     let pos = leaf.path.last_array_index();
-                    cmp_with_array(|v| cmp_gt(v,lit), cb_array_pos, pos, leaf.v.unwrap_or(&null))
+                    cmp_with_array(|v| cmp_gt(&collate(c, v), lit), cb_array_pos, pos, leaf.v.unwrap_or(&null))
                 }
             )
         },
@@ -444,7 +449,7 @@ fn match_walk<'v, 'p, F: Fn(usize)>(pred: &Pred, walk: &bson::WalkRoot<'v, 'p>,
             walk.leaves().any(
                 |leaf| {
                     let pos = leaf.path.last_array_index();
-                    cmp_with_array(|v| cmp_lte(v,lit), cb_array_pos, pos, leaf.v.unwrap_or(&null))
+                    cmp_with_array(|v| cmp_lte(&collate(c, v), lit), cb_array_pos, pos, leaf.v.unwrap_or(&null))
                 }
             )
         },
@@ -452,7 +457,7 @@ fn match_walk<'v, 'p, F: Fn(usize)>(pred: &Pred, walk: &bson::WalkRoot<'v, 'p>,
             walk.leaves().any(
                 |leaf| {
                     let pos = leaf.path.last_array_index();
-                    cmp_with_array(|v| cmp_gte(v,lit), cb_array_pos, pos, leaf.v.unwrap_or(&null))
+                    cmp_with_array(|v| cmp_gte(&collate(c, v), lit), cb_array_pos, pos, leaf.v.unwrap_or(&null))
                 }
             )
         },
@@ -483,7 +488,7 @@ fn match_walk<'v, 'p, F: Fn(usize)>(pred: &Pred, walk: &bson::WalkRoot<'v, 'p>,
                     walk.leaves().any( 
                         |leaf|
                         // apparently cb_array_pos doesn't matter here
-                        cmp_with_array(|v| cmp_eq(v,lit), cb_array_pos, None, leaf.v.unwrap_or(&null))
+                        cmp_with_array(|v| cmp_eq(&collate(c, v), lit), cb_array_pos, None, leaf.v.unwrap_or(&null))
                     )
                 )
             }
@@ -492,12 +497,12 @@ fn match_walk<'v, 'p, F: Fn(usize)>(pred: &Pred, walk: &bson::WalkRoot<'v, 'p>,
             b == walk.exists()
         },
         &Pred::Not(ref preds) => {
-            let any_matches = preds.iter().any(|p| !match_walk(p, walk, cb_array_pos));
+            let any_matches = preds.iter().any(|p| !match_walk(p, walk, cb_array_pos, c));
             any_matches
         },
         &Pred::Nin(ref a) => {
             // TODO clone below is awful
-            !match_walk(&Pred::In(a.clone()), walk, cb_array_pos)
+            !match_walk(&Pred::In(a.clone()), walk, cb_array_pos, c)
         },
         &Pred::REGEX(ref re) => {
             walk.leaves().any(
@@ -528,7 +533,12 @@ fn match_walk<'v, 'p, F: Fn(usize)>(pred: &Pred, walk: &bson::WalkRoot<'v, 'p>,
                 walk.leaves().any( 
                     |leaf|
                     // apparently cb_array_pos doesn't matter here
-                    cmp_with_array(|v| cmp_in(v,lit), cb_array_pos, None, leaf.v.unwrap_or(&null))
+                    cmp_with_array(
+                        |v| match lit {
+                            &bson::Value::BRegex(_, _) => cmp_in(v, lit),
+                            _ => cmp_in(&collate(c, v), lit),
+                        },
+                        cb_array_pos, None, leaf.v.unwrap_or(&null))
                 )
             )
         },
@@ -589,7 +599,7 @@ fn match_walk<'v, 'p, F: Fn(usize)>(pred: &Pred, walk: &bson::WalkRoot<'v, 'p>,
                             match v {
                                 &bson::Value::BArray(ref ba) => {
                                     let found = 
-                                        ba.items.iter().position(|vsub| preds.iter().all(|p| match_walk(p, &vsub.fake_walk(), cb_array_pos)));
+                                        ba.items.iter().position(|vsub| preds.iter().all(|p| match_walk(p, &vsub.fake_walk(), cb_array_pos, c)));
                                     match found {
                                         Some(n) => {
                                             cb_array_pos(n);
@@ -609,28 +619,76 @@ fn match_walk<'v, 'p, F: Fn(usize)>(pred: &Pred, walk: &bson::WalkRoot<'v, 'p>,
             )
         },
 
-        // TODO don't panic here.  need to return Result<>
-        &Pred::Near(_) => panic!("TODO geo"),
-        &Pred::NearSphere(_) => panic!("TODO geo"),
-        &Pred::GeoWithin(_) => panic!("TODO geo"),
-        &Pred::GeoIntersects(_) => panic!("TODO geo"),
+        &Pred::Near(ref near) | &Pred::NearSphere(ref near) => {
+            // sorting by distance is the index's job.  here we only
+            // care about $maxDistance.
+            walk.leaves().any(
+                |leaf| {
+                    match leaf.v {
+                        Some(v) => {
+                            match super::geo::geometry_from_value(v) {
+                                Some(g) => near.contains(&g),
+                                None => false,
+                            }
+                        },
+                        None => {
+                            false
+                        },
+                    }
+                }
+            )
+        },
+        &Pred::GeoWithin(ref shape) => {
+            walk.leaves().any(
+                |leaf| {
+                    match leaf.v {
+                        Some(v) => {
+                            match super::geo::geometry_from_value(v) {
+                                Some(g) => shape.contains(&g),
+                                None => false,
+                            }
+                        },
+                        None => {
+                            false
+                        },
+                    }
+                }
+            )
+        },
+        &Pred::GeoIntersects(ref q) => {
+            walk.leaves().any(
+                |leaf| {
+                    match leaf.v {
+                        Some(v) => {
+                            match super::geo::geometry_from_value(v) {
+                                Some(g) => g.intersects(q),
+                                None => false,
+                            }
+                        },
+                        None => {
+                            false
+                        },
+                    }
+                }
+            )
+        },
     }
 }
 
-fn match_query_item<F: Fn(usize)>(qit: &QueryItem, d: &bson::Value, cb_array_pos: &F) -> bool {
+fn match_query_item<F: Fn(usize)>(qit: &QueryItem, d: &bson::Value, cb_array_pos: &F, c: Option<&Collation>) -> bool {
     match qit {
         &QueryItem::Compare(ref path, ref preds) => {
             let walk = d.walk_path(path);
-            preds.iter().all(|p| match_walk(p, &walk, cb_array_pos))
+            preds.iter().all(|p| match_walk(p, &walk, cb_array_pos, c))
         },
         &QueryItem::AND(ref qd) => {
-            qd.iter().all(|v| match_query_doc(v, d, cb_array_pos))
+            qd.iter().all(|v| match_query_doc(v, d, cb_array_pos, c))
         },
         &QueryItem::OR(ref qd) => {
-            qd.iter().any(|v| match_query_doc(v, d, cb_array_pos))
+            qd.iter().any(|v| match_query_doc(v, d, cb_array_pos, c))
         },
         &QueryItem::NOR(ref qd) => {
-            !qd.iter().any(|v| match_query_doc(v, d, cb_array_pos))
+            !qd.iter().any(|v| match_query_doc(v, d, cb_array_pos, c))
         },
         &QueryItem::Where(ref v) => {
             // TODO no panic here.  need to return Result.
@@ -643,11 +701,11 @@ fn match_query_item<F: Fn(usize)>(qit: &QueryItem, d: &bson::Value, cb_array_pos
     }
 }
 
-fn match_query_doc<F: Fn(usize)>(q: &QueryDoc, d: &bson::Value, cb_array_pos: &F) -> bool {
+fn match_query_doc<F: Fn(usize)>(q: &QueryDoc, d: &bson::Value, cb_array_pos: &F, c: Option<&Collation>) -> bool {
     let &QueryDoc::QueryDoc(ref items) = q;
     // AND
     for qit in items {
-        if !match_query_item(qit, d, cb_array_pos) {
+        if !match_query_item(qit, d, cb_array_pos, c) {
             return false;
         }
     }
@@ -660,20 +718,109 @@ pub fn match_pred_list(preds: &Vec<Pred>, d: &bson::Value) -> (bool,Option<usize
         // TODO error if it is already set?
         pos.set(Some(n));
     };
-    let b = preds.iter().all(|p| match_walk(p, &d.fake_walk(), &cb));
+    let b = preds.iter().all(|p| match_walk(p, &d.fake_walk(), &cb, None));
     (b, pos.get())
 }
 
 pub fn match_query(m: &QueryDoc, d: &bson::Value) -> (bool,Option<usize>) {
+    match_query_collated(m, d, None)
+}
+
+// with a collation, m has to have been through key_literals() first
+pub fn match_query_collated(m: &QueryDoc, d: &bson::Value, c: Option<&Collation>) -> (bool,Option<usize>) {
     let pos = std::cell::Cell::new(None);
     let cb = |n: usize| {
         // TODO error if it is already set?
         pos.set(Some(n));
     };
-    let b = match_query_doc(m, d, &cb);
+    let b = match_query_doc(m, d, &cb, c);
     (b, pos.get())
 }
 
+// the literals of a query replaced by their sort keys, so that matching
+// it against every doc doesn't key them all over again.  the original is
+// still what the planner wants, since index bounds get keyed when they
+// are encoded.
+pub fn key_literals(mut q: QueryDoc, c: Option<&Collation>) -> QueryDoc {
+    fn pred(p: &mut Pred, c: &Collation) {
+        match *p {
+            Pred::EQ(ref mut v) | Pred::NE(ref mut v) | Pred::GT(ref mut v) | Pred::GTE(ref mut v) | Pred::LT(ref mut v) | Pred::LTE(ref mut v) => {
+                *v = c.key_value(v);
+            },
+            Pred::In(ref mut a) | Pred::Nin(ref mut a) | Pred::All(ref mut a) => {
+                // a regex in $in stays as it is
+                for v in a.iter_mut() {
+                    *v = c.key_value(v);
+                }
+            },
+            Pred::Not(ref mut preds) | Pred::ElemMatchPreds(ref mut preds) => {
+                for p in preds.iter_mut() {
+                    pred(p, c);
+                }
+            },
+            Pred::ElemMatchObjects(ref mut q) => doc(q, c),
+            Pred::AllElemMatchObjects(ref mut a) => {
+                for q in a.iter_mut() {
+                    doc(q, c);
+                }
+            },
+            _ => (),
+        }
+    }
+    fn doc(q: &mut QueryDoc, c: &Collation) {
+        let &mut QueryDoc::QueryDoc(ref mut items) = q;
+        for qit in items.iter_mut() {
+            match *qit {
+                QueryItem::Compare(_, ref mut preds) => {
+                    for p in preds.iter_mut() {
+                        pred(p, c);
+                    }
+                },
+                QueryItem::AND(ref mut a) | QueryItem::OR(ref mut a) | QueryItem::NOR(ref mut a) => {
+                    for q in a.iter_mut() {
+                        doc(q, c);
+                    }
+                },
+                _ => (),
+            }
+        }
+    }
+    if let Some(c) = c {
+        doc(&mut q, c);
+    }
+    q
+}
+
+// whether the query compares anything with a string.  if not, collation
+// doesn't matter to it.
+pub fn compares_strings(q: &QueryDoc) -> bool {
+    fn has_strings(v: &bson::Value) -> bool {
+        match v {
+            &bson::Value::BString(_) => true,
+            &bson::Value::BArray(ref a) => a.items.iter().any(has_strings),
+            &bson::Value::BDocument(ref d) => d.pairs.iter().any(|&(_, ref v)| has_strings(v)),
+            _ => false,
+        }
+    }
+    fn pred(p: &Pred) -> bool {
+        match p {
+            &Pred::EQ(ref v) | &Pred::NE(ref v) | &Pred::GT(ref v) | &Pred::GTE(ref v) | &Pred::LT(ref v) | &Pred::LTE(ref v) => has_strings(v),
+            &Pred::In(ref a) | &Pred::Nin(ref a) | &Pred::All(ref a) => a.iter().any(has_strings),
+            &Pred::Not(ref preds) | &Pred::ElemMatchPreds(ref preds) => preds.iter().any(pred),
+            &Pred::ElemMatchObjects(ref q) => compares_strings(q),
+            &Pred::AllElemMatchObjects(ref a) => a.iter().any(compares_strings),
+            _ => false,
+        }
+    }
+    let &QueryDoc::QueryDoc(ref items) = q;
+    items.iter().any(
+        |qit| match qit {
+            &QueryItem::Compare(_, ref preds) => preds.iter().any(pred),
+            &QueryItem::AND(ref a) | &QueryItem::OR(ref a) | &QueryItem::NOR(ref a) => a.iter().any(compares_strings),
+            _ => false,
+        })
+}
+
 pub fn uses_where(m: &QueryDoc) -> bool {
     let &QueryDoc::QueryDoc(ref items) = m;
     items.iter().any(
@@ -691,6 +838,7 @@ pub fn uses_near(m: &QueryDoc) -> bool {
                 preds.iter().any(
                     |p| match p {
                         &Pred::Near(_) => true,
+                        &Pred::NearSphere(_) => true,
                         _ => false,
                     }
                     )
@@ -792,6 +940,187 @@ pub fn get_eqs(q: &QueryDoc) -> Vec<(&str, &bson::Value)> {
     a
 }
 
+// every comparison in the query, with the ones inside $and pulled up
+fn get_compares(q: &QueryDoc) -> Vec<(&str, &Pred)> {
+    fn f<'q>(a: &mut Vec<(&'q str, &'q Pred)>, q: &'q QueryDoc) {
+        let &QueryDoc::QueryDoc(ref items) = q;
+        for qit in items {
+            match qit {
+                &QueryItem::Compare(ref path, ref preds) => {
+                    for psub in preds {
+                        a.push((path, psub));
+                    }
+                },
+                &QueryItem::AND(ref docs) => {
+                    for d in docs {
+                        f(a, d);
+                    }
+                },
+                _ => {
+                },
+            }
+        }
+    }
+    let mut a = Vec::new();
+    f(&mut a, q);
+    a
+}
+
+// the only things mongo allows in a partialFilterExpression
+pub fn is_partial_filter(q: &QueryDoc) -> bool {
+    let &QueryDoc::QueryDoc(ref items) = q;
+    items.iter().all(
+        |qit| match qit {
+            &QueryItem::Compare(_, ref preds) => {
+                preds.iter().all(
+                    |p| match p {
+                        &Pred::EQ(_) | &Pred::GT(_) | &Pred::GTE(_) | &Pred::LT(_) | &Pred::LTE(_) => true,
+                        &Pred::Type(_) | &Pred::Exists(true) => true,
+                        _ => false,
+                    })
+            },
+            &QueryItem::AND(ref docs) => docs.iter().all(is_partial_filter),
+            _ => false,
+        })
+}
+
+// whether any value which matches p must also match f
+fn pred_implies(p: &Pred, f: &Pred) -> bool {
+    // only values of the same kind compare, like in cmp_lt_gt
+    fn cmp_same(v: &bson::Value, w: &bson::Value) -> Option<Ordering> {
+        if v.is_nan() || w.is_nan() || v.get_type_order() != w.get_type_order() {
+            None
+        } else {
+            Some(cmp(v, w))
+        }
+    }
+    fn gt(v: &bson::Value, w: &bson::Value) -> bool {
+        cmp_same(v, w) == Some(Ordering::Greater)
+    }
+    fn gte(v: &bson::Value, w: &bson::Value) -> bool {
+        gt(v, w) || cmp_same(v, w) == Some(Ordering::Equal)
+    }
+    fn lt(v: &bson::Value, w: &bson::Value) -> bool {
+        cmp_same(v, w) == Some(Ordering::Less)
+    }
+    fn lte(v: &bson::Value, w: &bson::Value) -> bool {
+        lt(v, w) || cmp_same(v, w) == Some(Ordering::Equal)
+    }
+    match (p, f) {
+        (&Pred::Exists(true), &Pred::Exists(true)) => true,
+        // comparing with null matches a missing field
+        (&Pred::EQ(ref v), &Pred::Exists(true)) => !v.is_null(),
+        (&Pred::GT(ref v), &Pred::Exists(true)) => !v.is_null(),
+        (&Pred::GTE(ref v), &Pred::Exists(true)) => !v.is_null(),
+        (&Pred::LT(ref v), &Pred::Exists(true)) => !v.is_null(),
+        (&Pred::LTE(ref v), &Pred::Exists(true)) => !v.is_null(),
+        (&Pred::Type(a), &Pred::Type(b)) => a == b,
+        (&Pred::EQ(ref v), &Pred::EQ(ref w)) => cmp_same(v, w) == Some(Ordering::Equal),
+        (&Pred::EQ(ref v), &Pred::GT(ref w)) => gt(v, w),
+        (&Pred::EQ(ref v), &Pred::GTE(ref w)) => gte(v, w),
+        (&Pred::EQ(ref v), &Pred::LT(ref w)) => lt(v, w),
+        (&Pred::EQ(ref v), &Pred::LTE(ref w)) => lte(v, w),
+        (&Pred::GT(ref v), &Pred::GT(ref w)) => gte(v, w),
+        (&Pred::GT(ref v), &Pred::GTE(ref w)) => gte(v, w),
+        (&Pred::GTE(ref v), &Pred::GT(ref w)) => gt(v, w),
+        (&Pred::GTE(ref v), &Pred::GTE(ref w)) => gte(v, w),
+        (&Pred::LT(ref v), &Pred::LT(ref w)) => lte(v, w),
+        (&Pred::LT(ref v), &Pred::LTE(ref w)) => lte(v, w),
+        (&Pred::LTE(ref v), &Pred::LT(ref w)) => lt(v, w),
+        (&Pred::LTE(ref v), &Pred::LTE(ref w)) => lte(v, w),
+        _ => false,
+    }
+}
+
+// whether every doc which matches q also matches filter.  false just means
+// we couldn't tell.
+pub fn implies(q: &QueryDoc, filter: &QueryDoc) -> bool {
+    if !is_partial_filter(filter) {
+        return false;
+    }
+    let have = get_compares(q);
+    get_compares(filter).iter().all(
+        |&(fpath, fp)| have.iter().any(|&(path, p)| path == fpath && pred_implies(p, fp))
+        )
+}
+
+// the query without its values, so queries which differ only in what
+// they compare with have the same shape.  the plan cache is keyed by it.
+pub fn query_shape(q: &QueryDoc) -> String {
+    fn preds(s: &mut String, op: &str, a: &Vec<Pred>) {
+        s.push_str(op);
+        s.push('(');
+        for p in a {
+            pred(s, p);
+        }
+        s.push(')');
+    }
+    fn pred(s: &mut String, p: &Pred) {
+        let name =
+            match p {
+                &Pred::ElemMatchObjects(ref q) => {
+                    s.push_str("$elemMatch");
+                    return doc(s, q);
+                },
+                &Pred::AllElemMatchObjects(ref a) => return docs(s, "$all$elemMatch", a),
+                &Pred::ElemMatchPreds(ref a) => return preds(s, "$elemMatch", a),
+                &Pred::Not(ref a) => return preds(s, "$not", a),
+                &Pred::Exists(_) => "$exists",
+                &Pred::Size(_) => "$size",
+                &Pred::Type(_) => "$type",
+                &Pred::Mod(_, _) => "$mod",
+                &Pred::In(_) => "$in",
+                &Pred::Nin(_) => "$nin",
+                &Pred::All(_) => "$all",
+                &Pred::EQ(_) => "$eq",
+                &Pred::NE(_) => "$ne",
+                &Pred::GT(_) => "$gt",
+                &Pred::LT(_) => "$lt",
+                &Pred::GTE(_) => "$gte",
+                &Pred::LTE(_) => "$lte",
+                &Pred::REGEX(_) => "$regex",
+                &Pred::Near(_) => "$near",
+                &Pred::NearSphere(_) => "$nearSphere",
+                &Pred::GeoWithin(_) => "$geoWithin",
+                &Pred::GeoIntersects(_) => "$geoIntersects",
+            };
+        s.push_str(name);
+        s.push(' ');
+    }
+    fn docs(s: &mut String, op: &str, a: &[QueryDoc]) {
+        s.push_str(op);
+        s.push('[');
+        for q in a {
+            doc(s, q);
+        }
+        s.push(']');
+    }
+    fn doc(s: &mut String, q: &QueryDoc) {
+        let &QueryDoc::QueryDoc(ref items) = q;
+        s.push('{');
+        for qit in items {
+            match qit {
+                &QueryItem::Compare(ref path, ref preds) => {
+                    s.push_str(&format!("{:?}:", path));
+                    for p in preds {
+                        pred(s, p);
+                    }
+                },
+                &QueryItem::AND(ref a) => docs(s, "$and", a),
+                &QueryItem::OR(ref a) => docs(s, "$or", a),
+                &QueryItem::NOR(ref a) => docs(s, "$nor", a),
+                &QueryItem::Where(_) => s.push_str("$where"),
+                &QueryItem::Text(_) => s.push_str("$text"),
+            }
+            s.push(',');
+        }
+        s.push('}');
+    }
+    let mut s = String::new();
+    doc(&mut s, q);
+    s
+}
+
 pub fn doc_is_query_doc(bd: &bson::Document) -> bool {
     let has_path = bd.pairs.iter().any(|&(ref k, _)| !k.starts_with("$"));
     let has_and = bd.pairs.iter().any(|&(ref k, _)| k == "$and");
@@ -970,18 +1299,38 @@ fn parse_pred(k: &str, v: bson::Value) -> Result<Pred> {
             }
         },
 
-        // TODO the following items need more parsing
-        "$near" => Ok(Pred::Near(v)),
-        "$nearSphere" => Ok(Pred::NearSphere(v)),
-        "$geoWithin" => Ok(Pred::GeoWithin(v)),
-        "$geoIntersects" => Ok(Pred::GeoIntersects(v)),
+        "$near" => Ok(Pred::Near(try!(super::geo::Near::parse(v, false)))),
+        "$nearSphere" => Ok(Pred::NearSphere(try!(super::geo::Near::parse(v, true)))),
+        "$geoWithin" | "$within" => Ok(Pred::GeoWithin(try!(super::geo::Shape::parse(v)))),
+        "$geoIntersects" => Ok(Pred::GeoIntersects(try!(super::geo::parse_dollar_geometry(&v)))),
         _ => Err(super::Error::Misc(format!("unknown pred: {}", k))),
     }
 }
 
 pub fn parse_pred_list(pairs: Vec<(String, bson::Value)>) -> Result<Vec<Pred>> {
+    let (max_distance, pairs): (Vec<_>, Vec<_>) = pairs.into_iter().partition(|&(ref k,_)| k == "$maxDistance");
     let (regex, other): (Vec<_>, Vec<_>) = pairs.into_iter().partition(|&(ref k,_)| k == "$regex" || k == "$options");
     let mut preds = try!(other.into_iter().map(|(k,v)| parse_pred(&k,v)).collect::<Result<Vec<_>>>());
+    if let Some((_, d)) = max_distance.into_iter().last() {
+        let d = try!(d.numeric_to_f64());
+        if d < 0.0 {
+            return Err(super::Error::MongoCode(16895, String::from("$maxDistance must be non-negative")));
+        }
+        let near = preds.iter_mut().filter_map(
+            |p| match p {
+                &mut Pred::Near(ref mut near) => Some(near),
+                &mut Pred::NearSphere(ref mut near) => Some(near),
+                _ => None,
+            }).next();
+        match near {
+            Some(near) => {
+                near.max_distance = Some(d);
+            },
+            None => {
+                return Err(super::Error::Misc(String::from("$maxDistance without $near")));
+            },
+        }
+    }
     let (mut expr, mut options): (Vec<_>, Vec<_>) = regex.into_iter().partition(|&(ref k,_)| k == "$regex");
     // TODO need a function which takes a vector of len 0 or 1 and consumes it in Option<T>
     let expr = expr.pop();
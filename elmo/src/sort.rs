@@ -0,0 +1,91 @@
+/*
+    Copyright 2014-2016 Zumero, LLC
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+
+// how the rows of a find with an orderby that no index can give go
+// through the external sort in misc.
+
+use std::io::Read;
+use std::io::Write;
+
+use misc;
+
+use super::Result;
+use super::Error;
+use super::Row;
+
+extern crate bson;
+
+impl misc::sort::Spill for Row {
+    type Error = Error;
+
+    // the doc is nearly all of it
+    fn size(&self) -> usize {
+        self.doc.bson_len() + 32
+    }
+
+    // each row goes into the file as a document of its own, so the score
+    // and position come back along with the doc.
+    fn write_to<W: Write>(self, w: &mut W) -> Result<()> {
+        let mut d = bson::Document::new();
+        d.set("d", self.doc);
+        if let Some(s) = self.score {
+            d.set_f64("s", s);
+        }
+        if let Some(p) = self.pos {
+            d.set_i64("p", p as i64);
+        }
+        try!(w.write_all(&d.to_bson_array()));
+        Ok(())
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> Result<Option<Row>> {
+        let mut len = [0; 4];
+        let got = try!(r.read(&mut len[0 .. 1]));
+        if got == 0 {
+            return Ok(None);
+        }
+        try!(misc::sort::read_exact(r, &mut len[1 ..]));
+        let n = misc::endian::i32_from_bytes_le(len) as usize;
+        if n < 5 {
+            return Err(Error::CorruptFile("sort run has a bad length"));
+        }
+        let mut buf = vec![0; n];
+        buf[0 .. 4].clone_from_slice(&len);
+        try!(misc::sort::read_exact(r, &mut buf[4 ..]));
+        let mut d = try!(bson::Document::from_bson(&buf));
+        let doc =
+            match d.remove("d") {
+                Some(v) => v,
+                None => return Err(Error::CorruptFile("sort run row without a doc")),
+            };
+        let score =
+            match d.get("s") {
+                Some(v) => Some(try!(v.numeric_to_f64())),
+                None => None,
+            };
+        let pos =
+            match d.get("p") {
+                Some(v) => Some(try!(v.numeric_to_i64()) as usize),
+                None => None,
+            };
+        let row = Row {
+            doc: doc,
+            pos: pos,
+            score: score,
+        };
+        Ok(Some(row))
+    }
+}
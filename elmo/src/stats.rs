@@ -0,0 +1,242 @@
+/*
+    Copyright 2014-2016 Zumero, LLC
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+
+// statistics about the keys of an index, so the planner can guess how
+// many entries each plan would have to look at instead of just counting
+// how many fields it has bounds for.  they get gathered by a pass over
+// the collection (see Connection::analyze) and are kept in the options
+// of the index under "stats", so every engine stores them the same way.
+//
+// the samples are every so many entries, in whatever order the scan
+// found them.  a range which covers a tenth of the samples probably
+// covers about a tenth of the index.  the number of distinct keys is
+// guessed from how often the samples repeat.
+//
+// each sample is the key of the entry, encoded just the way the index
+// encodes it, collation and all.  so a sample is within the bounds
+// exactly when the entry would be found by a scan with those bounds.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use super::Result;
+use super::Error;
+use super::QueryBounds;
+use super::QueryKey;
+
+extern crate bson;
+
+// the gatherer keeps between this many samples and twice as many
+const MIN_SAMPLES: usize = 100;
+
+#[derive(Debug,Clone)]
+pub struct IndexStats {
+    pub keys: u64,
+    pub distinct: u64,
+    pub samples: Vec<Box<[u8]>>,
+}
+
+impl IndexStats {
+    // None if the stats were never gathered
+    pub fn from_options(options: &bson::Document) -> Result<Option<IndexStats>> {
+        fn get_u64(d: &bson::Document, k: &str) -> Result<u64> {
+            match d.get(k) {
+                Some(v) if v.is_numeric() => Ok(try!(v.numeric_to_i64()) as u64),
+                _ => Err(Error::Misc(format!("index stats without {}", k))),
+            }
+        }
+        match options.get("stats") {
+            Some(&bson::Value::BDocument(ref d)) => {
+                let keys = try!(get_u64(d, "keys"));
+                let distinct = try!(get_u64(d, "distinct"));
+                let mut samples = vec![];
+                match d.get("samples") {
+                    Some(&bson::Value::BArray(ref a)) => {
+                        for v in &a.items {
+                            match v {
+                                &bson::Value::BBinary(_, ref s) => samples.push(s.clone().into_boxed_slice()),
+                                // stats from before the samples were
+                                // encoded keys don't count
+                                &bson::Value::BArray(_) => return Ok(None),
+                                _ => return Err(Error::Misc(String::from("index stats sample must be binary"))),
+                            }
+                        }
+                    },
+                    _ => return Err(Error::Misc(String::from("index stats without samples"))),
+                }
+                Ok(Some(IndexStats {
+                    keys: keys,
+                    distinct: distinct,
+                    samples: samples,
+                }))
+            },
+            Some(_) => Err(Error::Misc(String::from("index stats must be a document"))),
+            None => Ok(None),
+        }
+    }
+
+    pub fn to_document(&self) -> bson::Document {
+        let mut samples = bson::Array::new();
+        for s in &self.samples {
+            samples.push(bson::Value::BBinary(0, s.to_vec()));
+        }
+        let mut d = bson::Document::new();
+        d.set_i64("keys", self.keys as i64);
+        d.set_i64("distinct", self.distinct as i64);
+        d.set_array("samples", samples);
+        d
+    }
+
+    // about how many entries of the index are within the bounds.  None
+    // if there are no samples to go on, which is an empty index, or one
+    // that was empty when the stats were gathered.
+    pub fn estimate(&self, bounds: &QueryBounds, c: Option<&bson::CollationSpec>) -> Option<f64> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        let keys = self.keys as f64;
+        let n = self.samples.len() as f64;
+        let (lo, hi) = key_range(bounds, c);
+        let hits = self.samples.iter().filter(|s| in_range(s, &lo, &hi)).count();
+        if hits > 0 {
+            Some(keys * (hits as f64) / n)
+        } else {
+            // too rare to show up in the samples.  for an EQ, a key that
+            // is as common as the average one.  for a range, a bit less
+            // than one sample's worth.
+            match bounds {
+                &QueryBounds::EQ(_) => Some(keys / (::std::cmp::max(self.distinct, 1) as f64)),
+                _ => Some(keys / (2.0 * n)),
+            }
+        }
+    }
+}
+
+// compares a sample with a key, as far as the key goes
+fn cmp_prefix(s: &[u8], k: &[u8]) -> Ordering {
+    if s.len() > k.len() {
+        s[.. k.len()].cmp(k)
+    } else {
+        s.cmp(k)
+    }
+}
+
+// each end of the range is an encoded key, and whether it is inclusive
+fn key_range(bounds: &QueryBounds, c: Option<&bson::CollationSpec>) -> (Option<(Vec<u8>, bool)>, Option<(Vec<u8>, bool)>) {
+    fn key(vals: &QueryKey, extra: Option<&QueryKey>, c: Option<&bson::CollationSpec>) -> Vec<u8> {
+        bson::Value::encode_multi_for_index_collated(vals, extra, c)
+    }
+    match bounds {
+        &QueryBounds::EQ(ref k) => (Some((key(k, None, c), true)), Some((key(k, None, c), true))),
+        &QueryBounds::GT(ref k) => (Some((key(k, None, c), false)), None),
+        &QueryBounds::GTE(ref k) => (Some((key(k, None, c), true)), None),
+        &QueryBounds::LT(ref k) => (None, Some((key(k, None, c), false))),
+        &QueryBounds::LTE(ref k) => (None, Some((key(k, None, c), true))),
+        &QueryBounds::GT_LT(ref eqs, ref min, ref max) => (Some((key(eqs, Some(min), c), false)), Some((key(eqs, Some(max), c), false))),
+        &QueryBounds::GT_LTE(ref eqs, ref min, ref max) => (Some((key(eqs, Some(min), c), false)), Some((key(eqs, Some(max), c), true))),
+        &QueryBounds::GTE_LT(ref eqs, ref min, ref max) => (Some((key(eqs, Some(min), c), true)), Some((key(eqs, Some(max), c), false))),
+        &QueryBounds::GTE_LTE(ref eqs, ref min, ref max) => (Some((key(eqs, Some(min), c), true)), Some((key(eqs, Some(max), c), true))),
+    }
+}
+
+// a sample which starts with the key of one end counts as equal to it,
+// the same as an index scan, where the rest of the entry is whatever
+// follows that key.
+fn in_range(s: &[u8], lo: &Option<(Vec<u8>, bool)>, hi: &Option<(Vec<u8>, bool)>) -> bool {
+    let above =
+        match lo {
+            &Some((ref k, inclusive)) => {
+                match cmp_prefix(s, k) {
+                    Ordering::Greater => true,
+                    Ordering::Equal => inclusive,
+                    Ordering::Less => false,
+                }
+            },
+            &None => true,
+        };
+    let below =
+        match hi {
+            &Some((ref k, inclusive)) => {
+                match cmp_prefix(s, k) {
+                    Ordering::Less => true,
+                    Ordering::Equal => inclusive,
+                    Ordering::Greater => false,
+                }
+            },
+            &None => true,
+        };
+    above && below
+}
+
+// the entries get fed in one at a time, and every stride-th one is kept.
+// whenever too many have been kept, every other one is thrown out and
+// the stride doubles, so the samples stay evenly spread without knowing
+// ahead of time how many entries there will be.
+pub struct Gatherer {
+    keys: u64,
+    stride: u64,
+    samples: Vec<Box<[u8]>>,
+}
+
+impl Gatherer {
+    pub fn new() -> Gatherer {
+        Gatherer {
+            keys: 0,
+            stride: 1,
+            samples: vec![],
+        }
+    }
+
+    // the encoded key of an entry
+    pub fn add(&mut self, k: Vec<u8>) {
+        if self.keys % self.stride == 0 {
+            self.samples.push(k.into_boxed_slice());
+            if self.samples.len() >= 2 * MIN_SAMPLES {
+                let kept = self.samples.drain(..).enumerate().filter(|&(i, _)| i % 2 == 0).map(|(_, s)| s).collect();
+                self.samples = kept;
+                self.stride = self.stride * 2;
+            }
+        }
+        self.keys = self.keys + 1;
+    }
+
+    pub fn finish(self) -> IndexStats {
+        let distinct = {
+            let mut counts = HashMap::new();
+            for s in &self.samples {
+                *counts.entry(s).or_insert(0) += 1;
+            }
+            let once = counts.values().filter(|&&c| c == 1).count();
+            let more = counts.len() - once;
+            // a key seen only once in the samples stands for lots of
+            // others which never got sampled at all, and one seen more
+            // than once probably doesn't.  this is the GEE estimator.
+            let n = self.samples.len() as f64;
+            let guess =
+                if n > 0.0 {
+                    ((self.keys as f64 / n).sqrt() * (once as f64) + (more as f64)).round() as u64
+                } else {
+                    0
+                };
+            ::std::cmp::min(::std::cmp::max(guess, counts.len() as u64), self.keys)
+        };
+        IndexStats {
+            keys: self.keys,
+            distinct: distinct,
+            samples: self.samples,
+        }
+    }
+}
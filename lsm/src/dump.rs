@@ -0,0 +1,328 @@
+/*
+    Copyright 2014-2016 Zumero, LLC
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+
+// a portable dump of the contents of a database, which does not depend
+// on the page format.  this is how a database gets moved from one
+// version of the file format to another.
+//
+// the stream looks like this:
+//
+//     magic (8 bytes)
+//     version (u32, big endian)
+//     for each pair:
+//         1u8
+//         key len (u64, big endian)
+//         key
+//         value len (u64, big endian)
+//         value
+//     0u8
+//     count of pairs (u64, big endian)
+//     checksum (u64, big endian)
+//
+// the checksum is FNV-1a over every byte which precedes it.  tombstones
+// are never dumped.  pairs are in key order.
+
+use std;
+use std::io::Read;
+use std::io::Write;
+
+use misc;
+
+use super::Error;
+use super::Result;
+use super::DatabaseFile;
+use super::DbSettings;
+use super::KeyForStorage;
+use super::ValueForStorage;
+use super::PairForStorage;
+use super::IForwardCursor;
+use super::ILiveValue;
+
+const MAGIC: &'static [u8] = b"lsmdump\0";
+const VERSION: u32 = 1;
+
+const TAG_PAIR: u8 = 1;
+const TAG_END: u8 = 0;
+
+// values bigger than this are not held in memory during load.  they
+// get spooled into a temp file and then streamed into the new database.
+const MAX_IN_MEMORY_VALUE: u64 = 1024 * 1024;
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+fn fnv(mut h: u64, a: &[u8]) -> u64 {
+    for b in a {
+        h = h ^ (*b as u64);
+        h = h.wrapping_mul(FNV_PRIME);
+    }
+    h
+}
+
+struct HashWriter<'a, W: Write + 'a> {
+    w: &'a mut W,
+    hash: u64,
+}
+
+impl<'a, W: Write> HashWriter<'a, W> {
+    fn put(&mut self, a: &[u8]) -> Result<()> {
+        self.hash = fnv(self.hash, a);
+        try!(self.w.write_all(a));
+        Ok(())
+    }
+
+    fn put_u64(&mut self, n: u64) -> Result<()> {
+        self.put(&misc::endian::u64_to_bytes_be(n))
+    }
+}
+
+struct HashReader<'a, R: Read + 'a> {
+    r: &'a mut R,
+    hash: u64,
+}
+
+impl<'a, R: Read> HashReader<'a, R> {
+    fn get(&mut self, a: &mut [u8]) -> Result<()> {
+        let got = try!(misc::io::read_fully(self.r, a));
+        if got != a.len() {
+            return Err(Error::CorruptFile("dump is truncated"));
+        }
+        self.hash = fnv(self.hash, a);
+        Ok(())
+    }
+
+    fn get_u8(&mut self) -> Result<u8> {
+        let mut a = [0; 1];
+        try!(self.get(&mut a));
+        Ok(a[0])
+    }
+
+    fn get_u64(&mut self) -> Result<u64> {
+        let mut a = [0; 8];
+        try!(self.get(&mut a));
+        Ok(misc::endian::u64_from_bytes_be(a))
+    }
+
+    fn get_boxed(&mut self, len: u64) -> Result<Box<[u8]>> {
+        let mut a = vec![0; len as usize].into_boxed_slice();
+        try!(self.get(&mut a));
+        Ok(a)
+    }
+}
+
+/// Writes every live pair in the database to `w`.
+pub fn dump<W: Write>(db: &DatabaseFile, w: &mut W) -> Result<u64> {
+    let mut hw = HashWriter {
+        w: w,
+        hash: FNV_OFFSET,
+    };
+    try!(hw.put(MAGIC));
+    try!(hw.put(&misc::endian::u32_to_bytes_be(VERSION)));
+
+    let mut count = 0;
+    let mut csr = try!(db.open_cursor());
+    try!(csr.first());
+    while csr.is_valid() {
+        {
+            let k = try!(csr.key()).into_boxed_slice();
+            try!(hw.put(&[TAG_PAIR]));
+            try!(hw.put_u64(k.len() as u64));
+            try!(hw.put(&k));
+
+            let v = try!(csr.value());
+            let (len, mut strm) = try!(v.read());
+            try!(hw.put_u64(len));
+            // stream the value through, since it might be big
+            let mut buf = [0; 4096];
+            let mut sofar = 0;
+            while sofar < len {
+                let want = std::cmp::min(buf.len() as u64, len - sofar) as usize;
+                let got = try!(misc::io::read_fully(&mut strm, &mut buf[0 .. want]));
+                if got != want {
+                    return Err(Error::CorruptFile("value shorter than its length"));
+                }
+                try!(hw.put(&buf[0 .. got]));
+                sofar += got as u64;
+            }
+        }
+
+        count += 1;
+        try!(csr.next());
+    }
+
+    try!(hw.put(&[TAG_END]));
+    try!(hw.put_u64(count));
+    let hash = hw.hash;
+    try!(hw.w.write_all(&misc::endian::u64_to_bytes_be(hash)));
+    try!(hw.w.flush());
+    Ok(count)
+}
+
+struct LoadPairs<'a, R: Read + 'a> {
+    rdr: HashReader<'a, R>,
+    spool: String,
+    spooled: Vec<String>,
+    prev_key: Option<Box<[u8]>>,
+    count: u64,
+    done: bool,
+}
+
+impl<'a, R: Read> LoadPairs<'a, R> {
+    fn read_pair(&mut self) -> Result<Option<PairForStorage>> {
+        match try!(self.rdr.get_u8()) {
+            TAG_PAIR => {
+                let klen = try!(self.rdr.get_u64());
+                let k = try!(self.rdr.get_boxed(klen));
+                if let Some(ref prev) = self.prev_key {
+                    if k <= *prev {
+                        return Err(Error::CorruptFile("dump keys out of order"));
+                    }
+                }
+                self.prev_key = Some(k.clone());
+
+                let vlen = try!(self.rdr.get_u64());
+                let v =
+                    if vlen <= MAX_IN_MEMORY_VALUE {
+                        ValueForStorage::Boxed(try!(self.rdr.get_boxed(vlen)))
+                    } else {
+                        let name = format!("{}_{}", self.spool, self.spooled.len());
+                        {
+                            let mut fs = try!(std::fs::File::create(&name));
+                            let mut buf = [0; 4096];
+                            let mut sofar = 0;
+                            while sofar < vlen {
+                                let want = std::cmp::min(buf.len() as u64, vlen - sofar) as usize;
+                                try!(self.rdr.get(&mut buf[0 .. want]));
+                                try!(fs.write_all(&buf[0 .. want]));
+                                sofar += want as u64;
+                            }
+                        }
+                        let fs = try!(std::fs::File::open(&name));
+                        self.spooled.push(name);
+                        ValueForStorage::Read(box fs, vlen)
+                    };
+
+                self.count += 1;
+                let p = PairForStorage {
+                    key: KeyForStorage::Boxed(k),
+                    value: v,
+                };
+                Ok(Some(p))
+            },
+            TAG_END => {
+                let count = try!(self.rdr.get_u64());
+                if count != self.count {
+                    return Err(Error::CorruptFile("dump count mismatch"));
+                }
+                let expected = self.rdr.hash;
+                let mut a = [0; 8];
+                let got = try!(misc::io::read_fully(self.rdr.r, &mut a));
+                if got != a.len() {
+                    return Err(Error::CorruptFile("dump is truncated"));
+                }
+                if misc::endian::u64_from_bytes_be(a) != expected {
+                    return Err(Error::CorruptFile("dump checksum mismatch"));
+                }
+                Ok(None)
+            },
+            _ => {
+                Err(Error::CorruptFile("invalid tag in dump"))
+            },
+        }
+    }
+}
+
+impl<'a, R: Read> Iterator for LoadPairs<'a, R> {
+    type Item = Result<PairForStorage>;
+
+    fn next(&mut self) -> Option<Result<PairForStorage>> {
+        if self.done {
+            return None;
+        }
+        match self.read_pair() {
+            Ok(Some(p)) => {
+                Some(Ok(p))
+            },
+            Ok(None) => {
+                self.done = true;
+                None
+            },
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            },
+        }
+    }
+}
+
+/// Creates a new database at `path` from a stream written by `dump`.
+/// The whole dump gets written as one segment.
+pub fn load<R: Read>(r: &mut R, path: String, settings: DbSettings) -> Result<std::sync::Arc<DatabaseFile>> {
+    if let Ok(m) = std::fs::metadata(&path) {
+        if m.len() > 0 {
+            return Err(Error::Misc(format!("load needs a new file: {}", path)));
+        }
+    }
+
+    let mut rdr = HashReader {
+        r: r,
+        hash: FNV_OFFSET,
+    };
+
+    let mut magic = [0; 8];
+    try!(rdr.get(&mut magic));
+    if &magic[..] != MAGIC {
+        return Err(Error::CorruptFile("not a dump"));
+    }
+    let mut version = [0; 4];
+    try!(rdr.get(&mut version));
+    let version = misc::endian::u32_from_bytes_be(version);
+    if version != VERSION {
+        return Err(Error::Misc(format!("unsupported dump version: {}", version)));
+    }
+
+    let db = try!(DatabaseFile::new(path.clone(), settings));
+
+    let mut pairs = LoadPairs {
+        rdr: rdr,
+        spool: format!("{}.load", path),
+        spooled: vec![],
+        prev_key: None,
+        count: 0,
+        done: false,
+    };
+
+    let seg = db.write_segment_from_sorted_sequence(&mut pairs);
+
+    for name in pairs.spooled.iter() {
+        let _ = std::fs::remove_file(name);
+    }
+
+    // the iterator only checks the checksum once it reaches the end.
+    // if there was an error, it was the last item.
+    let seg = try!(seg);
+    if !pairs.done {
+        return Err(Error::CorruptFile("dump was not consumed"));
+    }
+
+    if let Some(seg) = seg {
+        let lck = try!(db.get_write_lock());
+        try!(lck.commit_segment(seg));
+    }
+
+    Ok(db)
+}
+
@@ -22,6 +22,10 @@
 
 extern crate misc;
 extern crate time;
+extern crate rand;
+extern crate chacha20poly1305;
+#[cfg(unix)]
+extern crate libc;
 
 use misc::varint;
 use misc::Lend;
@@ -42,6 +46,10 @@ use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::collections::HashSet;
 
+mod dump;
+pub use dump::dump;
+pub use dump::load;
+
 const MIN_OVERFLOW_HEADER_LEN: usize = 32;
 
 // TODO does this need to be a constant?  maybe we should just allow it
@@ -127,6 +135,22 @@ pub enum Error {
     InvalidPageType(u8),
     RootPageNotInSegmentBlockList,
     Poisoned,
+
+    // the file is encrypted, but no key was given
+    MissingKey,
+    // a key was given, but the file is not encrypted
+    UnexpectedKey,
+    // the key does not match the key check value in the header
+    WrongKey,
+    // a page failed authentication.  either the file was
+    // damaged or somebody tampered with it.
+    DecryptFailed(PageNum),
+
+    // the file was written by a newer version of this library
+    UnsupportedFormatVersion(u8),
+    // the file uses an older format and was opened without
+    // permission to upgrade it, so it cannot be written
+    ReadOnly,
 }
 
 impl std::fmt::Display for Error {
@@ -141,6 +165,12 @@ impl std::fmt::Display for Error {
             Error::InvalidPageNumber => write!(f, "Invalid page number"),
             Error::InvalidPageType(b) => write!(f, "Invalid page type: {}", b),
             Error::RootPageNotInSegmentBlockList => write!(f, "Root page not in segment block list"),
+            Error::MissingKey => write!(f, "File is encrypted but no key was given"),
+            Error::UnexpectedKey => write!(f, "A key was given but the file is not encrypted"),
+            Error::WrongKey => write!(f, "Wrong encryption key"),
+            Error::DecryptFailed(pg) => write!(f, "Page failed to decrypt: {}", pg),
+            Error::UnsupportedFormatVersion(v) => write!(f, "Unsupported file format version: {} (newest supported is {})", v, FORMAT_VERSION),
+            Error::ReadOnly => write!(f, "File uses an older format and was opened read-only"),
         }
     }
 }
@@ -157,6 +187,12 @@ impl std::error::Error for Error {
             Error::InvalidPageNumber => "invalid page number",
             Error::InvalidPageType(b) => "invalid page type",
             Error::RootPageNotInSegmentBlockList => "Root page not in segment block list",
+            Error::MissingKey => "file is encrypted but no key was given",
+            Error::UnexpectedKey => "a key was given but the file is not encrypted",
+            Error::WrongKey => "wrong encryption key",
+            Error::DecryptFailed(_) => "page failed to decrypt",
+            Error::UnsupportedFormatVersion(_) => "unsupported file format version",
+            Error::ReadOnly => "file uses an older format and was opened read-only",
         }
     }
 
@@ -228,6 +264,12 @@ pub struct PairForStorage {
     value: ValueForStorage,
 }
 
+impl PairForStorage {
+    pub fn new(k: Box<[u8]>, v: ValueForStorage) -> Self {
+        PairForStorage {key: KeyForStorage::Boxed(k), value: v}
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct BlockList {
     // TODO we could keep track of how this is sorted if
@@ -886,6 +928,27 @@ impl<'a> ValueRef<'a> {
         }
     }
 
+    /// Like LiveValueRef::map, for a cursor which can also land on a
+    /// tombstone, which has no bytes to give the func.
+    pub fn map<T, F: Fn(&[u8]) -> Result<T>>(self, func: F) -> Result<T> {
+        match self {
+            ValueRef::Slice(a) => {
+                let t = try!(func(a));
+                Ok(t)
+            },
+            ValueRef::Overflowed(ref f, page) => {
+                let mut a = vec![];
+                let mut strm = try!(OverflowReader::new(f.clone(), page));
+                try!(strm.read_to_end(&mut a));
+                let t = try!(func(&a));
+                Ok(t)
+            },
+            ValueRef::Tombstone => {
+                Err(Error::Misc(String::from("map: tombstone has no value")))
+            },
+        }
+    }
+
 }
 
 impl<'a> std::fmt::Debug for ValueRef<'a> {
@@ -1268,6 +1331,25 @@ pub struct DbSettings {
     pub desperate_waiting: usize,
     pub desperate_level_factor: u64,
     pub num_leaves_promote: usize,
+    // upper bound on the memory the page cache will use to keep
+    // recently used pages around.  this is not a cap on all the pages
+    // in memory: a page held by an open cursor stays alive until the
+    // cursor lets go of it, whether the cache has evicted it or not.
+    // PageCacheStats counts those separately, as pinned.  0 disables
+    // caching.
+    pub page_cache_max_bytes: usize,
+    // read pages by mapping the file into memory instead of copying
+    // them into buffers.  writes still go through PageWriter.  this is
+    // ignored on platforms where we don't have mmap.
+    pub mmap_reads: bool,
+    // when this is set, every page in the file (including the header)
+    // is encrypted and authenticated with this key.  it must be the
+    // same key every time the file is opened.
+    pub encryption_key: Option<[u8; 32]>,
+    // when a file with an older format version is opened, its header
+    // gets rewritten in the current format.  if this is false, the
+    // file is opened read-only instead, and left untouched.
+    pub upgrade_format: bool,
     // TODO min consecutive recycle
     // TODO recent_free
     // TODO level factor
@@ -1285,12 +1367,16 @@ pub const DEFAULT_SETTINGS: DbSettings =
         desperate_waiting: 128,
         desperate_level_factor: 2,
         num_leaves_promote: 16,
+        page_cache_max_bytes: 8 * 1024 * 1024,
+        mmap_reads: false,
+        encryption_key: None,
+        upgrade_format: true,
     };
 
 #[derive(Clone)]
 pub struct SegmentHeaderInfo {
     pub root_page: PageNum,
-    buf: std::sync::Arc<Box<[u8]>>,
+    buf: std::sync::Arc<PageBuffer>,
 }
 
 impl std::fmt::Debug for SegmentHeaderInfo {
@@ -1300,7 +1386,7 @@ impl std::fmt::Debug for SegmentHeaderInfo {
 }
 
 impl SegmentHeaderInfo {
-    pub fn new(root_page: PageNum, buf: std::sync::Arc<Box<[u8]>>) -> Self {
+    pub fn new(root_page: PageNum, buf: std::sync::Arc<PageBuffer>) -> Self {
         SegmentHeaderInfo {
             root_page: root_page,
             buf: buf,
@@ -1410,6 +1496,109 @@ pub mod utils {
 
 }
 
+mod crypt {
+    use super::PageNum;
+    use super::Error;
+    use super::Result;
+    use super::rand;
+    use super::rand::Rng;
+    use chacha20poly1305::XChaCha20Poly1305;
+    use chacha20poly1305::XNonce;
+    use chacha20poly1305::Key;
+    use chacha20poly1305::Tag;
+    use chacha20poly1305::aead::AeadInPlace;
+    use chacha20poly1305::aead::KeyInit;
+
+    pub const SCHEME_NONE: u8 = 0;
+    pub const SCHEME_XCHACHA20_POLY1305: u8 = 1;
+
+    // 192 bits is long enough that random nonces won't collide,
+    // no matter how many pages get written with one key.
+    const NONCE_LEN: usize = 24;
+    const TAG_LEN: usize = 16;
+    pub const KEY_CHECK_LEN: usize = TAG_LEN;
+
+    // every sealed thing (a page, the header, the header overflow)
+    // carries its own nonce and tag at the end.
+    pub const OVERHEAD: usize = NONCE_LEN + TAG_LEN;
+
+    fn encode_id(id: u64) -> [u8; 8] {
+        let mut a = [0; 8];
+        for i in 0 .. 8 {
+            a[i] = (id >> (56 - 8 * i)) as u8;
+        }
+        a
+    }
+
+    #[derive(Copy, Clone)]
+    pub struct PageCrypt {
+        key: [u8; 32],
+    }
+
+    impl PageCrypt {
+        pub fn new(key: [u8; 32]) -> PageCrypt {
+            PageCrypt {
+                key: key,
+            }
+        }
+
+        fn cipher(&self) -> XChaCha20Poly1305 {
+            XChaCha20Poly1305::new(Key::from_slice(&self.key))
+        }
+
+        // stored in the header so that a wrong key can be reported
+        // as such, instead of as a corrupt file.  the all-zero nonce
+        // is never used for anything else.
+        pub fn key_check(&self) -> [u8; KEY_CHECK_LEN] {
+            let mut tag = [0; KEY_CHECK_LEN];
+            let t = self.cipher()
+                .encrypt_in_place_detached(XNonce::from_slice(&[0; NONCE_LEN]), b"lsm key check", &mut [])
+                .expect("sealing nothing cannot fail");
+            tag.clone_from_slice(&t);
+            tag
+        }
+
+        // the id (usually the page number) is authenticated along with
+        // the contents, so a page cannot be swapped with another one.
+        pub fn seal(&self, id: u64, plain: &[u8], out: &mut [u8]) {
+            assert!(out.len() == plain.len() + OVERHEAD);
+            let mut nonce = [0; NONCE_LEN];
+            rand::thread_rng().fill(&mut nonce);
+            // zero is reserved for key_check
+            if nonce == [0; NONCE_LEN] {
+                nonce[NONCE_LEN - 1] = 1;
+            }
+            let (body, trailer) = out.split_at_mut(plain.len());
+            let (out_nonce, out_tag) = trailer.split_at_mut(NONCE_LEN);
+            out_nonce.clone_from_slice(&nonce);
+            body.clone_from_slice(plain);
+            let tag = self.cipher()
+                .encrypt_in_place_detached(XNonce::from_slice(&nonce), &encode_id(id), body)
+                .expect("page is too big to seal");
+            out_tag.clone_from_slice(&tag);
+        }
+
+        pub fn open(&self, id: PageNum, sealed: &[u8], out: &mut [u8]) -> Result<()> {
+            if sealed.len() != out.len() + OVERHEAD {
+                return Err(Error::DecryptFailed(id));
+            }
+            let (body, trailer) = sealed.split_at(out.len());
+            let (nonce, tag) = trailer.split_at(NONCE_LEN);
+            out.clone_from_slice(body);
+            match self.cipher().decrypt_in_place_detached(XNonce::from_slice(nonce), &encode_id(id as u64), out, Tag::from_slice(tag)) {
+                Ok(()) => Ok(()),
+                Err(_) => {
+                    // don't leave the unauthenticated plaintext around
+                    for b in out.iter_mut() {
+                        *b = 0;
+                    }
+                    Err(Error::DecryptFailed(id))
+                },
+            }
+        }
+    }
+}
+
 mod bcmp {
     use std::cmp::Ordering;
     use std::cmp::min;
@@ -2542,6 +2731,27 @@ impl RangeCursor {
         }
     }
 
+    // for going through the range backwards, the mirror image of first()
+    pub fn last(&mut self) -> Result<()> {
+        let sr = try!(self.chain.seek(&KeyRef::for_slice(&self.max.k), SeekOp::LessOrEqual));
+        match (sr, self.max.cmp) {
+            (SeekResult::Equal, OpLt::LT) => {
+                try!(self.chain.prev());
+            },
+            _ => {
+            },
+        }
+        Ok(())
+    }
+
+    pub fn prev(&mut self) -> Result<()> {
+        if self.is_valid() {
+            self.chain.prev()
+        } else {
+            Err(Error::CursorNotValid)
+        }
+    }
+
 }
 
 impl IForwardCursor for RangeCursor {
@@ -3596,7 +3806,7 @@ fn write_leaves<I: Iterator<Item=Result<PairForStorage>>, >(
                         },
                     };
 
-                let buf = std::sync::Arc::new(buf);
+                let buf = std::sync::Arc::new(PageBuffer::Owned(buf));
                 try!(f.put(seg.root_page, &buf));
                 let seg = SegmentHeaderInfo::new(seg.root_page, buf);
                 Some(seg)
@@ -4002,7 +4212,7 @@ fn write_merge_from_incoming(
                         },
                     };
 
-                let buf = std::sync::Arc::new(buf);
+                let buf = std::sync::Arc::new(PageBuffer::Owned(buf));
                 try!(f.put(seg.root_page, &buf));
                 let seg = SegmentHeaderInfo::new(seg.root_page, buf);
                 Some(seg)
@@ -4142,7 +4352,7 @@ fn write_merge(
                         },
                     };
 
-                let buf = std::sync::Arc::new(buf);
+                let buf = std::sync::Arc::new(PageBuffer::Owned(buf));
                 try!(f.put(seg.root_page, &buf));
                 let seg = SegmentHeaderInfo::new(seg.root_page, buf);
                 Some(seg)
@@ -4308,7 +4518,7 @@ fn write_survivors(
             Some(seg) => {
                 match seg.buf {
                     Some(buf) => {
-                        let buf = std::sync::Arc::new(buf);
+                        let buf = std::sync::Arc::new(PageBuffer::Owned(buf));
                         try!(f.put(seg.root_page, &buf));
                         Some(SegmentHeaderInfo::new(seg.root_page, buf))
                     },
@@ -4850,8 +5060,7 @@ impl OverflowReader {
     pub fn new(fs: std::sync::Arc<PageCache>, first_page: PageNum) -> Result<OverflowReader> {
         //println!("reading overflow: {}", first_page);
         let mut hdr = [0; MIN_OVERFLOW_HEADER_LEN];
-        let pos = try!(utils::page_offset(fs.page_size(), first_page));
-        let got = try!(fs.seek_and_read_fully(&mut hdr, pos));
+        let got = try!(fs.read_page_part(first_page, 0, &mut hdr));
         assert!(got == MIN_OVERFLOW_HEADER_LEN);
         let (len, blocks, actual_header_len) =
             match hdr[0] {
@@ -4859,7 +5068,7 @@ impl OverflowReader {
                     let actual_header_len = MIN_OVERFLOW_HEADER_LEN;
                     let mut cur = 1;
                     let len = varint::read(&hdr, &mut cur);
-                    let pages = calc_overflow_pages(len, actual_header_len as u64, fs.page_size() as u64);
+                    let pages = calc_overflow_pages(len, actual_header_len as u64, fs.usable_page_size() as u64);
                     let blk = PageBlock::new(first_page, first_page + pages - 1);
                     let mut blocks = BlockList::new();
                     blocks.add_block_no_reorder(blk);
@@ -4869,16 +5078,16 @@ impl OverflowReader {
                     let actual_header_len = MIN_OVERFLOW_HEADER_LEN;
                     let mut cur = 1;
                     let len = varint::read(&hdr, &mut cur);
-                    let pages = calc_overflow_pages(len, actual_header_len as u64, fs.page_size() as u64);
+                    let pages = calc_overflow_pages(len, actual_header_len as u64, fs.usable_page_size() as u64);
                     let blocks = BlockList::read(&hdr, &mut cur);
                     assert!(blocks.first_page() == first_page);
                     (len, blocks, actual_header_len)
                 },
                 _ => {
-                    panic!(); // TODO
+                    return Err(Error::CorruptFile("unknown overflow format"));
                 },
             };
-        let bytes_in_this_block = (blocks.blocks[0].count_pages() as u64) * (fs.page_size() as u64) - (actual_header_len as u64);
+        let bytes_in_this_block = (blocks.blocks[0].count_pages() as u64) * (fs.usable_page_size() as u64) - (actual_header_len as u64);
         let mut res = 
             OverflowReader {
                 fs: fs,
@@ -4902,7 +5111,7 @@ impl OverflowReader {
         self.current_block = i;
         self.sofar_this_block = 0;
         // TODO adjust for sofar_overfall on the last block?
-        self.bytes_in_this_block = (self.blocks.blocks[i].count_pages() as u64) * (self.fs.page_size() as u64);
+        self.bytes_in_this_block = (self.blocks.blocks[i].count_pages() as u64) * (self.fs.usable_page_size() as u64);
         Ok(())
     }
 
@@ -4924,8 +5133,19 @@ impl OverflowReader {
             } else {
                 0
             };
-            let pos = try!(utils::page_offset(self.fs.page_size(), first_page)) + self.sofar_this_block + offset_this_block;
-            let got = try!(self.fs.seek_and_read_fully(&mut ba[offset .. offset + num], pos));
+            let pgsz = self.fs.usable_page_size() as u64;
+            let pos = self.sofar_this_block + offset_this_block;
+            let page = first_page + pos / pgsz;
+            let offset_this_page = (pos % pgsz) as usize;
+            let num =
+                if self.fs.is_encrypted() {
+                    // each page is sealed separately, so we can only
+                    // read up to the end of this one.
+                    std::cmp::min(num, (pgsz as usize) - offset_this_page)
+                } else {
+                    num
+                };
+            let got = try!(self.fs.read_page_part(page, offset_this_page, &mut ba[offset .. offset + num]));
             self.sofar_overall += got as u64;
             self.sofar_this_block += got as u64;
             Ok(got)
@@ -4954,7 +5174,7 @@ impl Read for OverflowReader {
 
 pub struct LeafPage {
     f: std::sync::Arc<PageCache>,
-    pr: std::sync::Arc<Box<[u8]>>,
+    pr: std::sync::Arc<PageBuffer>,
     pagenum: PageNum,
 
     prefix: Option<Box<[u8]>>,
@@ -4990,7 +5210,7 @@ impl LeafPage {
           ) -> LeafPage {
 
         // TODO this is dumb
-        let buf = std::sync::Arc::new(vec![0; 1].into_boxed_slice());
+        let buf = std::sync::Arc::new(PageBuffer::Owned(vec![0; 1].into_boxed_slice()));
         let res = LeafPage {
             f: f,
             pagenum: 0,
@@ -5835,7 +6055,7 @@ impl Iterator for NodeIterator {
 
 pub struct ParentPage {
     f: std::sync::Arc<PageCache>,
-    pr: std::sync::Arc<Box<[u8]>>,
+    pr: std::sync::Arc<PageBuffer>,
     pagenum: PageNum,
 
     prefix: Option<Box<[u8]>>,
@@ -5873,7 +6093,7 @@ impl ParentPage {
           ) -> ParentPage {
 
         // TODO this is dumb
-        let buf = std::sync::Arc::new(vec![0; 1].into_boxed_slice());
+        let buf = std::sync::Arc::new(PageBuffer::Owned(vec![0; 1].into_boxed_slice()));
 
         let res = ParentPage {
             f: f,
@@ -6591,13 +6811,61 @@ struct HeaderData {
 struct HeaderStuff {
     data: HeaderData,
     f: File,
+    crypt: Option<crypt::PageCrypt>,
 }
 
 // TODO how big should the header be?  this defines the minimum size of a
 // database file.
 const HEADER_SIZE_IN_BYTES: usize = 4096;
 
-fn read_header(path: &str) -> Result<(HeaderData, PageCache, PageNum)> {
+// the first byte of the file is the format version, and it is never
+// encrypted.
+//
+// 0: the original format.  the body of the header follows the version
+//    and may use the whole header.  no encryption.
+//
+// 1: the end of the header is reserved for encryption info, which has
+//    to be readable without the key.  the very last byte is the
+//    encryption scheme.  before that is the key check value.  the body
+//    of the header follows the version, and when the file is encrypted,
+//    it is sealed (which adds the nonce and tag after it).  in a file
+//    which is not encrypted, the trailer is all zeroes.
+//
+// the format of the pages themselves has not changed between these
+// versions, so upgrading a file only requires rewriting its header.
+pub const FORMAT_VERSION: u8 = 1;
+
+const HEADER_TRAILER_LEN: usize = crypt::OVERHEAD + crypt::KEY_CHECK_LEN + 1;
+const HEADER_BODY_LEN: usize = HEADER_SIZE_IN_BYTES - 1 - HEADER_TRAILER_LEN;
+
+fn check_header_key(pr: &[u8], settings: &DbSettings) -> Result<Option<crypt::PageCrypt>> {
+    let scheme = pr[HEADER_SIZE_IN_BYTES - 1];
+    match (scheme, settings.encryption_key) {
+        (crypt::SCHEME_NONE, None) => {
+            Ok(None)
+        },
+        (crypt::SCHEME_NONE, Some(_)) => {
+            Err(Error::UnexpectedKey)
+        },
+        (crypt::SCHEME_XCHACHA20_POLY1305, None) => {
+            Err(Error::MissingKey)
+        },
+        (crypt::SCHEME_XCHACHA20_POLY1305, Some(key)) => {
+            let c = crypt::PageCrypt::new(key);
+            let pos = HEADER_SIZE_IN_BYTES - 1 - crypt::KEY_CHECK_LEN;
+            if &pr[pos .. pos + crypt::KEY_CHECK_LEN] != &c.key_check()[..] {
+                Err(Error::WrongKey)
+            } else {
+                Ok(Some(c))
+            }
+        },
+        _ => {
+            Err(Error::CorruptFile("unknown encryption scheme"))
+        },
+    }
+}
+
+fn read_header(path: &str, settings: &DbSettings) -> Result<(HeaderData, PageCache, PageNum, u8)> {
     fn read<R>(fs: &mut R) -> Result<Box<[u8]>> where R : Read {
         let mut pr = vec![0; HEADER_SIZE_IN_BYTES].into_boxed_slice();
         let got = try!(misc::io::read_fully(fs, &mut pr));
@@ -6608,7 +6876,8 @@ fn read_header(path: &str) -> Result<(HeaderData, PageCache, PageNum)> {
         }
     }
 
-    fn parse(pr: &Box<[u8]>, f: File) -> Result<(HeaderData, PageCache)> {
+    // pr is the body of the header, after the version byte, decrypted
+    fn parse(pr: &[u8], f: File, settings: &DbSettings) -> Result<(HeaderData, PageCache)> {
         fn read_segment_list(pr: &[u8], cur: &mut usize) -> Result<Vec<PageNum>> {
             let count = varint::read(&pr, cur) as usize;
             let mut a = Vec::with_capacity(count);
@@ -6648,14 +6917,11 @@ fn read_header(path: &str) -> Result<(HeaderData, PageCache, PageNum)> {
 
         let mut cur = 0;
 
-        let file_format = pr[0];
-        cur += 1;
-
         let pgsz = varint::read(&pr, &mut cur) as usize;
         let change_counter = varint::read(&pr, &mut cur);
         let merge_counter = varint::read(&pr, &mut cur);
 
-        let f = PageCache::new(f, pgsz);
+        let f = PageCache::new(f, pgsz, settings);
 
         let has_header_overflow = pr[cur] != 0;
         cur += 1;
@@ -6670,10 +6936,22 @@ fn read_header(path: &str) -> Result<(HeaderData, PageCache, PageNum)> {
                 let block = PageBlock::new(first_page, first_page + offset_to_last_page);
                 let pos_back = try!(utils::page_offset(pgsz, first_page));
                 let mut seglist = vec![0; total_len].into_boxed_slice();
-                seglist[0 .. len_front].clone_from_slice(&pr[cur ..]);
-                let got = try!(f.seek_and_read_fully(&mut seglist[len_front ..], pos_back));
-                if got != len_back {
-                    return Err(Error::Misc(format!("failed reading header overflow")));
+                seglist[0 .. len_front].clone_from_slice(&pr[cur .. cur + len_front]);
+                match f.crypt {
+                    Some(ref c) => {
+                        let mut sealed = vec![0; len_back + crypt::OVERHEAD].into_boxed_slice();
+                        let got = try!(f.seek_and_read_fully(&mut sealed, pos_back));
+                        if got != sealed.len() {
+                            return Err(Error::Misc(format!("failed reading header overflow")));
+                        }
+                        try!(c.open(first_page, &sealed, &mut seglist[len_front ..]));
+                    },
+                    None => {
+                        let got = try!(f.seek_and_read_fully(&mut seglist[len_front ..], pos_back));
+                        if got != len_back {
+                            return Err(Error::Misc(format!("failed reading header overflow")));
+                        }
+                    },
                 }
                 let mut cur = 0;
                 let incoming = try!(read_segment_list(&seglist, &mut cur));
@@ -6719,9 +6997,39 @@ fn read_header(path: &str) -> Result<(HeaderData, PageCache, PageNum)> {
     let len = try!(f.metadata()).len();
     if len > 0 {
         let pr = try!(read(&mut f));
-        let (h, f) = try!(parse(&pr, f));
+        let format_version = pr[0];
+        let body =
+            match format_version {
+                0 => {
+                    if settings.encryption_key.is_some() {
+                        return Err(Error::UnexpectedKey);
+                    }
+                    let mut body = vec![0; HEADER_SIZE_IN_BYTES - 1].into_boxed_slice();
+                    body.clone_from_slice(&pr[1 ..]);
+                    body
+                },
+                1 => {
+                    match try!(check_header_key(&pr, settings)) {
+                        Some(c) => {
+                            let mut body = vec![0; HEADER_BODY_LEN].into_boxed_slice();
+                            // the header is sealed with id 0, since there is no page 0
+                            try!(c.open(0, &pr[1 .. 1 + HEADER_BODY_LEN + crypt::OVERHEAD], &mut body));
+                            body
+                        },
+                        None => {
+                            let mut body = vec![0; HEADER_BODY_LEN].into_boxed_slice();
+                            body.clone_from_slice(&pr[1 .. 1 + HEADER_BODY_LEN]);
+                            body
+                        },
+                    }
+                },
+                _ => {
+                    return Err(Error::UnsupportedFormatVersion(format_version));
+                },
+            };
+        let (h, f) = try!(parse(&body, f, settings));
         let next_available_page = calc_next_page(f.page_size(), len as usize);
-        Ok((h, f, next_available_page))
+        Ok((h, f, next_available_page, format_version))
     } else {
         // TODO shouldn't this use settings passed in?
         let default_page_size = DEFAULT_SETTINGS.default_page_size;
@@ -6736,8 +7044,8 @@ fn read_header(path: &str) -> Result<(HeaderData, PageCache, PageNum)> {
             }
         };
         let next_available_page = calc_next_page(default_page_size, HEADER_SIZE_IN_BYTES);
-        let f = PageCache::new(f, default_page_size);
-        Ok((h, f, next_available_page))
+        let f = PageCache::new(f, default_page_size, settings);
+        Ok((h, f, next_available_page, FORMAT_VERSION))
     }
 
 }
@@ -6798,6 +7106,10 @@ struct Space {
     zombies: HashMap<PageNum, BlockList>,
 
     dependencies: HashMap<PageNum, HashSet<PageNum>>,
+
+    // freed pages get dropped from the cache, since their page
+    // numbers are about to be reused.
+    page_cache: std::sync::Arc<PageCache>,
 }
 
 impl Space {
@@ -6929,6 +7241,10 @@ impl Space {
     }
 
     fn add_free_blocks(&mut self, blocks: BlockList) {
+        // the only way this fails is a poisoned lock, in which case
+        // the cache is no good to anybody anyway.
+        let _ = self.page_cache.forget_blocks(&blocks);
+
         // organizing the free block list can be expensive
         // if the list is large, so we only do it every so
         // often.
@@ -6983,6 +7299,9 @@ impl Space {
                     .write(true)
                     .open(&self.path)
                     );
+            // drop what the cache has past the new end first, so
+            // nothing can read it through the mapping after set_len
+            try!(self.page_cache.truncated(self.next_page));
             try!(fs.set_len(((self.next_page - 1) as u64) * page_size));
         }
 
@@ -7217,25 +7536,183 @@ struct Senders {
     notify_regular: Vec<mpsc::Sender<MergeMessage>>,
 }
 
+#[cfg(unix)]
+mod mmap {
+    use std::io;
+    use std::fs::File;
+    use std::os::unix::io::AsRawFd;
+    use super::libc;
+
+    // a read-only mapping of (a prefix of) the database file.  the
+    // file grows as segments are written, so a mapping can become too
+    // short.  when that happens, the PageCache creates a new, longer
+    // one.  old mappings stay alive as long as any page borrowed from
+    // them is still in use.
+    pub struct MappedFile {
+        ptr: *const u8,
+        len: usize,
+    }
+
+    // the mapping is read-only, but the file under it does get
+    // written, since page numbers are reused once they are freed.  a
+    // page only gets freed when there is no rlock left on its segment,
+    // so no cursor is reading it, and at that point the PageCache
+    // forgets it too (Space::add_free_blocks), as it does right before
+    // PageWriter writes any page.  so by the time part of the mapping
+    // changes underneath it, there is no PageBuffer left which could
+    // read that part, and sharing the mapping across threads is fine.
+    // the same goes for pages past the end when the file gets
+    // truncated, which would otherwise be a SIGBUS.
+    unsafe impl Send for MappedFile {}
+    unsafe impl Sync for MappedFile {}
+
+    impl MappedFile {
+        pub fn new(f: &File, len: usize) -> io::Result<MappedFile> {
+            assert!(len > 0);
+            let p = unsafe {
+                libc::mmap(
+                    std::ptr::null_mut(), 
+                    len as libc::size_t, 
+                    libc::PROT_READ, 
+                    libc::MAP_SHARED, 
+                    f.as_raw_fd(), 
+                    0
+                    )
+            };
+            if p == libc::MAP_FAILED {
+                Err(io::Error::last_os_error())
+            } else {
+                let m = MappedFile {
+                    ptr: p as *const u8,
+                    len: len,
+                };
+                Ok(m)
+            }
+        }
+
+        pub fn len(&self) -> usize {
+            self.len
+        }
+
+        pub fn slice(&self, offset: usize, len: usize) -> &[u8] {
+            assert!(offset + len <= self.len);
+            unsafe {
+                std::slice::from_raw_parts(self.ptr.offset(offset as isize), len)
+            }
+        }
+    }
+
+    impl Drop for MappedFile {
+        fn drop(&mut self) {
+            unsafe {
+                libc::munmap(self.ptr as *mut libc::c_void, self.len as libc::size_t);
+            }
+        }
+    }
+}
+
+// the contents of one page, either read into a buffer of our own,
+// or borrowed directly from a mapping of the file.
+pub enum PageBuffer {
+    Owned(Box<[u8]>),
+    #[cfg(unix)]
+    Mapped(std::sync::Arc<mmap::MappedFile>, usize, usize),
+}
+
+impl std::ops::Deref for PageBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            &PageBuffer::Owned(ref a) => a,
+            #[cfg(unix)]
+            &PageBuffer::Mapped(ref m, offset, len) => m.slice(offset, len),
+        }
+    }
+}
+
+impl std::fmt::Debug for PageBuffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let a: &[u8] = self;
+        write!(f, "{:?}", a)
+    }
+}
+
 struct InnerPageCache {
     f: File,
-    pages: HashMap<PageNum, std::sync::Weak<Box<[u8]>>>,
+    pages: HashMap<PageNum, std::sync::Weak<PageBuffer>>,
+
+    // the weak references above will find a page as long as somebody
+    // (usually a cursor) is holding it.  in addition, the cache itself
+    // keeps strong references to the most recently used pages, up to
+    // max_pages.  the tick is used to find the least recently used one.
+    recent: HashMap<PageNum, (u64, std::sync::Arc<PageBuffer>)>,
+    lru: BTreeMap<u64, PageNum>,
+    tick: u64,
+    max_pages: usize,
+
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+
+    use_mmap: bool,
+    #[cfg(unix)]
+    map: Option<std::sync::Arc<mmap::MappedFile>>,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct PageCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    // number of pages the cache itself is keeping alive.  this
+    // never goes over max_pages.
+    pub pages: usize,
+    pub max_pages: usize,
+    // pages which the cache has let go of, but which are still alive
+    // because somebody (usually a cursor) is holding them.
+    pub pinned: usize,
 }
 
 pub struct PageCache {
+    // this is the size of a page in the file.  when the file is
+    // encrypted, a little bit at the end of each page is used for
+    // the nonce and tag, so less than this is available for the
+    // contents.  see usable_page_size().
     pgsz: usize,
+    crypt: Option<crypt::PageCrypt>,
     stuff: Mutex<InnerPageCache>,
     // TODO pool of empty pages to be reused?
 }
 
+impl std::fmt::Debug for PageCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "PageCache {{ pgsz: {} }}", self.pgsz)
+    }
+}
+
 impl PageCache {
-    fn new(f: File, pgsz: usize) -> Self {
+    fn new(f: File, pgsz: usize, settings: &DbSettings) -> Self {
+        let max_bytes = settings.page_cache_max_bytes;
         let stuff = InnerPageCache {
             f: f,
             pages: HashMap::new(),
+            recent: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            max_pages: max_bytes / pgsz,
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+            // pages in an encrypted file have to be decrypted into
+            // a buffer anyway, so there is no point in mapping them.
+            use_mmap: settings.mmap_reads && settings.encryption_key.is_none(),
+            #[cfg(unix)]
+            map: None,
         };
         PageCache {
             pgsz: pgsz,
+            crypt: settings.encryption_key.map(|k| crypt::PageCrypt::new(k)),
             stuff: Mutex::new(stuff),
         }
     }
@@ -7244,6 +7721,68 @@ impl PageCache {
         self.pgsz
     }
 
+    fn usable_page_size(&self) -> usize {
+        match self.crypt {
+            Some(_) => self.pgsz - crypt::OVERHEAD,
+            None => self.pgsz,
+        }
+    }
+
+    fn is_encrypted(&self) -> bool {
+        self.crypt.is_some()
+    }
+
+    // read part of the contents of a page, without going through
+    // the cache.  in a file which is not encrypted, the read may
+    // continue into the pages that follow.
+    fn read_page_part(&self, pgnum: PageNum, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        match self.crypt {
+            Some(_) => {
+                assert!(offset + buf.len() <= self.usable_page_size());
+                let mut stuff = try!(self.stuff.lock());
+                let page = try!(Self::inner_read_page(&mut stuff, &self.crypt, self.pgsz, pgnum));
+                buf.clone_from_slice(&page[offset .. offset + buf.len()]);
+                Ok(buf.len())
+            },
+            None => {
+                let pos = try!(utils::page_offset(self.pgsz, pgnum)) + (offset as u64);
+                self.seek_and_read_fully(buf, pos)
+            },
+        }
+    }
+
+    fn inner_read_page(stuff: &mut InnerPageCache, crypt: &Option<crypt::PageCrypt>, pgsz: usize, pgnum: PageNum) -> Result<Box<[u8]>> {
+        let mut buf = vec![0; pgsz].into_boxed_slice();
+        try!(Self::inner_read(stuff, pgnum, &mut buf));
+        match crypt {
+            &Some(ref c) => {
+                let mut plain = vec![0; pgsz - crypt::OVERHEAD].into_boxed_slice();
+                try!(c.open(pgnum, &buf, &mut plain));
+                Ok(plain)
+            },
+            &None => {
+                Ok(buf)
+            },
+        }
+    }
+
+    pub fn stats(&self) -> Result<PageCacheStats> {
+        let stuff = try!(self.stuff.lock());
+        let pinned =
+            stuff.pages.iter()
+            .filter(|&(pg, weak)| !stuff.recent.contains_key(pg) && weak.upgrade().is_some())
+            .count();
+        let st = PageCacheStats {
+            hits: stuff.hits,
+            misses: stuff.misses,
+            evictions: stuff.evictions,
+            pages: stuff.recent.len(),
+            max_pages: stuff.max_pages,
+            pinned: pinned,
+        };
+        Ok(st)
+    }
+
     fn seek_and_read_fully(&self, buf: &mut [u8], pos: u64) -> Result<usize> {
         let mut stuff = try!(self.stuff.lock());
         let v = try!(stuff.f.seek(SeekFrom::Start(pos)));
@@ -7257,49 +7796,217 @@ impl PageCache {
         Ok(())
     }
 
-    fn inner_put(stuff: &mut InnerPageCache, pgnum: PageNum, strong: &std::sync::Arc<Box<[u8]>>) {
+    #[cfg(unix)]
+    fn inner_map(stuff: &mut InnerPageCache, page: PageNum, pgsz: usize) -> Result<Option<PageBuffer>> {
+        let pos = try!(utils::page_offset(pgsz, page)) as usize;
+        let end = pos + pgsz;
+        let cur_len =
+            match stuff.map {
+                Some(ref m) => m.len(),
+                None => 0,
+            };
+        if end > cur_len {
+            // the file has grown since we mapped it.  map all of it.
+            let file_len = try!(stuff.f.metadata()).len() as usize;
+            if end > file_len {
+                return Ok(None);
+            }
+            let m = try!(mmap::MappedFile::new(&stuff.f, file_len));
+            stuff.map = Some(std::sync::Arc::new(m));
+        }
+        match stuff.map {
+            Some(ref m) => Ok(Some(PageBuffer::Mapped(m.clone(), pos, pgsz))),
+            None => unreachable!(),
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn inner_map(stuff: &mut InnerPageCache, page: PageNum, pgsz: usize) -> Result<Option<PageBuffer>> {
+        Ok(None)
+    }
+
+    fn inner_touch(stuff: &mut InnerPageCache, pgnum: PageNum, strong: &std::sync::Arc<PageBuffer>) {
+        if stuff.max_pages == 0 {
+            return;
+        }
+
+        stuff.tick += 1;
+        let tick = stuff.tick;
+        if let Some((old_tick, _)) = stuff.recent.insert(pgnum, (tick, strong.clone())) {
+            stuff.lru.remove(&old_tick);
+        }
+        stuff.lru.insert(tick, pgnum);
+
+        while stuff.recent.len() > stuff.max_pages {
+            let oldest = 
+                match stuff.lru.iter().next() {
+                    Some((&t, &pg)) => (t, pg),
+                    None => unreachable!(),
+                };
+            let (t, pg) = oldest;
+            stuff.lru.remove(&t);
+            stuff.recent.remove(&pg);
+            stuff.evictions += 1;
+            // if nobody else is holding this page, the weak ref is
+            // now dead, so get rid of it.
+            let dead =
+                match stuff.pages.get(&pg) {
+                    Some(weak) => weak.upgrade().is_none(),
+                    None => false,
+                };
+            if dead {
+                stuff.pages.remove(&pg);
+            }
+        }
+    }
+
+    fn inner_put(stuff: &mut InnerPageCache, pgnum: PageNum, strong: &std::sync::Arc<PageBuffer>) {
         let weak = std::sync::Arc::downgrade(strong);
         match stuff.pages.entry(pgnum) {
             std::collections::hash_map::Entry::Vacant(e) => {
                 e.insert(weak);
             },
             std::collections::hash_map::Entry::Occupied(mut e) => {
-                // TODO the following assert failed a couple times.  why?
-                // not reproduceable
+                // the cache forgets a page when it gets freed or
+                // written, and nobody can be holding a page whose
+                // segment is gone, so whatever is here is dead.
                 assert!(e.get().upgrade().is_none());
                 e.insert(weak);
             },
         }
+        Self::inner_touch(stuff, pgnum, strong);
+    }
+
+    // a page which has been freed, or is about to be written, no longer
+    // has the contents the cache remembers, so they have to go.  pages
+    // only get freed once there is no rlock on their segment, so no
+    // cursor is holding them either.
+    fn inner_forget(stuff: &mut InnerPageCache, pgnum: PageNum) {
+        if let Some((tick, _)) = stuff.recent.remove(&pgnum) {
+            stuff.lru.remove(&tick);
+        }
+        stuff.pages.remove(&pgnum);
+    }
+
+    fn forget(&self, pgnum: PageNum) -> Result<()> {
+        let mut stuff = try!(self.stuff.lock());
+        Self::inner_forget(&mut stuff, pgnum);
+        Ok(())
+    }
+
+    // the file is about to get shorter, so pages from first_gone on
+    // won't be there anymore.  reading them through a mapping would be a SIGBUS, so
+    // they go, and so does any mapping longer than the file.  a
+    // mapping which is still in use by some page before first_gone
+    // stays alive with it, but nothing reads past the end through it.
+    fn truncated(&self, first_gone: PageNum) -> Result<()> {
+        let mut stuff = try!(self.stuff.lock());
+        let victims =
+            stuff.pages.keys()
+            .chain(stuff.recent.keys())
+            .filter(|&&pg| pg >= first_gone)
+            .map(|&pg| pg)
+            .collect::<Vec<_>>();
+        for pg in victims {
+            Self::inner_forget(&mut stuff, pg);
+        }
+        Self::inner_unmap_past(&mut stuff, try!(utils::page_offset(self.pgsz, first_gone)) as usize);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn inner_unmap_past(stuff: &mut InnerPageCache, len: usize) {
+        let too_long =
+            match stuff.map {
+                Some(ref m) => m.len() > len,
+                None => false,
+            };
+        if too_long {
+            stuff.map = None;
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn inner_unmap_past(_stuff: &mut InnerPageCache, _len: usize) {
+    }
+
+    fn forget_blocks(&self, blocks: &BlockList) -> Result<()> {
+        let mut stuff = try!(self.stuff.lock());
+        // a freed block can be much bigger than the cache, so go
+        // through whichever of the two is smaller.
+        let count = blocks.count_pages() as usize;
+        if count > stuff.pages.len() {
+            let victims =
+                stuff.pages.keys()
+                .filter(|&&pg| blocks.contains_page(pg))
+                .map(|&pg| pg)
+                .collect::<Vec<_>>();
+            for pg in victims {
+                Self::inner_forget(&mut stuff, pg);
+            }
+        } else {
+            for blk in blocks.blocks.iter() {
+                for pg in blk.first_page .. blk.last_page + 1 {
+                    Self::inner_forget(&mut stuff, pg);
+                }
+            }
+        }
+        Ok(())
     }
 
-    fn put(&self, pgnum: PageNum, strong: &std::sync::Arc<Box<[u8]>>) -> Result<()> {
+    fn put(&self, pgnum: PageNum, strong: &std::sync::Arc<PageBuffer>) -> Result<()> {
         let mut stuff = try!(self.stuff.lock());
         Self::inner_put(&mut stuff, pgnum, strong);
         Ok(())
     }
 
-    fn get(&self, pgnum: PageNum) -> Result<std::sync::Arc<Box<[u8]>>> {
+    fn get(&self, pgnum: PageNum) -> Result<std::sync::Arc<PageBuffer>> {
         let mut stuff = try!(self.stuff.lock());
-        match stuff.pages.entry(pgnum) {
-            std::collections::hash_map::Entry::Vacant(e) => {
+        let found =
+            match stuff.pages.entry(pgnum) {
+                std::collections::hash_map::Entry::Vacant(e) => {
+                    None
+                },
+                std::collections::hash_map::Entry::Occupied(e) => {
+                    match e.get().upgrade() {
+                        Some(strong) => {
+                            Some(strong)
+                        },
+                        None => {
+                            e.remove();
+                            None
+                        },
+                    }
+                },
+            };
+
+        match found {
+            Some(strong) => {
+                stuff.hits += 1;
+                Self::inner_touch(&mut stuff, pgnum, &strong);
+                Ok(strong)
             },
-            std::collections::hash_map::Entry::Occupied(e) => {
-                match e.get().upgrade() {
-                    Some(strong) => {
-                        return Ok(strong)
-                    },
-                    None => {
-                        e.remove();
-                    },
-                }
+            None => {
+                stuff.misses += 1;
+                let mapped =
+                    if stuff.use_mmap {
+                        try!(Self::inner_map(&mut stuff, pgnum, self.pgsz))
+                    } else {
+                        None
+                    };
+                let buf =
+                    match mapped {
+                        Some(buf) => buf,
+                        None => {
+                            let buf = try!(Self::inner_read_page(&mut stuff, &self.crypt, self.pgsz, pgnum));
+                            PageBuffer::Owned(buf)
+                        },
+                    };
+                let strong = std::sync::Arc::new(buf);
+                Self::inner_put(&mut stuff, pgnum, &strong);
+                Ok(strong)
             },
         }
-
-        let mut buf = vec![0; self.pgsz].into_boxed_slice();
-        try!(Self::inner_read(&mut stuff, pgnum, &mut buf));
-        let strong = std::sync::Arc::new(buf);
-        Self::inner_put(&mut stuff, pgnum, &strong);
-        Ok(strong)
     }
 }
 
@@ -7308,6 +8015,10 @@ struct InnerPart {
     path: String,
     settings: DbSettings,
 
+    // the format version of the file.  if it is older than
+    // FORMAT_VERSION, then the file is read-only.
+    format_version: u8,
+
     // TODO are we concerned here about readers starving the
     // writers?  In other words, so many cursors that a merge
     // cannot get committed?
@@ -7390,7 +8101,13 @@ pub struct WriteLock {
 
 impl WriteLock {
     pub fn commit_segment(&self, seg: SegmentHeaderInfo) -> Result<()> {
-        try!(self.inner.commit_segment(seg));
+        try!(self.inner.commit_segments(vec![seg]));
+        Ok(())
+    }
+
+    // all of these become visible with one header write, or none do
+    pub fn commit_segments(&self, segs: Vec<SegmentHeaderInfo>) -> Result<()> {
+        try!(self.inner.commit_segments(segs));
         Ok(())
     }
 
@@ -7420,8 +8137,10 @@ pub struct DatabaseFile {
 impl DatabaseFile {
     pub fn new(path: String, settings: DbSettings) -> Result<std::sync::Arc<DatabaseFile>> {
 
-        // TODO we should pass in settings to read_header, right?
-        let (header, f, first_available_page) = try!(read_header(&path));
+        let (header, f, first_available_page, format_version) = try!(read_header(&path, &settings));
+
+        let needs_upgrade = format_version < FORMAT_VERSION && settings.upgrade_format;
+        let format_version = if needs_upgrade { FORMAT_VERSION } else { format_version };
 
         // when we first open the file, we find all the blocks that are in use by
         // an active segment.  all OTHER blocks are considered free.
@@ -7470,6 +8189,7 @@ impl DatabaseFile {
             rlocks: HashMap::new(),
             zombies: HashMap::new(),
             dependencies: HashMap::new(),
+            page_cache: f.clone(),
         };
 
         // each merge level is handled by its own thread.  a Rust channel is used to
@@ -7507,12 +8227,14 @@ impl DatabaseFile {
         let header = HeaderStuff {
             data: header,
             f: file_header_write,
+            crypt: f.crypt,
         };
 
         let inner = InnerPart {
             path: path,
             page_cache: f,
             settings: settings, 
+            format_version: format_version,
             header: RwLock::new(header),
             space: Mutex::new(space),
             mergelock_incoming: Mutex::new(0),
@@ -7521,6 +8243,15 @@ impl DatabaseFile {
             senders: Mutex::new(senders),
         };
 
+        if needs_upgrade {
+            // the page format has not changed, so the header is all
+            // that needs to be rewritten.
+            let mut headerstuff = try!(inner.header.write());
+            let hdr = headerstuff.data.clone();
+            let mut space = try!(inner.space.lock());
+            try!(headerstuff.write_header(&mut space, hdr, inner.page_cache.page_size()));
+        }
+
         let inner = std::sync::Arc::new(inner);
 
         let lck = 
@@ -7773,6 +8504,8 @@ impl DatabaseFile {
     // TODO func to ask for the write lock without blocking?
 
     pub fn get_write_lock(&self) -> Result<std::sync::MutexGuard<WriteLock>> {
+        try!(self.inner.check_writable());
+
         while NeedsMerge::Desperate == try!(InnerPart::needs_merge(&self.inner, FromLevel::Incoming)) {
             // TODO if we need to sleep more than once, do we really need to notify_work
             // every time?
@@ -7813,7 +8546,7 @@ impl DatabaseFile {
         InnerPart::write_segment(&self.inner, pairs)
     }
 
-    // tests use this
+    // tests use this, and so does the elmo bulk loader
     pub fn write_segment_from_sorted_sequence<I>(&self, source: I) -> Result<Option<SegmentHeaderInfo>>
         where I: Iterator<Item=Result<PairForStorage>>  {
         InnerPart::write_segment_from_sorted_sequence(&self.inner, source)
@@ -7823,17 +8556,31 @@ impl DatabaseFile {
         InnerPart::list_segments(&self.inner)
     }
 
-    pub fn release_pending_segment(&self, location: PageNum) -> Result<()> {
-        InnerPart::release_pending_segment(&self.inner, location)
+    // a segment which was written but is never going to be committed.
+    // nothing else can be looking at it, so its pages are free now.
+    pub fn release_pending_segment(&self, seg: SegmentHeaderInfo) -> Result<()> {
+        InnerPart::release_pending_segment(&self.inner, seg)
     }
 
     pub fn list_free_blocks(&self) -> Result<BlockList> {
         InnerPart::list_free_blocks(&self.inner)
     }
 
-    pub fn get_page(&self, pgnum: PageNum) -> Result<std::sync::Arc<Box<[u8]>>> {
+    pub fn get_page(&self, pgnum: PageNum) -> Result<std::sync::Arc<PageBuffer>> {
         InnerPart::get_page(&self.inner, pgnum)
     }
+
+    pub fn page_cache_stats(&self) -> Result<PageCacheStats> {
+        self.inner.page_cache.stats()
+    }
+
+    pub fn format_version(&self) -> u8 {
+        self.inner.format_version
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.inner.format_version < FORMAT_VERSION
+    }
 }
 
 impl HeaderStuff {
@@ -7872,9 +8619,7 @@ impl HeaderStuff {
             pb
         }
 
-        let mut pb = PageBuilder::new(HEADER_SIZE_IN_BYTES);
-        let file_format = 0;
-        pb.put_u8(file_format);
+        let mut pb = PageBuilder::new(HEADER_BODY_LEN);
         pb.put_varint(pgsz as u64);
         // TODO aren't there some settings that should go in here?
 
@@ -7899,10 +8644,24 @@ impl HeaderStuff {
                     ;
                 let fits = pb.available() - needed_for_overhead;
                 let extra_bytes = (seglist.len() - fits) as u64;
-                let extra_pages = pages_needed_for(extra_bytes, pgsz as u64);
+                let extra_bytes_in_file =
+                    match self.crypt {
+                        Some(_) => extra_bytes + (crypt::OVERHEAD as u64),
+                        None => extra_bytes,
+                    };
+                let extra_pages = pages_needed_for(extra_bytes_in_file, pgsz as u64);
                 let block = space.get_block(BlockRequest::ExactSize(extra_pages));
                 try!(utils::seek_page(&mut self.f, pgsz, block.first_page));
-                try!(self.f.write_all(&seglist[fits ..]));
+                match self.crypt {
+                    Some(ref c) => {
+                        let mut sealed = vec![0; extra_bytes_in_file as usize];
+                        c.seal(block.first_page, &seglist[fits ..], &mut sealed);
+                        try!(self.f.write_all(&sealed));
+                    },
+                    None => {
+                        try!(self.f.write_all(&seglist[fits ..]));
+                    },
+                }
                 pb.put_varint(block.first_page as u64);
                 pb.put_varint((block.last_page - block.first_page) as u64);
                 pb.put_varint(fits as u64);
@@ -7910,8 +8669,21 @@ impl HeaderStuff {
                 Some(block)
             };
 
+        let mut whole = vec![0; HEADER_SIZE_IN_BYTES];
+        whole[0] = FORMAT_VERSION;
+        match self.crypt {
+            Some(ref c) => {
+                c.seal(0, pb.buf(), &mut whole[1 .. 1 + HEADER_BODY_LEN + crypt::OVERHEAD]);
+                let pos = HEADER_SIZE_IN_BYTES - 1 - crypt::KEY_CHECK_LEN;
+                whole[pos .. pos + crypt::KEY_CHECK_LEN].clone_from_slice(&c.key_check());
+                whole[HEADER_SIZE_IN_BYTES - 1] = crypt::SCHEME_XCHACHA20_POLY1305;
+            },
+            None => {
+                whole[1 .. 1 + HEADER_BODY_LEN].clone_from_slice(pb.buf());
+            },
+        }
         try!(self.f.seek(SeekFrom::Start(0)));
-        try!(self.f.write_all(pb.buf()));
+        try!(self.f.write_all(&whole));
         try!(self.f.flush());
 
         //println!("header, incoming,{}, waiting,{}, regular,{}", hdr.incoming.len(), hdr.waiting.len(), hdr.regular.len());
@@ -8089,7 +8861,7 @@ impl InnerPart {
         Ok(lc)
     }
 
-    fn get_page(inner: &std::sync::Arc<InnerPart>, pgnum: PageNum) -> Result<std::sync::Arc<Box<[u8]>>> {
+    fn get_page(inner: &std::sync::Arc<InnerPart>, pgnum: PageNum) -> Result<std::sync::Arc<PageBuffer>> {
         let buf = try!(inner.page_cache.get(pgnum));
         Ok(buf)
     }
@@ -8101,10 +8873,14 @@ impl InnerPart {
         Ok(blocks)
     }
 
-    fn release_pending_segment(inner: &std::sync::Arc<InnerPart>, location: PageNum) -> Result<()> {
-        //let mut space = try!(inner.space.lock());
-        // TODO have to read the page to get the block list
-        //try!(Self::addFreeBlocks(&mut space, &inner.path, inner.pgsz, location.blocks.blocks));
+    fn release_pending_segment(inner: &std::sync::Arc<InnerPart>, seg: SegmentHeaderInfo) -> Result<()> {
+        let mut blocks = try!(seg.blocklist_unsorted(&inner.page_cache));
+        blocks.add_page_no_reorder(seg.root_page);
+        // the segment holds its root page, which the cache would
+        // otherwise keep alive past the free
+        drop(seg);
+        let mut space = try!(inner.space.lock());
+        space.add_free_blocks(blocks);
         Ok(())
     }
 
@@ -8145,15 +8921,18 @@ impl InnerPart {
         Ok((incoming, waiting, regular))
     }
 
-    fn commit_segment(&self, seg: SegmentHeaderInfo) -> Result<()> {
+    fn commit_segments(&self, segs: Vec<SegmentHeaderInfo>) -> Result<()> {
         {
             let mut headerstuff = try!(self.header.write());
 
-            // TODO assert new seg shares no pages with any seg in current state
+            // TODO assert new segs share no pages with any seg in current state
 
             let mut new_header = headerstuff.data.clone();
 
-            new_header.incoming.insert(0, seg);
+            // the last one in the list ends up newest
+            for seg in segs {
+                new_header.incoming.insert(0, seg);
+            }
 
             new_header.change_counter += 1;
 
@@ -8170,7 +8949,16 @@ impl InnerPart {
         Ok(())
     }
 
+    fn check_writable(&self) -> Result<()> {
+        if self.format_version < FORMAT_VERSION {
+            Err(Error::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
     fn write_segment(inner: &std::sync::Arc<InnerPart>, pairs: BTreeMap<Box<[u8]>, ValueForStorage>) -> Result<Option<SegmentHeaderInfo>> {
+        try!(inner.check_writable());
         //println!("writing segment with {} pairs", pairs.len());
         let source = pairs.into_iter().map(|t| {
             let (k, v) = t;
@@ -8199,6 +8987,7 @@ impl InnerPart {
     // tests use this
     fn write_segment_from_sorted_sequence<I>(inner: &std::sync::Arc<InnerPart>, source: I) -> Result<Option<SegmentHeaderInfo>> 
         where I: Iterator<Item=Result<PairForStorage>>  {
+        try!(inner.check_writable());
         let pw = try!(PageWriter::new(inner.clone()));
         let seg = try!(create_segment(pw, source, inner.page_cache.clone()));
         Ok(seg)
@@ -9751,15 +10540,28 @@ impl PageWriter {
         Ok(pg)
     }
 
+    // the number of bytes of content which fit on a page.  this is
+    // less than the page size in the file when it is encrypted.
     fn page_size(&self) -> usize {
-        self.inner.page_cache.page_size()
+        self.inner.page_cache.usable_page_size()
     }
 
     fn write_page_at(&mut self, buf: &[u8], pg: PageNum) -> Result<()> {
+        let pgsz = self.inner.page_cache.page_size();
+        try!(self.inner.page_cache.forget(pg));
         if pg != self.last_page + 1 {
-            try!(utils::seek_page(&mut self.f, self.inner.page_cache.page_size(), pg));
+            try!(utils::seek_page(&mut self.f, pgsz, pg));
+        }
+        match self.inner.page_cache.crypt {
+            Some(ref c) => {
+                let mut sealed = vec![0; pgsz];
+                c.seal(pg, buf, &mut sealed);
+                try!(self.f.write_all(&sealed));
+            },
+            None => {
+                try!(self.f.write_all(buf));
+            },
         }
-        try!(self.f.write_all(buf));
         self.last_page = pg;
         Ok(())
     }
@@ -90,6 +90,27 @@ fn empty_cursor() {
     assert!(f().is_ok());
 }
 
+// a segment which never gets committed gives its pages back, so writing
+// it again doesn't make the file any bigger.
+#[test]
+fn release_pending_segment() {
+    fn f() -> lsm::Result<()> {
+        let path = tempfile("release_pending_segment");
+        let db = try!(lsm::DatabaseFile::new(path.clone(), lsm::DEFAULT_SETTINGS));
+        let g = try!(db.write_segment_from_sorted_sequence(lsm::GenerateNumbers {cur: 0, end: 10000, step: 1})).unwrap();
+        let len = try!(std::fs::metadata(&path)).len();
+        try!(db.release_pending_segment(g));
+        let g = try!(db.write_segment_from_sorted_sequence(lsm::GenerateNumbers {cur: 0, end: 10000, step: 1})).unwrap();
+        assert!(try!(std::fs::metadata(&path)).len() <= len);
+        let lck = try!(db.get_write_lock());
+        try!(lck.commit_segment(g));
+        let mut csr = try!(db.open_cursor());
+        assert_eq!(try!(count_keys_forward(&mut csr)), 10000);
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
 #[test]
 fn first_prev() {
     fn f() -> lsm::Result<()> {
@@ -1044,3 +1065,387 @@ fn threads_with_weird_pairs() {
 
 }
 
+
+#[test]
+fn page_cache_bounded() {
+    fn f() -> lsm::Result<()> {
+        let settings = lsm::DbSettings {
+                page_cache_max_bytes : 4 * 4096,
+                .. lsm::DEFAULT_SETTINGS
+            };
+        let db = try!(lsm::DatabaseFile::new(tempfile("page_cache_bounded"), settings));
+        let g = try!(db.write_segment_from_sorted_sequence(lsm::GenerateNumbers {cur: 0, end: 10000, step: 1}));
+        if let Some(g) = g {
+            let lck = try!(db.get_write_lock());
+            try!(lck.commit_segment(g));
+        }
+        {
+            let mut csr = try!(db.open_cursor());
+            assert_eq!(10001, try!(count_keys_forward(&mut csr)));
+            assert_eq!(10001, try!(count_keys_backward(&mut csr)));
+        }
+        let st = try!(db.page_cache_stats());
+        assert_eq!(4, st.max_pages);
+        assert!(st.pages <= st.max_pages);
+        assert!(st.misses > 0);
+        assert!(st.evictions > 0);
+
+        // reading the same page twice in a row should be a hit
+        let before = try!(db.page_cache_stats());
+        {
+            let mut csr = try!(db.open_cursor());
+            try!(csr.first());
+            try!(csr.first());
+        }
+        let after = try!(db.page_cache_stats());
+        assert!(after.hits > before.hits);
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn page_cache_concurrent_eviction() {
+    fn f() -> lsm::Result<()> {
+        use std::sync::Arc;
+        use std::thread;
+
+        // a cache which holds only two pages, with lots of cursors
+        // open at once, so pages are being evicted while cursors
+        // are still using them.
+        let settings = lsm::DbSettings {
+                default_page_size : 256,
+                pages_per_block : 4,
+                page_cache_max_bytes : 2 * 256,
+                .. lsm::DEFAULT_SETTINGS
+            };
+        let db = try!(lsm::DatabaseFile::new(tempfile("page_cache_concurrent_eviction"), settings));
+        let g = try!(db.write_segment_from_sorted_sequence(lsm::GenerateNumbers {cur: 0, end: 5000, step: 1}));
+        if let Some(g) = g {
+            let lck = try!(db.get_write_lock());
+            try!(lck.commit_segment(g));
+        }
+        let g = try!(db.write_segment_from_sorted_sequence(lsm::GenerateNumbers {cur: 1, end: 5000, step: 2}));
+        if let Some(g) = g {
+            let lck = try!(db.get_write_lock());
+            try!(lck.commit_segment(g));
+        }
+
+        let data = Arc::new(db);
+
+        // this cursor stays open for the whole test
+        let mut held = try!(data.open_cursor());
+        try!(held.first());
+        assert!(held.is_valid());
+
+        let mut handles = Vec::new();
+        for i in 0 .. 8 {
+            let data = data.clone();
+            let h = thread::spawn(move || -> lsm::Result<()> {
+                for _ in 0 .. 4 {
+                    let mut csr = try!(data.open_cursor());
+                    if i % 2 == 0 {
+                        assert_eq!(5001, try!(count_keys_forward(&mut csr)));
+                    } else {
+                        assert_eq!(5001, try!(count_keys_backward(&mut csr)));
+                    }
+                }
+                Ok(())
+            });
+            handles.push(h);
+        }
+
+        for h in handles {
+            let r = h.join();
+            assert!(r.is_ok());
+            assert!(r.unwrap().is_ok());
+        }
+
+        // the long-lived cursor must still be able to walk everything
+        let mut count = 0;
+        while held.is_valid() {
+            count += 1;
+            try!(held.next());
+        }
+        assert_eq!(5001, count);
+
+        let st = try!(data.page_cache_stats());
+        assert!(st.pages <= st.max_pages);
+        assert!(st.evictions > 0);
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn mmap_reads() {
+    fn f() -> lsm::Result<()> {
+        let settings = lsm::DbSettings {
+                mmap_reads : true,
+                .. lsm::DEFAULT_SETTINGS
+            };
+        let name = tempfile("mmap_reads");
+        let db = try!(lsm::DatabaseFile::new(name.clone(), settings));
+        let g = try!(db.write_segment_from_sorted_sequence(lsm::GenerateNumbers {cur: 0, end: 10000, step: 1}));
+        if let Some(g) = g {
+            let lck = try!(db.get_write_lock());
+            try!(lck.commit_segment(g));
+        }
+        {
+            let mut csr = try!(db.open_cursor());
+            assert_eq!(10001, try!(count_keys_forward(&mut csr)));
+        }
+
+        // the file grows after the first mapping was made
+        let g = try!(db.write_segment_from_sorted_sequence(lsm::GenerateNumbers {cur: 20000, end: 30000, step: 1}));
+        if let Some(g) = g {
+            let lck = try!(db.get_write_lock());
+            try!(lck.commit_segment(g));
+        }
+        let mut csr = try!(db.open_cursor());
+        assert_eq!(20002, try!(count_keys_forward(&mut csr)));
+        try!(csr.seek(&lsm::KeyRef::Slice(&into_utf8(format!("{:08}", 25000))), lsm::SeekOp::Equal));
+        assert!(csr.is_valid());
+        let v = try!(read_value(try!(csr.value())));
+        assert_eq!("50000", from_utf8(v));
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn encrypted() {
+    fn f() -> lsm::Result<()> {
+        let key = [7u8; 32];
+        let settings = lsm::DbSettings {
+                encryption_key : Some(key),
+                .. lsm::DEFAULT_SETTINGS
+            };
+        let name = tempfile("encrypted");
+        {
+            let db = try!(lsm::DatabaseFile::new(name.clone(), settings));
+            let mut d = std::collections::BTreeMap::new();
+            for i in 1 .. 1000 {
+                let k = format!("{:04}", i);
+                let mut v = String::new();
+                // some of these will need overflow pages
+                for j in 0 .. (i % 50) * 20 {
+                    v.push_str(&format!("{}", j));
+                }
+                insert_pair_string_string(&mut d, &k, &v);
+            }
+            let g = try!(db.write_segment(d));
+            if let Some(g) = g {
+                let lck = try!(db.get_write_lock());
+                try!(lck.commit_segment(g));
+            }
+        }
+
+        // the plaintext should not appear anywhere in the file
+        {
+            use std::io::Read;
+            let mut a = vec![];
+            let mut fs = try!(std::fs::File::open(&name));
+            try!(fs.read_to_end(&mut a));
+            assert!(!a.windows(8).any(|w| w == b"12345678"));
+        }
+
+        let db = try!(lsm::DatabaseFile::new(name.clone(), settings));
+        let mut csr = try!(db.open_cursor());
+        assert_eq!(999, try!(count_keys_forward(&mut csr)));
+        try!(csr.seek(&lsm::KeyRef::Slice(&str_to_utf8("0049")), lsm::SeekOp::Equal));
+        assert!(csr.is_valid());
+        let v = try!(read_value(try!(csr.value())));
+        let mut expected = String::new();
+        for j in 0 .. 49 * 20 {
+            expected.push_str(&format!("{}", j));
+        }
+        assert_eq!(expected, from_utf8(v));
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn encrypted_wrong_key() {
+    fn f() -> lsm::Result<()> {
+        let settings = lsm::DbSettings {
+                encryption_key : Some([1u8; 32]),
+                .. lsm::DEFAULT_SETTINGS
+            };
+        let name = tempfile("encrypted_wrong_key");
+        {
+            let db = try!(lsm::DatabaseFile::new(name.clone(), settings));
+            let g = try!(db.write_segment_from_sorted_sequence(lsm::GenerateNumbers {cur: 0, end: 100, step: 1}));
+            if let Some(g) = g {
+                let lck = try!(db.get_write_lock());
+                try!(lck.commit_segment(g));
+            }
+        }
+
+        let wrong = lsm::DbSettings {
+                encryption_key : Some([2u8; 32]),
+                .. lsm::DEFAULT_SETTINGS
+            };
+        match lsm::DatabaseFile::new(name.clone(), wrong) {
+            Err(lsm::Error::WrongKey) => {},
+            _ => panic!(),
+        }
+
+        match lsm::DatabaseFile::new(name.clone(), lsm::DEFAULT_SETTINGS) {
+            Err(lsm::Error::MissingKey) => {},
+            _ => panic!(),
+        }
+
+        let plain = tempfile("encrypted_not_encrypted");
+        {
+            let db = try!(lsm::DatabaseFile::new(plain.clone(), lsm::DEFAULT_SETTINGS));
+            let g = try!(db.write_segment_from_sorted_sequence(lsm::GenerateNumbers {cur: 0, end: 100, step: 1}));
+            if let Some(g) = g {
+                let lck = try!(db.get_write_lock());
+                try!(lck.commit_segment(g));
+            }
+        }
+        match lsm::DatabaseFile::new(plain.clone(), settings) {
+            Err(lsm::Error::UnexpectedKey) => {},
+            _ => panic!(),
+        }
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn dump_and_load() {
+    fn f() -> lsm::Result<()> {
+        let db = try!(lsm::DatabaseFile::new(tempfile("dump_and_load"), lsm::DEFAULT_SETTINGS));
+        let mut d = std::collections::BTreeMap::new();
+        for i in 1 .. 500 {
+            let s = format!("{}", i);
+            insert_pair_string_string(&mut d, &s, &s);
+        }
+        let mut big = vec![];
+        for i in 0 .. 2 * 1024 * 1024 {
+            big.push((i % 251) as u8);
+        }
+        insert_pair_string_blob(&mut d, "big", lsm::ValueForStorage::Boxed(big.clone().into_boxed_slice()));
+        let g = try!(db.write_segment(d));
+        if let Some(g) = g {
+            let lck = try!(db.get_write_lock());
+            try!(lck.commit_segment(g));
+        }
+        let mut d = std::collections::BTreeMap::new();
+        insert_pair_string_blob(&mut d, "73", lsm::ValueForStorage::Tombstone);
+        let g = try!(db.write_segment(d));
+        if let Some(g) = g {
+            let lck = try!(db.get_write_lock());
+            try!(lck.commit_segment(g));
+        }
+
+        let mut a = vec![];
+        let count = try!(lsm::dump(&db, &mut a));
+        assert_eq!(499, count);
+
+        let db2 = try!(lsm::load(&mut std::io::Cursor::new(a.clone()), tempfile("dump_and_load_2"), lsm::DEFAULT_SETTINGS));
+        let mut csr = try!(db2.open_cursor());
+        assert_eq!(499, try!(count_keys_forward(&mut csr)));
+        try!(csr.seek(&lsm::KeyRef::Slice(&str_to_utf8("73")), lsm::SeekOp::Equal));
+        assert!(!csr.is_valid());
+        try!(csr.seek(&lsm::KeyRef::Slice(&str_to_utf8("big")), lsm::SeekOp::Equal));
+        assert!(csr.is_valid());
+        let v = try!(read_value(try!(csr.value())));
+        assert_eq!(big.into_boxed_slice(), v);
+
+        // a damaged dump must be rejected
+        let n = a.len();
+        a[n / 2] ^= 0xff;
+        match lsm::load(&mut std::io::Cursor::new(a), tempfile("dump_and_load_3"), lsm::DEFAULT_SETTINGS) {
+            Err(lsm::Error::CorruptFile(_)) => {},
+            _ => panic!(),
+        }
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+fn set_first_byte(name: &str, b: u8) -> lsm::Result<()> {
+    use std::io::Seek;
+    use std::io::Write;
+    let mut fs = try!(std::fs::OpenOptions::new().read(true).write(true).open(name));
+    try!(fs.seek(std::io::SeekFrom::Start(0)));
+    try!(fs.write_all(&[b]));
+    Ok(())
+}
+
+fn get_first_byte(name: &str) -> lsm::Result<u8> {
+    let mut fs = try!(std::fs::File::open(name));
+    let mut a = [0; 1];
+    try!(fs.read_exact(&mut a));
+    Ok(a[0])
+}
+
+#[test]
+fn read_header_format_version() {
+    fn f() -> lsm::Result<()> {
+        let name = tempfile("read_header_format_version");
+        {
+            let db = try!(lsm::DatabaseFile::new(name.clone(), lsm::DEFAULT_SETTINGS));
+            assert_eq!(lsm::FORMAT_VERSION, db.format_version());
+            let g = try!(db.write_segment_from_sorted_sequence(lsm::GenerateNumbers {cur: 0, end: 100, step: 1}));
+            if let Some(g) = g {
+                let lck = try!(db.get_write_lock());
+                try!(lck.commit_segment(g));
+            }
+        }
+        assert_eq!(lsm::FORMAT_VERSION, try!(get_first_byte(&name)));
+
+        // a version from the future
+        try!(set_first_byte(&name, lsm::FORMAT_VERSION + 1));
+        match lsm::DatabaseFile::new(name.clone(), lsm::DEFAULT_SETTINGS) {
+            Err(lsm::Error::UnsupportedFormatVersion(v)) => assert_eq!(lsm::FORMAT_VERSION + 1, v),
+            _ => panic!(),
+        }
+
+        // format 0 differs from format 1 only in the header, and a file
+        // which is not encrypted has the same layout in both, except
+        // for the version.
+        try!(set_first_byte(&name, 0));
+        {
+            let settings = lsm::DbSettings {
+                    upgrade_format : false,
+                    .. lsm::DEFAULT_SETTINGS
+                };
+            let db = try!(lsm::DatabaseFile::new(name.clone(), settings));
+            assert_eq!(0, db.format_version());
+            assert!(db.is_read_only());
+            let mut csr = try!(db.open_cursor());
+            assert_eq!(101, try!(count_keys_forward(&mut csr)));
+            match db.get_write_lock() {
+                Err(lsm::Error::ReadOnly) => {},
+                _ => panic!(),
+            }
+            match db.write_segment_from_sorted_sequence(lsm::GenerateNumbers {cur: 200, end: 300, step: 1}) {
+                Err(lsm::Error::ReadOnly) => {},
+                _ => panic!(),
+            }
+        }
+        assert_eq!(0, try!(get_first_byte(&name)));
+
+        // now upgrade it in place
+        {
+            let db = try!(lsm::DatabaseFile::new(name.clone(), lsm::DEFAULT_SETTINGS));
+            assert_eq!(lsm::FORMAT_VERSION, db.format_version());
+            assert!(!db.is_read_only());
+            let g = try!(db.write_segment_from_sorted_sequence(lsm::GenerateNumbers {cur: 200, end: 300, step: 1}));
+            if let Some(g) = g {
+                let lck = try!(db.get_write_lock());
+                try!(lck.commit_segment(g));
+            }
+            let mut csr = try!(db.open_cursor());
+            assert_eq!(202, try!(count_keys_forward(&mut csr)));
+        }
+        assert_eq!(lsm::FORMAT_VERSION, try!(get_first_byte(&name)));
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
@@ -22,6 +22,8 @@ extern crate rand;
 
 use std::collections::HashMap;
 
+pub mod sort;
+
 pub fn fix_ms(n: i64) -> (i64, i64) {
     if n < 0 {
         let sec = -((-n) / 1000);
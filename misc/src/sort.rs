@@ -0,0 +1,336 @@
+/*
+    Copyright 2014-2016 Zumero, LLC
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+
+// an external sort.  items pile up in memory until there are too many
+// bytes of them, then they get sorted and spilled to a file.  when
+// everything is in, the spilled runs and whatever is still in memory get
+// merged back together, in whatever order the comparer says.  the lsm
+// bulk loader sorts key/value pairs with this, and elmo sorts the rows of
+// a find with an orderby that no index can give.
+
+use std;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::cmp::Ordering;
+
+use super::varint;
+use super::tid;
+
+// what the sorter needs from the things it sorts
+pub trait Spill: Sized {
+    type Error: From<io::Error>;
+
+    // about how many bytes this takes up while it waits in memory.  it
+    // gets called for every item, so it should be cheap.
+    fn size(&self) -> usize;
+
+    fn write_to<W: Write>(self, w: &mut W) -> Result<(), Self::Error>;
+
+    // None means we hit the end of the run cleanly
+    fn read_from<R: Read>(r: &mut R) -> Result<Option<Self>, Self::Error>;
+}
+
+pub fn read_exact<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<()> {
+    let mut sofar = 0;
+    while sofar < buf.len() {
+        let got = try!(r.read(&mut buf[sofar ..]));
+        if got == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "sort run ended early"));
+        }
+        sofar = sofar + got;
+    }
+    Ok(())
+}
+
+// a length as a varint, then the bytes
+pub fn write_blob<W: Write>(w: &mut W, ba: &[u8]) -> io::Result<()> {
+    let mut buf = [0; 9];
+    let mut cur = 0;
+    varint::write(&mut buf, &mut cur, ba.len() as u64);
+    try!(w.write_all(&buf[0 .. cur]));
+    try!(w.write_all(ba));
+    Ok(())
+}
+
+// None means we hit the end of the run cleanly
+pub fn read_blob<R: Read>(r: &mut R) -> io::Result<Option<Box<[u8]>>> {
+    let mut buf = [0; 9];
+    let got = try!(r.read(&mut buf[0 .. 1]));
+    if got == 0 {
+        return Ok(None);
+    }
+    let len = varint::first_byte_to_len(buf[0]);
+    try!(read_exact(r, &mut buf[1 .. len]));
+    let mut cur = 0;
+    let n = varint::read(&buf, &mut cur);
+    let mut v = vec![0; n as usize];
+    try!(read_exact(r, &mut v));
+    Ok(Some(v.into_boxed_slice()))
+}
+
+// a key and a value, the way the lsm bulk loader has them
+pub type Pair = (Box<[u8]>, Box<[u8]>);
+
+impl Spill for Pair {
+    type Error = io::Error;
+
+    fn size(&self) -> usize {
+        self.0.len() + self.1.len()
+    }
+
+    fn write_to<W: Write>(self, w: &mut W) -> io::Result<()> {
+        try!(write_blob(w, &self.0));
+        try!(write_blob(w, &self.1));
+        Ok(())
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<Option<Pair>> {
+        match try!(read_blob(r)) {
+            None => Ok(None),
+            Some(k) => {
+                match try!(read_blob(r)) {
+                    Some(v) => Ok(Some((k, v))),
+                    None => Err(io::Error::new(io::ErrorKind::InvalidInput, "sort run ended early")),
+                }
+            },
+        }
+    }
+}
+
+pub fn compare_keys(a: &Pair, b: &Pair) -> Ordering {
+    a.0.cmp(&b.0)
+}
+
+// pairs in key order.  the comparer is a plain fn, so the type can be
+// named by whoever keeps one of these around.
+pub type PairSorter = Sorter<Pair, fn(&Pair, &Pair) -> Ordering>;
+pub type PairMerged = Merged<Pair, fn(&Pair, &Pair) -> Ordering>;
+
+pub fn pair_sorter(limit: usize) -> PairSorter {
+    Sorter::new(limit, compare_keys as fn(&Pair, &Pair) -> Ordering)
+}
+
+// the most runs merged at once.  each one is an open file with a buffer
+// of its own.
+pub const MAX_FAN_IN: usize = 64;
+
+fn run_path() -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("elmo_sort_{}", tid()))
+}
+
+// the spilled files, which go away with this, whether the sort finished
+// or not
+struct Runs {
+    paths: Vec<std::path::PathBuf>,
+}
+
+impl Drop for Runs {
+    fn drop(&mut self) {
+        for path in self.paths.iter() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+pub struct Sorter<T, F> where T: Spill, F: Fn(&T, &T) -> Ordering {
+    limit: usize,
+    bytes: usize,
+    items: Vec<T>,
+    runs: Runs,
+    cmp: F,
+}
+
+impl<T, F> Sorter<T, F> where T: Spill, F: Fn(&T, &T) -> Ordering {
+    pub fn new(limit: usize, cmp: F) -> Sorter<T, F> {
+        Sorter {
+            limit: limit,
+            bytes: 0,
+            items: vec![],
+            runs: Runs { paths: vec![] },
+            cmp: cmp,
+        }
+    }
+
+    pub fn push(&mut self, item: T) -> Result<(), T::Error> {
+        self.bytes = self.bytes + item.size();
+        self.items.push(item);
+        if self.bytes >= self.limit {
+            try!(self.spill());
+        }
+        Ok(())
+    }
+
+    fn spill(&mut self) -> Result<(), T::Error> {
+        let mut items = std::mem::replace(&mut self.items, vec![]);
+        self.bytes = 0;
+        {
+            let cmp = &self.cmp;
+            items.sort_by(|a, b| cmp(a, b));
+        }
+        let path = run_path();
+        // remember it before writing, so it gets cleaned up even if the
+        // write fails partway
+        self.runs.paths.push(path.clone());
+        let f = try!(std::fs::File::create(&path));
+        let mut w = io::BufWriter::new(f);
+        for item in items {
+            try!(item.write_to(&mut w));
+        }
+        try!(w.flush());
+        Ok(())
+    }
+
+    // how many runs went to disk so far
+    pub fn spilled(&self) -> usize {
+        self.runs.paths.len()
+    }
+
+    pub fn finish(self) -> Result<Merged<T, F>, T::Error> {
+        let Sorter { mut items, mut runs, cmp, .. } = self;
+        items.sort_by(|a, b| cmp(a, b));
+
+        // with too many runs, groups of them get merged into bigger runs
+        // first.  each group is runs which were next to each other, and
+        // the bigger runs stay in the same order, so the merge is still
+        // stable.
+        while runs.paths.len() > MAX_FAN_IN {
+            // the smaller runs go away at the end of each pass, or as
+            // soon as something fails
+            let smaller = Runs { paths: std::mem::replace(&mut runs.paths, vec![]) };
+            for group in smaller.paths.chunks(MAX_FAN_IN) {
+                let path = run_path();
+                runs.paths.push(path.clone());
+                let f = try!(std::fs::File::create(&path));
+                let mut w = io::BufWriter::new(f);
+                let mut heads = try!(Heads::new(try!(open_runs(group)), &cmp));
+                while let Some(r) = heads.next(&cmp) {
+                    let item = try!(r);
+                    try!(item.write_to(&mut w));
+                }
+                try!(w.flush());
+            }
+        }
+
+        let mut sources = try!(open_runs(&runs.paths));
+        sources.push(Source::Memory(items.into_iter()));
+        let heads = try!(Heads::new(sources, &cmp));
+        let m = Merged {
+            heads: heads,
+            runs: runs,
+            cmp: cmp,
+        };
+        Ok(m)
+    }
+}
+
+fn open_runs<T>(paths: &[std::path::PathBuf]) -> Result<Vec<Source<T>>, T::Error> where T: Spill {
+    let mut sources = vec![];
+    for path in paths {
+        let f = try!(std::fs::File::open(path));
+        sources.push(Source::File(io::BufReader::new(f)));
+    }
+    Ok(sources)
+}
+
+enum Source<T> {
+    File(io::BufReader<std::fs::File>),
+    Memory(std::vec::IntoIter<T>),
+}
+
+impl<T> Source<T> where T: Spill {
+    fn next(&mut self) -> Result<Option<T>, T::Error> {
+        match self {
+            &mut Source::Memory(ref mut it) => Ok(it.next()),
+            &mut Source::File(ref mut r) => T::read_from(r),
+        }
+    }
+}
+
+// the merge itself, for the final one and for the ones in between
+struct Heads<T> {
+    sources: Vec<Source<T>>,
+    // the next item of each source which still has one, along with which
+    // source that was, largest first.  a BinaryHeap would need its items
+    // to know the comparer, and with one buffer full per run there are
+    // never so many that keeping a vec in order costs much.
+    heads: Vec<(T, usize)>,
+}
+
+impl<T> Heads<T> where T: Spill {
+    fn new<F>(sources: Vec<Source<T>>, cmp: &F) -> Result<Heads<T>, T::Error> where F: Fn(&T, &T) -> Ordering {
+        let mut h = Heads {
+            sources: sources,
+            heads: vec![],
+        };
+        for i in 0 .. h.sources.len() {
+            try!(h.refill(i, cmp));
+        }
+        Ok(h)
+    }
+
+    fn refill<F>(&mut self, i: usize, cmp: &F) -> Result<(), T::Error> where F: Fn(&T, &T) -> Ordering {
+        if let Some(item) = try!(self.sources[i].next()) {
+            // ties go to the earlier source, which keeps the merge stable
+            let pos = {
+                let found = self.heads.binary_search_by(|h| {
+                    match cmp(&item, &h.0) {
+                        Ordering::Equal => i.cmp(&h.1),
+                        c => c,
+                    }
+                });
+                match found {
+                    Ok(pos) => pos,
+                    Err(pos) => pos,
+                }
+            };
+            self.heads.insert(pos, (item, i));
+        }
+        Ok(())
+    }
+
+    fn next<F>(&mut self, cmp: &F) -> Option<Result<T, T::Error>> where F: Fn(&T, &T) -> Ordering {
+        match self.heads.pop() {
+            None => None,
+            Some((item, i)) => {
+                if let Err(e) = self.refill(i, cmp) {
+                    return Some(Err(e));
+                }
+                Some(Ok(item))
+            },
+        }
+    }
+}
+
+pub struct Merged<T, F> where T: Spill, F: Fn(&T, &T) -> Ordering {
+    heads: Heads<T>,
+    runs: Runs,
+    cmp: F,
+}
+
+impl<T, F> Iterator for Merged<T, F> where T: Spill, F: Fn(&T, &T) -> Ordering {
+    type Item = Result<T, T::Error>;
+    fn next(&mut self) -> Option<Result<T, T::Error>> {
+        self.heads.next(&self.cmp)
+    }
+}
+
+impl<T, F> Drop for Merged<T, F> where T: Spill, F: Fn(&T, &T) -> Ordering {
+    fn drop(&mut self) {
+        // close the files before the runs remove them
+        self.heads.sources.clear();
+    }
+}
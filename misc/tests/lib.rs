@@ -0,0 +1,82 @@
+extern crate misc;
+
+use misc::sort;
+
+fn sort_all(limit: usize, pairs: &[(&str, &str)]) -> std::io::Result<(usize, Vec<(String, String)>)> {
+    let mut sorter = sort::pair_sorter(limit);
+    for &(k, v) in pairs {
+        try!(sorter.push((k.as_bytes().to_vec().into_boxed_slice(), v.as_bytes().to_vec().into_boxed_slice())));
+    }
+    let spilled = sorter.spilled();
+    let mut a = vec![];
+    for r in try!(sorter.finish()) {
+        let (k, v) = try!(r);
+        a.push((String::from_utf8(k.to_vec()).unwrap(), String::from_utf8(v.to_vec()).unwrap()));
+    }
+    Ok((spilled, a))
+}
+
+fn owned(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+    pairs.iter().map(|&(k, v)| (String::from(k), String::from(v))).collect()
+}
+
+// the real limits are far more than any test loads, so these use a tiny
+// one to make the sorter spill.
+#[test]
+fn external_sort() {
+    fn f() -> std::io::Result<()> {
+        // nothing at all
+        assert_eq!(try!(sort_all(1, &[])), (0, vec![]));
+
+        // everything fits
+        let (spilled, a) = try!(sort_all(1000, &[("c", "3"), ("a", "1"), ("b", "2")]));
+        assert_eq!(spilled, 0);
+        assert_eq!(a, owned(&[("a", "1"), ("b", "2"), ("c", "3")]));
+
+        // every pair is a run of its own, and nothing is left in memory
+        // at the end
+        let (spilled, a) = try!(sort_all(1, &[("c", "3"), ("a", "1"), ("b", "2")]));
+        assert_eq!(spilled, 3);
+        assert_eq!(a, owned(&[("a", "1"), ("b", "2"), ("c", "3")]));
+
+        // some runs and some left in memory
+        let pairs = (0 .. 100).map(|i| (format!("{:03}", (i * 37) % 100), format!("{}", i))).collect::<Vec<_>>();
+        let refs = pairs.iter().map(|&(ref k, ref v)| (&k[..], &v[..])).collect::<Vec<_>>();
+        let (spilled, a) = try!(sort_all(40, &refs));
+        assert!(spilled > 1);
+        let keys = a.iter().map(|&(ref k, _)| k.clone()).collect::<Vec<_>>();
+        assert_eq!(keys, (0 .. 100).map(|i| format!("{:03}", i)).collect::<Vec<_>>());
+
+        // duplicates all come out, in the order they went in, whether they
+        // ended up in the same run or not
+        let (spilled, a) = try!(sort_all(6, &[("b", "1"), ("a", "1"), ("b", "2"), ("a", "2"), ("b", "3")]));
+        assert!(spilled > 0);
+        assert_eq!(a, owned(&[("a", "1"), ("a", "2"), ("b", "1"), ("b", "2"), ("b", "3")]));
+
+        // and the order is whatever the comparer says
+        let mut sorter = sort::Sorter::new(4, |a: &sort::Pair, b: &sort::Pair| b.0.cmp(&a.0));
+        for i in 0 .. 20 {
+            let k = format!("{:02}", (i * 7) % 20);
+            try!(sorter.push((k.as_bytes().to_vec().into_boxed_slice(), vec![].into_boxed_slice())));
+        }
+        assert!(sorter.spilled() > 1);
+        let keys = try!(try!(sorter.finish()).collect::<std::io::Result<Vec<_>>>()).into_iter().map(|(k, _)| String::from_utf8(k.to_vec()).unwrap()).collect::<Vec<_>>();
+        assert_eq!(keys, (0 .. 20).rev().map(|i| format!("{:02}", i)).collect::<Vec<_>>());
+
+        // more runs than get merged at once, so some get merged into
+        // bigger runs first.  the duplicates still come out in the order
+        // they went in.
+        let n = sort::MAX_FAN_IN * 3 + 5;
+        let pairs = (0 .. n).map(|i| (format!("{:03}", (i * 7) % 50), format!("{:04}", i))).collect::<Vec<_>>();
+        let refs = pairs.iter().map(|&(ref k, ref v)| (&k[..], &v[..])).collect::<Vec<_>>();
+        let (spilled, a) = try!(sort_all(1, &refs));
+        assert_eq!(spilled, n);
+        let mut expected = pairs.clone();
+        expected.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(a, expected);
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
@@ -34,6 +34,7 @@ extern crate bson;
 extern crate elmo;
 
 extern crate elmo_lsm;
+extern crate elmo_memory;
 
 use std::io::Read;
 
@@ -50,6 +51,42 @@ struct Reply {
     docs : Vec<bson::Document>,
 }
 
+// OP_QUERY flags
+const QUERY_FLAG_TAILABLE: i32 = 2;
+const QUERY_FLAG_AWAIT_DATA: i32 = 32;
+
+#[derive(Copy, Clone)]
+pub struct ServerSettings {
+    // how long a getMore on an awaitData cursor waits for something new
+    // before it gives up and returns nothing.  --await-data-timeout-ms
+    // on the command line.
+    pub await_data_timeout_ms: u64,
+}
+
+pub const DEFAULT_SETTINGS: ServerSettings =
+    ServerSettings {
+        await_data_timeout_ms: 1000,
+    };
+
+impl ServerSettings {
+    fn from_args(args: &[String]) -> Result<ServerSettings> {
+        let mut settings = DEFAULT_SETTINGS;
+        let mut i = 0;
+        while i < args.len() {
+            if args[i] == "--await-data-timeout-ms" {
+                settings.await_data_timeout_ms =
+                    match args.get(i + 1).and_then(|s| s.parse::<u64>().ok()) {
+                        Some(ms) => ms,
+                        None => return Err(Error::Misc(String::from("--await-data-timeout-ms needs a number of milliseconds"))),
+                    };
+                i = i + 1;
+            }
+            i = i + 1;
+        }
+        Ok(settings)
+    }
+}
+
 #[derive(Debug)]
 // TODO consider calling this Msg2004
 struct MsgQuery {
@@ -247,24 +284,129 @@ fn reply_errmsg(req_id: i32, err: Error) -> Reply {
 
 // TODO mongo has a way of automatically killing a cursor after 10 minutes idle
 
+// long running operations, like background index builds, so that
+// currentOp from any connection can see them.
+struct InProgress {
+    next_opid: i32,
+    ops: std::collections::BTreeMap<i32, bson::Document>,
+}
+
+impl InProgress {
+    fn new() -> InProgress {
+        InProgress {
+            next_opid: 1,
+            ops: std::collections::BTreeMap::new(),
+        }
+    }
+}
+
+type SharedInProgress = std::sync::Arc<std::sync::Mutex<InProgress>>;
+
+// a getMore on an awaitData cursor waits here for an insert into its
+// collection, instead of asking its seq over and over.  each namespace
+// counts its inserts, so a waiter can tell whether one happened since it
+// last looked.
+struct Inserts {
+    counts: std::sync::Mutex<std::collections::HashMap<String, u64>>,
+    cond: std::sync::Condvar,
+}
+
+type SharedInserts = std::sync::Arc<Inserts>;
+
+impl Inserts {
+    fn new() -> Inserts {
+        Inserts {
+            counts: std::sync::Mutex::new(std::collections::HashMap::new()),
+            cond: std::sync::Condvar::new(),
+        }
+    }
+
+    fn count(&self, ns: &str) -> u64 {
+        let counts = self.counts.lock().unwrap();
+        counts.get(ns).cloned().unwrap_or(0)
+    }
+
+    fn inserted(&self, ns: &str) {
+        let mut counts = self.counts.lock().unwrap();
+        *counts.entry(String::from(ns)).or_insert(0) += 1;
+        self.cond.notify_all();
+    }
+
+    // returns when the count for ns has moved past seen, or when timeout
+    // is up, whichever is first
+    fn wait(&self, ns: &str, seen: u64, timeout: std::time::Duration) {
+        let start = std::time::Instant::now();
+        let mut counts = self.counts.lock().unwrap();
+        loop {
+            if counts.get(ns).cloned().unwrap_or(0) != seen {
+                return;
+            }
+            let elapsed = start.elapsed();
+            if elapsed >= timeout {
+                return;
+            }
+            counts = self.cond.wait_timeout(counts, timeout - elapsed).unwrap().0;
+        }
+    }
+}
+
+fn begin_op(inprog: &SharedInProgress, ns: &str, msg: &str) -> i32 {
+    let mut inprog = inprog.lock().unwrap();
+    let opid = inprog.next_opid;
+    inprog.next_opid = opid + 1;
+    let mut doc = bson::Document::new();
+    doc.set_i32("opid", opid);
+    doc.set_bool("active", true);
+    doc.set_str("op", "command");
+    doc.set_str("ns", ns);
+    doc.set_str("msg", msg);
+    inprog.ops.insert(opid, doc);
+    opid
+}
+
+fn op_progress(inprog: &SharedInProgress, opid: i32, done: usize, total: usize) {
+    let mut inprog = inprog.lock().unwrap();
+    if let Some(doc) = inprog.ops.get_mut(&opid) {
+        let mut progress = bson::Document::new();
+        progress.set_i64("done", done as i64);
+        progress.set_i64("total", total as i64);
+        doc.set_document("progress", progress);
+    }
+}
+
+fn end_op(inprog: &SharedInProgress, opid: i32) {
+    let mut inprog = inprog.lock().unwrap();
+    inprog.ops.remove(&opid);
+}
+
 struct Server<'a> {
     factory: Box<elmo::ConnectionFactory>,
+    inprog: SharedInProgress,
+    inserts: SharedInserts,
     cursor_num: i64,
     conn: elmo::Connection,
     // TODO this is problematic when/if the Iterator has a reference to or the same lifetime
     // as self.conn.
     cursors: std::collections::HashMap<i64, (String, elmo::Connection, Box<Iterator<Item=Result<elmo::Row>> + 'a>)>,
+    // cursors which stay open when they run out, because more docs may
+    // show up later.  the bool is awaitData.
+    tailable: std::collections::HashMap<i64, bool>,
+    settings: ServerSettings,
 }
 
 impl<'b> Server<'b> {
 
-    pub fn new(factory: Box<elmo::ConnectionFactory>) -> Server<'b> {
+    pub fn new(factory: Box<elmo::ConnectionFactory>, inprog: SharedInProgress, inserts: SharedInserts, settings: ServerSettings) -> Server<'b> {
         let conn = factory.open().expect("TODO");
         Server {
             factory: factory,
+            inprog: inprog,
+            inserts: inserts,
             conn: conn,
             cursor_num: 0,
             cursors: std::collections::HashMap::new(),
+            tailable: std::collections::HashMap::new(),
+            settings: settings,
         }
     }
 
@@ -366,8 +508,15 @@ impl<'b> Server<'b> {
     }
 
     fn reply_cmd_sys_inprog(&self, req: &MsgQuery, db: &str) -> Result<Reply> {
+        let mut a = bson::Array::new();
+        {
+            let inprog = self.inprog.lock().unwrap();
+            for op in inprog.ops.values() {
+                a.push(bson::Value::BDocument(op.clone()));
+            }
+        }
         let mut doc = bson::Document::new();
-        doc.set_array("inprog", bson::Array::new());
+        doc.set_array("inprog", a);
         doc.set_i32("ok", 1);
         Ok(create_reply(req.req_id, vec![doc], 0))
     }
@@ -389,6 +538,7 @@ impl<'b> Server<'b> {
                     "buildinfo" => self.reply_buildinfo(req),
                     "serverstatus" => self.reply_serverstatus(req),
                     "setparameter" => self.reply_setparameter(req),
+                    "currentop" => self.reply_cmd_sys_inprog(req, db),
                     _ => Err(Error::Misc(format!("unknown admin cmd: {}", cmd)))
                 };
             res
@@ -412,9 +562,12 @@ impl<'b> Server<'b> {
         let coll = try!(req.query.must_remove_string("update"));
         let updates = try!(req.query.must_remove_array("updates"));
         let mut updates = try!(vec_values_to_docs(updates.items));
-        // TODO ordered
+        let ordered = match req.query.remove("ordered") {
+            Some(bson::Value::BBoolean(b)) => b,
+            _ => true,
+        };
         // TODO do we need to keep ownership of updates?
-        let results = try!(self.conn.update(db, &coll, &mut updates, &*self.factory));
+        let results = try!(self.conn.update(db, &coll, &mut updates, ordered, &*self.factory));
         let mut matches = 0;
         let mut mods = 0;
         let mut upserts = bson::Array::new();
@@ -443,6 +596,11 @@ impl<'b> Server<'b> {
                 },
             }
         }
+        if upserts.len() > 0 {
+            // an upsert which inserted wakes up anybody tailing this
+            // collection, the same as an insert
+            self.inserts.inserted(&format!("{}.{}", db, coll));
+        }
         let mut doc = bson::Document::new();
         doc.set_i32("n", matches + (upserts.len() as i32));
         doc.set_i32("modified", mods);
@@ -470,8 +628,9 @@ impl<'b> Server<'b> {
         let key = try!(key.into_string().map_err(|e| elmo::Error::MongoCode(18510, format!("must be string"))));
         let query = try!(req.query.must_remove("query"));
         let query = try!(query.into_document().map_err(|e| elmo::Error::MongoCode(18511, format!("must be document"))));
+        let collation = try!(Self::try_remove_collation(&mut req.query));
 
-        let values = try!(self.conn.distinct(db, &coll, &key, query));
+        let values = try!(self.conn.distinct(db, &coll, &key, query, collation));
         let mut doc = bson::Document::new();
         doc.set_array("values", values);
         doc.set_i32("ok", 1);
@@ -500,6 +659,7 @@ impl<'b> Server<'b> {
         let max = Self::try_remove_optional_prefix(&mut options, "$max");
         let hint = Self::try_remove_optional_prefix(&mut options, "$hint");
         let explain = Self::try_remove_optional_prefix(&mut options, "$explain");
+        let collation = try!(Self::try_remove_collation(&mut options));
 
         let mut seq = try!(self.conn.find(
                 db, 
@@ -510,7 +670,8 @@ impl<'b> Server<'b> {
                 min,
                 max,
                 hint,
-                None
+                None,
+                collation
                 ));
 
         if number_to_skip < 0 {
@@ -624,6 +785,7 @@ impl<'b> Server<'b> {
         match upserted {
             Some(id) => {
                 last_error_object.set("upserted", id);
+                self.inserts.inserted(&format!("{}.{}", db, coll));
             },
             _ => (),
         }
@@ -690,10 +852,13 @@ impl<'b> Server<'b> {
 
         let docs = try!(req.query.must_remove_array("documents"));
         let mut docs = try!(vec_values_to_docs(docs.items));
+        let ordered = match req.query.remove("ordered") {
+            Some(bson::Value::BBoolean(b)) => b,
+            _ => true,
+        };
 
-        // TODO ordered
         // TODO do we need to keep ownership of docs?
-        let results = try!(self.conn.insert(db, &coll, &mut docs));
+        let results = try!(self.conn.insert(db, &coll, &mut docs, ordered));
         let mut errors = Vec::new();
         for i in 0 .. results.len() {
             if results[i].is_err() {
@@ -702,6 +867,10 @@ impl<'b> Server<'b> {
                 errors.push(err);
             }
         }
+        if errors.len() < results.len() {
+            // wake up anybody tailing this collection
+            self.inserts.inserted(&format!("{}.{}", db, coll));
+        }
         let mut doc = bson::Document::new();
         doc.set_i32("n", ((results.len() - errors.len()) as i32));
         if errors.len() > 0 {
@@ -721,6 +890,7 @@ impl<'b> Server<'b> {
         let remove = self.cursors.iter().filter_map(|(&num, &(ref s, _, _))| if s.as_str() == ns { Some(num) } else { None }).collect::<Vec<_>>();
         for cursor_num in remove {
             self.cursors.remove(&cursor_num);
+            self.tailable.remove(&cursor_num);
         }
     }
 
@@ -888,6 +1058,11 @@ impl<'b> Server<'b> {
             // TODO error on bad values?
             _ => (),
         }
+        match q.get("clustered") {
+            Some(&bson::Value::BBoolean(b)) => options.set_bool("clustered", b),
+            // TODO error on bad values?
+            _ => (),
+        }
         match q.get("size") {
             Some(&bson::Value::BInt32(n)) => options.set_i64("size", n as i64),
             Some(&bson::Value::BInt64(n)) => options.set_i64("size", n as i64),
@@ -902,6 +1077,16 @@ impl<'b> Server<'b> {
             // TODO error on bad values?
             _ => (),
         }
+        // the validation and collation options get checked by elmo, so
+        // they just go through as they are
+        for k in &["validator", "validationLevel", "validationAction", "collation"] {
+            match q.get(k) {
+                Some(v) => {
+                    options.set(k, v.clone());
+                },
+                None => (),
+            }
+        }
         // TODO more options here ?
         let result = try!(self.conn.create_collection(db, coll, options));
         let mut doc = bson::Document::new();
@@ -909,6 +1094,16 @@ impl<'b> Server<'b> {
         Ok(create_reply(req.req_id, vec![doc], 0))
     }
 
+    fn reply_coll_mod(&self, mut req: MsgQuery, db: &str) -> Result<Reply> {
+        let coll = try!(req.query.must_remove_string("collMod"));
+        // everything else in the command is a change to the options
+        let changes = req.query;
+        try!(self.conn.coll_mod(db, &coll, changes));
+        let mut doc = bson::Document::new();
+        doc.set_i32("ok", 1);
+        Ok(create_reply(req.req_id, vec![doc], 0))
+    }
+
     fn reply_create_indexes(&mut self, mut req: MsgQuery, db: &str) -> Result<Reply> {
         let coll = try!(req.query.must_remove_string("createIndexes"));
         let indexes = try!(req.query.must_remove_array("indexes"));
@@ -930,7 +1125,24 @@ impl<'b> Server<'b> {
             a.push(ndx);
         }
 
-        let result = try!(self.conn.create_indexes(a));
+        // the ones with background set get built one at a time, without
+        // holding the write lock the whole time.
+        let (background, foreground): (Vec<_>, Vec<_>) = a.into_iter().partition(
+            |ndx| {
+                match ndx.options.get("background") {
+                    Some(&bson::Value::BBoolean(b)) => b,
+                    _ => false,
+                }
+            });
+        let result = try!(self.conn.create_indexes(foreground));
+        for ndx in background {
+            let ns = format!("{}.{}", ndx.db, ndx.coll);
+            let opid = begin_op(&self.inprog, &ns, &format!("Index Build (background): {}", ndx.name));
+            let inprog = self.inprog.clone();
+            let r = self.conn.create_index_background(ndx, &*self.factory, &mut |done, total| op_progress(&inprog, opid, done, total));
+            end_op(&self.inprog, opid);
+            try!(r);
+        }
         // TODO createdCollectionAutomatically
         // TODO numIndexesBefore
         // TODO numIndexesAfter
@@ -1095,6 +1307,13 @@ impl<'b> Server<'b> {
         }
     }
 
+    fn try_remove_collation(v: &mut bson::Document) -> Result<Option<bson::Document>> {
+        match Self::try_remove_optional_prefix(v, "$collation") {
+            Some(c) => Ok(Some(try!(c.into_document()))),
+            None => Ok(None),
+        }
+    }
+
     fn reply_validate(&mut self, req: MsgQuery, db: &str) -> Result<Reply> {
         let MsgQuery {
             req_id,
@@ -1131,8 +1350,9 @@ impl<'b> Server<'b> {
             Some(_) => return Err(Error::Misc(format!("aggregate.cursor must be a document: {:?}", cursor_options))),
             None => (),
         }
+        let collation = try!(Self::try_remove_collation(&mut query));
         let conn = try!(self.factory.open());
-        let (out, seq) = try!(conn.aggregate(db, &coll, pipeline));
+        let (out, seq) = try!(conn.aggregate(db, &coll, pipeline, collation));
         match out {
             Some(new_coll_name) => {
                 let full_coll = format!("{}.{}", db, new_coll_name);
@@ -1184,6 +1404,7 @@ impl<'b> Server<'b> {
         } = req;
         let coll = try!(query.must_remove_string("count"));
         let hint = query.remove("hint");
+        let collation = try!(Self::try_remove_collation(&mut query));
         let q = 
             match query.remove("query") {
                 Some(bson::Value::BDocument(bd)) => {
@@ -1205,7 +1426,8 @@ impl<'b> Server<'b> {
                 None,
                 None,
                 hint,
-                None
+                None,
+                collation
                 ));
         let mut count = seq.count() as i32;
         match query.remove("skip") {
@@ -1259,45 +1481,70 @@ impl<'b> Server<'b> {
         // This other stuff is called query modifiers.  
         // Sigh.
 
+        let tailable = (flags & QUERY_FLAG_TAILABLE) != 0;
+        let await_data = tailable && (flags & QUERY_FLAG_AWAIT_DATA) != 0;
+
         let conn = try!(self.factory.open());
         let seq = 
-            match Self::try_remove_optional_prefix(&mut query, "$query") {
-                Some(q) => {
-                    // TODO what if somebody queries on a field named query?  ambiguous.
-
-                    let orderby = Self::try_remove_optional_prefix(&mut query, "$orderby");
-                    let min = Self::try_remove_optional_prefix(&mut query, "$min");
-                    let max = Self::try_remove_optional_prefix(&mut query, "$max");
-                    let hint = Self::try_remove_optional_prefix(&mut query, "$hint");
-                    let explain = Self::try_remove_optional_prefix(&mut query, "$explain");
-                    let q = try!(q.into_document());
-                    let seq = try!(conn.find(
-                            db, 
-                            coll, 
-                            q,
-                            orderby,
-                            return_fields_selector,
-                            min,
-                            max,
-                            hint,
-                            explain
-                            ));
-                    seq
-                },
-                None => {
-                    let seq = try!(conn.find(
-                            db, 
-                            coll, 
-                            query,
-                            None,
-                            return_fields_selector,
-                            None,
-                            None,
-                            None,
-                            None
-                            ));
-                    seq
-                },
+            if tailable {
+                // a tailable cursor only goes in natural order, so it
+                // can't have a sort.
+                let q =
+                    match Self::try_remove_optional_prefix(&mut query, "$query") {
+                        Some(q) => {
+                            if Self::try_remove_optional_prefix(&mut query, "$orderby").is_some() {
+                                return Err(Error::Misc(String::from("tailable cursor cannot have a sort")));
+                            }
+                            try!(q.into_document())
+                        },
+                        None => query,
+                    };
+                // it opens readers as it goes, so it needs a connection of
+                // its own.
+                let tconn = try!(self.factory.open());
+                try!(tconn.into_tailable_find(db, coll, q, return_fields_selector))
+            } else {
+                match Self::try_remove_optional_prefix(&mut query, "$query") {
+                    Some(q) => {
+                        // TODO what if somebody queries on a field named query?  ambiguous.
+
+                        let orderby = Self::try_remove_optional_prefix(&mut query, "$orderby");
+                        let min = Self::try_remove_optional_prefix(&mut query, "$min");
+                        let max = Self::try_remove_optional_prefix(&mut query, "$max");
+                        let hint = Self::try_remove_optional_prefix(&mut query, "$hint");
+                        let explain = Self::try_remove_optional_prefix(&mut query, "$explain");
+                        let collation = try!(Self::try_remove_collation(&mut query));
+                        let q = try!(q.into_document());
+                        let seq = try!(conn.find(
+                                db, 
+                                coll, 
+                                q,
+                                orderby,
+                                return_fields_selector,
+                                min,
+                                max,
+                                hint,
+                                explain,
+                                collation
+                                ));
+                        seq
+                    },
+                    None => {
+                        let seq = try!(conn.find(
+                                db, 
+                                coll, 
+                                query,
+                                None,
+                                return_fields_selector,
+                                None,
+                                None,
+                                None,
+                                None,
+                                None
+                                ));
+                        seq
+                    },
+                }
             };
 
         if number_to_skip < 0 {
@@ -1314,8 +1561,15 @@ impl<'b> Server<'b> {
         //Ok(create_reply(req_id, docs, 0))
 
         let (docs, more) = try!(Self::do_limit(&full_collection_name, &mut seq, number_to_return));
-        let cursor_id = if more {
-            self.store_cursor(&full_collection_name, conn, seq)
+        // a tailable cursor stays open even when it runs out, unless the
+        // client asked for a hard limit.
+        let keep_tailing = tailable && number_to_return >= 0 && number_to_return != 1;
+        let cursor_id = if more || keep_tailing {
+            let cursor_id = self.store_cursor(&full_collection_name, conn, seq);
+            if keep_tailing {
+                self.tailable.insert(cursor_id, await_data);
+            }
+            cursor_id
             //0
         } else {
             // TODO conn wasted here
@@ -1354,6 +1608,7 @@ impl<'b> Server<'b> {
                     "listcollections" => self.reply_list_collections(req, db),
                     "listindexes" => self.reply_list_indexes(req, db),
                     "create" => self.reply_create_collection(&req, db),
+                    "collmod" => self.reply_coll_mod(req, db),
                     //"features" => reply_features &req db
                     _ => Err(Error::Misc(format!("unknown cmd: {}", cmd)))
                 };
@@ -1372,7 +1627,9 @@ impl<'b> Server<'b> {
                 Err(Error::Misc(format!("bad collection name: {}", req.full_collection_name)))
             } else {
                 let db = &parts[0];
-                if db == "admin" {
+                if parts.len() == 4 && parts[1] == "$cmd" && parts[2]=="sys" && parts[3]=="inprog" {
+                    self.reply_cmd_sys_inprog(&req, db)
+                } else if db == "admin" {
                     if parts[1] == "$cmd" {
                         //reply_AdminCmd req
                         // TODO probably want to pass ownership of req down here
@@ -1382,11 +1639,7 @@ impl<'b> Server<'b> {
                     }
                 } else {
                     if parts[1] == "$cmd" {
-                        if parts.len() == 4 && parts[2]=="sys" && parts[3]=="inprog" {
-                            self.reply_cmd_sys_inprog(&req, db)
-                        } else {
-                            self.reply_cmd(req, db)
-                        }
+                        self.reply_cmd(req, db)
                     } else if parts.len()==3 && parts[1]=="system" && parts[2]=="indexes" {
                         //reply_system_indexes req db
                         Err(Error::Misc(format!("TODO: {:?}", req)))
@@ -1405,21 +1658,51 @@ impl<'b> Server<'b> {
         r
     }
 
+    // a tailable cursor just asks its seq again.  the seq knows how to
+    // pick up where it left off.  when it comes up empty, it only asks
+    // again after an insert into the collection.
+    fn await_data<T: Iterator<Item=Result<elmo::Row>>>(&self, ns: &str, seq: &mut T, number_to_return: i32) -> Result<(Vec<elmo::Row>, bool)> {
+        let timeout = std::time::Duration::from_millis(self.settings.await_data_timeout_ms);
+        let start = std::time::Instant::now();
+        loop {
+            // counted before looking, so an insert in between isn't missed
+            let seen = self.inserts.count(ns);
+            let (docs, more) = try!(Self::do_limit(ns, seq, number_to_return));
+            let elapsed = start.elapsed();
+            if docs.len() > 0 || elapsed >= timeout {
+                return Ok((docs, more));
+            }
+            self.inserts.wait(ns, seen, timeout - elapsed);
+        }
+    }
+
     fn reply_2005(&mut self, req: MsgGetMore) -> Reply {
+        let tailable = self.tailable.remove(&req.cursor_id);
         match self.cursors.remove(&req.cursor_id) {
             Some((ns, conn, mut seq)) => {
-                match Self::do_limit(&ns, &mut seq, req.number_to_return) {
+                let r =
+                    match tailable {
+                        Some(true) => self.await_data(&ns, &mut seq, req.number_to_return),
+                        _ => Self::do_limit(&ns, &mut seq, req.number_to_return),
+                    };
+                match r {
                     Ok((docs, more)) => {
-                        if more {
+                        // a tailable cursor which ran out this time may
+                        // find more next time
+                        let keep = more || tailable.is_some();
+                        if keep {
                             // put the cursor back for next time
                             self.cursors.insert(req.cursor_id, (ns, conn, box seq));
+                            if let Some(await_data) = tailable {
+                                self.tailable.insert(req.cursor_id, await_data);
+                            }
                         } else {
                             // TODO conn wasted here
                         }
                         let docs = vec_rows_to_values(docs);
                         match vec_values_to_docs(docs) {
                             Ok(docs) => {
-                                create_reply(req.req_id, docs, if more { req.cursor_id } else { 0 })
+                                create_reply(req.req_id, docs, if keep { req.cursor_id } else { 0 })
                             },
                             Err(e) => {
                                 reply_err(req.req_id, e)
@@ -1463,6 +1746,7 @@ impl<'b> Server<'b> {
                     Request::KillCursors(req) => {
                         for cursor_id in req.cursor_ids {
                             self.cursors.remove(&cursor_id);
+                            self.tailable.remove(&cursor_id);
                         }
                         // there is no reply to this
                         Ok(true)
@@ -1506,20 +1790,55 @@ impl<'b> Server<'b> {
 
 }
 
+// how long the stats thread waits before it checks again anyway
+const STATS_WAIT_MS: u64 = 10000;
+
 // TODO args:  ipaddr, port
-pub fn serve(factory: Box<elmo::ConnectionFactory>) {
+pub fn serve(factory: Box<elmo::ConnectionFactory>, settings: ServerSettings) {
+    // a background index build which was cut off the last time around
+    // would otherwise stay invisible, but keep getting maintained.
+    let conn = factory.open().expect("TODO");
+    conn.resume_index_builds(&*factory).expect("TODO");
+    drop(conn);
+
+    // the planner stats go stale as docs get written.  they get gathered
+    // again here, so no request has to wait for that.
+    let stats_factory = factory.clone_for_new_thread();
+    std::thread::spawn(move || {
+        let conn = stats_factory.open().expect("TODO");
+        loop {
+            match conn.wait_for_stale_stats(STATS_WAIT_MS) {
+                Ok(true) => {
+                    if let Err(e) = conn.analyze_stale() {
+                        println!("gathering stats failed: {:?}", e);
+                    }
+                },
+                Ok(false) => (),
+                Err(e) => {
+                    println!("gathering stats failed: {:?}", e);
+                    break;
+                },
+            }
+        }
+    });
+
     let listener = std::net::TcpListener::bind("127.0.0.1:27017").unwrap();
 
+    let inprog = std::sync::Arc::new(std::sync::Mutex::new(InProgress::new()));
+    let inserts = std::sync::Arc::new(Inserts::new());
+
     // accept connections and process them, spawning a new thread for each one
     for stream in listener.incoming() {
         match stream {
             Ok(stream) => {
                 let factory = factory.clone_for_new_thread();
+                let inprog = inprog.clone();
+                let inserts = inserts.clone();
                 // TODO thread::spawn panics when the OS cannot create
                 // a thread.  use thread::Builder::spawn() instead.
                 std::thread::spawn(move || {
                     // connection succeeded
-                    let mut s = Server::new(factory);
+                    let mut s = Server::new(factory, inprog, inserts, settings);
                     s.handle_client(stream).expect("TODO");
                 });
             }
@@ -1532,9 +1851,26 @@ pub fn serve(factory: Box<elmo::ConnectionFactory>) {
 }
 
 pub fn main() {
+    // --memory keeps everything in memory instead of elmodata.lsm, which
+    // is handy for running the jstests, and for scratch $out results
+    // that don't need to outlive the server.
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let memory = args.iter().any(|a| a == "--memory");
+    let settings =
+        match ServerSettings::from_args(&args) {
+            Ok(settings) => settings,
+            Err(e) => {
+                println!("Error: {}", e);
+                panic!();
+            },
+        };
+    if memory {
+        serve(box elmo_memory::MyFactory::new(), settings);
+        return;
+    }
     match elmo_lsm::MyFactory::new(String::from("elmodata.lsm")) {
         Ok(factory) => {
-            serve(box factory);
+            serve(box factory, settings);
         },
         Err(e) => {
             println!("Error: {}", e);
@@ -1543,3 +1879,224 @@ pub fn main() {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use std;
+    use bson;
+    use elmo;
+    use elmo_memory;
+    use elmo::Result;
+    use super::{Server, ServerSettings, DEFAULT_SETTINGS, InProgress, Inserts, SharedInserts};
+    use super::{MsgQuery, MsgGetMore, Reply, QUERY_FLAG_TAILABLE, QUERY_FLAG_AWAIT_DATA};
+
+    fn server(factory: Box<elmo::ConnectionFactory>, inserts: SharedInserts, timeout_ms: u64) -> Server<'static> {
+        let inprog = std::sync::Arc::new(std::sync::Mutex::new(InProgress::new()));
+        let settings = ServerSettings {
+            await_data_timeout_ms: timeout_ms,
+        };
+        Server::new(factory, inprog, inserts, settings)
+    }
+
+    fn id_doc(id: i32) -> bson::Document {
+        let mut d = bson::Document::new();
+        d.set_i32("_id", id);
+        d
+    }
+
+    fn insert_cmd(s: &mut Server, ids: &[i32]) -> Result<()> {
+        let mut q = bson::Document::new();
+        q.set_str("insert", "c");
+        q.set_array("documents", bson::Array {items: ids.iter().map(|&i| bson::Value::BDocument(id_doc(i))).collect()});
+        run_cmd(s, q)
+    }
+
+    // an update of a doc which isn't there, with upsert, so it gets
+    // inserted
+    fn upsert_cmd(s: &mut Server, id: i32) -> Result<()> {
+        let mut u = bson::Document::new();
+        u.set_document("q", id_doc(id));
+        u.set_document("u", id_doc(id));
+        u.set_bool("upsert", true);
+        let mut q = bson::Document::new();
+        q.set_str("update", "c");
+        q.set_array("updates", bson::Array {items: vec![bson::Value::BDocument(u)]});
+        run_cmd(s, q)
+    }
+
+    fn find_and_modify_upsert_cmd(s: &mut Server, id: i32) -> Result<()> {
+        let mut q = bson::Document::new();
+        q.set_str("findandmodify", "c");
+        q.set_document("query", id_doc(id));
+        q.set_document("update", id_doc(id));
+        q.set_bool("upsert", true);
+        run_cmd(s, q)
+    }
+
+    fn run_cmd(s: &mut Server, q: bson::Document) -> Result<()> {
+        let req = MsgQuery {
+            req_id: 1,
+            flags: 0,
+            full_collection_name: String::from("tail.$cmd"),
+            number_to_skip: 0,
+            number_to_return: -1,
+            query: q,
+            return_fields_selector: None,
+        };
+        try!(s.reply_2004(req));
+        Ok(())
+    }
+
+    fn get_more(s: &mut Server, cursor_id: i64) -> Reply {
+        let req = MsgGetMore {
+            req_id: 3,
+            full_collection_name: String::from("tail.c"),
+            number_to_return: 0,
+            cursor_id: cursor_id,
+        };
+        s.reply_2005(req)
+    }
+
+    fn ids(r: &Reply) -> Vec<i32> {
+        r.docs.iter().map(|d| match d.get("_id") { Some(&bson::Value::BInt32(n)) => n, _ => panic!() }).collect()
+    }
+
+    // a tailable cursor on tail.c, with awaitData or not, after inserting
+    // a couple of docs
+    fn tail(s: &mut Server, await_data: bool) -> Result<i64> {
+        let mut options = bson::Document::new();
+        options.set_bool("capped", true);
+        options.set_i64("size", 100000);
+        try!(s.conn.create_collection("tail", "c", options));
+        try!(insert_cmd(s, &[1, 2]));
+        let flags = QUERY_FLAG_TAILABLE | if await_data { QUERY_FLAG_AWAIT_DATA } else { 0 };
+        let req = MsgQuery {
+            req_id: 2,
+            flags: flags,
+            full_collection_name: String::from("tail.c"),
+            number_to_skip: 0,
+            number_to_return: 0,
+            query: bson::Document::new(),
+            return_fields_selector: None,
+        };
+        let r = try!(s.reply_2004(req));
+        assert_eq!(ids(&r), vec![1, 2]);
+        assert!(r.cursor_id != 0);
+        Ok(r.cursor_id)
+    }
+
+    #[test]
+    fn tailable_without_await_data() {
+        fn f() -> Result<()> {
+            let inserts = std::sync::Arc::new(Inserts::new());
+            let mut s = server(box elmo_memory::MyFactory::new(), inserts, 60000);
+            let cursor_id = try!(tail(&mut s, false));
+
+            // runs out right away, but stays open
+            let start = std::time::Instant::now();
+            let r = get_more(&mut s, cursor_id);
+            assert!(start.elapsed() < std::time::Duration::from_millis(10000));
+            assert_eq!(ids(&r), Vec::<i32>::new());
+            assert_eq!(r.cursor_id, cursor_id);
+
+            try!(insert_cmd(&mut s, &[3]));
+            assert_eq!(ids(&get_more(&mut s, cursor_id)), vec![3]);
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn await_data_times_out() {
+        fn f() -> Result<()> {
+            let inserts = std::sync::Arc::new(Inserts::new());
+            let mut s = server(box elmo_memory::MyFactory::new(), inserts, 200);
+            let cursor_id = try!(tail(&mut s, true));
+
+            let start = std::time::Instant::now();
+            let r = get_more(&mut s, cursor_id);
+            assert!(start.elapsed() >= std::time::Duration::from_millis(200));
+            assert_eq!(ids(&r), Vec::<i32>::new());
+            assert_eq!(r.cursor_id, cursor_id);
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn await_data_wakes_on_insert() {
+        fn f() -> Result<()> {
+            let factory = elmo_memory::MyFactory::new();
+            let inserts = std::sync::Arc::new(Inserts::new());
+            let mut s = server(box factory.clone(), inserts.clone(), 60000);
+            let cursor_id = try!(tail(&mut s, true));
+
+            // another connection inserts while the getMore waits
+            let other = elmo::ConnectionFactory::clone_for_new_thread(&factory);
+            let t = std::thread::spawn(move || {
+                std::thread::sleep(std::time::Duration::from_millis(100));
+                let mut s = server(other, inserts, 60000);
+                insert_cmd(&mut s, &[3]).unwrap();
+            });
+            let start = std::time::Instant::now();
+            let r = get_more(&mut s, cursor_id);
+            // long before the timeout
+            assert!(start.elapsed() < std::time::Duration::from_millis(30000));
+            assert_eq!(ids(&r), vec![3]);
+            assert_eq!(r.cursor_id, cursor_id);
+            t.join().unwrap();
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn await_data_wakes_on_upsert() {
+        fn f() -> Result<()> {
+            let factory = elmo_memory::MyFactory::new();
+            let inserts = std::sync::Arc::new(Inserts::new());
+            let mut s = server(box factory.clone(), inserts.clone(), 60000);
+            let cursor_id = try!(tail(&mut s, true));
+
+            // an update and a findAndModify, each of which ends up
+            // inserting, while the getMore waits
+            let cmds: Vec<fn(&mut Server, i32) -> Result<()>> = vec![upsert_cmd, find_and_modify_upsert_cmd];
+            for (i, cmd) in cmds.into_iter().enumerate() {
+                let id = 3 + i as i32;
+                let other = elmo::ConnectionFactory::clone_for_new_thread(&factory);
+                let inserts = inserts.clone();
+                let t = std::thread::spawn(move || {
+                    std::thread::sleep(std::time::Duration::from_millis(100));
+                    let mut s = server(other, inserts, 60000);
+                    cmd(&mut s, id).unwrap();
+                });
+                let start = std::time::Instant::now();
+                let r = get_more(&mut s, cursor_id);
+                // long before the timeout
+                assert!(start.elapsed() < std::time::Duration::from_millis(30000));
+                assert_eq!(ids(&r), vec![id]);
+                assert_eq!(r.cursor_id, cursor_id);
+                t.join().unwrap();
+            }
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn await_data_timeout_from_args() {
+        let args = |a: &[&str]| a.iter().map(|s| String::from(*s)).collect::<Vec<_>>();
+        assert_eq!(ServerSettings::from_args(&args(&[])).unwrap().await_data_timeout_ms, DEFAULT_SETTINGS.await_data_timeout_ms);
+        assert_eq!(ServerSettings::from_args(&args(&["--memory", "--await-data-timeout-ms", "250"])).unwrap().await_data_timeout_ms, 250);
+        assert!(ServerSettings::from_args(&args(&["--await-data-timeout-ms"])).is_err());
+        assert!(ServerSettings::from_args(&args(&["--await-data-timeout-ms", "soon"])).is_err());
+    }
+}
@@ -18,6 +18,7 @@
 #![feature(associated_consts)]
 
 use std::collections::BTreeMap;
+use std::collections::Bound;
 use std::collections::HashMap;
 use std::collections::HashSet;
 
@@ -30,6 +31,12 @@ extern crate lsm;
 use lsm::IForwardCursor;
 use lsm::ISeekableCursor;
 use lsm::ILiveValue;
+use lsm::IValue;
+
+use misc::sort;
+
+// how much the bulk loader sorts in memory before it spills to a file
+const BULK_SORT_MEMORY: usize = 64 * 1024 * 1024;
 
 pub type Result<T> = elmo::Result<T>;
 
@@ -85,6 +92,7 @@ struct MyIndexPrep {
     options: bson::Document,
     normspec: Vec<(String,elmo::IndexType)>,
     weights: Option<HashMap<String,i32>>,
+    collation: Option<bson::CollationSpec>,
     // TODO maybe keep the options we need here directly, sparse and unique
 }
 
@@ -96,6 +104,19 @@ struct MyCollectionWriter {
     coll: String,
     indexes: Vec<MyIndexPrep>,
     collection_id: u64,
+    clustered: bool,
+    capped: Option<elmo::Capped>,
+}
+
+// what a writer knows about the records in a capped collection.  the
+// totals are stored with the collection, under CAPPED_TOTALS, and read
+// the first time they're needed.  evicted is the last record this writer
+// evicted, so finding the next oldest doesn't walk past its tombstones
+// again.
+struct CappedRecords {
+    count: u64,
+    bytes: u64,
+    evicted: Option<Box<[u8]>>,
 }
 
 struct MyCollectionReader {
@@ -106,6 +127,7 @@ struct MyCollectionReader {
 
 struct RangeCursorBsonValueIterator {
     cursor: lsm::RangeCursor,
+    reverse: bool,
 }
 
 impl RangeCursorBsonValueIterator {
@@ -122,7 +144,11 @@ impl RangeCursorBsonValueIterator {
                 };
                 row
             };
-            try!(self.cursor.next().map_err(elmo::wrap_err));
+            if self.reverse {
+                try!(self.cursor.prev().map_err(elmo::wrap_err));
+            } else {
+                try!(self.cursor.next().map_err(elmo::wrap_err));
+            }
             Ok(Some(row))
         } else {
             Ok(None)
@@ -154,20 +180,25 @@ impl Iterator for RangeCursorBsonValueIterator {
     }
 }
 
-struct RangeCursorVarintIterator {
+struct RangeCursorBoxValueIterator {
     cursor: lsm::RangeCursor,
+    reverse: bool,
 }
 
-impl RangeCursorVarintIterator {
-    fn iter_next(&mut self) -> Result<Option<u64>> {
+impl RangeCursorBoxValueIterator {
+    fn iter_next(&mut self) -> Result<Option<Box<[u8]>>> {
         if self.cursor.is_valid() {
             let v = {
                 let v = try!(self.cursor.value().map_err(elmo::wrap_err));
                 //println!("got {:?}", v);
-                let v = try!(v.map(lsm_map_to_varint).map_err(elmo::wrap_err));
+                let v = try!(v.map(lsm_map_to_box).map_err(elmo::wrap_err));
                 v
             };
-            try!(self.cursor.next().map_err(elmo::wrap_err));
+            if self.reverse {
+                try!(self.cursor.prev().map_err(elmo::wrap_err));
+            } else {
+                try!(self.cursor.next().map_err(elmo::wrap_err));
+            }
             Ok(Some(v))
         } else {
             Ok(None)
@@ -175,8 +206,8 @@ impl RangeCursorVarintIterator {
     }
 }
 
-impl Iterator for RangeCursorVarintIterator {
-    type Item = Result<u64>;
+impl Iterator for RangeCursorBoxValueIterator {
+    type Item = Result<Box<[u8]>>;
     fn next(&mut self) -> Option<Self::Item> {
         match self.iter_next() {
             Err(e) => {
@@ -206,11 +237,19 @@ struct MyWriter<'a> {
     myconn: std::rc::Rc<MyConn>,
     tx: std::sync::MutexGuard<'a, lsm::WriteLock>,
     pending: BTreeMap<Box<[u8]>, lsm::ValueForStorage>,
+    // each savepoint pushes pending here and starts a fresh one.  the
+    // newest layer is at the end.
+    savepoints: Vec<BTreeMap<Box<[u8]>, lsm::ValueForStorage>>,
     max_collection_id: Option<u64>,
     max_record_id: HashMap<u64, u64>,
     max_index_id: HashMap<u64, u64>,
     cw: Option<MyCollectionWriter>,
     cursor: lsm::LivingCursor,
+    // segments written by the bulk loader, to be committed along with
+    // pending, each with the collection it loaded
+    bulk: Vec<(u64, lsm::SegmentHeaderInfo)>,
+    // by collection id
+    capped: HashMap<u64, CappedRecords>,
 }
 
 struct MyConn {
@@ -221,11 +260,7 @@ struct MyPublicConn {
     myconn: std::rc::Rc<MyConn>,
 }
 
-// TODO should all these value encodings be switching around to have
-// the collid first, before the tag?  drop_collection gets trivial.
-// and the collection gets good locality of storage.
-
-// TODO should we have record ids?  or just have the _id of each record
+// should we have record ids?  or just have the _id of each record
 // be its actual key?  
 //
 // the pk can be big, and it will be duplicated,
@@ -236,6 +271,23 @@ struct MyPublicConn {
 //
 // if we don't have a recid, how would we store a document that doesn't
 // have any _id at all?
+//
+// so record ids are the default, but a collection created with the
+// option clustered:true uses the encoded _id as the record key instead.
+// then there are no entries for the _id index at all, since the records
+// themselves are in _id order, and a lookup by _id is one seek instead
+// of two.  secondary index entries point at the encoded _id.
+
+/// key:
+///     (tag)
+/// value:
+///     version of the key layout (varint)
+pub const KEY_LAYOUT: u8 = 1;
+
+// layout 1 had RECORD and INDEX_ENTRY as the first byte of the key,
+// followed by the collid.  it never wrote the KEY_LAYOUT key.
+// layout 2 puts those under COLLECTION_DATA, collid first.
+pub const CURRENT_KEY_LAYOUT: u64 = 2;
 
 /// key:
 ///     (tag)
@@ -273,28 +325,56 @@ pub const NAME_TO_INDEX_ID: u8 = 20;
 ///         n: name (str)
 ///         s: spec (bson)
 ///         o: options (bson)
+///         b: true while a background build is still running
 pub const INDEX_ID_TO_PROPERTIES: u8 = 21;
 
 pub const PRIMARY_INDEX_ID: u64 = 0;
 
 /// key:
-///     (tag)
+///     (COLLECTION_DATA)
 ///     collid (varint)
-///     recid (varint)
+///     (tag)
+///     recid (varint), or encoded _id when clustered
 /// value:
 ///     doc (bson)
 pub const RECORD: u8 = 30;
 
 /// key:
-///     (tag)
+///     (COLLECTION_DATA)
 ///     collid (varint)
+///     (tag)
 ///     indexid (varint)
 ///     k (len + bytes)
 ///     recid (varint) (not present when index option unique)
 /// value:
 ///     recid (varint) (present only when index option unique?)
+///
+/// for a clustered collection, the recid in both places is the
+/// encoded _id instead.
 pub const INDEX_ENTRY: u8 = 40;
 
+/// key:
+///     (COLLECTION_DATA)
+///     collid (varint)
+///     (tag)
+/// value:
+///     the number of records and their total bytes, for a capped
+///     collection (bson, n and b)
+pub const CAPPED_TOTALS: u8 = 45;
+
+/// the records and index entries of a collection all live under this
+/// tag, with the collid before the tag of the entry itself.  so the
+/// contents of a collection are one contiguous range of keys, which
+/// makes drop and clear a single prefix, and keeps a scan of one
+/// collection from wandering through the others.
+///
+/// key:
+///     (tag)
+///     collid (varint)
+///     (RECORD, INDEX_ENTRY or CAPPED_TOTALS)
+///     ...
+pub const COLLECTION_DATA: u8 = 50;
+
 // TODO consider encoding primary and secondary indexes
 // separately.
 
@@ -351,10 +431,19 @@ fn decode_key_name_to_index_id(k: &lsm::KeyRef) -> Result<(u64, String)> {
     Ok((collection_id, name))
 }
 
-fn decode_key_record(k: &lsm::KeyRef) -> Result<(u64, u64)> {
-    // k[0] must be RECORD
+fn decode_key_collection_data(k: &lsm::KeyRef) -> Result<(u64, u8, usize)> {
+    // k[0] must be COLLECTION_DATA
     let cur = 1;
     let (collection_id, cur) = try!(decode_varint_from_key(k, cur));
+    let tag = try!(k.u8_at(cur).map_err(elmo::wrap_err));
+    Ok((collection_id, tag, cur + 1))
+}
+
+fn decode_key_record(k: &lsm::KeyRef) -> Result<(u64, u64)> {
+    let (collection_id, tag, cur) = try!(decode_key_collection_data(k));
+    if tag != RECORD {
+        return Err(elmo::Error::Misc(String::from("key is not a record")));
+    }
     let (record_id, _) = try!(decode_varint_from_key(k, cur));
     Ok((collection_id, record_id))
 }
@@ -384,6 +473,30 @@ fn encode_key_tag_and_varint(tag: u8, id: u64) -> Vec<u8> {
     k
 }
 
+fn encode_key_collection_data(collection_id: u64) -> Vec<u8> {
+    encode_key_tag_and_varint(COLLECTION_DATA, collection_id)
+}
+
+fn encode_key_collection_data_tag(collection_id: u64, tag: u8) -> Vec<u8> {
+    let mut k = encode_key_collection_data(collection_id);
+    k.push(tag);
+    k
+}
+
+// ba_record_id is whatever follows the RECORD tag.  a varint recid, or
+// for a clustered collection, the encoded _id.
+fn encode_key_record(collection_id: u64, ba_record_id: &[u8]) -> Vec<u8> {
+    let mut k = encode_key_collection_data_tag(collection_id, RECORD);
+    k.extend_from_slice(ba_record_id);
+    k
+}
+
+fn encode_key_index_entry_prefix(collection_id: u64, index_id: u64) -> Vec<u8> {
+    let mut k = encode_key_collection_data_tag(collection_id, INDEX_ENTRY);
+    misc::push_varint(&mut k, index_id);
+    k
+}
+
 fn encode_key_collection_id_to_properties(collection_id: u64) -> Vec<u8> {
     encode_key_tag_and_varint(COLLECTION_ID_TO_PROPERTIES, collection_id)
 }
@@ -452,14 +565,42 @@ fn lsm_map_to_bson(ba: &[u8]) -> lsm::Result<bson::Document> {
     r
 }
 
-fn find_record(cursor: &mut lsm::LivingCursor, collection_id: u64, id: &bson::Value) -> Result<Option<u64>> {
-    let mut k = vec![];
-    k.push(INDEX_ENTRY);
-    misc::push_varint(&mut k, collection_id);
-    misc::push_varint(&mut k, PRIMARY_INDEX_ID);
+fn is_clustered(options: &bson::Document) -> bool {
+    match options.get("clustered") {
+        Some(&bson::Value::BBoolean(b)) => b,
+        _ => false,
+    }
+}
+
+fn get_collection_options(cursor: &mut lsm::LivingCursor, collection_id: u64) -> Result<bson::Document> {
+    let k = encode_key_collection_id_to_properties(collection_id);
+    match try!(get_value_for_key_as_bson(cursor, &k)) {
+        Some(mut collection_properties) => {
+            let options = try!(collection_properties.must_remove_document("o"));
+            Ok(options)
+        },
+        None => {
+            Ok(bson::Document::new())
+        },
+    }
+}
+
+fn find_record(cursor: &mut lsm::LivingCursor, collection_id: u64, clustered: bool, id: &bson::Value) -> Result<Option<Box<[u8]>>> {
     let ba = bson::Value::encode_one_for_index(id, false);
-    k.extend_from_slice(&ba);
-    get_value_for_key_as_varint(cursor, &k)
+    if clustered {
+        let k = encode_key_record(collection_id, &ba);
+        try!(cursor.seek(&lsm::KeyRef::for_slice(&k), lsm::SeekOp::Equal).map_err(elmo::wrap_err));
+        if cursor.is_valid() {
+            Ok(Some(ba.into_boxed_slice()))
+        } else {
+            Ok(None)
+        }
+    } else {
+        let mut k = encode_key_index_entry_prefix(collection_id, PRIMARY_INDEX_ID);
+        k.extend_from_slice(&ba);
+        let record_id = try!(get_value_for_key_as_varint(cursor, &k));
+        Ok(record_id.map(u64_to_boxed_varint))
+    }
 }
 fn get_value_for_key_as_varint(cursor: &mut lsm::LivingCursor, k: &[u8]) -> Result<Option<u64>> {
     try!(cursor.seek(&lsm::KeyRef::for_slice(&k), lsm::SeekOp::Equal).map_err(elmo::wrap_err));
@@ -497,17 +638,11 @@ impl MyConn {
                 Ok(rdr)
             },
             Some(collection_id) => {
-                // TODO vec capacity
-                let mut kmin = vec![];
-                kmin.push(RECORD);
-                misc::push_varint(&mut kmin, collection_id);
+                let kmin = encode_key_collection_data_tag(collection_id, RECORD);
                 let kmin = kmin.into_boxed_slice();
                 let min = lsm::Min::new(kmin, lsm::OpGt::GT);
 
-                // TODO vec capacity
-                let mut kmax = vec![];
-                kmax.push(RECORD);
-                misc::push_varint(&mut kmax, collection_id + 1);
+                let kmax = encode_key_collection_data_tag(collection_id, RECORD + 1);
                 let kmax = kmax.into_boxed_slice();
                 let max = lsm::Max::new(kmax, lsm::OpLt::LT);
 
@@ -516,6 +651,7 @@ impl MyConn {
                 let seq = 
                     RangeCursorBsonValueIterator {
                         cursor: cursor,
+                        reverse: false,
                     };
                 let rdr = 
                     MyCollectionReader {
@@ -526,11 +662,211 @@ impl MyConn {
         }
     }
 
+    // record ids only go up, so everything inserted after the doc with
+    // this _id has a record key after its key.
+    fn get_reader_collection_scan_after(&self, db: &str, coll: &str, id: &bson::Value) -> Result<Option<MyCollectionReader>> {
+        let k = encode_key_name_to_collection_id(db, coll);
+        let mut cursor = try!(self.conn.open_cursor().map_err(elmo::wrap_err));
+        let collection_id =
+            match try!(get_value_for_key_as_varint(&mut cursor, &k)) {
+                None => return Ok(None),
+                Some(collection_id) => collection_id,
+            };
+        let clustered = is_clustered(&try!(get_collection_options(&mut cursor, collection_id)));
+        if !clustered {
+            let k = encode_key_name_to_index_id(collection_id, "_id_");
+            if try!(get_value_for_key_as_varint(&mut cursor, &k)).is_none() {
+                // TODO without the _id index, we would have to scan for it
+                return Err(elmo::Error::Misc(String::from("tailing a collection requires an _id index")));
+            }
+        }
+        match try!(find_record(&mut cursor, collection_id, clustered, id)) {
+            None => Ok(None),
+            Some(ba_record_id) => {
+                let kmin = encode_key_record(collection_id, &ba_record_id);
+                let kmin = kmin.into_boxed_slice();
+                let min = lsm::Min::new(kmin, lsm::OpGt::GT);
+
+                let kmax = encode_key_collection_data_tag(collection_id, RECORD + 1);
+                let kmax = kmax.into_boxed_slice();
+                let max = lsm::Max::new(kmax, lsm::OpLt::LT);
+
+                let mut cursor = lsm::RangeCursor::new(cursor, min, max);
+                try!(cursor.first().map_err(elmo::wrap_err));
+                let seq = 
+                    RangeCursorBsonValueIterator {
+                        cursor: cursor,
+                        reverse: false,
+                    };
+                let rdr = 
+                    MyCollectionReader {
+                        seq: box seq,
+                    };
+                Ok(Some(rdr))
+            },
+        }
+    }
+
+    // TODO like the sqlite version, this has logic which would prefer to
+    // be up above the storage layer.
     fn get_reader_text_index_scan(&self, ndx: &elmo::IndexInfo, eq: elmo::QueryKey, terms: Vec<elmo::TextQueryTerm>) -> Result<MyCollectionReader> {
-        unimplemented!();
+        let mut cursor = try!(self.conn.open_cursor().map_err(elmo::wrap_err));
+        let collection_id = 
+            match try!(get_value_for_key_as_varint(&mut cursor, &encode_key_name_to_collection_id(&ndx.db, &ndx.coll))) {
+                Some(id) => id,
+                None => {
+                    let rdr = 
+                        MyCollectionReader {
+                            seq: box std::iter::empty(),
+                        };
+                    return Ok(rdr);
+                },
+            };
+        let index_id = 
+            match try!(get_value_for_key_as_varint(&mut cursor, &encode_key_name_to_index_id(collection_id, &ndx.name))) {
+                Some(id) => id,
+                None => return Err(elmo::Error::Misc(String::from("index does not exist"))),
+            };
+        // TODO we wish we didn't have to call get_normalized_spec() here just to get weights
+        let (_, weights) = try!(elmo::get_normalized_spec(&ndx.spec, &ndx.options));
+        let weights = 
+            match weights {
+                None => return Err(elmo::Error::Misc(String::from("non text index"))),
+                Some(w) => w,
+            };
+
+        let mut key_preface = encode_key_index_entry_prefix(collection_id, index_id);
+        bson::Value::push_encode_multi_for_index(&mut key_preface, &eq, None);
+
+        // a text index entry has an array of the word and its weight,
+        // followed by the recid.  returns (recid, weight) for every
+        // entry of the word.
+        fn lookup(cursor: &mut lsm::LivingCursor, key_preface: &Vec<u8>, word: &str) -> Result<Vec<(Box<[u8]>, i32)>> {
+            // everything up through the end of the word is a prefix
+            // which all the entries for this word share.
+            let v = bson::Value::BArray(bson::Array {items: vec![bson::Value::BString(String::from(word)), bson::Value::BInt32(0)]});
+            let a = bson::Value::encode_one_for_index(&v, false);
+            let zero = bson::Value::encode_one_for_index(&bson::Value::BInt32(0), false);
+            let mut k = key_preface.clone();
+            k.extend_from_slice(&a[0 .. a.len() - zero.len()]);
+            let word_end = k.len();
+
+            let mut entries = Vec::new();
+            let mut cursor = lsm::PrefixCursor::new(cursor, k.into_boxed_slice());
+            try!(cursor.first().map_err(elmo::wrap_err));
+            while cursor.is_valid() {
+                {
+                    let k = try!(cursor.key().map_err(elmo::wrap_err)).into_boxed_slice();
+
+                    // the weight starts right after the nul at the end of
+                    // the word.  the recid comes after it.
+                    let (w, _) = try!(bson::Value::decode_weight_from_index_entry(&k[word_end ..]));
+
+                    let v = try!(cursor.value().map_err(elmo::wrap_err));
+                    let record_id = try!(v.map(lsm_map_to_box).map_err(elmo::wrap_err));
+                    entries.push((record_id, w));
+                }
+                try!(cursor.next().map_err(elmo::wrap_err));
+            }
+            Ok(entries)
+        }
+
+        let mut found = Vec::new();
+        for term in &terms {
+            let entries = 
+                match term {
+                    &elmo::TextQueryTerm::Word(_, ref s) => {
+                        try!(lookup(&mut cursor, &key_preface, &s))
+                    },
+                    &elmo::TextQueryTerm::Phrase(_, ref s) => {
+                        // TODO tokenize properly
+                        let mut entries = Vec::new();
+                        for w in s.split(" ") {
+                            entries.extend(try!(lookup(&mut cursor, &key_preface, w)));
+                        }
+                        entries
+                    },
+                };
+            found.push((term, entries));
+        }
+
+        let mut pos_entries = Vec::new();
+        let mut neg_entries = Vec::new();
+        for (term, entries) in found {
+            match term {
+                &elmo::TextQueryTerm::Word(neg, _) => {
+                    if neg {
+                        neg_entries.extend(entries);
+                    } else {
+                        pos_entries.extend(entries);
+                    }
+                },
+                &elmo::TextQueryTerm::Phrase(neg, _) => {
+                    if neg {
+                        // TODO probably should not negate a doc just because it contains one of the words in a negated phrase
+                    } else {
+                        pos_entries.extend(entries);
+                    }
+                },
+            }
+        }
+
+        let neg_record_ids = neg_entries.into_iter().map(|t| t.0).collect::<HashSet<_>>();
+
+        // keep the order in which records were first found, so the
+        // results don't depend on hashing.
+        let mut order = Vec::new();
+        let mut record_weights: HashMap<Box<[u8]>, Vec<i32>> = HashMap::new();
+        for (record_id, w) in pos_entries {
+            if neg_record_ids.contains(&record_id) {
+                continue;
+            }
+            match record_weights.entry(record_id.clone()) {
+                std::collections::hash_map::Entry::Occupied(mut e) => {
+                    e.get_mut().push(w);
+                },
+                std::collections::hash_map::Entry::Vacant(e) => {
+                    e.insert(vec![w]);
+                    order.push(record_id);
+                },
+            }
+        }
+
+        let mut res = Vec::new();
+        for record_id in order {
+            let k = encode_key_record(collection_id, &record_id);
+            match try!(get_value_for_key_as_bson(&mut cursor, &k)) {
+                Some(doc) => {
+                    let doc = doc.into_value();
+                    if elmo::check_text_phrases(&terms, &weights, &doc) {
+                        // TODO this is not the way mongo does this calculation
+                        let score = record_weights[&record_id].iter().sum::<i32>() as f64;
+                        let row = elmo::Row {
+                            doc: doc,
+                            pos: None,
+                            score: Some(score),
+                        };
+                        res.push(Ok(row));
+                    }
+                },
+                None => {
+                    return Err(elmo::Error::Misc(String::from("record id not found?!?")));
+                },
+            }
+        }
+
+        let rdr = 
+            MyCollectionReader {
+                seq: box res.into_iter(),
+            };
+        Ok(rdr)
     }
 
-    fn get_reader_regular_index_scan(&self, ndx: &elmo::IndexInfo, bounds: elmo::QueryBounds) -> Result<MyCollectionReader> {
+    // a cursor over the part of the index within the bounds, already on
+    // its first entry, or its last one for reverse.  for the _id index of
+    // a clustered collection, it is over the records themselves, and the
+    // bool says so.
+    fn get_index_range_cursor(&self, ndx: &elmo::IndexInfo, bounds: elmo::QueryBounds, reverse: bool) -> Result<(u64, bool, lsm::RangeCursor)> {
         let mut cursor = try!(self.conn.open_cursor().map_err(elmo::wrap_err));
         let collection_id = 
             match try!(get_value_for_key_as_varint(&mut cursor, &encode_key_name_to_collection_id(&ndx.db, &ndx.coll))) {
@@ -550,6 +886,7 @@ impl MyConn {
                 };
             !unique
         };
+        let collation = try!(ndx.collation_spec());
 
         fn add_one(a: &mut Vec<u8>) {
             let mut i = a.len() - 1;
@@ -577,15 +914,15 @@ impl MyConn {
             cursor
         }
 
-        fn f_two(has_recid: bool, preface: Vec<u8>, cursor: lsm::LivingCursor, eqvals: elmo::QueryKey, minvals: elmo::QueryKey, maxvals: elmo::QueryKey, min_cmp: lsm::OpGt, max_cmp: lsm::OpLt) -> lsm::RangeCursor {
+        fn f_two(has_recid: bool, preface: Vec<u8>, cursor: lsm::LivingCursor, eqvals: elmo::QueryKey, minvals: elmo::QueryKey, maxvals: elmo::QueryKey, min_cmp: lsm::OpGt, max_cmp: lsm::OpLt, c: Option<&bson::CollationSpec>) -> lsm::RangeCursor {
             let mut kmin = preface.clone();
-            bson::Value::push_encode_multi_for_index(&mut kmin, &eqvals, Some(&minvals));
+            bson::Value::push_encode_multi_for_index_collated(&mut kmin, &eqvals, Some(&minvals), c);
             if has_recid && min_cmp == lsm::OpGt::GT {
                 add_one(&mut kmin);
             }
 
             let mut kmax = preface;
-            bson::Value::push_encode_multi_for_index(&mut kmax, &eqvals, Some(&maxvals));
+            bson::Value::push_encode_multi_for_index_collated(&mut kmax, &eqvals, Some(&maxvals), c);
             if has_recid && max_cmp == lsm::OpLt::LTE {
                 add_one(&mut kmax);
             }
@@ -593,9 +930,9 @@ impl MyConn {
             f_twok(cursor, kmin, kmax, min_cmp, max_cmp)
         }
 
-        fn f_gt(has_recid: bool, preface: Vec<u8>, cursor: lsm::LivingCursor, vals: elmo::QueryKey, min_cmp: lsm::OpGt) -> lsm::RangeCursor {
+        fn f_gt(has_recid: bool, preface: Vec<u8>, cursor: lsm::LivingCursor, vals: elmo::QueryKey, min_cmp: lsm::OpGt, c: Option<&bson::CollationSpec>) -> lsm::RangeCursor {
             let mut kmin = preface.clone();
-            bson::Value::push_encode_multi_for_index(&mut kmin, &vals, None);
+            bson::Value::push_encode_multi_for_index_collated(&mut kmin, &vals, None, c);
             if has_recid && min_cmp == lsm::OpGt::GT {
                 add_one(&mut kmin);
             }
@@ -607,9 +944,9 @@ impl MyConn {
             f_twok(cursor, kmin, kmax, min_cmp, max_cmp)
         }
 
-        fn f_lt(has_recid: bool, preface: Vec<u8>, cursor: lsm::LivingCursor, vals: elmo::QueryKey, max_cmp: lsm::OpLt) -> lsm::RangeCursor {
+        fn f_lt(has_recid: bool, preface: Vec<u8>, cursor: lsm::LivingCursor, vals: elmo::QueryKey, max_cmp: lsm::OpLt, c: Option<&bson::CollationSpec>) -> lsm::RangeCursor {
             let mut kmax = preface.clone();
-            bson::Value::push_encode_multi_for_index(&mut kmax, &vals, None);
+            bson::Value::push_encode_multi_for_index_collated(&mut kmax, &vals, None, c);
             if has_recid && max_cmp == lsm::OpLt::LTE {
                 add_one(&mut kmax);
             }
@@ -620,27 +957,32 @@ impl MyConn {
             f_twok(cursor, kmin, kmax, min_cmp, max_cmp)
         }
 
-        let mut key_preface = vec![];
-        key_preface.push(INDEX_ENTRY);
-        misc::push_varint(&mut key_preface, collection_id);
-        misc::push_varint(&mut key_preface, index_id);
+        // for a clustered collection, the _id index is the records themselves
+        let clustered = is_clustered(&try!(get_collection_options(&mut cursor, collection_id)));
+        let scan_records = clustered && index_id == PRIMARY_INDEX_ID;
+        let key_preface =
+            if scan_records {
+                encode_key_collection_data_tag(collection_id, RECORD)
+            } else {
+                encode_key_index_entry_prefix(collection_id, index_id)
+            };
 
         let mut cursor =
             match bounds {
-                elmo::QueryBounds::GT(vals) => f_gt(has_recid, key_preface, cursor, vals, lsm::OpGt::GT),
-                elmo::QueryBounds::GTE(vals) => f_gt(has_recid, key_preface, cursor, vals, lsm::OpGt::GTE),
-                elmo::QueryBounds::LT(vals) => f_lt(has_recid, key_preface, cursor, vals, lsm::OpLt::LT),
-                elmo::QueryBounds::LTE(vals) => f_lt(has_recid, key_preface, cursor, vals, lsm::OpLt::LTE),
-                elmo::QueryBounds::GT_LT(eqvals, minvals, maxvals) => f_two(has_recid, key_preface, cursor, eqvals, minvals, maxvals, lsm::OpGt::GT, lsm::OpLt::LT),
-                elmo::QueryBounds::GTE_LT(eqvals, minvals, maxvals) => f_two(has_recid, key_preface, cursor, eqvals, minvals, maxvals, lsm::OpGt::GTE, lsm::OpLt::LT),
-                elmo::QueryBounds::GT_LTE(eqvals, minvals, maxvals) => f_two(has_recid, key_preface, cursor, eqvals, minvals, maxvals, lsm::OpGt::GT, lsm::OpLt::LTE),
-                elmo::QueryBounds::GTE_LTE(eqvals, minvals, maxvals) => f_two(has_recid, key_preface, cursor, eqvals, minvals, maxvals, lsm::OpGt::GTE, lsm::OpLt::LTE),
+                elmo::QueryBounds::GT(vals) => f_gt(has_recid, key_preface, cursor, vals, lsm::OpGt::GT, collation.as_ref()),
+                elmo::QueryBounds::GTE(vals) => f_gt(has_recid, key_preface, cursor, vals, lsm::OpGt::GTE, collation.as_ref()),
+                elmo::QueryBounds::LT(vals) => f_lt(has_recid, key_preface, cursor, vals, lsm::OpLt::LT, collation.as_ref()),
+                elmo::QueryBounds::LTE(vals) => f_lt(has_recid, key_preface, cursor, vals, lsm::OpLt::LTE, collation.as_ref()),
+                elmo::QueryBounds::GT_LT(eqvals, minvals, maxvals) => f_two(has_recid, key_preface, cursor, eqvals, minvals, maxvals, lsm::OpGt::GT, lsm::OpLt::LT, collation.as_ref()),
+                elmo::QueryBounds::GTE_LT(eqvals, minvals, maxvals) => f_two(has_recid, key_preface, cursor, eqvals, minvals, maxvals, lsm::OpGt::GTE, lsm::OpLt::LT, collation.as_ref()),
+                elmo::QueryBounds::GT_LTE(eqvals, minvals, maxvals) => f_two(has_recid, key_preface, cursor, eqvals, minvals, maxvals, lsm::OpGt::GT, lsm::OpLt::LTE, collation.as_ref()),
+                elmo::QueryBounds::GTE_LTE(eqvals, minvals, maxvals) => f_two(has_recid, key_preface, cursor, eqvals, minvals, maxvals, lsm::OpGt::GTE, lsm::OpLt::LTE, collation.as_ref()),
                 elmo::QueryBounds::EQ(vals) => {
                     // TODO if this is a unique index (which does not have the recid on the end of
                     // the key), we should maybe do this seek as Equal so the bloom filter
                     // can be used.
                     let mut kmin = key_preface.clone();
-                    bson::Value::push_encode_multi_for_index(&mut kmin, &vals, None);
+                    bson::Value::push_encode_multi_for_index_collated(&mut kmin, &vals, None, collation.as_ref());
 
                     let mut kmax = kmin.clone();
                     add_one(&mut kmax);
@@ -649,40 +991,98 @@ impl MyConn {
                 },
             };
 
-        try!(cursor.first().map_err(elmo::wrap_err));
+        if reverse {
+            try!(cursor.last().map_err(elmo::wrap_err));
+        } else {
+            try!(cursor.first().map_err(elmo::wrap_err));
+        }
+
+        Ok((collection_id, scan_records, cursor))
+    }
+
+    // DISTINCT. we don't want this producing the same record twice.
+    // the first time each one comes up is where it goes, so this
+    // keeps the order of the index, and nothing gets buffered.
+    fn distinct_record_ids(cursor: lsm::RangeCursor, reverse: bool) -> Box<Iterator<Item=Result<Box<[u8]>>>> {
         let seq = 
-            RangeCursorVarintIterator {
+            RangeCursorBoxValueIterator {
                 cursor: cursor,
+                reverse: reverse,
             };
+        let mut seen = HashSet::new();
+        box seq.filter(
+            move |r| match r {
+                &Ok(ref x) => seen.insert(x.clone()),
+                &Err(_) => true,
+            })
+    }
+
+    // the rows come in the order of the index, or the reverse of it
+    fn get_reader_regular_index_scan(&self, ndx: &elmo::IndexInfo, bounds: elmo::QueryBounds, reverse: bool) -> Result<MyCollectionReader> {
+        let (collection_id, scan_records, cursor) = try!(self.get_index_range_cursor(ndx, bounds, reverse));
+
+        if scan_records {
+            let seq = 
+                RangeCursorBsonValueIterator {
+                    cursor: cursor,
+                    reverse: reverse,
+                };
+            let rdr = 
+                MyCollectionReader {
+                    seq: box seq,
+                };
+            return Ok(rdr);
+        }
 
-        let seq = {
-            // DISTINCT. we don't want this producing the same record twice.
-            let q = try!(seq.collect::<Result<Vec<_>>>());
-            let mut seen = HashSet::new();
-            let mut a = vec![];
-            for x in q {
-                if !seen.contains(&x) {
-                    a.push(x);
-                    seen.insert(x);
-                }
-            }
-            a.into_iter().map(|x| Ok(x))
-        };
+        let seq = Self::distinct_record_ids(cursor, reverse);
+        self.get_records(collection_id, seq)
+    }
 
-        // the iterator above yields record ids.
-        // now we need something that, for each record id yielded by an
-        // index entry, looks up the actual record and yields THAT.  in
-        // sqlite, this was a join.
+    // the record ids for the same scan, without looking up the records
+    fn get_record_ids_regular_index_scan(&self, ndx: &elmo::IndexInfo, bounds: elmo::QueryBounds) -> Result<Box<Iterator<Item=Result<Box<[u8]>>>>> {
+        let (_, scan_records, cursor) = try!(self.get_index_range_cursor(ndx, bounds, false));
+
+        if scan_records {
+            // the record id of a clustered record is its encoded _id
+            let seq = 
+                RangeCursorBsonValueIterator {
+                    cursor: cursor,
+                    reverse: false,
+                };
+            let seq = seq.map(
+                |row: Result<elmo::Row>| -> Result<Box<[u8]>> {
+                    let row = try!(row);
+                    match try!(row.doc.as_document()).get("_id") {
+                        Some(id) => Ok(bson::Value::encode_one_for_index(id, false).into_boxed_slice()),
+                        None => Err(elmo::Error::Misc(String::from("clustered collection requires _id"))),
+                    }
+                });
+            return Ok(box seq);
+        }
+
+        Ok(Self::distinct_record_ids(cursor, false))
+    }
+
+    fn get_reader_records_by_id(&self, db: &str, coll: &str, ids: Vec<Box<[u8]>>) -> Result<MyCollectionReader> {
+        let mut cursor = try!(self.conn.open_cursor().map_err(elmo::wrap_err));
+        let collection_id = 
+            match try!(get_value_for_key_as_varint(&mut cursor, &encode_key_name_to_collection_id(db, coll))) {
+                Some(id) => id,
+                None => return Err(elmo::Error::Misc(String::from("collection does not exist"))),
+            };
+        self.get_records(collection_id, box ids.into_iter().map(|id| Ok(id)))
+    }
 
+    // the iterator given yields record ids (or encoded _ids, if clustered).
+    // now we need something that, for each record id, looks up the
+    // actual record and yields THAT.  in sqlite, this was a join.
+    fn get_records(&self, collection_id: u64, seq: Box<Iterator<Item=Result<Box<[u8]>>>>) -> Result<MyCollectionReader> {
         let mut cursor = try!(self.conn.open_cursor().map_err(elmo::wrap_err));
         let seq = seq.map(
-            move |record_id: Result<u64>| -> Result<elmo::Row> {
+            move |record_id: Result<Box<[u8]>>| -> Result<elmo::Row> {
                 match record_id {
                     Ok(record_id) => {
-                        let mut k = vec![];
-                        k.push(RECORD);
-                        misc::push_varint(&mut k, collection_id);
-                        misc::push_varint(&mut k, record_id);
+                        let k = encode_key_record(collection_id, &record_id);
                         try!(cursor.seek(&lsm::KeyRef::for_slice(&k), lsm::SeekOp::Equal).map_err(elmo::wrap_err));
                         if cursor.is_valid() {
                             let v = try!(cursor.value().map_err(elmo::wrap_err));
@@ -752,10 +1152,13 @@ impl MyConn {
         Ok(a)
     }
 
-    fn list_all_index_infos(&self) -> Result<Vec<elmo::IndexInfo>> {
+    // building picks between the indexes which are ready and the ones
+    // which are still being built.  the latter is not for anyone to see
+    // except whoever is finishing the build.
+    fn list_all_index_infos(&self, building: bool) -> Result<Vec<elmo::IndexInfo>> {
         let mut cursor = try!(self.conn.open_cursor().map_err(elmo::wrap_err));
         let indexes = try!(self.base_list_indexes(&mut cursor, None));
-        let indexes = indexes.into_iter().map(
+        let indexes = indexes.into_iter().filter(|&(_, _, ref props)| props.get("b").is_some() == building).map(
             |(collection_id, index_id, mut index_properties)| {
                 // TODO the extra lookup here is pretty expensive.
                 // maybe we should just store the db/coll names here too?
@@ -792,7 +1195,7 @@ impl MyConn {
             },
             Some(collection_id) => {
                 let indexes = try!(self.base_list_indexes(&mut cursor, Some(collection_id)));
-                let indexes = indexes.into_iter().map(
+                let indexes = indexes.into_iter().filter(|&(_, _, ref props)| props.get("b").is_none()).map(
                     |(collection_id, index_id, mut index_properties)| {
                         let name = try!(index_properties.must_remove_string("n"));
                         let spec = try!(index_properties.must_remove_document("s"));
@@ -858,7 +1261,79 @@ impl MyConn {
 
 }
 
+// look for a key in pending and then in each savepoint layer under it,
+// newest first.
+fn layered_get<'b>(pending: &'b BTreeMap<Box<[u8]>, lsm::ValueForStorage>, savepoints: &'b [BTreeMap<Box<[u8]>, lsm::ValueForStorage>], k: &[u8]) -> Option<&'b lsm::ValueForStorage> {
+    match pending.get(k) {
+        Some(v) => Some(v),
+        None => {
+            for layer in savepoints.iter().rev() {
+                if let Some(v) = layer.get(k) {
+                    return Some(v);
+                }
+            }
+            None
+        },
+    }
+}
+
 impl<'a> MyWriter<'a> {
+    // the cursor only sees what has been committed.  anything written
+    // earlier in this transaction is still sitting in pending, so look
+    // there first.
+    fn get_value_for_key(&mut self, k: &[u8]) -> Result<Option<Box<[u8]>>> {
+        match layered_get(&self.pending, &self.savepoints, k) {
+            Some(&lsm::ValueForStorage::Boxed(ref v)) => return Ok(Some(v.clone())),
+            Some(&lsm::ValueForStorage::Tombstone) => return Ok(None),
+            Some(_) => {
+                // we never put anything else in pending
+                unreachable!();
+            },
+            None => {
+            },
+        }
+        try!(self.cursor.seek(&lsm::KeyRef::for_slice(&k), lsm::SeekOp::Equal).map_err(elmo::wrap_err));
+        if self.cursor.is_valid() {
+            let v = try!(self.cursor.value().map_err(elmo::wrap_err));
+            let v = try!(v.map(lsm_map_to_box).map_err(elmo::wrap_err));
+            Ok(Some(v))
+        } else {
+            Ok(None)
+        }
+    }
+
+    // like find_record, but sees what this transaction has written so
+    // far, including records deleted in it.
+    fn find_pending_record(&mut self, collection_id: u64, clustered: bool, id: &bson::Value) -> Result<Option<Box<[u8]>>> {
+        let ba = bson::Value::encode_one_for_index(id, false);
+        if clustered {
+            let k = encode_key_record(collection_id, &ba);
+            match try!(self.get_value_for_key(&k)) {
+                Some(_) => Ok(Some(ba.into_boxed_slice())),
+                None => Ok(None),
+            }
+        } else {
+            let mut k = encode_key_index_entry_prefix(collection_id, PRIMARY_INDEX_ID);
+            k.extend_from_slice(&ba);
+            let record_id = try!(self.get_pending_value_for_key_as_varint(&k));
+            Ok(record_id.map(u64_to_boxed_varint))
+        }
+    }
+
+    fn get_pending_value_for_key_as_varint(&mut self, k: &[u8]) -> Result<Option<u64>> {
+        match try!(self.get_value_for_key(k)) {
+            Some(v) => Ok(Some(try!(lsm_map_to_varint(&v).map_err(elmo::wrap_err)))),
+            None => Ok(None),
+        }
+    }
+
+    fn get_pending_value_for_key_as_bson(&mut self, k: &[u8]) -> Result<Option<bson::Document>> {
+        match try!(self.get_value_for_key(k)) {
+            Some(v) => Ok(Some(try!(lsm_map_to_bson(&v).map_err(elmo::wrap_err)))),
+            None => Ok(None),
+        }
+    }
+
     fn use_next_index_id(&mut self, collection_id: u64) -> Result<u64> {
         match self.max_index_id.entry(collection_id) {
             std::collections::hash_map::Entry::Occupied(mut e) => {
@@ -904,16 +1379,14 @@ impl<'a> MyWriter<'a> {
             },
             std::collections::hash_map::Entry::Vacant(e) => {
                 let n = {
-                    // TODO capacity
-                    let mut k = vec![];
-                    k.push(RECORD);
-                    misc::push_varint(&mut k, collection_id + 1);
+                    let k = encode_key_collection_data_tag(collection_id, RECORD + 1);
                     try!(self.cursor.seek(&lsm::KeyRef::for_slice(&k), lsm::SeekOp::LessOrEqual).map_err(elmo::wrap_err));
                     if self.cursor.is_valid() {
                         let k = try!(self.cursor.key().map_err(elmo::wrap_err));
-                        if try!(k.u8_at(0).map_err(elmo::wrap_err)) == RECORD {
-                            let (k_collection_id, record_id) = try!(decode_key_record(&k));
-                            if collection_id == k_collection_id {
+                        if try!(k.u8_at(0).map_err(elmo::wrap_err)) == COLLECTION_DATA {
+                            let (k_collection_id, tag, _) = try!(decode_key_collection_data(&k));
+                            if collection_id == k_collection_id && tag == RECORD {
+                                let (_, record_id) = try!(decode_key_record(&k));
                                 1 + record_id
                             } else {
                                 1
@@ -960,7 +1433,21 @@ impl<'a> MyWriter<'a> {
     }
 
     fn list_indexes_for_collection_writer(&mut self, collection_id: u64) -> Result<Vec<MyIndexPrep>> {
-        let indexes = try!(self.myconn.base_list_indexes(&mut self.cursor, Some(collection_id)));
+        let mut indexes = try!(self.myconn.base_list_indexes(&mut self.cursor, Some(collection_id)));
+        // the cursor can't see indexes created or dropped earlier in this
+        // transaction, so fix up the list from pending.
+        // TODO this walks all of pending, and every savepoint layer
+        let prefix = encode_key_tag_and_varint(INDEX_ID_TO_PROPERTIES, collection_id);
+        for (k, v) in self.savepoints.iter().flat_map(|layer| layer.iter()).chain(self.pending.iter()) {
+            if k.starts_with(&prefix) {
+                let (_, index_id) = try!(decode_key_index_id_to_properties(&lsm::KeyRef::for_slice(k)));
+                indexes.retain(|&(_, id, _)| id != index_id);
+                if let &lsm::ValueForStorage::Boxed(ref v) = v {
+                    let props = try!(lsm_map_to_bson(v).map_err(elmo::wrap_err));
+                    indexes.push((collection_id, index_id, props));
+                }
+            }
+        }
         let indexes = indexes.into_iter().map(
             |(_, index_id, mut index_properties)| {
                 //let name = try!(index_properties.must_remove_string("n"));
@@ -980,11 +1467,13 @@ impl<'a> MyWriter<'a> {
                         _ => false,
                     };
                 let (normspec, weights) = try!(elmo::get_normalized_spec(&spec, &options));
+                let collation = try!(elmo::get_index_collation(&normspec, &weights, &options));
                 let prep = MyIndexPrep {
                     index_id: index_id,
                     options: options,
                     normspec: normspec,
                     weights: weights,
+                    collation: collation,
                 };
                 Ok(prep)
             }).collect::<Result<Vec<_>>>();
@@ -993,33 +1482,30 @@ impl<'a> MyWriter<'a> {
     }
 
     fn make_collection_writer(&mut self, db: &str, coll: &str) -> Result<MyCollectionWriter> {
-        let (just_created, collection_id) = try!(self.base_create_collection(db, coll, bson::Document::new()));
+        let (_created, collection_id) = try!(self.base_create_collection(db, coll, bson::Document::new()));
+        let k = encode_key_collection_id_to_properties(collection_id);
+        let options =
+            match try!(self.get_pending_value_for_key_as_bson(&k)) {
+                Some(mut collection_properties) => try!(collection_properties.must_remove_document("o")),
+                None => bson::Document::new(),
+            };
+        let clustered = is_clustered(&options);
+        let capped = try!(elmo::Capped::from_options(&options));
         let indexes = {
-            // TODO
-            // if the collection was just created, there will be no indexes found
-            // think of the following as a temporary hack.
-            // we currently have no way of including anything written during the
-            // current transaction in queries.
-            if just_created {
-                let spec = bson::Document {pairs: vec![(String::from("_id"), bson::Value::BInt32(1))]};
-                let options = bson::Document {pairs: vec![(String::from("unique"), bson::Value::BBoolean(true))]};
-                let (normspec, weights) = try!(elmo::get_normalized_spec(&spec, &options));
-                let prep = MyIndexPrep {
-                    index_id: PRIMARY_INDEX_ID,
-                    options: options,
-                    normspec: normspec,
-                    weights: weights,
-                };
-                vec![prep]
-            } else {
-                try!(self.list_indexes_for_collection_writer(collection_id))
-            }
+            // the collection may have been created earlier in this
+            // transaction, so its indexes may only be in pending.
+            // list_indexes_for_collection_writer() knows to look there.
+            let indexes = try!(self.list_indexes_for_collection_writer(collection_id));
+            // a clustered collection has no entries for the _id index
+            indexes.into_iter().filter(|ndx| !(clustered && ndx.index_id == PRIMARY_INDEX_ID)).collect()
         };
         let c = MyCollectionWriter {
             db: String::from(db),
             coll: String::from(coll),
             indexes: indexes,
             collection_id: collection_id,
+            clustered: clustered,
+            capped: capped,
         };
         Ok(c)
     }
@@ -1043,21 +1529,69 @@ impl<'a> MyWriter<'a> {
             self.cw = Some(cw);
         }
         // TODO this is an awful approach here.  clone.  temporary workaround.
-        match self.cw {
-            Some(ref cw) => {
-                Ok(cw.clone())
-            },
-            None => {
-                unreachable!();
-            },
+        let cw =
+            match self.cw {
+                Some(ref cw) => {
+                    cw.clone()
+                },
+                None => {
+                    unreachable!();
+                },
+            };
+        // whoever wants to write to the collection has to see what got
+        // bulk loaded into it
+        try!(self.unbulk(cw.collection_id));
+        Ok(cw)
+    }
+
+    // a bulk loaded segment is off to the side, where nothing else in
+    // this transaction looks, so before anything else touches that
+    // collection, its pairs get copied into the bottom layer of pending
+    // and the segment goes back.  nothing can have written to the
+    // collection since the load, so nothing newer gets clobbered.
+    fn unbulk(&mut self, collection_id: u64) -> Result<()> {
+        if !self.bulk.iter().any(|&(id, _)| id == collection_id) {
+            return Ok(());
+        }
+        let (mine, others): (Vec<_>, Vec<_>) = std::mem::replace(&mut self.bulk, vec![]).into_iter().partition(|&(id, _)| id == collection_id);
+        self.bulk = others;
+        for (_, seg) in mine {
+            {
+                let layer =
+                    if self.savepoints.is_empty() {
+                        &mut self.pending
+                    } else {
+                        &mut self.savepoints[0]
+                    };
+                let mut cursor = try!(self.myconn.conn.open_cursor_on_page(seg.root_page).map_err(elmo::wrap_err));
+                try!(cursor.first().map_err(elmo::wrap_err));
+                while cursor.is_valid() {
+                    {
+                        let k = try!(cursor.key().map_err(elmo::wrap_err)).into_boxed_slice();
+                        let v = try!(cursor.value().map_err(elmo::wrap_err));
+                        let v = try!(v.map(lsm_map_to_box).map_err(elmo::wrap_err));
+                        layer.insert(k, lsm::ValueForStorage::Boxed(v));
+                    }
+                    try!(cursor.next().map_err(elmo::wrap_err));
+                }
+            }
+            try!(self.myconn.conn.release_pending_segment(seg).map_err(elmo::wrap_err));
         }
+        Ok(())
     }
 
     fn delete_by_prefix(&mut self, prefix: Box<[u8]>) -> Result<()> {
         // TODO it would be nice if lsm had a "graveyard" delete, a way to do a
         // blind delete by prefix.
 
-        let mut cursor = lsm::PrefixCursor::new(&mut self.cursor, prefix);
+        // the prefix may cover a bulk loaded collection, and the cursor
+        // can't see those
+        let loaded = self.bulk.iter().map(|&(id, _)| id).collect::<Vec<_>>();
+        for collection_id in loaded {
+            try!(self.unbulk(collection_id));
+        }
+
+        let mut cursor = lsm::PrefixCursor::new(&mut self.cursor, prefix.clone());
         try!(cursor.first().map_err(elmo::wrap_err));
         while cursor.is_valid() {
             {
@@ -1068,6 +1602,18 @@ impl<'a> MyWriter<'a> {
             try!(cursor.next().map_err(elmo::wrap_err));
         }
 
+        // and whatever this transaction wrote under it, like the totals
+        // of a capped collection
+        let written =
+            self.savepoints.iter().chain(std::iter::once(&self.pending))
+            .flat_map(|layer| layer.keys())
+            .filter(|k| k.starts_with(&prefix))
+            .cloned()
+            .collect::<Vec<_>>();
+        for k in written {
+            self.pending.insert(k, lsm::ValueForStorage::Tombstone);
+        }
+
         Ok(())
     }
 
@@ -1078,6 +1624,11 @@ impl<'a> MyWriter<'a> {
         self.delete_by_prefix(k.into_boxed_slice())
     }
 
+    fn delete_collection_data(&mut self, collection_id: u64) -> Result<()> {
+        let k = encode_key_collection_data(collection_id);
+        self.delete_by_prefix(k.into_boxed_slice())
+    }
+
     fn delete_by_index_id_prefix(&mut self, tag: u8, collection_id: u64, index_id: u64) -> Result<()> {
         let mut k = vec![];
         k.push(tag);
@@ -1088,32 +1639,29 @@ impl<'a> MyWriter<'a> {
 
     fn base_clear_collection(&mut self, db: &str, coll: &str) -> Result<bool> {
         let k = encode_key_name_to_collection_id(db, coll);
-        match try!(get_value_for_key_as_varint(&mut self.cursor, &k)) {
+        match try!(self.get_pending_value_for_key_as_varint(&k)) {
             None => {
                 // TODO base_created_collection checks AGAIN to see if the collection exists
                 let (created, _) = try!(self.base_create_collection(db, coll, bson::Document::new()));
                 Ok(created)
             },
             Some(collection_id) => {
-                // all of the following tags are followed immediately by the
-                // collection_id, so we can delete by prefix:
-
-                try!(self.delete_by_collection_id_prefix(RECORD, collection_id));
-                try!(self.delete_by_collection_id_prefix(INDEX_ENTRY, collection_id));
+                try!(self.delete_collection_data(collection_id));
+                self.capped.remove(&collection_id);
 
                 Ok(false)
             },
         }
     }
 
-    fn create_index(&mut self, info: elmo::IndexInfo) -> Result<bool> {
+    fn create_index(&mut self, mut info: elmo::IndexInfo, building: bool) -> Result<bool> {
         //println!("create_index: {:?}", info);
         let (_created, collection_id) = try!(self.base_create_collection(&info.db, &info.coll, bson::Document::new()));
         let k = encode_key_name_to_index_id(collection_id, &info.name);
-        match try!(get_value_for_key_as_varint(&mut self.cursor, &k)) {
+        match try!(self.get_pending_value_for_key_as_varint(&k)) {
             Some(index_id) => {
                 let k = encode_key_index_id_to_properties(collection_id, index_id);
-                let mut index_properties = try!(get_value_for_key_as_bson(&mut self.cursor, &k)).unwrap_or(bson::Document::new());
+                let mut index_properties = try!(self.get_pending_value_for_key_as_bson(&k)).unwrap_or(bson::Document::new());
                 //let name = try!(index_properties.must_remove_string("n"));
                 let spec = try!(index_properties.must_remove_document("s"));
                 //let options = try!(index_properties.must_remove_document("o"));
@@ -1129,48 +1677,73 @@ impl<'a> MyWriter<'a> {
                 let index_id = try!(self.use_next_index_id(collection_id));
                 self.pending.insert(k.into_boxed_slice(), lsm::ValueForStorage::Boxed(u64_to_boxed_varint(index_id)));
 
-                // now create entries for all the existing records
-
-                let unique = 
-                    match info.options.get("unique") {
-                        Some(&bson::Value::BBoolean(b)) => b,
-                        _ => false,
-                    };
-                let (normspec, weights) = try!(elmo::get_normalized_spec(&info.spec, &info.options));
+                // now create entries for all the existing records, unless
+                // this is a background build, which adds them later.
 
-                let mut k = vec![];
-                k.push(RECORD);
-                misc::push_varint(&mut k, collection_id);
-                let mut cursor = lsm::PrefixCursor::new(&mut self.cursor, k.into_boxed_slice());
-                try!(cursor.first().map_err(elmo::wrap_err));
-                while cursor.is_valid() {
-                    {
-                        let k = try!(cursor.key().map_err(elmo::wrap_err));
-                        let (_, record_id) = try!(decode_key_record(&k));
-                        let ba_record_id = u64_to_boxed_varint(record_id);
-                        let v = try!(cursor.value().map_err(elmo::wrap_err));
-                        let v = try!(v.map(lsm_map_to_bson).map_err(elmo::wrap_err));
-                        let entries = try!(elmo::get_index_entries(&v, &normspec, &weights, &info.options));
-                        let ba_collection_id = u64_to_boxed_varint(collection_id);
-                        let ba_index_id = u64_to_boxed_varint(index_id);
-                        for vals in entries {
-                            let index_entry = try!(Self::make_index_entry(&ba_collection_id, &ba_index_id, &ba_record_id, vals, unique));
-                            self.pending.insert(index_entry, lsm::ValueForStorage::Boxed(ba_record_id.clone()));
+                let mut multikey = false;
+                if !building {
+                    let unique = 
+                        match info.options.get("unique") {
+                            Some(&bson::Value::BBoolean(b)) => b,
+                            _ => false,
+                        };
+                    let (normspec, weights) = try!(elmo::get_normalized_spec(&info.spec, &info.options));
+                    let collation = try!(elmo::get_index_collation(&normspec, &weights, &info.options));
+
+                    let k = encode_key_collection_data_tag(collection_id, RECORD);
+                    let prefix_len = k.len();
+                    let mut cursor = lsm::PrefixCursor::new(&mut self.cursor, k.into_boxed_slice());
+                    try!(cursor.first().map_err(elmo::wrap_err));
+                    while cursor.is_valid() {
+                        {
+                            let k = try!(cursor.key().map_err(elmo::wrap_err)).into_boxed_slice();
+                            // the rest of the key is the recid, or the encoded _id
+                            let ba_record_id = k[prefix_len ..].to_vec().into_boxed_slice();
+                            let v = try!(cursor.value().map_err(elmo::wrap_err));
+                            let v = try!(v.map(lsm_map_to_bson).map_err(elmo::wrap_err));
+                            let entries = try!(elmo::get_index_entries(&v, &normspec, &weights, &info.options));
+                            multikey = multikey || elmo::is_multikey_doc(&v, &normspec);
+                            let ba_collection_id = u64_to_boxed_varint(collection_id);
+                            let ba_index_id = u64_to_boxed_varint(index_id);
+                            for vals in entries {
+                                let index_entry = try!(Self::make_index_entry(&ba_collection_id, &ba_index_id, &ba_record_id, vals, unique, collation.as_ref()));
+                                if unique {
+                                    // the index is brand new, so anything it
+                                    // already contains is in pending.
+                                    match self.pending.get(&index_entry) {
+                                        Some(&lsm::ValueForStorage::Boxed(ref other)) if *other != ba_record_id => {
+                                            return Err(elmo::Error::MongoCode(11000, String::from("duplicate key error building unique index")));
+                                        },
+                                        _ => {
+                                        },
+                                    }
+                                }
+                                self.pending.insert(index_entry, lsm::ValueForStorage::Boxed(ba_record_id.clone()));
+                            }
                         }
+
+                        try!(cursor.next().map_err(elmo::wrap_err));
                     }
-
-                    try!(cursor.next().map_err(elmo::wrap_err));
-                }
+                }
 
                 // now store the index id to properties
 
+                if multikey {
+                    info.options.set_bool("multikey", true);
+                }
                 let k = encode_key_index_id_to_properties(collection_id, index_id);
                 let mut properties = bson::Document::new();
                 properties.set_string("n", info.name);
                 properties.set_document("s", info.spec);
                 properties.set_document("o", info.options);
+                if building {
+                    properties.set_bool("b", true);
+                }
                 self.pending.insert(k.into_boxed_slice(), lsm::ValueForStorage::Boxed(properties.to_bson_array().into_boxed_slice()));
 
+                // the cached collection writer doesn't know about this index
+                self.cw = None;
+
                 Ok(true)
             }
         }
@@ -1179,7 +1752,7 @@ impl<'a> MyWriter<'a> {
     fn base_create_indexes(&mut self, what: Vec<elmo::IndexInfo>) -> Result<Vec<bool>> {
         let mut v = Vec::new();
         for info in what {
-            let b = try!(self.create_index(info));
+            let b = try!(self.create_index(info, false));
             v.push(b);
         }
         Ok(v)
@@ -1187,10 +1760,11 @@ impl<'a> MyWriter<'a> {
 
     fn base_drop_collection(&mut self, db: &str, coll: &str) -> Result<bool> {
         let k = encode_key_name_to_collection_id(db, coll);
-        match try!(get_value_for_key_as_varint(&mut self.cursor, &k)) {
+        match try!(self.get_pending_value_for_key_as_varint(&k)) {
             None => Ok(false),
             Some(collection_id) => {
                 self.pending.insert(k, lsm::ValueForStorage::Tombstone);
+                self.cw = None;
  
                 // all of the following tags are followed immediately by the
                 // collection_id, so we can delete by prefix:
@@ -1198,8 +1772,9 @@ impl<'a> MyWriter<'a> {
                 try!(self.delete_by_collection_id_prefix(COLLECTION_ID_TO_PROPERTIES, collection_id));
                 try!(self.delete_by_collection_id_prefix(NAME_TO_INDEX_ID, collection_id));
                 try!(self.delete_by_collection_id_prefix(INDEX_ID_TO_PROPERTIES, collection_id));
-                try!(self.delete_by_collection_id_prefix(RECORD, collection_id));
-                try!(self.delete_by_collection_id_prefix(INDEX_ENTRY, collection_id));
+
+                // and all the records and index entries are one range
+                try!(self.delete_collection_data(collection_id));
 
                 Ok(true)
             },
@@ -1224,7 +1799,7 @@ impl<'a> MyWriter<'a> {
             let _deleted = try!(self.base_drop_collection(new_db, new_coll));
         } else {
             let k = encode_key_name_to_collection_id(new_db, new_coll);
-            match try!(get_value_for_key_as_varint(&mut self.cursor, &k)) {
+            match try!(self.get_pending_value_for_key_as_varint(&k)) {
                 None => {
                     // fine
                 },
@@ -1236,7 +1811,7 @@ impl<'a> MyWriter<'a> {
         }
 
         let k = encode_key_name_to_collection_id(old_db, old_coll);
-        match try!(get_value_for_key_as_varint(&mut self.cursor, &k)) {
+        match try!(self.get_pending_value_for_key_as_varint(&k)) {
             None => {
                 let created = try!(self.base_create_collection(new_db, new_coll, bson::Document::new()));
                 Ok(created)
@@ -1246,9 +1821,10 @@ impl<'a> MyWriter<'a> {
 
                 let k = encode_key_name_to_collection_id(new_db, new_coll);
                 self.pending.insert(k, lsm::ValueForStorage::Boxed(u64_to_boxed_varint(collection_id)));
+                self.cw = None;
 
                 let k = encode_key_collection_id_to_properties(collection_id);
-                match try!(get_value_for_key_as_bson(&mut self.cursor, &k)) {
+                match try!(self.get_pending_value_for_key_as_bson(&k)) {
                     Some(mut collection_properties) => {
                         collection_properties.set_str("d", new_db);
                         collection_properties.set_str("c", new_coll);
@@ -1277,17 +1853,19 @@ impl<'a> MyWriter<'a> {
     }
 
     fn base_drop_index(&mut self, db: &str, coll: &str, name: &str) -> Result<bool> {
-        match try!(get_value_for_key_as_varint(&mut self.cursor, &encode_key_name_to_collection_id(&db, &coll))) {
+        match try!(self.get_pending_value_for_key_as_varint(&encode_key_name_to_collection_id(&db, &coll))) {
             None => Ok(false),
             Some(collection_id) => {
                 let k = encode_key_name_to_index_id(collection_id, name);
-                match try!(get_value_for_key_as_varint(&mut self.cursor, &k)) {
+                match try!(self.get_pending_value_for_key_as_varint(&k)) {
                     None => Ok(false),
                     Some(index_id) => {
                         self.pending.insert(k.into_boxed_slice(), lsm::ValueForStorage::Tombstone);
+                        self.cw = None;
 
                         try!(self.delete_by_index_id_prefix(INDEX_ID_TO_PROPERTIES, collection_id, index_id));
-                        try!(self.delete_by_index_id_prefix(INDEX_ENTRY, collection_id, index_id));
+                        let k = encode_key_index_entry_prefix(collection_id, index_id);
+                        try!(self.delete_by_prefix(k.into_boxed_slice()));
 
                         Ok(true)
                     },
@@ -1298,7 +1876,7 @@ impl<'a> MyWriter<'a> {
 
     fn base_create_collection(&mut self, db: &str, coll: &str, options: bson::Document) -> Result<(bool, u64)> {
         let k = encode_key_name_to_collection_id(db, coll);
-        match try!(get_value_for_key_as_varint(&mut self.cursor, &k)) {
+        match try!(self.get_pending_value_for_key_as_varint(&k)) {
             Some(id) => Ok((false, id)),
             None => {
                 let collection_id = try!(self.use_next_collection_id());
@@ -1317,7 +1895,7 @@ impl<'a> MyWriter<'a> {
                         let mut properties = bson::Document::new();
                         properties.set_str("n", "_id_");
                         let spec = bson::Document {pairs: vec![(String::from("_id"), bson::Value::BInt32(1))]};
-                        let options = bson::Document {pairs: vec![(String::from("unique"), bson::Value::BBoolean(true))]};
+                        let options = bson::Document {pairs: vec![(String::from("unique"), bson::Value::BBoolean(true)), (String::from("multikey"), bson::Value::BBoolean(false))]};
                         properties.set_document("s", spec);
                         properties.set_document("o", options);
                         self.pending.insert(k.into_boxed_slice(), lsm::ValueForStorage::Boxed(properties.to_bson_array().into_boxed_slice()));
@@ -1336,13 +1914,14 @@ impl<'a> MyWriter<'a> {
         }
     }
 
-    fn make_index_entry(ba_collection_id: &Box<[u8]>, ba_index_id: &Box<[u8]>, ba_record_id: &Box<[u8]>, vals: Vec<(bson::Value, bool)>, unique: bool) -> Result<Box<[u8]>> {
+    fn make_index_entry(ba_collection_id: &Box<[u8]>, ba_index_id: &Box<[u8]>, ba_record_id: &Box<[u8]>, vals: Vec<(bson::Value, bool)>, unique: bool, collation: Option<&bson::CollationSpec>) -> Result<Box<[u8]>> {
         let vref = vals.iter().map(|&(ref v,neg)| (v,neg)).collect::<Vec<_>>();
-        let k = bson::Value::encode_multi_for_index(&vref, None);
+        let k = bson::Value::encode_multi_for_index_collated(&vref, None, collation);
         // TODO capacity
         let mut index_entry = vec![];
-        index_entry.push(INDEX_ENTRY);
+        index_entry.push(COLLECTION_DATA);
         index_entry.extend_from_slice(ba_collection_id);
+        index_entry.push(INDEX_ENTRY);
         index_entry.extend_from_slice(ba_index_id);
         index_entry.extend_from_slice(&k);
         if !unique {
@@ -1365,13 +1944,40 @@ impl<'a> MyWriter<'a> {
             // TODO store this in the cache?
             let ba_index_id = u64_to_boxed_varint(ndx.index_id);
             for vals in entries {
-                let index_entry = try!(Self::make_index_entry(ba_collection_id, &ba_index_id, ba_record_id, vals, unique));
+                let index_entry = try!(Self::make_index_entry(ba_collection_id, &ba_index_id, ba_record_id, vals, unique, ndx.collation.as_ref()));
                 a.push(index_entry);
             }
         }
         Ok(a)
     }
 
+    fn check_unique(&mut self, indexes: &Vec<MyIndexPrep>, ba_collection_id: &Box<[u8]>, ba_record_id: &Box<[u8]>, v: &bson::Document) -> Result<()> {
+        // an entry in a unique index does not have the record id on the end
+        // of its key, so if the key is already there pointing at some other
+        // record, that's a duplicate.  this has to happen before anything
+        // for this doc goes into pending.
+        // TODO this computes the index entries twice
+        let unique_indexes = indexes.iter().filter(|ndx| {
+            match ndx.options.get("unique") {
+                Some(&bson::Value::BBoolean(b)) => b,
+                _ => false,
+            }
+        }).cloned().collect::<Vec<_>>();
+        let a = try!(Self::get_index_entries(&unique_indexes, ba_collection_id, ba_record_id, v));
+        for e in a {
+            match try!(self.get_value_for_key(&e)) {
+                Some(other) => {
+                    if other != *ba_record_id {
+                        return Err(elmo::Error::MongoCode(11000, String::from("duplicate key error")));
+                    }
+                },
+                None => {
+                },
+            }
+        }
+        Ok(())
+    }
+
     fn update_indexes_delete(&mut self, indexes: &Vec<MyIndexPrep>, ba_collection_id: &Box<[u8]>, ba_record_id: &Box<[u8]>, v: &bson::Document) -> Result<()> {
         let a = try!(Self::get_index_entries(indexes, ba_collection_id, ba_record_id, v));
         for e in a {
@@ -1380,8 +1986,30 @@ impl<'a> MyWriter<'a> {
         Ok(())
     }
 
-    fn update_indexes_insert(&mut self, indexes: &Vec<MyIndexPrep>, ba_collection_id: &Box<[u8]>, ba_record_id: &Box<[u8]>, v: &bson::Document) -> Result<()> {
+    // the first doc with an array for one of the keys makes the index
+    // multikey for good.
+    fn set_index_multikey(&mut self, collection_id: u64, ndx: &mut MyIndexPrep) -> Result<()> {
+        ndx.options.set_bool("multikey", true);
+        let k = encode_key_index_id_to_properties(collection_id, ndx.index_id);
+        match try!(self.get_pending_value_for_key_as_bson(&k)) {
+            Some(mut properties) => {
+                properties.set_document("o", ndx.options.clone());
+                self.pending.insert(k.into_boxed_slice(), lsm::ValueForStorage::Boxed(properties.to_bson_array().into_boxed_slice()));
+                // the cached collection writer still has the old options
+                self.cw = None;
+                Ok(())
+            },
+            None => Err(elmo::Error::Misc(String::from("index does not exist"))),
+        }
+    }
+
+    fn update_indexes_insert(&mut self, collection_id: u64, indexes: &mut Vec<MyIndexPrep>, ba_collection_id: &Box<[u8]>, ba_record_id: &Box<[u8]>, v: &bson::Document) -> Result<()> {
         let a = try!(Self::get_index_entries(indexes, ba_collection_id, ba_record_id, v));
+        for ndx in indexes.iter_mut() {
+            if !elmo::is_multikey(&ndx.options) && elmo::is_multikey_doc(v, &ndx.normspec) {
+                try!(self.set_index_multikey(collection_id, ndx));
+            }
+        }
         for e in a {
             // TODO it might be better here to always put the record id on the
             // end of the key and store nothing in the value.  maybe introduce
@@ -1394,6 +2022,382 @@ impl<'a> MyWriter<'a> {
         Ok(())
     }
 
+    // anything under the collection at all, committed or pending, even a
+    // tombstone, means no.
+    fn collection_is_empty(&mut self, collection_id: u64) -> Result<bool> {
+        let prefix = encode_key_collection_data(collection_id);
+        if self.savepoints.iter().flat_map(|layer| layer.keys()).chain(self.pending.keys()).any(|k| k.starts_with(&prefix)) {
+            return Ok(false);
+        }
+        let mut cursor = lsm::PrefixCursor::new(&mut self.cursor, prefix.into_boxed_slice());
+        try!(cursor.first().map_err(elmo::wrap_err));
+        Ok(!cursor.is_valid())
+    }
+
+    // a capped collection from before the totals were stored has to
+    // count them once.  this has to happen before anything this
+    // transaction does to the collection goes into pending.
+    fn load_capped_totals(&mut self, collection_id: u64) -> Result<CappedRecords> {
+        let k = encode_key_collection_data_tag(collection_id, CAPPED_TOTALS);
+        if let Some(totals) = try!(self.get_pending_value_for_key_as_bson(&k)) {
+            let count = try!(try!(totals.must_get("n")).numeric_to_i64()) as u64;
+            let bytes = try!(try!(totals.must_get("b")).numeric_to_i64()) as u64;
+            return Ok(CappedRecords {
+                count: count,
+                bytes: bytes,
+                evicted: None,
+            });
+        }
+        let prefix = encode_key_collection_data_tag(collection_id, RECORD);
+        let mut records = BTreeMap::new();
+        {
+            let mut cursor = lsm::PrefixCursor::new(&mut self.cursor, prefix.clone().into_boxed_slice());
+            try!(cursor.first().map_err(elmo::wrap_err));
+            while cursor.is_valid() {
+                {
+                    let k = try!(cursor.key().map_err(elmo::wrap_err));
+                    let v = try!(cursor.value().map_err(elmo::wrap_err));
+                    let len = try!(v.map(|ba| Ok(ba.len() as u64)).map_err(elmo::wrap_err));
+                    records.insert(k.into_boxed_slice(), len);
+                }
+                try!(cursor.next().map_err(elmo::wrap_err));
+            }
+        }
+        // then whatever this transaction did on top of that, oldest layer
+        // first
+        for (k, v) in self.savepoints.iter().flat_map(|layer| layer.iter()).chain(self.pending.iter()) {
+            if k.starts_with(&prefix) {
+                match v {
+                    &lsm::ValueForStorage::Boxed(ref ba) => {
+                        records.insert(k.clone(), ba.len() as u64);
+                    },
+                    &lsm::ValueForStorage::Tombstone => {
+                        records.remove(k);
+                    },
+                    _ => {
+                        // we never put anything else in pending
+                        unreachable!();
+                    },
+                }
+            }
+        }
+        let bytes = records.values().fold(0, |sum, len| sum + len);
+        Ok(CappedRecords {
+            count: records.len() as u64,
+            bytes: bytes,
+            evicted: None,
+        })
+    }
+
+    fn capped_totals(&mut self, collection_id: u64) -> Result<&mut CappedRecords> {
+        if !self.capped.contains_key(&collection_id) {
+            let recs = try!(self.load_capped_totals(collection_id));
+            self.capped.insert(collection_id, recs);
+        }
+        Ok(self.capped.get_mut(&collection_id).unwrap())
+    }
+
+    fn save_capped_totals(&mut self, collection_id: u64) {
+        if let Some(recs) = self.capped.get(&collection_id) {
+            let mut totals = bson::Document::new();
+            totals.set_i64("n", recs.count as i64);
+            totals.set_i64("b", recs.bytes as i64);
+            let k = encode_key_collection_data_tag(collection_id, CAPPED_TOTALS);
+            self.pending.insert(k.into_boxed_slice(), lsm::ValueForStorage::Boxed(totals.to_bson_array().into_boxed_slice()));
+        }
+    }
+
+    // the key of the oldest record in a capped collection which is still
+    // there, past the one evicted last.  record keys are in the order the
+    // docs were inserted, so that's the lowest one, either committed or
+    // written by this transaction.
+    fn oldest_capped_record(&mut self, collection_id: u64, evicted: Option<&[u8]>) -> Result<Option<Box<[u8]>>> {
+        let prefix = encode_key_collection_data_tag(collection_id, RECORD).into_boxed_slice();
+        let start = evicted.unwrap_or(&prefix[..]).to_vec().into_boxed_slice();
+        let committed = {
+            let mut found = None;
+            try!(self.cursor.seek(&lsm::KeyRef::for_slice(&start), lsm::SeekOp::GreaterOrEqual).map_err(elmo::wrap_err));
+            while self.cursor.is_valid() {
+                let k = try!(self.cursor.key().map_err(elmo::wrap_err)).into_boxed_slice();
+                if !k.starts_with(&prefix) {
+                    break;
+                }
+                // skip what this transaction already deleted
+                let gone =
+                    match layered_get(&self.pending, &self.savepoints, &k) {
+                        Some(&lsm::ValueForStorage::Tombstone) => true,
+                        _ => evicted == Some(&*k),
+                    };
+                if !gone {
+                    found = Some(k);
+                    break;
+                }
+                try!(self.cursor.next().map_err(elmo::wrap_err));
+            }
+            found
+        };
+        let mut written: Option<Box<[u8]>> = None;
+        for layer in self.savepoints.iter().chain(std::iter::once(&self.pending)) {
+            for (k, _) in layer.range::<[u8], _>((Bound::Excluded(&*start), Bound::Unbounded)) {
+                if !k.starts_with(&prefix) {
+                    break;
+                }
+                // a newer layer may have deleted it
+                if let Some(&lsm::ValueForStorage::Boxed(_)) = layered_get(&self.pending, &self.savepoints, k) {
+                    if written.as_ref().map_or(true, |w| k < w) {
+                        written = Some(k.clone());
+                    }
+                    break;
+                }
+            }
+        }
+        match (committed, written) {
+            (Some(c), Some(w)) => Ok(Some(if c < w { c } else { w })),
+            (c, w) => Ok(c.or(w)),
+        }
+    }
+
+    // called after a doc went into a capped collection, and into its
+    // totals.  the oldest docs are the ones with the lowest record ids.
+    fn evict_capped(&mut self, cw: &MyCollectionWriter, capped: &elmo::Capped) -> Result<()> {
+        let prefix_len = encode_key_collection_data_tag(cw.collection_id, RECORD).len();
+        let ba_collection_id = u64_to_boxed_varint(cw.collection_id);
+        loop {
+            let evicted = {
+                let recs = try!(self.capped_totals(cw.collection_id));
+                if !capped.is_over(recs.count, recs.bytes) {
+                    break;
+                }
+                recs.evicted.clone()
+            };
+            let victim =
+                match try!(self.oldest_capped_record(cw.collection_id, evicted.as_ref().map(|k| &**k))) {
+                    Some(k) => k,
+                    None => break,
+                };
+            // it may still be in pending, so don't ask the cursor
+            let old =
+                match try!(self.get_value_for_key(&victim)) {
+                    Some(ba) => ba,
+                    None => return Err(elmo::Error::Misc(String::from("capped record went missing"))),
+                };
+            {
+                let recs = try!(self.capped_totals(cw.collection_id));
+                recs.count = recs.count - 1;
+                recs.bytes = recs.bytes - old.len() as u64;
+                recs.evicted = Some(victim.clone());
+            }
+            let old = try!(lsm_map_to_bson(&old).map_err(elmo::wrap_err));
+            let ba_record_id = victim[prefix_len ..].to_vec().into_boxed_slice();
+            self.pending.insert(victim, lsm::ValueForStorage::Tombstone);
+            try!(self.update_indexes_delete(&cw.indexes, &ba_collection_id, &ba_record_id, &old));
+        }
+        self.save_capped_totals(cw.collection_id);
+        Ok(())
+    }
+
+    // records and index entries for every doc go through an external
+    // sort and then straight into a segment of their own, skipping
+    // pending.  each pair carries the position of its doc in front of
+    // its value, so a doc which collides with an earlier one can be left
+    // out of the segment entirely.  the keys which have to be unique go
+    // through a sort of their own first, to find those docs before
+    // anything gets written.
+    fn bulk_load(&mut self, cw: &MyCollectionWriter, docs: &mut Iterator<Item=Result<bson::Document>>) -> Result<Vec<Result<()>>> {
+        let ba_collection_id = u64_to_boxed_varint(cw.collection_id);
+        let mut sorter = sort::pair_sorter(BULK_SORT_MEMORY);
+        let mut unique_sorter = sort::pair_sorter(BULK_SORT_MEMORY);
+        let mut results = Vec::new();
+        let (unique_indexes, other_indexes): (Vec<_>, Vec<_>) = cw.indexes.iter().cloned().partition(|ndx| {
+            match ndx.options.get("unique") {
+                Some(&bson::Value::BBoolean(b)) => b,
+                _ => false,
+            }
+        });
+        let mut indexes = cw.indexes.clone();
+        for r in docs {
+            match r {
+                Ok(v) => {
+                    let ba_record_id =
+                        if cw.clustered {
+                            match v.get("_id") {
+                                None => {
+                                    results.push(Err(elmo::Error::Misc(String::from("clustered collection requires _id"))));
+                                    continue;
+                                },
+                                Some(id) => bson::Value::encode_one_for_index(id, false).into_boxed_slice(),
+                            }
+                        } else {
+                            let record_id = try!(self.use_next_record_id(cw.collection_id));
+                            u64_to_boxed_varint(record_id)
+                        };
+                    let pos = results.len() as u64;
+                    let k = encode_key_record(cw.collection_id, &ba_record_id).into_boxed_slice();
+                    if cw.clustered {
+                        // the record key is the _id
+                        try!(unique_sorter.push((k.clone(), u64_to_boxed_varint(pos))));
+                    }
+                    try!(sorter.push((k, with_position(pos, &v.to_bson_array()))));
+                    for e in try!(Self::get_index_entries(&unique_indexes, &ba_collection_id, &ba_record_id, &v)) {
+                        try!(unique_sorter.push((e.clone(), u64_to_boxed_varint(pos))));
+                        try!(sorter.push((e, with_position(pos, &ba_record_id))));
+                    }
+                    for e in try!(Self::get_index_entries(&other_indexes, &ba_collection_id, &ba_record_id, &v)) {
+                        try!(sorter.push((e, with_position(pos, &ba_record_id))));
+                    }
+                    for ndx in indexes.iter_mut() {
+                        if !elmo::is_multikey(&ndx.options) && elmo::is_multikey_doc(&v, &ndx.normspec) {
+                            try!(self.set_index_multikey(cw.collection_id, ndx));
+                        }
+                    }
+                    results.push(Ok(()));
+                },
+                Err(e) => {
+                    results.push(Err(e));
+                },
+            }
+        }
+
+        let losers = try!(Self::find_bulk_losers(unique_sorter));
+        for &pos in losers.iter() {
+            results[pos as usize] = Err(elmo::Error::MongoCode(11000, String::from("duplicate key error")));
+        }
+
+        let mut source = BulkSource {
+            merged: try!(sorter.finish()),
+            losers: losers,
+            prev: None,
+            err: None,
+        };
+        let seg = self.myconn.conn.write_segment_from_sorted_sequence(&mut source);
+        if let Some(e) = source.err {
+            return Err(e);
+        }
+        if let Some(seg) = try!(seg.map_err(elmo::wrap_err)) {
+            self.bulk.push((cw.collection_id, seg));
+        }
+        Ok(results)
+    }
+
+    // the docs which share a unique key come out of the sort next to each
+    // other, earliest first.  the first one to get a key keeps it, unless
+    // that doc already lost out over some other key, the same as if they
+    // had gone in one at a time.  so a doc can only be judged after every
+    // doc before it.  collisions are rare, so the ones we find just go
+    // into memory for that.
+    fn find_bulk_losers(sorter: sort::PairSorter) -> Result<HashSet<u64>> {
+        let mut groups: Vec<Vec<u64>> = vec![];
+        let mut prev: Option<Box<[u8]>> = None;
+        let mut prev_pos = 0;
+        let mut in_group = false;
+        for r in try!(sorter.finish()) {
+            let (k, v) = try!(r);
+            let (pos, _) = try!(split_position(&v));
+            if prev.as_ref().map_or(false, |prev| *prev == k) {
+                if !in_group {
+                    groups.push(vec![prev_pos]);
+                    in_group = true;
+                }
+                groups.last_mut().unwrap().push(pos);
+            } else {
+                in_group = false;
+            }
+            prev = Some(k);
+            prev_pos = pos;
+        }
+
+        let mut by_doc: BTreeMap<u64, Vec<usize>> = BTreeMap::new();
+        for (i, group) in groups.iter().enumerate() {
+            for &pos in group {
+                by_doc.entry(pos).or_insert(vec![]).push(i);
+            }
+        }
+        let mut losers = HashSet::new();
+        for (&pos, in_groups) in by_doc.iter() {
+            let lost = in_groups.iter().any(|&i| groups[i].iter().any(|p| *p < pos && !losers.contains(p)));
+            if lost {
+                losers.insert(pos);
+            }
+        }
+        Ok(losers)
+    }
+
+}
+
+// the position of a doc in a bulk load, in front of a value.  the sort
+// keeps the order pairs went in for equal keys, but only this says
+// which doc a pair came from.
+fn with_position(pos: u64, ba: &[u8]) -> Box<[u8]> {
+    let mut v = vec![];
+    misc::push_varint(&mut v, pos);
+    v.extend_from_slice(ba);
+    v.into_boxed_slice()
+}
+
+fn split_position(ba: &[u8]) -> Result<(u64, &[u8])> {
+    if ba.is_empty() {
+        return Err(elmo::Error::CorruptFile("bulk load pair without a position"));
+    }
+    let mut cur = 0;
+    let pos = misc::varint::read(ba, &mut cur);
+    Ok((pos, &ba[cur ..]))
+}
+
+// feeds the sorted pairs to the segment writer, leaving out the ones
+// from docs which lost over a unique key.  the segment writer only knows
+// about lsm errors, so ours get set aside here and the writer is just
+// told to stop.
+struct BulkSource {
+    merged: sort::PairMerged,
+    losers: HashSet<u64>,
+    prev: Option<(Box<[u8]>, Box<[u8]>)>,
+    err: Option<elmo::Error>,
+}
+
+impl BulkSource {
+    fn fail(&mut self, e: elmo::Error) -> Option<lsm::Result<lsm::PairForStorage>> {
+        self.err = Some(e);
+        Some(Err(lsm::Error::Misc(String::from("bulk load failed"))))
+    }
+}
+
+impl Iterator for BulkSource {
+    type Item = lsm::Result<lsm::PairForStorage>;
+    fn next(&mut self) -> Option<lsm::Result<lsm::PairForStorage>> {
+        loop {
+            match self.merged.next() {
+                None => return None,
+                Some(Err(e)) => {
+                    return self.fail(elmo::Error::from(e));
+                },
+                Some(Ok((k, v))) => {
+                    let v =
+                        match split_position(&v) {
+                            Ok((pos, _)) if self.losers.contains(&pos) => continue,
+                            Ok((_, v)) => v.to_vec().into_boxed_slice(),
+                            Err(e) => return self.fail(e),
+                        };
+                    // a doc with the same value twice in an array gives
+                    // the same entry twice, which only goes in once.  any
+                    // other collision was found before the load, so that
+                    // should never happen.
+                    let (same_key, same_value) =
+                        match self.prev {
+                            Some((ref prev_k, ref prev_v)) => (*prev_k == k, *prev_v == v),
+                            None => (false, false),
+                        };
+                    if same_key {
+                        if same_value {
+                            continue;
+                        }
+                        return self.fail(elmo::Error::MongoCode(11000, String::from("duplicate key error during bulk load")));
+                    }
+                    self.prev = Some((k.clone(), v.clone()));
+                    return Some(Ok(lsm::PairForStorage::new(k, lsm::ValueForStorage::Boxed(v))));
+                },
+            }
+        }
+    }
 }
 
 impl<'a> elmo::StorageWriter for MyWriter<'a> {
@@ -1401,25 +2405,34 @@ impl<'a> elmo::StorageWriter for MyWriter<'a> {
         match v.get("_id") {
             None => Err(elmo::Error::Misc(String::from("cannot update without _id"))),
             Some(id) => {
-                let cw = try!(self.get_collection_writer(db, coll));
-                match try!(find_record(&mut self.cursor, cw.collection_id, &id)) {
+                let mut cw = try!(self.get_collection_writer(db, coll));
+                match try!(self.find_pending_record(cw.collection_id, cw.clustered, &id)) {
                     None => {
                         Err(elmo::Error::Misc(String::from("update but does not exist")))
                     },
-                    Some(record_id) => {
-                        // TODO capacity
-                        let mut k = vec![];
-                        k.push(RECORD);
+                    Some(ba_record_id) => {
+                        let k = encode_key_record(cw.collection_id, &ba_record_id);
                         let ba_collection_id = u64_to_boxed_varint(cw.collection_id);
-                        k.extend_from_slice(&ba_collection_id);
-                        let ba_record_id = u64_to_boxed_varint(record_id);
-                        k.extend_from_slice(&ba_record_id);
-
-                        let old = try!(get_value_for_key_as_bson(&mut self.cursor, &k)).unwrap();
-                        self.pending.insert(k.into_boxed_slice(), lsm::ValueForStorage::Boxed(v.to_bson_array().into_boxed_slice()));
+                        try!(self.check_unique(&cw.indexes, &ba_collection_id, &ba_record_id, v));
+
+                        let old = try!(self.get_pending_value_for_key_as_bson(&k)).unwrap();
+                        let ba = v.to_bson_array();
+                        if cw.capped.is_some() {
+                            // TODO the old length is right there in the cursor
+                            let old_len = old.to_bson_array().len() as u64;
+                            if ba.len() as u64 > old_len {
+                                return Err(elmo::capped_cannot_grow());
+                            }
+                            {
+                                let recs = try!(self.capped_totals(cw.collection_id));
+                                recs.bytes = recs.bytes - old_len + ba.len() as u64;
+                            }
+                            self.save_capped_totals(cw.collection_id);
+                        }
+                        self.pending.insert(k.into_boxed_slice(), lsm::ValueForStorage::Boxed(ba.into_boxed_slice()));
 
                         try!(self.update_indexes_delete(&cw.indexes, &ba_collection_id, &ba_record_id, &old));
-                        try!(self.update_indexes_insert(&cw.indexes, &ba_collection_id, &ba_record_id, v));
+                        try!(self.update_indexes_insert(cw.collection_id, &mut cw.indexes, &ba_collection_id, &ba_record_id, v));
 
                         Ok(())
                     },
@@ -1430,19 +2443,17 @@ impl<'a> elmo::StorageWriter for MyWriter<'a> {
 
     fn delete(&mut self, db: &str, coll: &str, id: &bson::Value) -> Result<bool> {
         let cw = try!(self.get_collection_writer(db, coll));
-        match try!(find_record(&mut self.cursor, cw.collection_id, &id)) {
-            Some(record_id) => {
-                // TODO capacity
-                let mut k = vec![];
-                k.push(RECORD);
+        if cw.capped.is_some() {
+            return Err(elmo::capped_cannot_delete());
+        }
+        match try!(self.find_pending_record(cw.collection_id, cw.clustered, &id)) {
+            Some(ba_record_id) => {
+                let k = encode_key_record(cw.collection_id, &ba_record_id);
                 let ba_collection_id = u64_to_boxed_varint(cw.collection_id);
-                k.extend_from_slice(&ba_collection_id);
-                let ba_record_id = u64_to_boxed_varint(record_id);
-                k.extend_from_slice(&ba_record_id);
 
                 // TODO if there are no secondary indexes, we should be able to avoid
                 // lookup of the old record.
-                let old = try!(get_value_for_key_as_bson(&mut self.cursor, &k)).unwrap();
+                let old = try!(self.get_pending_value_for_key_as_bson(&k)).unwrap();
                 self.pending.insert(k.into_boxed_slice(), lsm::ValueForStorage::Tombstone);
 
                 try!(self.update_indexes_delete(&cw.indexes, &ba_collection_id, &ba_record_id, &old));
@@ -1456,42 +2467,219 @@ impl<'a> elmo::StorageWriter for MyWriter<'a> {
     }
 
     fn insert(&mut self, db: &str, coll: &str, v: &bson::Document) -> Result<()> {
-        let cw = try!(self.get_collection_writer(db, coll));
-        // TODO capacity
-        let mut k = vec![];
-        k.push(RECORD);
+        let mut cw = try!(self.get_collection_writer(db, coll));
+        let ba_record_id =
+            if cw.clustered {
+                match v.get("_id") {
+                    None => {
+                        return Err(elmo::Error::Misc(String::from("clustered collection requires _id")));
+                    },
+                    Some(id) => {
+                        // there is no unique _id index to catch this, so
+                        // check before we overwrite the record.  it might
+                        // have been inserted (or deleted) earlier in this
+                        // transaction, so pending counts too.
+                        if try!(self.find_pending_record(cw.collection_id, true, id)).is_some() {
+                            return Err(elmo::Error::MongoCode(11000, String::from("duplicate key error on _id")));
+                        }
+                        bson::Value::encode_one_for_index(id, false).into_boxed_slice()
+                    },
+                }
+            } else {
+                let record_id = try!(self.use_next_record_id(cw.collection_id));
+                u64_to_boxed_varint(record_id)
+            };
+        let k = encode_key_record(cw.collection_id, &ba_record_id);
         let ba_collection_id = u64_to_boxed_varint(cw.collection_id);
-        k.extend_from_slice(&ba_collection_id);
-        let record_id = try!(self.use_next_record_id(cw.collection_id));
-        let ba_record_id = u64_to_boxed_varint(record_id);
-        k.extend_from_slice(&ba_record_id);
-        self.pending.insert(k.into_boxed_slice(), lsm::ValueForStorage::Boxed(v.to_bson_array().into_boxed_slice()));
+        let ba = v.to_bson_array();
+        if let Some(ref capped) = cw.capped {
+            try!(capped.check_doc_size(ba.len()));
+        }
+        try!(self.check_unique(&cw.indexes, &ba_collection_id, &ba_record_id, v));
+        if cw.capped.is_some() {
+            // before the doc goes into pending
+            let recs = try!(self.capped_totals(cw.collection_id));
+            recs.count = recs.count + 1;
+            recs.bytes = recs.bytes + ba.len() as u64;
+        }
+        self.pending.insert(k.into_boxed_slice(), lsm::ValueForStorage::Boxed(ba.into_boxed_slice()));
 
-        try!(self.update_indexes_insert(&cw.indexes, &ba_collection_id, &ba_record_id, v));
+        try!(self.update_indexes_insert(cw.collection_id, &mut cw.indexes, &ba_collection_id, &ba_record_id, v));
+
+        if let Some(ref capped) = cw.capped {
+            try!(self.evict_capped(&cw, capped));
+        }
 
         Ok(())
     }
 
     fn commit(mut self: Box<Self>) -> Result<()> {
+        // any savepoints still open get folded into pending
+        if !self.savepoints.is_empty() {
+            try!(elmo::StorageWriter::release(&mut *self, elmo::Savepoint(1)));
+        }
+        let mut segs = std::mem::replace(&mut self.bulk, vec![]).into_iter().map(|(_, seg)| seg).collect::<Vec<_>>();
         if !self.pending.is_empty() {
             let pending = std::mem::replace(&mut self.pending, BTreeMap::new());
             if let Some(seg) = try!(self.myconn.conn.write_segment(pending).map_err(elmo::wrap_err)) {
-                try!(self.tx.commit_segment(seg).map_err(elmo::wrap_err));
+                segs.push(seg);
             }
         }
+        if !segs.is_empty() {
+            try!(self.tx.commit_segments(segs).map_err(elmo::wrap_err));
+        }
         Ok(())
     }
 
     fn rollback(mut self: Box<Self>) -> Result<()> {
-        // since we haven't been writing segments, do nothing here
+        // the only segments we wrote are from the bulk loader, and those
+        // never got committed, so their pages can just go back.
+        for (_, seg) in std::mem::replace(&mut self.bulk, vec![]) {
+            try!(self.myconn.conn.release_pending_segment(seg).map_err(elmo::wrap_err));
+        }
+        Ok(())
+    }
+
+    fn begin_index_build(&mut self, info: elmo::IndexInfo) -> Result<bool> {
+        self.create_index(info, true)
+    }
+
+    fn index_build_add(&mut self, ndx: &elmo::IndexInfo, ids: &[bson::Value]) -> Result<()> {
+        let cw = try!(self.get_collection_writer(&ndx.db, &ndx.coll));
+        let k = encode_key_name_to_index_id(cw.collection_id, &ndx.name);
+        let index_id =
+            match try!(self.get_pending_value_for_key_as_varint(&k)) {
+                Some(index_id) => index_id,
+                None => return Err(elmo::Error::Misc(String::from("index does not exist"))),
+            };
+        let mut indexes = cw.indexes.iter().filter(|prep| prep.index_id == index_id).cloned().collect::<Vec<_>>();
+        let ba_collection_id = u64_to_boxed_varint(cw.collection_id);
+        for id in ids {
+            let ba_record_id =
+                match try!(self.find_pending_record(cw.collection_id, cw.clustered, id)) {
+                    Some(ba_record_id) => ba_record_id,
+                    None => {
+                        // deleted since the snapshot
+                        continue;
+                    },
+                };
+            let k = encode_key_record(cw.collection_id, &ba_record_id);
+            if let Some(v) = try!(self.get_pending_value_for_key_as_bson(&k)) {
+                // a writer may have already put these entries in when it
+                // changed the doc.  putting them in again is harmless.
+                try!(self.check_unique(&indexes, &ba_collection_id, &ba_record_id, &v));
+                try!(self.update_indexes_insert(cw.collection_id, &mut indexes, &ba_collection_id, &ba_record_id, &v));
+            }
+        }
+        Ok(())
+    }
+
+    fn list_index_builds(&mut self) -> Result<Vec<elmo::IndexInfo>> {
+        self.myconn.list_all_index_infos(true)
+    }
+
+    fn finish_index_build(&mut self, ndx: &elmo::IndexInfo) -> Result<()> {
+        let cw = try!(self.get_collection_writer(&ndx.db, &ndx.coll));
+        let k = encode_key_name_to_index_id(cw.collection_id, &ndx.name);
+        let index_id =
+            match try!(self.get_pending_value_for_key_as_varint(&k)) {
+                Some(index_id) => index_id,
+                None => return Err(elmo::Error::Misc(String::from("index does not exist"))),
+            };
+        let k = encode_key_index_id_to_properties(cw.collection_id, index_id);
+        match try!(self.get_pending_value_for_key_as_bson(&k)) {
+            Some(mut properties) => {
+                properties.remove("b");
+                self.pending.insert(k.into_boxed_slice(), lsm::ValueForStorage::Boxed(properties.to_bson_array().into_boxed_slice()));
+                Ok(())
+            },
+            None => Err(elmo::Error::Misc(String::from("index does not exist"))),
+        }
+    }
+
+    fn insert_bulk(&mut self, db: &str, coll: &str, docs: &mut Iterator<Item=Result<bson::Document>>) -> Result<Vec<Result<()>>> {
+        let cw = try!(self.get_collection_writer(db, coll));
+        // a bulk segment is outside pending, where a savepoint can't undo
+        // it, so only take the fast path without any.  a capped collection
+        // has to evict as it goes.  a collection loaded earlier in this
+        // transaction got its rows put into pending by
+        // get_collection_writer(), so it isn't empty anymore.
+        if self.savepoints.is_empty() && cw.capped.is_none() && try!(self.collection_is_empty(cw.collection_id)) {
+            self.bulk_load(&cw, docs)
+        } else {
+            let mut results = Vec::new();
+            for r in docs {
+                match r {
+                    Ok(d) => results.push(elmo::StorageWriter::insert(self, db, coll, &d)),
+                    Err(e) => results.push(Err(e)),
+                }
+            }
+            Ok(results)
+        }
+    }
+
+    fn savepoint(&mut self) -> Result<elmo::Savepoint> {
+        let pending = std::mem::replace(&mut self.pending, BTreeMap::new());
+        self.savepoints.push(pending);
+        Ok(elmo::Savepoint(self.savepoints.len()))
+    }
+
+    fn rollback_to(&mut self, sp: elmo::Savepoint) -> Result<()> {
+        let elmo::Savepoint(n) = sp;
+        if n == 0 || n > self.savepoints.len() {
+            return Err(elmo::Error::Misc(String::from("no such savepoint")));
+        }
+        // everything after the savepoint is in the layers above it
+        self.savepoints.truncate(n);
+        self.pending = BTreeMap::new();
+        self.cw = None;
+        self.capped.clear();
+        Ok(())
+    }
+
+    fn release(&mut self, sp: elmo::Savepoint) -> Result<()> {
+        let elmo::Savepoint(n) = sp;
+        if n == 0 || n > self.savepoints.len() {
+            return Err(elmo::Error::Misc(String::from("no such savepoint")));
+        }
+        // squash the savepoint's layer and everything above it into pending
+        let mut layers = self.savepoints.split_off(n - 1).into_iter();
+        let mut merged = layers.next().unwrap();
+        for layer in layers {
+            merged.extend(layer.into_iter());
+        }
+        let pending = std::mem::replace(&mut self.pending, BTreeMap::new());
+        merged.extend(pending.into_iter());
+        self.pending = merged;
         Ok(())
     }
 
     fn create_collection(&mut self, db: &str, coll: &str, options: bson::Document) -> Result<bool> {
+        // the records of a clustered collection are in _id order, not the
+        // order they were inserted
+        if is_clustered(&options) && try!(elmo::Capped::from_options(&options)).is_some() {
+            return Err(elmo::Error::Misc(String::from("a clustered collection cannot be capped")));
+        }
         let (created, _collection_id) = try!(self.base_create_collection(db, coll, options));
         Ok(created)
     }
 
+    fn set_collection_options(&mut self, db: &str, coll: &str, options: bson::Document) -> Result<bool> {
+        let k = encode_key_name_to_collection_id(db, coll);
+        match try!(self.get_pending_value_for_key_as_varint(&k)) {
+            Some(collection_id) => {
+                let k = encode_key_collection_id_to_properties(collection_id);
+                let mut properties = try!(try!(self.get_pending_value_for_key_as_bson(&k)).ok_or(elmo::Error::Misc(String::from("collection properties not found"))));
+                properties.set_document("o", options);
+                self.pending.insert(k.into_boxed_slice(), lsm::ValueForStorage::Boxed(properties.to_bson_array().into_boxed_slice()));
+                // the cached collection writer has the old options
+                self.cw = None;
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+
     fn drop_collection(&mut self, db: &str, coll: &str) -> Result<bool> {
         self.base_drop_collection(db, coll)
     }
@@ -1509,6 +2697,30 @@ impl<'a> elmo::StorageWriter for MyWriter<'a> {
         self.base_drop_index(db, coll, name)
     }
 
+    fn set_index_options(&mut self, db: &str, coll: &str, name: &str, options: bson::Document) -> Result<bool> {
+        let collection_id =
+            match try!(self.get_pending_value_for_key_as_varint(&encode_key_name_to_collection_id(db, coll))) {
+                Some(collection_id) => collection_id,
+                None => return Ok(false),
+            };
+        let index_id =
+            match try!(self.get_pending_value_for_key_as_varint(&encode_key_name_to_index_id(collection_id, name))) {
+                Some(index_id) => index_id,
+                None => return Ok(false),
+            };
+        let k = encode_key_index_id_to_properties(collection_id, index_id);
+        match try!(self.get_pending_value_for_key_as_bson(&k)) {
+            Some(mut properties) => {
+                properties.set_document("o", options);
+                self.pending.insert(k.into_boxed_slice(), lsm::ValueForStorage::Boxed(properties.to_bson_array().into_boxed_slice()));
+                // the cached collection writer has the old options
+                self.cw = None;
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+
     fn drop_database(&mut self, db: &str) -> Result<bool> {
         self.base_drop_database(db)
     }
@@ -1522,7 +2734,12 @@ impl<'a> elmo::StorageWriter for MyWriter<'a> {
 // TODO do we need to declare that StorageWriter must implement Drop ?
 impl<'a> Drop for MyWriter<'a> {
     fn drop(&mut self) {
-        // TODO rollback
+        // a writer that goes away without commit or rollback still has
+        // to give back the pages of any bulk segments.  pending just
+        // goes away with it.
+        for (_, seg) in std::mem::replace(&mut self.bulk, vec![]) {
+            let _ = self.myconn.conn.release_pending_segment(seg);
+        }
     }
 }
 
@@ -1556,7 +2773,21 @@ impl elmo::StorageBase for MyReader {
     }
 
     fn get_reader_regular_index_scan(&self, ndx: &elmo::IndexInfo, bounds: elmo::QueryBounds) -> Result<Box<Iterator<Item=Result<elmo::Row>> + 'static>> {
-        let rdr = try!(self.myconn.get_reader_regular_index_scan(ndx, bounds));
+        let rdr = try!(self.myconn.get_reader_regular_index_scan(ndx, bounds, false));
+        Ok(box rdr)
+    }
+
+    fn get_reader_reversed_index_scan(&self, ndx: &elmo::IndexInfo, bounds: elmo::QueryBounds) -> Result<Box<Iterator<Item=Result<elmo::Row>> + 'static>> {
+        let rdr = try!(self.myconn.get_reader_regular_index_scan(ndx, bounds, true));
+        Ok(box rdr)
+    }
+
+    fn get_record_ids_regular_index_scan(&self, ndx: &elmo::IndexInfo, bounds: elmo::QueryBounds) -> Result<Box<Iterator<Item=Result<Box<[u8]>>> + 'static>> {
+        self.myconn.get_record_ids_regular_index_scan(ndx, bounds)
+    }
+
+    fn get_reader_records(&self, db: &str, coll: &str, ids: Vec<Box<[u8]>>) -> Result<Box<Iterator<Item=Result<elmo::Row>> + 'static>> {
+        let rdr = try!(self.myconn.get_reader_records_by_id(db, coll, ids));
         Ok(box rdr)
     }
 
@@ -1570,7 +2801,7 @@ impl elmo::StorageBase for MyReader {
                 self.myconn.list_index_infos_for_collection(db, coll)
             },
             None => {
-                self.myconn.list_all_index_infos()
+                self.myconn.list_all_index_infos(false)
             },
         }
     }
@@ -1589,10 +2820,22 @@ impl elmo::StorageReader for MyReader {
     }
 
     fn into_reader_regular_index_scan(&self, ndx: &elmo::IndexInfo, bounds: elmo::QueryBounds) -> Result<Box<Iterator<Item=Result<elmo::Row>> + 'static>> {
-        let rdr = try!(self.myconn.get_reader_regular_index_scan(ndx, bounds));
+        let rdr = try!(self.myconn.get_reader_regular_index_scan(ndx, bounds, false));
+        Ok(box rdr)
+    }
+
+    fn into_reader_reversed_index_scan(&self, ndx: &elmo::IndexInfo, bounds: elmo::QueryBounds) -> Result<Box<Iterator<Item=Result<elmo::Row>> + 'static>> {
+        let rdr = try!(self.myconn.get_reader_regular_index_scan(ndx, bounds, true));
         Ok(box rdr)
     }
 
+    fn into_reader_collection_scan_after(self: Box<Self>, db: &str, coll: &str, id: &bson::Value) -> Result<Option<Box<Iterator<Item=Result<elmo::Row>> + 'static>>> {
+        match try!(self.myconn.get_reader_collection_scan_after(db, coll, id)) {
+            Some(rdr) => Ok(Some(box rdr)),
+            None => Ok(None),
+        }
+    }
+
 }
 
 impl<'a> elmo::StorageBase for MyWriter<'a> {
@@ -1607,7 +2850,21 @@ impl<'a> elmo::StorageBase for MyWriter<'a> {
     }
 
     fn get_reader_regular_index_scan(&self, ndx: &elmo::IndexInfo, bounds: elmo::QueryBounds) -> Result<Box<Iterator<Item=Result<elmo::Row>> + 'static>> {
-        let rdr = try!(self.myconn.get_reader_regular_index_scan(ndx, bounds));
+        let rdr = try!(self.myconn.get_reader_regular_index_scan(ndx, bounds, false));
+        Ok(box rdr)
+    }
+
+    fn get_reader_reversed_index_scan(&self, ndx: &elmo::IndexInfo, bounds: elmo::QueryBounds) -> Result<Box<Iterator<Item=Result<elmo::Row>> + 'static>> {
+        let rdr = try!(self.myconn.get_reader_regular_index_scan(ndx, bounds, true));
+        Ok(box rdr)
+    }
+
+    fn get_record_ids_regular_index_scan(&self, ndx: &elmo::IndexInfo, bounds: elmo::QueryBounds) -> Result<Box<Iterator<Item=Result<Box<[u8]>>> + 'static>> {
+        self.myconn.get_record_ids_regular_index_scan(ndx, bounds)
+    }
+
+    fn get_reader_records(&self, db: &str, coll: &str, ids: Vec<Box<[u8]>>) -> Result<Box<Iterator<Item=Result<elmo::Row>> + 'static>> {
+        let rdr = try!(self.myconn.get_reader_records_by_id(db, coll, ids));
         Ok(box rdr)
     }
 
@@ -1621,7 +2878,7 @@ impl<'a> elmo::StorageBase for MyWriter<'a> {
                 self.myconn.list_index_infos_for_collection(db, coll)
             },
             None => {
-                self.myconn.list_all_index_infos()
+                self.myconn.list_all_index_infos(false)
             },
         }
     }
@@ -1640,11 +2897,14 @@ impl elmo::StorageConnection for MyPublicConn {
             myconn: self.myconn.clone(),
             tx: tx,
             pending: BTreeMap::new(),
+            savepoints: Vec::new(),
             max_collection_id: None,
             max_record_id: HashMap::new(),
             max_index_id: HashMap::new(),
             cw: None,
             cursor: cursor,
+            bulk: vec![],
+            capped: HashMap::new(),
         };
         Ok(box w)
     }
@@ -1661,19 +2921,101 @@ fn base_connect(name: &str) -> lsm::Result<std::sync::Arc<lsm::DatabaseFile>> {
     lsm::DatabaseFile::new(String::from(name), lsm::DEFAULT_SETTINGS)
 }
 
+// how many keys upgrade_key_layout moves per segment
+const UPGRADE_BATCH: usize = 4096;
+
+fn commit_upgrade_batch(conn: &std::sync::Arc<lsm::DatabaseFile>, pending: BTreeMap<Box<[u8]>, lsm::ValueForStorage>) -> Result<()> {
+    let lck = try!(conn.get_write_lock().map_err(elmo::wrap_err));
+    if let Some(seg) = try!(conn.write_segment(pending).map_err(elmo::wrap_err)) {
+        try!(lck.commit_segment(seg).map_err(elmo::wrap_err));
+    }
+    Ok(())
+}
+
+// moves records and index entries from the old tag-first layout into
+// COLLECTION_DATA.  the old layout had (tag, collid, rest), and the new
+// one is (COLLECTION_DATA, collid, tag, rest), so the rest of the key
+// and the value are copied as is.
+//
+// each batch moves some keys and deletes their old versions in one
+// segment, so a crash partway through leaves every key in exactly one
+// place, and the next open just picks up with whatever is left.  the
+// KEY_LAYOUT marker goes in with the last batch.
+fn upgrade_key_layout(conn: &std::sync::Arc<lsm::DatabaseFile>) -> Result<()> {
+    let mut cursor = try!(conn.open_cursor().map_err(elmo::wrap_err));
+    match try!(get_value_for_key_as_varint(&mut cursor, &[KEY_LAYOUT])) {
+        Some(v) => {
+            if v > CURRENT_KEY_LAYOUT {
+                return Err(elmo::Error::Misc(format!("unsupported key layout: {}", v)));
+            }
+            if v == CURRENT_KEY_LAYOUT {
+                return Ok(());
+            }
+        },
+        None => {
+        },
+    }
+
+    let mut moved = 0;
+    let mut pending = BTreeMap::new();
+    for tag in &[RECORD, INDEX_ENTRY] {
+        let mut cursor = lsm::PrefixCursor::new(&mut cursor, box [*tag]);
+        try!(cursor.first().map_err(elmo::wrap_err));
+        while cursor.is_valid() {
+            {
+                let k = try!(cursor.key().map_err(elmo::wrap_err)).into_boxed_slice();
+                let mut cur = 1;
+                let _ = misc::varint::read(&k, &mut cur);
+
+                let mut new_k = Vec::with_capacity(k.len() + 1);
+                new_k.push(COLLECTION_DATA);
+                new_k.extend_from_slice(&k[1 .. cur]);
+                new_k.push(*tag);
+                new_k.extend_from_slice(&k[cur ..]);
+
+                let v = try!(cursor.value().map_err(elmo::wrap_err));
+                let v = try!(v.map(lsm_map_to_box).map_err(elmo::wrap_err));
+                pending.insert(new_k.into_boxed_slice(), lsm::ValueForStorage::Boxed(v));
+                pending.insert(k, lsm::ValueForStorage::Tombstone);
+            }
+            moved = moved + 1;
+            if pending.len() >= 2 * UPGRADE_BATCH {
+                // the cursor keeps reading what was there when it was
+                // opened, so committing underneath it is fine.
+                let batch = std::mem::replace(&mut pending, BTreeMap::new());
+                try!(commit_upgrade_batch(conn, batch));
+            }
+            try!(cursor.next().map_err(elmo::wrap_err));
+        }
+    }
+
+    if moved == 0 && conn.is_read_only() {
+        // nothing to move, and no way to write the marker.
+        return Ok(());
+    }
+
+    let k: Box<[u8]> = box [KEY_LAYOUT];
+    pending.insert(k, lsm::ValueForStorage::Boxed(u64_to_boxed_varint(CURRENT_KEY_LAYOUT)));
+    try!(commit_upgrade_batch(conn, pending));
+    Ok(())
+}
+
 #[derive(Clone)]
 pub struct MyFactory {
     filename: String,
     conn: std::sync::Arc<lsm::DatabaseFile>,
+    planner: std::sync::Arc<elmo::PlannerState>,
 }
 
 impl MyFactory {
     pub fn new(filename: String) -> elmo::Result<MyFactory> {
         let conn = try!(base_connect(&filename).map_err(elmo::wrap_err));
+        try!(upgrade_key_layout(&conn));
         let f =
             MyFactory {
                 filename: filename,
                 conn: conn,
+                planner: std::sync::Arc::new(elmo::PlannerState::new()),
             };
         Ok(f)
     }
@@ -1688,7 +3030,7 @@ impl elmo::ConnectionFactory for MyFactory {
         let c = MyPublicConn {
             myconn: std::rc::Rc::new(c)
         };
-        let c = elmo::Connection::new(box c);
+        let c = elmo::Connection::new(box c, self.planner.clone());
         Ok(c)
     }
 
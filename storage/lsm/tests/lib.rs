@@ -0,0 +1,161 @@
+#![feature(box_syntax)]
+
+extern crate misc;
+extern crate bson;
+extern crate lsm;
+#[macro_use]
+extern crate elmo;
+extern crate elmo_lsm;
+
+use std::collections::BTreeMap;
+use lsm::IForwardCursor;
+use lsm::ILiveValue;
+
+// every engine runs the same conformance tests from elmo.  each one gets
+// its own #[test] and its own fresh database file.
+conformance_tests!(name => try!(elmo_lsm::MyFactory::new(misc::tempfile(name))));
+
+// puts the records and index entries of a new file back the way the
+// original layout had them, (tag, collid, rest) with no KEY_LAYOUT marker,
+// which is what a file written before the layout change looks like.
+fn downgrade_key_layout(path: &str) -> lsm::Result<()> {
+    let conn = try!(lsm::DatabaseFile::new(String::from(path), lsm::DEFAULT_SETTINGS));
+    let mut pending = BTreeMap::new();
+    {
+        let mut cursor = try!(conn.open_cursor());
+        let mut cursor = lsm::PrefixCursor::new(&mut cursor, box [elmo_lsm::COLLECTION_DATA]);
+        try!(cursor.first());
+        while cursor.is_valid() {
+            {
+                let k = try!(cursor.key()).into_boxed_slice();
+                let mut cur = 1;
+                let _ = misc::varint::read(&k, &mut cur);
+                let tag = k[cur];
+                if tag == elmo_lsm::RECORD || tag == elmo_lsm::INDEX_ENTRY {
+                    let mut old_k = Vec::with_capacity(k.len());
+                    old_k.push(tag);
+                    old_k.extend_from_slice(&k[1 .. cur]);
+                    old_k.extend_from_slice(&k[cur + 1 ..]);
+                    let v = try!(try!(cursor.value()).map(|a| Ok(a.to_vec().into_boxed_slice())));
+                    pending.insert(old_k.into_boxed_slice(), lsm::ValueForStorage::Boxed(v));
+                    pending.insert(k, lsm::ValueForStorage::Tombstone);
+                }
+            }
+            try!(cursor.next());
+        }
+    }
+    let k: Box<[u8]> = box [elmo_lsm::KEY_LAYOUT];
+    pending.insert(k, lsm::ValueForStorage::Tombstone);
+    let lck = try!(conn.get_write_lock());
+    if let Some(seg) = try!(conn.write_segment(pending)) {
+        try!(lck.commit_segment(seg));
+    }
+    Ok(())
+}
+
+#[test]
+fn upgrade_key_layout() {
+    fn f() -> elmo::Result<()> {
+        let db = "upgrade";
+        let path = misc::tempfile("upgrade_key_layout");
+        // enough keys that the upgrade takes more than one batch
+        let count = 5000;
+        {
+            let factory = try!(elmo_lsm::MyFactory::new(path.clone()));
+            let conn = try!(elmo::ConnectionFactory::open(&factory));
+            let mut docs = (0 .. count).map(|i| {
+                let mut d = bson::Document::new();
+                d.set_i32("_id", i);
+                d.set_i32("x", i % 10);
+                d
+            }).collect::<Vec<_>>();
+            for r in try!(conn.insert(db, "c", &mut docs, true)) {
+                try!(r);
+            }
+            let mut spec = bson::Document::new();
+            spec.set_i32("x", 1);
+            let ndx = elmo::IndexInfo {
+                db: String::from(db),
+                coll: String::from("c"),
+                name: String::from("x_1"),
+                spec: spec,
+                options: bson::Document::new(),
+            };
+            try!(conn.create_indexes(vec![ndx]));
+        }
+        try!(downgrade_key_layout(&path).map_err(elmo::wrap_err));
+
+        let factory = try!(elmo_lsm::MyFactory::new(path.clone()));
+        let conn = try!(elmo::ConnectionFactory::open(&factory));
+        let all = try!(conn.find(db, "c", bson::Document::new(), None, None, None, None, None, None, None));
+        assert_eq!(try!(all.collect::<elmo::Result<Vec<_>>>()).len(), count as usize);
+        // this one goes through the index, so it finds nothing unless
+        // the index entries moved too
+        let mut q = bson::Document::new();
+        q.set_i32("x", 3);
+        let mut hint = bson::Document::new();
+        hint.set_i32("x", 1);
+        let some = try!(conn.find(db, "c", q, None, None, None, None, Some(bson::Value::BDocument(hint)), None, None));
+        assert_eq!(try!(some.collect::<elmo::Result<Vec<_>>>()).len(), (count / 10) as usize);
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn resume_index_build_on_open() {
+    fn f() -> elmo::Result<()> {
+        let db = "resume";
+        let path = misc::tempfile("resume_index_build_on_open");
+        let mut spec = bson::Document::new();
+        spec.set_i32("x", 1);
+        let ndx = elmo::IndexInfo {
+            db: String::from(db),
+            coll: String::from("c"),
+            name: String::from("x_1"),
+            spec: spec,
+            options: bson::Document::new(),
+        };
+        {
+            let factory = try!(elmo_lsm::MyFactory::new(path.clone()));
+            let conn = try!(elmo::ConnectionFactory::open(&factory));
+            let mut docs = (0 .. 100).map(|i| {
+                let mut d = bson::Document::new();
+                d.set_i32("_id", i);
+                d.set_i32("x", i % 10);
+                d
+            }).collect::<Vec<_>>();
+            for r in try!(conn.insert(db, "c", &mut docs, true)) {
+                try!(r);
+            }
+            // begun, and then the process goes away
+            let mut writer = try!(conn.conn.begin_write());
+            assert!(try!(writer.begin_index_build(ndx.clone())));
+            try!(writer.commit());
+        }
+
+        let factory = try!(elmo_lsm::MyFactory::new(path.clone()));
+        let conn = try!(elmo::ConnectionFactory::open(&factory));
+        let names = |conn: &elmo::Connection| -> elmo::Result<Vec<String>> {
+            let reader = try!(conn.conn.begin_read());
+            let indexes = try!(reader.list_indexes(Some((db, "c"))));
+            Ok(indexes.into_iter().map(|ndx| ndx.name).collect())
+        };
+        assert_eq!(try!(names(&conn)), vec![String::from("_id_")]);
+        assert_eq!(try!(conn.resume_index_builds(&factory)), 1);
+        assert_eq!(try!(names(&conn)), vec![String::from("_id_"), String::from("x_1")]);
+
+        let mut q = bson::Document::new();
+        q.set_i32("x", 3);
+        let mut hint = bson::Document::new();
+        hint.set_i32("x", 1);
+        let some = try!(conn.find(db, "c", q, None, None, None, None, Some(bson::Value::BDocument(hint)), None, None));
+        assert_eq!(try!(some.collect::<elmo::Result<Vec<_>>>()).len(), 10);
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
@@ -0,0 +1,1127 @@
+/*
+    Copyright 2014-2016 Zumero, LLC
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+
+#![feature(box_syntax)]
+
+// a storage engine that keeps everything in memory.  nothing is ever
+// written to a file.  meant for tests and for scratch databases that
+// don't need to outlive the process.
+//
+// all the connections from one factory share one database.  the committed
+// state is an Arc<Database>.  a reader just grabs a clone of that Arc, so
+// it sees a snapshot which never changes underneath it.  a writer takes
+// the write lock, grabs the same Arc, and changes it copy-on-write, so
+// only the collections it actually touches get cloned.  commit swaps the
+// writer's copy in.  rollback just throws it away.
+//
+// the records and index entries are in persistent maps (im::OrdMap), so
+// cloning a collection doesn't copy them.  the clone shares its nodes
+// with the original, and a write copies only the path down to what it
+// changes.  a doc is in an Arc for the same reason, since the map clones
+// whatever is in a node it copies.
+//
+// index entries are encoded the same way as in the lsm engine, so the
+// bounds of an index scan work the same way too.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::Bound;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+extern crate bson;
+extern crate im;
+
+use im::OrdMap;
+
+extern crate misc;
+extern crate elmo;
+
+pub type Result<T> = elmo::Result<T>;
+
+#[derive(Clone)]
+struct Entry {
+    record_id: u64,
+    // for a text index, the word and its weight, so a text search doesn't
+    // have to pick them back out of the key.
+    word: Option<(String, i32)>,
+}
+
+#[derive(Clone)]
+struct Index {
+    info: elmo::IndexInfo,
+    normspec: Vec<(String, elmo::IndexType)>,
+    weights: Option<HashMap<String,i32>>,
+    unique: bool,
+    // strings in the keys are encoded as sort keys for this
+    collation: Option<bson::CollationSpec>,
+    // a background build is still running.  writers keep the index up to
+    // date, but nobody else can see it.
+    building: bool,
+    // the key is the encoded index entry.  unless the index is unique,
+    // the record id goes on the end of it, just like in lsm.
+    entries: OrdMap<Box<[u8]>, Entry>,
+}
+
+#[derive(Clone)]
+struct Collection {
+    options: bson::Document,
+    next_record_id: u64,
+    records: OrdMap<u64, Arc<bson::Document>>,
+    // in the order they were created
+    indexes: Vec<Index>,
+    capped: Option<elmo::Capped>,
+    // total bson size of the records, only kept up if capped
+    bytes: u64,
+}
+
+#[derive(Clone)]
+struct Database {
+    collections: OrdMap<(String, String), Arc<Collection>>,
+}
+
+struct Shared {
+    committed: Mutex<Arc<Database>>,
+    write_lock: Mutex<()>,
+}
+
+struct MyConn {
+    shared: Arc<Shared>,
+}
+
+struct MyReader {
+    db: Arc<Database>,
+}
+
+struct MyWriter<'a> {
+    shared: &'a Shared,
+    _tx: std::sync::MutexGuard<'a, ()>,
+    db: Arc<Database>,
+    // a savepoint is just the database as it was.  taking one copies an
+    // Arc.  the first write after it clones the Database and the
+    // Collection it touches, but those are mostly persistent maps, so
+    // that copies a path in each map and a few index specs, not the
+    // records.  so a savepoint for every doc, like Connection::insert
+    // takes, costs about the same no matter how many docs there are.
+    saved: Vec<Arc<Database>>,
+}
+
+fn poisoned<T>(_: T) -> elmo::Error {
+    elmo::Error::Misc(String::from("lock poisoned"))
+}
+
+fn get_unique(options: &bson::Document) -> bool {
+    match options.get("unique") {
+        Some(&bson::Value::BBoolean(b)) => b,
+        _ => false,
+    }
+}
+
+fn add_one(a: &mut Vec<u8>) {
+    let mut i = a.len() - 1;
+    loop {
+        if a[i] == 255 {
+            a[i] = 0;
+            if i == 0 {
+                panic!("TODO handle case where add_one to binary array overflows the first byte?");
+            } else {
+                i = i - 1;
+            }
+        } else {
+            a[i] = a[i] + 1;
+            break;
+        }
+    }
+}
+
+fn make_row(doc: &bson::Document, score: Option<f64>) -> elmo::Row {
+    elmo::Row {
+        doc: doc.clone().into_value(),
+        pos: None,
+        score: score,
+    }
+}
+
+// a record id here is a u64, but elmo only ever sees the bytes
+fn record_id_to_bytes(record_id: u64) -> Box<[u8]> {
+    misc::u64_to_bytes_be(record_id).to_vec().into_boxed_slice()
+}
+
+fn record_id_from_bytes(ba: &[u8]) -> Result<u64> {
+    if ba.len() != 8 {
+        return Err(elmo::Error::Misc(String::from("bad record id")));
+    }
+    let mut a = [0; 8];
+    for i in 0 .. 8 {
+        a[i] = ba[i];
+    }
+    Ok(misc::u64_from_bytes_be(a))
+}
+
+impl Index {
+    fn new(info: elmo::IndexInfo) -> Result<Index> {
+        let (normspec, weights) = try!(elmo::get_normalized_spec(&info.spec, &info.options));
+        let unique = get_unique(&info.options);
+        let collation = try!(info.collation_spec());
+        let ndx = Index {
+            info: info,
+            normspec: normspec,
+            weights: weights,
+            unique: unique,
+            collation: collation,
+            building: false,
+            entries: OrdMap::new(),
+        };
+        Ok(ndx)
+    }
+
+    fn primary(db: &str, coll: &str) -> Result<Index> {
+        let spec = bson::Document {pairs: vec![(String::from("_id"), bson::Value::BInt32(1))]};
+        let options = bson::Document {pairs: vec![(String::from("unique"), bson::Value::BBoolean(true)), (String::from("multikey"), bson::Value::BBoolean(false))]};
+        let info = elmo::IndexInfo {
+            db: String::from(db),
+            coll: String::from(coll),
+            name: String::from("_id_"),
+            spec: spec,
+            options: options,
+        };
+        Index::new(info)
+    }
+
+    fn get_entries(&self, record_id: u64, doc: &bson::Document) -> Result<Vec<(Box<[u8]>, Entry)>> {
+        let entries = try!(elmo::get_index_entries(doc, &self.normspec, &self.weights, &self.info.options));
+        let mut a = vec![];
+        for vals in entries {
+            // a text entry has the word and its weight as its last value
+            let word =
+                if self.weights.is_some() {
+                    match vals.last() {
+                        Some(&(bson::Value::BArray(ref ba), _)) => {
+                            match (ba.items.get(0), ba.items.get(1)) {
+                                (Some(&bson::Value::BString(ref s)), Some(&bson::Value::BInt32(w))) => Some((s.clone(), w)),
+                                _ => None,
+                            }
+                        },
+                        _ => None,
+                    }
+                } else {
+                    None
+                };
+            let vref = vals.iter().map(|&(ref v,neg)| (v,neg)).collect::<Vec<_>>();
+            let mut k = bson::Value::encode_multi_for_index_collated(&vref, None, self.collation.as_ref());
+            if !self.unique {
+                misc::push_varint(&mut k, record_id);
+            }
+            let e = Entry {
+                record_id: record_id,
+                word: word,
+            };
+            a.push((k.into_boxed_slice(), e));
+        }
+        Ok(a)
+    }
+
+    fn check_unique(&self, record_id: u64, doc: &bson::Document) -> Result<()> {
+        if self.unique {
+            for (k, _) in try!(self.get_entries(record_id, doc)) {
+                match self.entries.get(&k) {
+                    Some(e) => {
+                        if e.record_id != record_id {
+                            return Err(elmo::Error::MongoCode(11000, String::from("duplicate key error")));
+                        }
+                    },
+                    None => {
+                    },
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, record_id: u64, doc: &bson::Document) -> Result<()> {
+        for (k, e) in try!(self.get_entries(record_id, doc)) {
+            self.entries.insert(k, e);
+        }
+        if !self.info.is_multikey() && elmo::is_multikey_doc(doc, &self.normspec) {
+            self.info.options.set_bool("multikey", true);
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, record_id: u64, doc: &bson::Document) -> Result<()> {
+        for (k, _) in try!(self.get_entries(record_id, doc)) {
+            self.entries.remove(&k);
+        }
+        Ok(())
+    }
+}
+
+impl Collection {
+    fn new(db: &str, coll: &str, options: bson::Document) -> Result<Collection> {
+        let indexes =
+            match options.get("autoIndexId") {
+                Some(&bson::Value::BBoolean(false)) => vec![],
+                _ => vec![try!(Index::primary(db, coll))],
+            };
+        let capped = try!(elmo::Capped::from_options(&options));
+        let c = Collection {
+            options: options,
+            next_record_id: 1,
+            records: OrdMap::new(),
+            indexes: indexes,
+            capped: capped,
+            bytes: 0,
+        };
+        Ok(c)
+    }
+
+    fn find_record(&self, id: &bson::Value) -> Option<u64> {
+        match self.indexes.iter().filter(|ndx| ndx.info.name == "_id_").next() {
+            Some(ndx) => {
+                let k = bson::Value::encode_multi_for_index(&vec![(id, false)], None);
+                ndx.entries.get(&k.into_boxed_slice()).map(|e| e.record_id)
+            },
+            None => {
+                // no _id index.  look at every record.
+                self.records.iter().filter(|&(_, d)| d.get("_id") == Some(id)).map(|(&record_id, _)| record_id).next()
+            },
+        }
+    }
+
+    fn insert(&mut self, doc: &bson::Document) -> Result<()> {
+        let len =
+            match self.capped {
+                Some(ref capped) => {
+                    let len = doc.to_bson_array().len();
+                    try!(capped.check_doc_size(len));
+                    len as u64
+                },
+                None => 0,
+            };
+        let record_id = self.next_record_id;
+        // check every index before changing anything, so a duplicate
+        // doesn't leave part of the doc behind.
+        for ndx in &self.indexes {
+            try!(ndx.check_unique(record_id, doc));
+        }
+        for ndx in &mut self.indexes {
+            try!(ndx.add(record_id, doc));
+        }
+        self.records.insert(record_id, Arc::new(doc.clone()));
+        self.next_record_id = record_id + 1;
+        self.bytes = self.bytes + len;
+        let capped = self.capped;
+        if let Some(capped) = capped {
+            try!(self.evict(&capped));
+        }
+        Ok(())
+    }
+
+    // the oldest docs are the ones with the lowest record ids
+    fn evict(&mut self, capped: &elmo::Capped) -> Result<()> {
+        while capped.is_over(self.records.len() as u64, self.bytes) {
+            let record_id =
+                match self.records.keys().next() {
+                    Some(&record_id) => record_id,
+                    None => break,
+                };
+            try!(self.delete(record_id));
+        }
+        Ok(())
+    }
+
+    fn update(&mut self, record_id: u64, doc: &bson::Document) -> Result<()> {
+        for ndx in &self.indexes {
+            try!(ndx.check_unique(record_id, doc));
+        }
+        let old = self.records[&record_id].clone();
+        if self.capped.is_some() {
+            let old_len = old.to_bson_array().len() as u64;
+            let len = doc.to_bson_array().len() as u64;
+            if len > old_len {
+                return Err(elmo::capped_cannot_grow());
+            }
+            self.bytes = self.bytes - old_len + len;
+        }
+        for ndx in &mut self.indexes {
+            try!(ndx.remove(record_id, &old));
+            try!(ndx.add(record_id, doc));
+        }
+        self.records.insert(record_id, Arc::new(doc.clone()));
+        Ok(())
+    }
+
+    fn delete(&mut self, record_id: u64) -> Result<()> {
+        if let Some(old) = self.records.remove(&record_id) {
+            if self.capped.is_some() {
+                self.bytes = self.bytes - old.to_bson_array().len() as u64;
+            }
+            for ndx in &mut self.indexes {
+                try!(ndx.remove(record_id, &old));
+            }
+        }
+        Ok(())
+    }
+
+    fn create_index(&mut self, info: elmo::IndexInfo, building: bool) -> Result<bool> {
+        match self.indexes.iter().filter(|ndx| ndx.info.name == info.name).next() {
+            Some(ndx) => {
+                if ndx.info.spec != info.spec {
+                    // note that we do not compare the options.
+                    // I think mongo does it this way too.
+                    return Err(elmo::Error::Misc(String::from("index already exists with different keys")));
+                } else {
+                    return Ok(false);
+                }
+            },
+            None => {
+            },
+        }
+        let mut ndx = try!(Index::new(info));
+        if building {
+            ndx.building = true;
+        } else {
+            for (&record_id, doc) in &self.records {
+                if ndx.unique {
+                    try!(ndx.check_unique(record_id, doc));
+                }
+                try!(ndx.add(record_id, doc));
+            }
+        }
+        self.indexes.push(ndx);
+        Ok(true)
+    }
+
+    fn index_build_add(&mut self, name: &str, ids: &[bson::Value]) -> Result<()> {
+        let i =
+            match self.indexes.iter().position(|ndx| ndx.info.name == name) {
+                Some(i) => i,
+                None => return Err(elmo::Error::Misc(String::from("index does not exist"))),
+            };
+        for id in ids {
+            // if it's gone, it was deleted since the snapshot
+            if let Some(record_id) = self.find_record(id) {
+                let doc = self.records[&record_id].clone();
+                let ndx = &mut self.indexes[i];
+                try!(ndx.check_unique(record_id, &doc));
+                try!(ndx.add(record_id, &doc));
+            }
+        }
+        Ok(())
+    }
+
+    fn get_index(&self, name: &str) -> Result<&Index> {
+        match self.indexes.iter().filter(|ndx| ndx.info.name == name).next() {
+            Some(ndx) => Ok(ndx),
+            None => Err(elmo::Error::Misc(String::from("index does not exist"))),
+        }
+    }
+
+    fn rows(&self, record_ids: Vec<u64>, scores: Option<&HashMap<u64, f64>>) -> Result<Vec<Result<elmo::Row>>> {
+        let mut a = vec![];
+        for record_id in record_ids {
+            match self.records.get(&record_id) {
+                Some(doc) => {
+                    let score = scores.map(|s| s[&record_id]);
+                    a.push(Ok(make_row(doc, score)));
+                },
+                None => {
+                    return Err(elmo::Error::Misc(String::from("record id not found?!?")));
+                },
+            }
+        }
+        Ok(a)
+    }
+}
+
+impl Database {
+    fn get_collection(&self, db: &str, coll: &str) -> Option<&Arc<Collection>> {
+        self.collections.get(&(String::from(db), String::from(coll)))
+    }
+
+    fn list_collections(&self) -> Vec<elmo::CollectionInfo> {
+        self.collections.iter().map(
+            |(&(ref db, ref coll), c)| {
+                elmo::CollectionInfo {
+                    db: db.clone(),
+                    coll: coll.clone(),
+                    options: c.options.clone(),
+                }
+            }).collect()
+    }
+
+    fn list_indexes(&self, ns: Option<(&str, &str)>) -> Vec<elmo::IndexInfo> {
+        self.collections.iter().filter(
+            |&(&(ref db, ref coll), _)| {
+                match ns {
+                    Some((want_db, want_coll)) => db == want_db && coll == want_coll,
+                    None => true,
+                }
+            }).flat_map(|(_, c)| c.indexes.iter().filter(|ndx| !ndx.building).map(|ndx| ndx.info.clone())).collect()
+    }
+
+    fn list_index_builds(&self) -> Vec<elmo::IndexInfo> {
+        self.collections.iter().flat_map(|(_, c)| c.indexes.iter().filter(|ndx| ndx.building).map(|ndx| ndx.info.clone())).collect()
+    }
+
+    // cloning the records is cheap, and the clone can be walked as it
+    // goes, since nothing changes it.
+    fn collection_scan(&self, db: &str, coll: &str) -> Result<Box<Iterator<Item=Result<elmo::Row>> + 'static>> {
+        match self.get_collection(db, coll) {
+            Some(c) => {
+                let records = c.records.clone();
+                Ok(box records.into_iter().map(|(_, doc)| Ok(make_row(&doc, None))))
+            },
+            None => Ok(box std::iter::empty()),
+        }
+    }
+
+    fn collection_scan_after(&self, db: &str, coll: &str, id: &bson::Value) -> Result<Option<Box<Iterator<Item=Result<elmo::Row>> + 'static>>> {
+        let c =
+            match self.get_collection(db, coll) {
+                Some(c) => c,
+                None => return Ok(None),
+            };
+        match c.find_record(id) {
+            Some(after) => {
+                let (_, later) = c.records.split(&after);
+                Ok(Some(box later.into_iter().map(|(_, doc)| Ok(make_row(&doc, None)))))
+            },
+            None => Ok(None),
+        }
+    }
+
+    // TODO like the sqlite and lsm versions, this has logic which would
+    // prefer to be up above the storage layer.
+    fn text_index_scan(&self, ndx: &elmo::IndexInfo, eq: elmo::QueryKey, terms: Vec<elmo::TextQueryTerm>) -> Result<Box<Iterator<Item=Result<elmo::Row>> + 'static>> {
+        let c =
+            match self.get_collection(&ndx.db, &ndx.coll) {
+                Some(c) => c,
+                None => return Ok(box std::iter::empty()),
+            };
+        let ndx = try!(c.get_index(&ndx.name));
+        let weights =
+            match ndx.weights {
+                None => return Err(elmo::Error::Misc(String::from("non text index"))),
+                Some(ref w) => w,
+            };
+
+        let mut key_preface = vec![];
+        bson::Value::push_encode_multi_for_index(&mut key_preface, &eq, None);
+
+        // returns (recid, weight) for every entry of the word.  like lsm,
+        // everything up through the end of the word is a prefix which all
+        // the entries for the word share, so only those get looked at.
+        let lookup = |word: &str| -> Vec<(u64, i32)> {
+            let v = bson::Value::BArray(bson::Array {items: vec![bson::Value::BString(String::from(word)), bson::Value::BInt32(0)]});
+            let a = bson::Value::encode_one_for_index(&v, false);
+            let zero = bson::Value::encode_one_for_index(&bson::Value::BInt32(0), false);
+            let mut kmin = key_preface.clone();
+            kmin.extend_from_slice(&a[0 .. a.len() - zero.len()]);
+            let mut kmax = kmin.clone();
+            add_one(&mut kmax);
+            ndx.entries.range::<_, [u8]>((Bound::Included(&kmin[..]), Bound::Excluded(&kmax[..]))).filter_map(
+                |(_, e)| {
+                    match e.word {
+                        Some((ref s, w)) if &s[..] == word => Some((e.record_id, w)),
+                        _ => None,
+                    }
+                }).collect()
+        };
+
+        let mut pos_entries = Vec::new();
+        let mut neg_entries = Vec::new();
+        for term in &terms {
+            match term {
+                &elmo::TextQueryTerm::Word(neg, ref s) => {
+                    if neg {
+                        neg_entries.extend(lookup(&s[..]));
+                    } else {
+                        pos_entries.extend(lookup(&s[..]));
+                    }
+                },
+                &elmo::TextQueryTerm::Phrase(neg, ref s) => {
+                    if neg {
+                        // TODO probably should not negate a doc just because it contains one of the words in a negated phrase
+                    } else {
+                        // TODO tokenize properly
+                        for w in s.split(" ") {
+                            pos_entries.extend(lookup(w));
+                        }
+                    }
+                },
+            }
+        }
+
+        let neg_record_ids = neg_entries.into_iter().map(|t| t.0).collect::<HashSet<_>>();
+
+        // keep the order in which records were first found, like lsm does
+        let mut order = Vec::new();
+        let mut scores = HashMap::new();
+        for (record_id, w) in pos_entries {
+            if neg_record_ids.contains(&record_id) {
+                continue;
+            }
+            match scores.entry(record_id) {
+                std::collections::hash_map::Entry::Occupied(mut e) => {
+                    // TODO this is not the way mongo does this calculation
+                    *e.get_mut() += w as f64;
+                },
+                std::collections::hash_map::Entry::Vacant(e) => {
+                    e.insert(w as f64);
+                    order.push(record_id);
+                },
+            }
+        }
+
+        let order = order.into_iter().filter(
+            |record_id| {
+                let doc = (*c.records[record_id]).clone().into_value();
+                elmo::check_text_phrases(&terms, weights, &doc)
+            }).collect::<Vec<_>>();
+        let a = try!(c.rows(order, Some(&scores)));
+        Ok(box a.into_iter())
+    }
+
+    fn regular_index_scan(&self, ndx: &elmo::IndexInfo, bounds: elmo::QueryBounds, reverse: bool) -> Result<Box<Iterator<Item=Result<elmo::Row>> + 'static>> {
+        let c =
+            match self.get_collection(&ndx.db, &ndx.coll) {
+                Some(c) => c,
+                None => return Err(elmo::Error::Misc(String::from("collection does not exist"))),
+            };
+        let record_ids = try!(self.index_scan_record_ids(c, ndx, bounds, reverse));
+        let a = try!(c.rows(record_ids, None));
+        Ok(box a.into_iter())
+    }
+
+    fn record_ids_index_scan(&self, ndx: &elmo::IndexInfo, bounds: elmo::QueryBounds) -> Result<Box<Iterator<Item=Result<Box<[u8]>>> + 'static>> {
+        let c =
+            match self.get_collection(&ndx.db, &ndx.coll) {
+                Some(c) => c,
+                None => return Err(elmo::Error::Misc(String::from("collection does not exist"))),
+            };
+        let record_ids = try!(self.index_scan_record_ids(c, ndx, bounds, false));
+        let a = record_ids.into_iter().map(|record_id| Ok(record_id_to_bytes(record_id))).collect::<Vec<_>>();
+        Ok(box a.into_iter())
+    }
+
+    fn records(&self, db: &str, coll: &str, ids: Vec<Box<[u8]>>) -> Result<Box<Iterator<Item=Result<elmo::Row>> + 'static>> {
+        let c =
+            match self.get_collection(db, coll) {
+                Some(c) => c,
+                None => return Err(elmo::Error::Misc(String::from("collection does not exist"))),
+            };
+        let record_ids = try!(ids.iter().map(|ba| record_id_from_bytes(ba)).collect::<Result<Vec<_>>>());
+        let a = try!(c.rows(record_ids, None));
+        Ok(box a.into_iter())
+    }
+
+    // the record ids in the part of the index within the bounds, each once
+    fn index_scan_record_ids(&self, c: &Collection, ndx: &elmo::IndexInfo, bounds: elmo::QueryBounds, reverse: bool) -> Result<Vec<u64>> {
+        let ndx = try!(c.get_index(&ndx.name));
+        let has_recid = !ndx.unique;
+        let collation = ndx.collation.as_ref();
+
+        // each side of the range is the key and whether it is inclusive.
+        // a unique index doesn't have the record id on the end of its keys,
+        // but any other index does, so for GT and LTE, we bump the key past
+        // all the record ids that could follow it.
+
+        fn lower(has_recid: bool, eqvals: &elmo::QueryKey, vals: Option<&elmo::QueryKey>, inclusive: bool, c: Option<&bson::CollationSpec>) -> (Vec<u8>, bool) {
+            let mut k = vec![];
+            bson::Value::push_encode_multi_for_index_collated(&mut k, eqvals, vals, c);
+            if has_recid && !inclusive {
+                add_one(&mut k);
+                (k, true)
+            } else {
+                (k, inclusive)
+            }
+        }
+
+        fn upper(has_recid: bool, eqvals: &elmo::QueryKey, vals: Option<&elmo::QueryKey>, inclusive: bool, c: Option<&bson::CollationSpec>) -> (Vec<u8>, bool) {
+            let mut k = vec![];
+            bson::Value::push_encode_multi_for_index_collated(&mut k, eqvals, vals, c);
+            if has_recid && inclusive {
+                add_one(&mut k);
+                (k, false)
+            } else {
+                (k, inclusive)
+            }
+        }
+
+        let (min, max) =
+            match bounds {
+                elmo::QueryBounds::GT(vals) => (Some(lower(has_recid, &vals, None, false, collation)), None),
+                elmo::QueryBounds::GTE(vals) => (Some(lower(has_recid, &vals, None, true, collation)), None),
+                elmo::QueryBounds::LT(vals) => (None, Some(upper(has_recid, &vals, None, false, collation))),
+                elmo::QueryBounds::LTE(vals) => (None, Some(upper(has_recid, &vals, None, true, collation))),
+                elmo::QueryBounds::GT_LT(eqvals, minvals, maxvals) => (Some(lower(has_recid, &eqvals, Some(&minvals), false, collation)), Some(upper(has_recid, &eqvals, Some(&maxvals), false, collation))),
+                elmo::QueryBounds::GTE_LT(eqvals, minvals, maxvals) => (Some(lower(has_recid, &eqvals, Some(&minvals), true, collation)), Some(upper(has_recid, &eqvals, Some(&maxvals), false, collation))),
+                elmo::QueryBounds::GT_LTE(eqvals, minvals, maxvals) => (Some(lower(has_recid, &eqvals, Some(&minvals), false, collation)), Some(upper(has_recid, &eqvals, Some(&maxvals), true, collation))),
+                elmo::QueryBounds::GTE_LTE(eqvals, minvals, maxvals) => (Some(lower(has_recid, &eqvals, Some(&minvals), true, collation)), Some(upper(has_recid, &eqvals, Some(&maxvals), true, collation))),
+                elmo::QueryBounds::EQ(vals) => {
+                    let (kmin, _) = lower(false, &vals, None, true, collation);
+                    let mut kmax = kmin.clone();
+                    add_one(&mut kmax);
+                    (Some((kmin, true)), Some((kmax, false)))
+                },
+            };
+
+        if let (&Some((ref kmin, min_inclusive)), &Some((ref kmax, max_inclusive))) = (&min, &max) {
+            // nothing can be in a range which is empty or backwards, and
+            // asking the map for one would panic.
+            if kmin > kmax || (kmin == kmax && !(min_inclusive && max_inclusive)) {
+                return Ok(vec![]);
+            }
+        }
+        let lo =
+            match min {
+                Some((ref kmin, true)) => Bound::Included(&kmin[..]),
+                Some((ref kmin, false)) => Bound::Excluded(&kmin[..]),
+                None => Bound::Unbounded,
+            };
+        let hi =
+            match max {
+                Some((ref kmax, true)) => Bound::Included(&kmax[..]),
+                Some((ref kmax, false)) => Bound::Excluded(&kmax[..]),
+                None => Bound::Unbounded,
+            };
+        let entries: Box<Iterator<Item=(&Box<[u8]>, &Entry)>> =
+            if reverse {
+                box ndx.entries.range::<_, [u8]>((lo, hi)).rev()
+            } else {
+                box ndx.entries.range::<_, [u8]>((lo, hi))
+            };
+        let mut seen = HashSet::new();
+        let mut record_ids = vec![];
+        for (_, e) in entries {
+            // DISTINCT. we don't want this producing the same record twice.
+            if !seen.contains(&e.record_id) {
+                seen.insert(e.record_id);
+                record_ids.push(e.record_id);
+            }
+        }
+        Ok(record_ids)
+    }
+}
+
+impl<'a> MyWriter<'a> {
+    fn key(db: &str, coll: &str) -> (String, String) {
+        (String::from(db), String::from(coll))
+    }
+
+    fn database_mut(&mut self) -> &mut Database {
+        Arc::make_mut(&mut self.db)
+    }
+
+    // the collection, cloned if anybody else is still looking at it.
+    // None if it doesn't exist.
+    fn collection_mut(&mut self, db: &str, coll: &str) -> Option<&mut Collection> {
+        self.database_mut().collections.get_mut(&Self::key(db, coll)).map(|c| Arc::make_mut(c))
+    }
+
+    fn base_create_collection(&mut self, db: &str, coll: &str, options: bson::Document) -> Result<bool> {
+        if self.db.get_collection(db, coll).is_some() {
+            Ok(false)
+        } else {
+            let c = try!(Collection::new(db, coll, options));
+            self.database_mut().collections.insert(Self::key(db, coll), Arc::new(c));
+            Ok(true)
+        }
+    }
+
+    // creates the collection if it isn't there
+    fn collection_for_write(&mut self, db: &str, coll: &str) -> Result<&mut Collection> {
+        try!(self.base_create_collection(db, coll, bson::Document::new()));
+        Ok(self.collection_mut(db, coll).unwrap())
+    }
+}
+
+impl<'a> elmo::StorageWriter for MyWriter<'a> {
+    fn update(&mut self, db: &str, coll: &str, v: &bson::Document) -> Result<()> {
+        match v.get("_id") {
+            None => Err(elmo::Error::Misc(String::from("cannot update without _id"))),
+            Some(id) => {
+                let c = try!(self.collection_for_write(db, coll));
+                match c.find_record(id) {
+                    None => Err(elmo::Error::Misc(String::from("update but does not exist"))),
+                    Some(record_id) => c.update(record_id, v),
+                }
+            },
+        }
+    }
+
+    fn delete(&mut self, db: &str, coll: &str, id: &bson::Value) -> Result<bool> {
+        let record_id =
+            match self.db.get_collection(db, coll) {
+                Some(c) => {
+                    if c.capped.is_some() {
+                        return Err(elmo::capped_cannot_delete());
+                    }
+                    c.find_record(id)
+                },
+                None => None,
+            };
+        match record_id {
+            Some(record_id) => {
+                try!(self.collection_mut(db, coll).unwrap().delete(record_id));
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+
+    fn insert(&mut self, db: &str, coll: &str, v: &bson::Document) -> Result<()> {
+        let c = try!(self.collection_for_write(db, coll));
+        c.insert(v)
+    }
+
+    fn commit(self: Box<Self>) -> Result<()> {
+        let mut committed = try!(self.shared.committed.lock().map_err(poisoned));
+        *committed = self.db.clone();
+        Ok(())
+    }
+
+    fn rollback(self: Box<Self>) -> Result<()> {
+        // nothing was ever visible to anybody else
+        Ok(())
+    }
+
+    fn savepoint(&mut self) -> Result<elmo::Savepoint> {
+        self.saved.push(self.db.clone());
+        Ok(elmo::Savepoint(self.saved.len()))
+    }
+
+    fn rollback_to(&mut self, sp: elmo::Savepoint) -> Result<()> {
+        let elmo::Savepoint(n) = sp;
+        if n == 0 || n > self.saved.len() {
+            return Err(elmo::Error::Misc(String::from("no such savepoint")));
+        }
+        self.db = self.saved[n - 1].clone();
+        self.saved.truncate(n);
+        Ok(())
+    }
+
+    fn release(&mut self, sp: elmo::Savepoint) -> Result<()> {
+        let elmo::Savepoint(n) = sp;
+        if n == 0 || n > self.saved.len() {
+            return Err(elmo::Error::Misc(String::from("no such savepoint")));
+        }
+        self.saved.truncate(n - 1);
+        Ok(())
+    }
+
+    fn create_collection(&mut self, db: &str, coll: &str, options: bson::Document) -> Result<bool> {
+        self.base_create_collection(db, coll, options)
+    }
+
+    fn set_collection_options(&mut self, db: &str, coll: &str, options: bson::Document) -> Result<bool> {
+        match self.collection_mut(db, coll) {
+            Some(c) => {
+                c.options = options;
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+
+    fn drop_collection(&mut self, db: &str, coll: &str) -> Result<bool> {
+        if self.db.get_collection(db, coll).is_some() {
+            self.database_mut().collections.remove(&Self::key(db, coll));
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn create_indexes(&mut self, what: Vec<elmo::IndexInfo>) -> Result<Vec<bool>> {
+        let mut v = Vec::new();
+        for info in what {
+            let c = try!(self.collection_for_write(&info.db, &info.coll));
+            let b = try!(c.create_index(info, false));
+            v.push(b);
+        }
+        Ok(v)
+    }
+
+    fn begin_index_build(&mut self, info: elmo::IndexInfo) -> Result<bool> {
+        let c = try!(self.collection_for_write(&info.db, &info.coll));
+        c.create_index(info, true)
+    }
+
+    fn index_build_add(&mut self, ndx: &elmo::IndexInfo, ids: &[bson::Value]) -> Result<()> {
+        match self.collection_mut(&ndx.db, &ndx.coll) {
+            Some(c) => c.index_build_add(&ndx.name, ids),
+            None => Err(elmo::Error::Misc(String::from("collection does not exist"))),
+        }
+    }
+
+    fn list_index_builds(&mut self) -> Result<Vec<elmo::IndexInfo>> {
+        Ok(self.db.list_index_builds())
+    }
+
+    fn finish_index_build(&mut self, ndx: &elmo::IndexInfo) -> Result<()> {
+        match self.collection_mut(&ndx.db, &ndx.coll) {
+            Some(c) => {
+                match c.indexes.iter_mut().filter(|i| i.info.name == ndx.name).next() {
+                    Some(i) => {
+                        i.building = false;
+                        Ok(())
+                    },
+                    None => Err(elmo::Error::Misc(String::from("index does not exist"))),
+                }
+            },
+            None => Err(elmo::Error::Misc(String::from("collection does not exist"))),
+        }
+    }
+
+    fn rename_collection(&mut self, old_name: &str, new_name: &str, drop_target: bool) -> Result<bool> {
+        let (old_db, old_coll) = try!(bson::split_name(old_name));
+        let (new_db, new_coll) = try!(bson::split_name(new_name));
+
+        // jstests/core/rename8.js seems to think that renaming to/from a system collection is illegal unless
+        // that collection is system.users, which is "whitelisted".  for now, we emulate this behavior, even
+        // though system.users isn't supported.
+        if old_coll != "system.users" && old_coll.starts_with("system.") {
+            return Err(elmo::Error::Misc(String::from("renameCollection with a system collection not allowed.")));
+        }
+        if new_coll != "system.users" && new_coll.starts_with("system.") {
+            return Err(elmo::Error::Misc(String::from("renameCollection with a system collection not allowed.")));
+        }
+
+        if drop_target {
+            try!(elmo::StorageWriter::drop_collection(self, new_db, new_coll));
+        } else if self.db.get_collection(new_db, new_coll).is_some() {
+            return Err(elmo::Error::Misc(String::from("renameCollection to something that already exists")));
+        }
+
+        let old = self.database_mut().collections.remove(&Self::key(old_db, old_coll));
+        match old {
+            None => {
+                self.base_create_collection(new_db, new_coll, bson::Document::new())
+            },
+            Some(mut c) => {
+                for ndx in &mut Arc::make_mut(&mut c).indexes {
+                    ndx.info.db = String::from(new_db);
+                    ndx.info.coll = String::from(new_coll);
+                }
+                self.database_mut().collections.insert(Self::key(new_db, new_coll), c);
+                Ok(false)
+            },
+        }
+    }
+
+    fn drop_index(&mut self, db: &str, coll: &str, name: &str) -> Result<bool> {
+        let exists =
+            match self.db.get_collection(db, coll) {
+                Some(c) => c.indexes.iter().any(|ndx| ndx.info.name == name),
+                None => false,
+            };
+        if exists {
+            self.collection_mut(db, coll).unwrap().indexes.retain(|ndx| ndx.info.name != name);
+        }
+        Ok(exists)
+    }
+
+    fn set_index_options(&mut self, db: &str, coll: &str, name: &str, options: bson::Document) -> Result<bool> {
+        let exists =
+            match self.db.get_collection(db, coll) {
+                Some(c) => c.indexes.iter().any(|ndx| ndx.info.name == name),
+                None => false,
+            };
+        if exists {
+            for ndx in self.collection_mut(db, coll).unwrap().indexes.iter_mut() {
+                if ndx.info.name == name {
+                    ndx.info.options = options.clone();
+                }
+            }
+        }
+        Ok(exists)
+    }
+
+    fn drop_database(&mut self, db: &str) -> Result<bool> {
+        let before = self.db.collections.len();
+        let keep = self.db.collections.iter().filter(|&(&(ref d, _), _)| d != db).map(|(k, c)| (k.clone(), c.clone())).collect();
+        self.database_mut().collections = keep;
+        Ok(self.db.collections.len() != before)
+    }
+
+    fn clear_collection(&mut self, db: &str, coll: &str) -> Result<bool> {
+        match self.collection_mut(db, coll) {
+            Some(c) => {
+                c.records.clear();
+                c.bytes = 0;
+                for ndx in &mut c.indexes {
+                    ndx.entries.clear();
+                }
+                return Ok(false);
+            },
+            None => {
+            },
+        }
+        self.base_create_collection(db, coll, bson::Document::new())
+    }
+}
+
+impl elmo::StorageBase for MyReader {
+    fn get_reader_collection_scan(&self, db: &str, coll: &str) -> Result<Box<Iterator<Item=Result<elmo::Row>> + 'static>> {
+        self.db.collection_scan(db, coll)
+    }
+
+    fn get_reader_text_index_scan(&self, ndx: &elmo::IndexInfo, eq: elmo::QueryKey, terms: Vec<elmo::TextQueryTerm>) -> Result<Box<Iterator<Item=Result<elmo::Row>> + 'static>> {
+        self.db.text_index_scan(ndx, eq, terms)
+    }
+
+    fn get_reader_regular_index_scan(&self, ndx: &elmo::IndexInfo, bounds: elmo::QueryBounds) -> Result<Box<Iterator<Item=Result<elmo::Row>> + 'static>> {
+        self.db.regular_index_scan(ndx, bounds, false)
+    }
+
+    fn get_reader_reversed_index_scan(&self, ndx: &elmo::IndexInfo, bounds: elmo::QueryBounds) -> Result<Box<Iterator<Item=Result<elmo::Row>> + 'static>> {
+        self.db.regular_index_scan(ndx, bounds, true)
+    }
+
+    fn get_record_ids_regular_index_scan(&self, ndx: &elmo::IndexInfo, bounds: elmo::QueryBounds) -> Result<Box<Iterator<Item=Result<Box<[u8]>>> + 'static>> {
+        self.db.record_ids_index_scan(ndx, bounds)
+    }
+
+    fn get_reader_records(&self, db: &str, coll: &str, ids: Vec<Box<[u8]>>) -> Result<Box<Iterator<Item=Result<elmo::Row>> + 'static>> {
+        self.db.records(db, coll, ids)
+    }
+
+    fn list_collections(&self) -> Result<Vec<elmo::CollectionInfo>> {
+        Ok(self.db.list_collections())
+    }
+
+    fn list_indexes(&self, ns: Option<(&str, &str)>) -> Result<Vec<elmo::IndexInfo>> {
+        Ok(self.db.list_indexes(ns))
+    }
+}
+
+impl elmo::StorageReader for MyReader {
+    fn into_reader_collection_scan(self: Box<Self>, db: &str, coll: &str) -> Result<Box<Iterator<Item=Result<elmo::Row>> + 'static>> {
+        self.db.collection_scan(db, coll)
+    }
+
+    fn into_reader_text_index_scan(&self, ndx: &elmo::IndexInfo, eq: elmo::QueryKey, terms: Vec<elmo::TextQueryTerm>) -> Result<Box<Iterator<Item=Result<elmo::Row>> + 'static>> {
+        self.db.text_index_scan(ndx, eq, terms)
+    }
+
+    fn into_reader_regular_index_scan(&self, ndx: &elmo::IndexInfo, bounds: elmo::QueryBounds) -> Result<Box<Iterator<Item=Result<elmo::Row>> + 'static>> {
+        self.db.regular_index_scan(ndx, bounds, false)
+    }
+
+    fn into_reader_reversed_index_scan(&self, ndx: &elmo::IndexInfo, bounds: elmo::QueryBounds) -> Result<Box<Iterator<Item=Result<elmo::Row>> + 'static>> {
+        self.db.regular_index_scan(ndx, bounds, true)
+    }
+
+    fn into_reader_collection_scan_after(self: Box<Self>, db: &str, coll: &str, id: &bson::Value) -> Result<Option<Box<Iterator<Item=Result<elmo::Row>> + 'static>>> {
+        self.db.collection_scan_after(db, coll, id)
+    }
+}
+
+// unlike the other engines, a writer here sees its own changes when it
+// reads.
+impl<'a> elmo::StorageBase for MyWriter<'a> {
+    fn get_reader_collection_scan(&self, db: &str, coll: &str) -> Result<Box<Iterator<Item=Result<elmo::Row>> + 'static>> {
+        self.db.collection_scan(db, coll)
+    }
+
+    fn get_reader_text_index_scan(&self, ndx: &elmo::IndexInfo, eq: elmo::QueryKey, terms: Vec<elmo::TextQueryTerm>) -> Result<Box<Iterator<Item=Result<elmo::Row>> + 'static>> {
+        self.db.text_index_scan(ndx, eq, terms)
+    }
+
+    fn get_reader_regular_index_scan(&self, ndx: &elmo::IndexInfo, bounds: elmo::QueryBounds) -> Result<Box<Iterator<Item=Result<elmo::Row>> + 'static>> {
+        self.db.regular_index_scan(ndx, bounds, false)
+    }
+
+    fn get_reader_reversed_index_scan(&self, ndx: &elmo::IndexInfo, bounds: elmo::QueryBounds) -> Result<Box<Iterator<Item=Result<elmo::Row>> + 'static>> {
+        self.db.regular_index_scan(ndx, bounds, true)
+    }
+
+    fn get_record_ids_regular_index_scan(&self, ndx: &elmo::IndexInfo, bounds: elmo::QueryBounds) -> Result<Box<Iterator<Item=Result<Box<[u8]>>> + 'static>> {
+        self.db.record_ids_index_scan(ndx, bounds)
+    }
+
+    fn get_reader_records(&self, db: &str, coll: &str, ids: Vec<Box<[u8]>>) -> Result<Box<Iterator<Item=Result<elmo::Row>> + 'static>> {
+        self.db.records(db, coll, ids)
+    }
+
+    fn list_collections(&self) -> Result<Vec<elmo::CollectionInfo>> {
+        Ok(self.db.list_collections())
+    }
+
+    fn list_indexes(&self, ns: Option<(&str, &str)>) -> Result<Vec<elmo::IndexInfo>> {
+        Ok(self.db.list_indexes(ns))
+    }
+}
+
+impl elmo::StorageConnection for MyConn {
+    fn begin_write<'a>(&'a self) -> Result<Box<elmo::StorageWriter + 'a>> {
+        let tx = try!(self.shared.write_lock.lock().map_err(poisoned));
+        let db = try!(self.shared.committed.lock().map_err(poisoned)).clone();
+        let w = MyWriter {
+            shared: &*self.shared,
+            _tx: tx,
+            db: db,
+            saved: Vec::new(),
+        };
+        Ok(box w)
+    }
+
+    fn begin_read(&self) -> Result<Box<elmo::StorageReader + 'static>> {
+        let db = try!(self.shared.committed.lock().map_err(poisoned)).clone();
+        let r = MyReader {
+            db: db,
+        };
+        Ok(box r)
+    }
+}
+
+#[derive(Clone)]
+pub struct MyFactory {
+    shared: Arc<Shared>,
+    planner: Arc<elmo::PlannerState>,
+}
+
+impl MyFactory {
+    pub fn new() -> MyFactory {
+        let db = Database {
+            collections: OrdMap::new(),
+        };
+        let shared = Shared {
+            committed: Mutex::new(Arc::new(db)),
+            write_lock: Mutex::new(()),
+        };
+        MyFactory {
+            shared: Arc::new(shared),
+            planner: Arc::new(elmo::PlannerState::new()),
+        }
+    }
+}
+
+impl elmo::ConnectionFactory for MyFactory {
+    fn open(&self) -> elmo::Result<elmo::Connection> {
+        let c = MyConn {
+            shared: self.shared.clone(),
+        };
+        let c = elmo::Connection::new(box c, self.planner.clone());
+        Ok(c)
+    }
+
+    fn clone_for_new_thread(&self) -> Box<elmo::ConnectionFactory + Send> {
+        box self.clone()
+    }
+}
@@ -0,0 +1,48 @@
+extern crate bson;
+#[macro_use]
+extern crate elmo;
+extern crate elmo_memory;
+
+use elmo::ConnectionFactory;
+
+// every engine runs the same conformance tests from elmo.  each one gets
+// its own #[test] and its own fresh database.
+conformance_tests!(_name => elmo_memory::MyFactory::new());
+
+// Connection::insert takes a savepoint for every doc.  each one used to
+// clone the whole collection, which made this quadratic.
+#[test]
+fn insert_many_in_one_statement() {
+    fn f() -> elmo::Result<()> {
+        let factory = elmo_memory::MyFactory::new();
+        let conn = try!(factory.open());
+        let mut spec = bson::Document::new();
+        spec.set_i32("x", 1);
+        let ndx = elmo::IndexInfo {
+            db: String::from("many"),
+            coll: String::from("c"),
+            name: String::from("x_1"),
+            spec: spec,
+            options: bson::Document::new(),
+        };
+        try!(conn.create_indexes(vec![ndx]));
+        let count = 50000;
+        let mut docs = (0 .. count).map(|i| {
+            let mut d = bson::Document::new();
+            d.set_i32("_id", i);
+            d.set_i32("x", i % 100);
+            d
+        }).collect::<Vec<_>>();
+        for r in try!(conn.insert("many", "c", &mut docs, true)) {
+            try!(r);
+        }
+        let mut q = bson::Document::new();
+        q.set_i32("x", 7);
+        let seq = try!(conn.find("many", "c", q, None, None, None, None, None, None, None));
+        assert_eq!(try!(seq.collect::<elmo::Result<Vec<_>>>()).len(), (count / 100) as usize);
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
@@ -22,6 +22,8 @@
 use std::collections::HashMap;
 use std::collections::HashSet;
 
+extern crate misc;
+
 extern crate bson;
 
 extern crate elmo;
@@ -36,6 +38,7 @@ struct IndexPrep {
     stmt_delete: sqlite3::PreparedStatement,
     normspec: Vec<(String,elmo::IndexType)>,
     weights: Option<HashMap<String,i32>>,
+    collation: Option<bson::CollationSpec>,
 }
 
 struct MyCollectionWriter {
@@ -46,6 +49,12 @@ struct MyCollectionWriter {
     update: sqlite3::PreparedStatement,
     stmt_find_rowid: Option<sqlite3::PreparedStatement>,
     indexes: Vec<IndexPrep>,
+    capped: Option<elmo::Capped>,
+    // count and bytes, read from the capped table the first time they're
+    // needed.  this whole struct goes away on rollback_to(), so these
+    // can't get stale.
+    capped_stats: Option<(u64, u64)>,
+    save_capped_stats: Option<sqlite3::PreparedStatement>,
 }
 
 struct StatementBsonValueIterator {
@@ -95,6 +104,54 @@ impl Iterator for StatementBsonValueIterator {
     }
 }
 
+// for an index scan, which selects the doc and its rowid, in the order of
+// the index.  a doc can have more than one entry in an index, so this
+// skips the ones it has already produced.  DISTINCT in the sql would do
+// that too, but then sqlite is free to hand them back in whatever order.
+
+struct StatementDistinctBsonValueIterator {
+    stmt: sqlite3::PreparedStatement,
+    seen: HashSet<i64>,
+}
+
+impl StatementDistinctBsonValueIterator {
+    fn iter_next(&mut self) -> Result<Option<elmo::Row>> {
+        loop {
+            match try!(self.stmt.step().map_err(elmo::wrap_err)) {
+                None => {
+                    return Ok(None);
+                },
+                Some(r) => {
+                    let did = r.column_int64(1);
+                    if !self.seen.insert(did) {
+                        continue;
+                    }
+                    let b = r.column_blob(0).expect("NOT NULL");
+                    let v = try!(bson::Document::from_bson(&b));
+                    let v = bson::Value::BDocument(v);
+                    let row = elmo::Row {
+                        doc: v,
+                        pos: None,
+                        score: None,
+                    };
+                    return Ok(Some(row));
+                },
+            }
+        }
+    }
+}
+
+impl Iterator for StatementDistinctBsonValueIterator {
+    type Item = Result<elmo::Row>;
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.iter_next() {
+            Err(e) => Some(Err(e)),
+            Ok(None) => None,
+            Ok(Some(v)) => Some(Ok(v)),
+        }
+    }
+}
+
 // TODO it is sad to have two completely distinct versions of
 // this iterator, one which owns the statement, and one which
 // does not.
@@ -163,6 +220,7 @@ struct MyWriter {
     myconn: std::rc::Rc<MyConn>,
     cw: Option<MyCollectionWriter>,
     in_tx: bool,
+    savepoints: usize,
 }
 
 struct MyConn {
@@ -235,7 +293,24 @@ fn index_insert_step(stmt: &mut sqlite3::PreparedStatement, k: Vec<u8>, doc_rowi
     stmt.clear_bindings();
     try!(stmt.bind_blob(1, &k).map_err(elmo::wrap_err));
     try!(stmt.bind_int64(2, doc_rowid).map_err(elmo::wrap_err));
-    try!(step_done(stmt));
+    let r =
+        match stmt.step() {
+            Ok(None) => Ok(()),
+            Ok(Some(_)) => Err(elmo::Error::Misc(String::from("step_done() returned a row"))),
+            Err(e) => {
+                if e.kind == sqlite3::SqliteErrorCode::SQLITE_CONSTRAINT {
+                    Err(elmo::Error::MongoCode(11000, String::from("duplicate key error")))
+                } else {
+                    Err(elmo::wrap_err(e))
+                }
+            },
+        };
+    if r.is_err() {
+        // the statement has to be reset or it can't be used for the
+        // next doc.
+        stmt.reset();
+        return r;
+    }
     try!(verify_changes(stmt, 1));
     stmt.reset();
     Ok(())
@@ -256,9 +331,13 @@ impl MyConn {
         }
     }
 
-    fn get_stmt_for_index_scan(myconn: &MyConn, ndx: &elmo::IndexInfo, bounds: elmo::QueryBounds) -> Result<sqlite3::PreparedStatement> {
+    // the rows have the doc in the first column and its rowid in the
+    // second.  with ids_only, the first one is NULL, and the table for the
+    // collection isn't even joined.
+    fn get_stmt_for_index_scan(myconn: &MyConn, ndx: &elmo::IndexInfo, bounds: elmo::QueryBounds, reverse: bool, ids_only: bool) -> Result<sqlite3::PreparedStatement> {
         let tbl_coll = get_table_name_for_collection(&ndx.db, &ndx.coll);
         let tbl_ndx = get_table_name_for_index(&ndx.db, &ndx.coll, &ndx.name);
+        let collation = try!(ndx.collation_spec());
 
         fn add_one(ba: &Vec<u8>) -> Vec<u8> {
             let mut a = ba.clone();
@@ -279,15 +358,23 @@ impl MyConn {
             a
         }
 
-        // note that one of the reasons we need to do DISTINCT here is because a
-        // single index in a single document can produce multiple index entries,
-        // because, for example, when a value is an array, we don't just index
-        // the array as a value, but we also index each of its elements.
-        //
-        // TODO it would be nice if the DISTINCT here was happening on the rowids, not on the blobs
+        // note that a single index in a single document can produce multiple
+        // index entries, because, for example, when a value is an array, we
+        // don't just index the array as a value, but we also index each of
+        // its elements.  so the did comes back too, and the iterator skips
+        // the docs it has already seen.  the rows have to come in the order
+        // of the index, so it can be used for a sort.
+
+        let order = if reverse { "DESC" } else { "ASC" };
+        let select =
+            if ids_only {
+                format!("SELECT NULL, i.doc_rowid FROM \"{}\" i", tbl_ndx)
+            } else {
+                format!("SELECT d.bson, d.did FROM \"{}\" d INNER JOIN \"{}\" i ON (d.did = i.doc_rowid)", tbl_coll, tbl_ndx)
+            };
 
         let f_twok = |kmin: Vec<u8>, kmax: Vec<u8>, op1: &str, op2: &str| -> Result<sqlite3::PreparedStatement> {
-            let sql = format!("SELECT DISTINCT d.bson FROM \"{}\" d INNER JOIN \"{}\" i ON (d.did = i.doc_rowid) WHERE k {} ? AND k {} ?", tbl_coll, tbl_ndx, op1, op2);
+            let sql = format!("{} WHERE k {} ? AND k {} ? ORDER BY i.k {}", select, op1, op2, order);
             //println!("using sql: {}", sql);
             let mut stmt = try!(myconn.conn.prepare(&sql).map_err(elmo::wrap_err));
             try!(stmt.bind_blob(1, &kmin).map_err(elmo::wrap_err));
@@ -296,14 +383,14 @@ impl MyConn {
         };
 
         let f_two = |eqvals: elmo::QueryKey, minvals: elmo::QueryKey, maxvals: elmo::QueryKey, op1: &str, op2: &str| -> Result<sqlite3::PreparedStatement> {
-            let kmin = bson::Value::encode_multi_for_index(&eqvals, Some(&minvals));
-            let kmax = bson::Value::encode_multi_for_index(&eqvals, Some(&maxvals));
+            let kmin = bson::Value::encode_multi_for_index_collated(&eqvals, Some(&minvals), collation.as_ref());
+            let kmax = bson::Value::encode_multi_for_index_collated(&eqvals, Some(&maxvals), collation.as_ref());
             f_twok(kmin, kmax, op1, op2)
         };
 
         let f_one = |vals: elmo::QueryKey, op: &str| -> Result<sqlite3::PreparedStatement> {
-            let k = bson::Value::encode_multi_for_index(&vals, None);
-            let sql = format!("SELECT DISTINCT d.bson FROM \"{}\" d INNER JOIN \"{}\" i ON (d.did = i.doc_rowid) WHERE k {} ?", tbl_coll, tbl_ndx, op);
+            let k = bson::Value::encode_multi_for_index_collated(&vals, None, collation.as_ref());
+            let sql = format!("{} WHERE k {} ? ORDER BY i.k {}", select, op, order);
             let mut stmt = try!(myconn.conn.prepare(&sql).map_err(elmo::wrap_err));
             try!(stmt.bind_blob(1, &k).map_err(elmo::wrap_err));
             Ok(stmt)
@@ -319,7 +406,7 @@ impl MyConn {
             elmo::QueryBounds::GT_LTE(eqvals, minvals, maxvals) => f_two(eqvals, minvals, maxvals, ">", "<="),
             elmo::QueryBounds::GTE_LTE(eqvals, minvals, maxvals) => f_two(eqvals, minvals, maxvals, ">=", "<="),
             elmo::QueryBounds::EQ(vals) => {
-                let kmin = bson::Value::encode_multi_for_index(&vals, None);
+                let kmin = bson::Value::encode_multi_for_index_collated(&vals, None, collation.as_ref());
                 let kmax = add_one(&kmin);
                 f_twok(kmin, kmax, ">=", "<")
             },
@@ -343,13 +430,52 @@ impl MyConn {
         Ok(rdr)
     }
 
-    fn get_nontext_index_scan_reader(myconn: std::rc::Rc<MyConn>, commit_on_drop: bool, ndx: &elmo::IndexInfo, bounds: elmo::QueryBounds) -> Result<MyCollectionReader> {
-        let stmt = try!(Self::get_stmt_for_index_scan(&myconn, ndx, bounds));
+    // rowids only go up, so everything inserted after the doc with this
+    // _id has a bigger one.
+    fn get_table_scan_reader_after(myconn: std::rc::Rc<MyConn>, commit_on_drop: bool, db: &str, coll: &str, id: &bson::Value) -> Result<Option<MyCollectionReader>> {
+        if try!(myconn.get_index_info(db, coll, "_id_")).is_none() {
+            if try!(myconn.get_collection_options(db, coll)).is_none() {
+                return Ok(None);
+            }
+            // TODO without the _id index, we would have to scan for it
+            return Err(elmo::Error::Misc(String::from("tailing a collection requires an _id index")));
+        }
+        let rowid = {
+            let tbl_ndx = get_table_name_for_index(db, coll, "_id_");
+            let mut stmt = try!(myconn.conn.prepare(&format!("SELECT doc_rowid FROM \"{}\" WHERE k=?", tbl_ndx)).map_err(elmo::wrap_err));
+            let ba = bson::Value::encode_one_for_index(id, false);
+            try!(stmt.bind_blob(1, &ba).map_err(elmo::wrap_err));
+            let rowid =
+                match try!(stmt.step().map_err(elmo::wrap_err)) {
+                    None => return Ok(None),
+                    Some(r) => r.column_int64(0),
+                };
+            rowid
+        };
+        let tbl = get_table_name_for_collection(db, coll);
+        let mut stmt = try!(myconn.conn.prepare(&format!("SELECT bson FROM \"{}\" WHERE did>? ORDER BY did", tbl)).map_err(elmo::wrap_err));
+        try!(stmt.bind_int64(1, rowid).map_err(elmo::wrap_err));
+        let seq = 
+            StatementBsonValueIterator {
+                     stmt: stmt,
+            };
+        let rdr = 
+            MyCollectionReader {
+                commit_on_drop: commit_on_drop,
+                seq: box seq,
+                myconn: myconn,
+            };
+        Ok(Some(rdr))
+    }
+
+    fn get_nontext_index_scan_reader(myconn: std::rc::Rc<MyConn>, commit_on_drop: bool, ndx: &elmo::IndexInfo, bounds: elmo::QueryBounds, reverse: bool) -> Result<MyCollectionReader> {
+        let stmt = try!(Self::get_stmt_for_index_scan(&myconn, ndx, bounds, reverse, false));
 
         // TODO keep track of total keys examined, etc.
         let seq = 
-            StatementBsonValueIterator {
+            StatementDistinctBsonValueIterator {
                      stmt: stmt,
+                     seen: HashSet::new(),
             };
         let rdr = 
             MyCollectionReader {
@@ -425,47 +551,6 @@ impl MyConn {
             found.push(v);
         };
 
-        fn contains_phrase(weights: &HashMap<String, i32>, doc: &bson::Value, p: &str) -> bool {
-            weights.keys().any(
-                |k|
-                doc.walk_path(k).leaves().any(
-                    |leaf|
-                    match leaf.v {
-                        Some(v) => {
-                            match v {
-                                &bson::Value::BString(ref s) => s.find(p).is_some(),
-                                _ => false,
-                            }
-                        },
-                        None => {
-                            false
-                        },
-                    }
-                    )
-                )
-        }
-
-        fn check_phrase(terms: &Vec<elmo::TextQueryTerm>, weights: &HashMap<String, i32>, doc: &bson::Value) -> bool {
-            for term in terms {
-                let b = 
-                    match term {
-                        &elmo::TextQueryTerm::Word(neg, ref s) => true,
-                        &elmo::TextQueryTerm::Phrase(neg, ref s) => {
-                            let has = contains_phrase(weights, doc, s);
-                            if neg {
-                                !has
-                            } else {
-                                has
-                            }
-                        },
-                    };
-                if !b {
-                    return false;
-                }
-            }
-            return true;
-        }
-
         let mut pos_entries = Vec::new();
         let mut neg_entries = Vec::new();
         for e in found {
@@ -522,7 +607,7 @@ impl MyConn {
                     };
                 for r in rdr {
                     let mut r = try!(r);
-                    let keep = check_phrase(&terms, &weights, &r.doc);
+                    let keep = elmo::check_text_phrases(&terms, &weights, &r.doc);
                     if keep {
                         // TODO this is not the way mongo does this calculation
                         let score = cur_weights.iter().sum::<i32>() as f64;
@@ -581,7 +666,7 @@ impl MyConn {
         }
     }
 
-    fn get_reader_regular_index_scan(&self, myconn: std::rc::Rc<MyConn>, commit_on_drop: bool, ndx: &elmo::IndexInfo, bounds: elmo::QueryBounds) -> Result<MyCollectionReader> {
+    fn get_reader_regular_index_scan(&self, myconn: std::rc::Rc<MyConn>, commit_on_drop: bool, ndx: &elmo::IndexInfo, bounds: elmo::QueryBounds, reverse: bool) -> Result<MyCollectionReader> {
         // TODO is this check right?
         // should we instead check for the existence of the index?
         // in theory, if the index passed into us is valid, then we already know
@@ -597,12 +682,69 @@ impl MyConn {
                 Ok(rdr)
             },
             Some(_) => {
-                let rdr = try!(Self::get_nontext_index_scan_reader(myconn, commit_on_drop, ndx, bounds));
+                let rdr = try!(Self::get_nontext_index_scan_reader(myconn, commit_on_drop, ndx, bounds, reverse));
                 return Ok(rdr);
             },
         }
     }
 
+    // the rowids of the docs in the index scan, each once, as bytes
+    fn get_record_ids_regular_index_scan(&self, ndx: &elmo::IndexInfo, bounds: elmo::QueryBounds) -> Result<Box<Iterator<Item=Result<Box<[u8]>>>>> {
+        if try!(self.get_collection_options(&ndx.db, &ndx.coll)).is_none() {
+            return Ok(box std::iter::empty());
+        }
+        let mut stmt = try!(Self::get_stmt_for_index_scan(self, ndx, bounds, false, true));
+        let mut seen = HashSet::new();
+        let mut ids = vec![];
+        loop {
+            match try!(stmt.step().map_err(elmo::wrap_err)) {
+                None => break,
+                Some(r) => {
+                    let did = r.column_int64(1);
+                    if seen.insert(did) {
+                        ids.push(Ok(misc::i64_to_bytes_be(did).to_vec().into_boxed_slice()));
+                    }
+                },
+            }
+        }
+        Ok(box ids.into_iter())
+    }
+
+    fn get_reader_records(&self, myconn: std::rc::Rc<MyConn>, commit_on_drop: bool, db: &str, coll: &str, ids: Vec<Box<[u8]>>) -> Result<MyCollectionReader> {
+        let mut res = Vec::new();
+        if try!(self.get_collection_options(db, coll)).is_some() {
+            let tbl_coll = get_table_name_for_collection(db, coll);
+            let mut stmt = try!(self.conn.prepare(&format!("SELECT bson FROM \"{}\" WHERE did=?", tbl_coll)).map_err(elmo::wrap_err));
+            for ba in ids {
+                if ba.len() != 8 {
+                    return Err(elmo::Error::Misc(String::from("bad record id")));
+                }
+                let mut a = [0; 8];
+                for i in 0 .. 8 {
+                    a[i] = ba[i];
+                }
+                try!(stmt.bind_int64(1, misc::i64_from_bytes_be(a)).map_err(elmo::wrap_err));
+                {
+                    let rdr = 
+                        RefStatementBsonValueIterator {
+                            stmt: &mut stmt,
+                        };
+                    for r in rdr {
+                        res.push(Ok(try!(r)));
+                    }
+                }
+                stmt.reset();
+            }
+        }
+        let rdr = 
+            MyCollectionReader {
+                commit_on_drop: commit_on_drop,
+                seq: box res.into_iter(),
+                myconn: myconn,
+            };
+        Ok(rdr)
+    }
+
     fn get_index_info(&self, db: &str, coll: &str, name: &str) -> Result<Option<elmo::IndexInfo>> {
         // TODO DRY this string, below
         let mut stmt = try!(self.conn.prepare("SELECT ndxName, spec, options, dbName, collName FROM \"indexes\" WHERE dbName=? AND collName=? AND ndxName=?").map_err(elmo::wrap_err));
@@ -618,16 +760,38 @@ impl MyConn {
         }
     }
 
-    fn base_list_indexes(&self, ns: Option<(&str, &str)>) -> Result<Vec<elmo::IndexInfo>> {
+    // the first doc with an array for one of the keys makes the index
+    // multikey for good.
+    fn set_index_multikey(&self, info: &mut elmo::IndexInfo) -> Result<()> {
+        info.options.set_bool("multikey", true);
+        let ba_options = info.options.to_bson_array();
+        let mut stmt = try!(self.conn.prepare("UPDATE \"indexes\" SET options=? WHERE dbName=? AND collName=? AND ndxName=?").map_err(elmo::wrap_err));
+        try!(stmt.bind_blob(1, &ba_options).map_err(elmo::wrap_err));
+        try!(stmt.bind_text(2, &info.db).map_err(elmo::wrap_err));
+        try!(stmt.bind_text(3, &info.coll).map_err(elmo::wrap_err));
+        try!(stmt.bind_text(4, &info.name).map_err(elmo::wrap_err));
+        try!(step_done(&mut stmt));
+        Ok(())
+    }
+
+    // writers need every index, but an index still being built in the
+    // background is not ready for anyone else.
+    fn base_list_indexes(&self, ns: Option<(&str, &str)>, ready_only: bool) -> Result<Vec<elmo::IndexInfo>> {
+        let ready =
+            if ready_only {
+                " AND NOT EXISTS (SELECT 1 FROM \"building\" b WHERE b.dbName=i.dbName AND b.collName=i.collName AND b.ndxName=i.ndxName)"
+            } else {
+                ""
+            };
         // TODO DRY this string, above
         let mut stmt =
             match ns {
                 None => {
-                    let stmt = try!(self.conn.prepare("SELECT ndxName, spec, options, dbName, collName FROM \"indexes\"").map_err(elmo::wrap_err));
+                    let stmt = try!(self.conn.prepare(&format!("SELECT ndxName, spec, options, dbName, collName FROM \"indexes\" i WHERE 1{}", ready)).map_err(elmo::wrap_err));
                     stmt
                 },
                 Some((db,coll)) => {
-                    let mut stmt = try!(self.conn.prepare("SELECT ndxName, spec, options, dbName, collName FROM \"indexes\" WHERE dbName=? AND collName=?").map_err(elmo::wrap_err));
+                    let mut stmt = try!(self.conn.prepare(&format!("SELECT ndxName, spec, options, dbName, collName FROM \"indexes\" i WHERE dbName=? AND collName=?{}", ready)).map_err(elmo::wrap_err));
                     try!(stmt.bind_text(1, db).map_err(elmo::wrap_err));
                     try!(stmt.bind_text(2, coll).map_err(elmo::wrap_err));
                     stmt
@@ -698,25 +862,118 @@ impl MyWriter {
         Ok(())
     }
 
-    fn update_indexes_insert(indexes: &mut Vec<IndexPrep>, rowid: i64, v: &bson::Document) -> Result<()> {
+    fn update_indexes_insert(myconn: &MyConn, indexes: &mut Vec<IndexPrep>, rowid: i64, v: &bson::Document) -> Result<()> {
         for t in indexes {
             let entries = try!(elmo::get_index_entries(&v, &t.normspec, &t.weights, &t.info.options));
             for vals in entries {
                 let vref = vals.iter().map(|&(ref v,neg)| (v,neg)).collect::<Vec<_>>();
-                let k = bson::Value::encode_multi_for_index(&vref, None);
+                let k = bson::Value::encode_multi_for_index_collated(&vref, None, t.collation.as_ref());
                 try!(index_insert_step(&mut t.stmt_insert, k, rowid));
             }
+            if !t.info.is_multikey() && elmo::is_multikey_doc(&v, &t.normspec) {
+                try!(myconn.set_index_multikey(&mut t.info));
+            }
         }
         Ok(())
     }
 
+    // the count and bytes of a capped collection, as of before whatever
+    // the caller is about to do to it.  a collection from before the
+    // capped table existed has to count them once.
+    fn load_capped_stats(myconn: &MyConn, cw: &mut MyCollectionWriter) -> Result<(u64, u64)> {
+        if let Some(stats) = cw.capped_stats {
+            return Ok(stats);
+        }
+        let stats = {
+            let mut stmt = try!(myconn.conn.prepare("SELECT count, bytes FROM \"capped\" WHERE dbName=? AND collName=?").map_err(elmo::wrap_err));
+            try!(stmt.bind_text(1, &cw.db).map_err(elmo::wrap_err));
+            try!(stmt.bind_text(2, &cw.coll).map_err(elmo::wrap_err));
+            match try!(stmt.step().map_err(elmo::wrap_err)) {
+                Some(r) => Some((r.column_int64(0) as u64, r.column_int64(1) as u64)),
+                None => None,
+            }
+        };
+        let stats =
+            match stats {
+                Some(stats) => stats,
+                None => {
+                    let tbl = get_table_name_for_collection(&cw.db, &cw.coll);
+                    let mut stmt = try!(myconn.conn.prepare(&format!("SELECT COUNT(*), COALESCE(SUM(length(bson)),0) FROM \"{}\"", tbl)).map_err(elmo::wrap_err));
+                    let stats =
+                        match try!(stmt.step().map_err(elmo::wrap_err)) {
+                            None => return Err(elmo::Error::Misc(String::from("count returned no row"))),
+                            Some(r) => (r.column_int64(0) as u64, r.column_int64(1) as u64),
+                        };
+                    stats
+                },
+            };
+        cw.capped_stats = Some(stats);
+        Ok(stats)
+    }
+
+    fn set_capped_stats(cw: &mut MyCollectionWriter, count: u64, bytes: u64) -> Result<()> {
+        cw.capped_stats = Some((count, bytes));
+        if let Some(ref mut stmt) = cw.save_capped_stats {
+            stmt.clear_bindings();
+            try!(stmt.bind_text(1, &cw.db).map_err(elmo::wrap_err));
+            try!(stmt.bind_text(2, &cw.coll).map_err(elmo::wrap_err));
+            try!(stmt.bind_int64(3, count as i64).map_err(elmo::wrap_err));
+            try!(stmt.bind_int64(4, bytes as i64).map_err(elmo::wrap_err));
+            try!(step_done(stmt));
+            stmt.reset();
+        }
+        Ok(())
+    }
+
+    // called after a doc of len bytes went into a capped collection, with
+    // the stats from before it did.  the oldest docs are the ones with the
+    // lowest rowids.  their index entries go with them, because of the
+    // foreign key cascade.
+    fn evict_capped(myconn: &MyConn, cw: &mut MyCollectionWriter, capped: &elmo::Capped, len: usize) -> Result<()> {
+        let tbl = get_table_name_for_collection(&cw.db, &cw.coll);
+        let (count, bytes) = try!(Self::load_capped_stats(myconn, cw));
+        let (mut count, mut bytes) = (count + 1, bytes + len as u64);
+        let mut victims = Vec::new();
+        if capped.is_over(count, bytes) {
+            let mut stmt = try!(myconn.conn.prepare(&format!("SELECT did, length(bson) FROM \"{}\" ORDER BY did", tbl)).map_err(elmo::wrap_err));
+            while capped.is_over(count, bytes) {
+                match try!(stmt.step().map_err(elmo::wrap_err)) {
+                    None => break,
+                    Some(r) => {
+                        victims.push(r.column_int64(0));
+                        count = count - 1;
+                        bytes = bytes - r.column_int64(1) as u64;
+                    },
+                }
+            }
+        }
+        for rowid in victims {
+            cw.delete.clear_bindings();
+            try!(cw.delete.bind_int64(1, rowid).map_err(elmo::wrap_err));
+            try!(step_done(&mut cw.delete));
+            cw.delete.reset();
+        }
+        Self::set_capped_stats(cw, count, bytes)
+    }
+
     fn get_collection_writer(&self, db: &str, coll: &str) -> Result<MyCollectionWriter> {
         let _created = try!(self.base_create_collection(db, coll, bson::Document::new()));
         let tbl = get_table_name_for_collection(db, coll);
         let stmt_insert = try!(self.myconn.conn.prepare(&format!("INSERT INTO \"{}\" (bson) VALUES (?)", tbl)).map_err(elmo::wrap_err));
         let stmt_delete = try!(self.myconn.conn.prepare(&format!("DELETE FROM \"{}\" WHERE rowid=?", tbl)).map_err(elmo::wrap_err));
         let stmt_update = try!(self.myconn.conn.prepare(&format!("UPDATE \"{}\" SET bson=? WHERE rowid=?", tbl)).map_err(elmo::wrap_err));
-        let indexes = try!(self.myconn.base_list_indexes(Some((db, coll))));
+        let capped =
+            match try!(self.myconn.get_collection_options(db, coll)) {
+                Some(options) => try!(elmo::Capped::from_options(&options)),
+                None => None,
+            };
+        let save_capped_stats =
+            if capped.is_some() {
+                Some(try!(self.myconn.conn.prepare("INSERT OR REPLACE INTO \"capped\" (dbName,collName,count,bytes) VALUES (?,?,?,?)").map_err(elmo::wrap_err)))
+            } else {
+                None
+            };
+        let indexes = try!(self.myconn.base_list_indexes(Some((db, coll)), false));
         let mut find_rowid = None;
         for info in &indexes {
             if info.name == "_id_" {
@@ -731,12 +988,14 @@ impl MyWriter {
             let stmt_insert = try!(self.prepare_index_insert(&tbl_ndx));
             let stmt_delete = try!(self.myconn.conn.prepare(&format!("DELETE FROM \"{}\" WHERE doc_rowid=?", tbl_ndx)).map_err(elmo::wrap_err));
             let (normspec, weights) = try!(elmo::get_normalized_spec(&info.spec, &info.options));
+            let collation = try!(info.collation_spec());
             let t = IndexPrep {
                 info: info, 
                 stmt_insert: stmt_insert, 
                 stmt_delete: stmt_delete,
                 normspec: normspec,
                 weights: weights,
+                collation: collation,
             };
             index_stmts.push(t);
         }
@@ -748,6 +1007,9 @@ impl MyWriter {
             update: stmt_update,
             stmt_find_rowid: find_rowid,
             indexes: index_stmts,
+            capped: capped,
+            capped_stats: None,
+            save_capped_stats: save_capped_stats,
         };
         Ok(c)
     }
@@ -776,7 +1038,7 @@ impl MyWriter {
         Ok(stmt)
     }
 
-    fn create_index(&self, info: elmo::IndexInfo) -> Result<bool> {
+    fn create_index(&self, mut info: elmo::IndexInfo, building: bool) -> Result<bool> {
         //println!("create_index: {:?}", info);
         let _created = try!(self.base_create_collection(&info.db, &info.coll, bson::Document::new()));
         match try!(self.myconn.get_index_info(&info.db, &info.coll, &info.name)) {
@@ -816,24 +1078,40 @@ impl MyWriter {
                         };
                         try!(self.myconn.conn.exec(&s).map_err(elmo::wrap_err));
                         try!(self.myconn.conn.exec(&format!("CREATE INDEX \"childndx_{}\" ON \"{}\" (doc_rowid)", tbl_ndx, tbl_ndx)).map_err(elmo::wrap_err));
-                        // now insert index entries for every doc that already exists
-                        let (normspec, weights) = try!(elmo::get_normalized_spec(&info.spec, &info.options));
-                        let mut stmt2 = try!(self.myconn.conn.prepare(&format!("SELECT did,bson FROM \"{}\"", tbl_coll)).map_err(elmo::wrap_err));
-                        let mut stmt_insert = try!(self.prepare_index_insert(&tbl_ndx));
-                        loop {
-                            match try!(stmt2.step().map_err(elmo::wrap_err)) {
-                                None => break,
-                                Some(row) => {
-                                    let doc_rowid = row.column_int64(0);
-                                    let new_doc = try!(bson::Document::from_bson(&row.column_slice(1).expect("NOT NULL")));
-                                    let entries = try!(elmo::get_index_entries(&new_doc, &normspec, &weights, &info.options));
-                                    for vals in entries {
-                                        //println!("index entry: {:?}", vals);
-                                        let vref = vals.iter().map(|&(ref v,neg)| (v,neg)).collect::<Vec<_>>();
-                                        let k = bson::Value::encode_multi_for_index(&vref, None);
-                                        try!(index_insert_step(&mut stmt_insert, k, doc_rowid));
-                                    }
-                                },
+                        if building {
+                            // writers keep the index up to date from here
+                            // on.  the docs already there get added later.
+                            let mut stmt = try!(self.myconn.conn.prepare("INSERT INTO \"building\" (dbName,collName,ndxName) VALUES (?,?,?)").map_err(elmo::wrap_err));
+                            try!(stmt.bind_text(1, &info.db).map_err(elmo::wrap_err));
+                            try!(stmt.bind_text(2, &info.coll).map_err(elmo::wrap_err));
+                            try!(stmt.bind_text(3, &info.name).map_err(elmo::wrap_err));
+                            try!(step_done(&mut stmt));
+                        } else {
+                            // now insert index entries for every doc that already exists
+                            let (normspec, weights) = try!(elmo::get_normalized_spec(&info.spec, &info.options));
+                            let collation = try!(info.collation_spec());
+                            let mut stmt2 = try!(self.myconn.conn.prepare(&format!("SELECT did,bson FROM \"{}\"", tbl_coll)).map_err(elmo::wrap_err));
+                            let mut stmt_insert = try!(self.prepare_index_insert(&tbl_ndx));
+                            let mut multikey = false;
+                            loop {
+                                match try!(stmt2.step().map_err(elmo::wrap_err)) {
+                                    None => break,
+                                    Some(row) => {
+                                        let doc_rowid = row.column_int64(0);
+                                        let new_doc = try!(bson::Document::from_bson(&row.column_slice(1).expect("NOT NULL")));
+                                        let entries = try!(elmo::get_index_entries(&new_doc, &normspec, &weights, &info.options));
+                                        for vals in entries {
+                                            //println!("index entry: {:?}", vals);
+                                            let vref = vals.iter().map(|&(ref v,neg)| (v,neg)).collect::<Vec<_>>();
+                                            let k = bson::Value::encode_multi_for_index_collated(&vref, None, collation.as_ref());
+                                            try!(index_insert_step(&mut stmt_insert, k, doc_rowid));
+                                        }
+                                        multikey = multikey || elmo::is_multikey_doc(&new_doc, &normspec);
+                                    },
+                                }
+                            }
+                            if multikey && !info.is_multikey() {
+                                try!(self.myconn.set_index_multikey(&mut info));
                             }
                         }
                         Ok(true)
@@ -856,6 +1134,10 @@ impl MyWriter {
             Some(_) => {
                 let tbl = get_table_name_for_collection(db, coll);
                 try!(self.myconn.conn.exec(&format!("DELETE FROM \"{}\"", tbl)).map_err(elmo::wrap_err));
+                let mut stmt = try!(self.myconn.conn.prepare("DELETE FROM \"capped\" WHERE dbName=? AND collName=?").map_err(elmo::wrap_err));
+                try!(stmt.bind_text(1, db).map_err(elmo::wrap_err));
+                try!(stmt.bind_text(2, coll).map_err(elmo::wrap_err));
+                try!(step_done(&mut stmt));
                 Ok(false)
             },
         }
@@ -877,6 +1159,8 @@ impl MyWriter {
 
         if drop_target {
             let _deleted = try!(self.base_drop_collection(new_db, new_coll));
+        } else if try!(self.myconn.get_collection_options(new_db, new_coll)).is_some() {
+            return Err(elmo::Error::Misc(String::from("renameCollection to something that already exists")));
         }
 
         match try!(self.myconn.get_collection_options(old_db, old_coll)) {
@@ -887,7 +1171,7 @@ impl MyWriter {
             Some(_) => {
                 let old_tbl = get_table_name_for_collection(old_db, old_coll);
                 let new_tbl = get_table_name_for_collection(new_db, new_coll);
-                let indexes = try!(self.myconn.base_list_indexes(Some((old_db, old_coll))));
+                let indexes = try!(self.myconn.base_list_indexes(Some((old_db, old_coll)), false));
 
                 let mut stmt = try!(self.myconn.conn.prepare("UPDATE \"collections\" SET dbName=?, collName=? WHERE dbName=? AND collName=?").map_err(elmo::wrap_err));
                 try!(stmt.bind_text(1, new_db).map_err(elmo::wrap_err));
@@ -931,7 +1215,7 @@ impl MyWriter {
                                     coll: String::from(coll),
                                     name: String::from("_id_"),
                                     spec: bson::Document {pairs: vec![(String::from("_id"), bson::Value::BInt32(1))]},
-                                    options: bson::Document {pairs: vec![(String::from("unique"), bson::Value::BBoolean(true))]},
+                                    options: bson::Document {pairs: vec![(String::from("unique"), bson::Value::BBoolean(true)), (String::from("multikey"), bson::Value::BBoolean(false))]},
                                 };
                                 let _created = self.create_index(info);
                             },
@@ -949,7 +1233,7 @@ impl MyWriter {
     fn base_create_indexes(&self, what: Vec<elmo::IndexInfo>) -> Result<Vec<bool>> {
         let mut v = Vec::new();
         for info in what {
-            let b = try!(self.create_index(info));
+            let b = try!(self.create_index(info, false));
             v.push(b);
         }
         Ok(v)
@@ -990,7 +1274,7 @@ impl MyWriter {
         match try!(self.myconn.get_collection_options(db, coll)) {
             None => Ok(false),
             Some(_) => {
-                let indexes = try!(self.myconn.base_list_indexes(Some((db, coll))));
+                let indexes = try!(self.myconn.base_list_indexes(Some((db, coll)), false));
                 for info in indexes {
                     try!(self.base_drop_index(&info.db, &info.coll, &info.name));
                 }
@@ -1020,6 +1304,21 @@ impl elmo::StorageWriter for MyWriter {
                     None => Err(elmo::Error::Misc(String::from("update but does not exist"))),
                     Some(rowid) => {
                         let ba = v.to_bson_array();
+                        if cw.capped.is_some() {
+                            let tbl = get_table_name_for_collection(db, coll);
+                            let mut stmt = try!(self.myconn.conn.prepare(&format!("SELECT length(bson) FROM \"{}\" WHERE did=?", tbl)).map_err(elmo::wrap_err));
+                            try!(stmt.bind_int64(1, rowid).map_err(elmo::wrap_err));
+                            let old_len =
+                                match try!(stmt.step().map_err(elmo::wrap_err)) {
+                                    None => return Err(elmo::Error::Misc(String::from("update but does not exist"))),
+                                    Some(r) => r.column_int64(0) as u64,
+                                };
+                            if ba.len() as u64 > old_len {
+                                return Err(elmo::capped_cannot_grow());
+                            }
+                            let (count, bytes) = try!(Self::load_capped_stats(&self.myconn, cw));
+                            try!(Self::set_capped_stats(cw, count, bytes - old_len + ba.len() as u64));
+                        }
                         cw.update.clear_bindings();
                         try!(cw.update.bind_blob(1,&ba).map_err(elmo::wrap_err));
                         try!(cw.update.bind_int64(2, rowid).map_err(elmo::wrap_err));
@@ -1027,7 +1326,7 @@ impl elmo::StorageWriter for MyWriter {
                         try!(verify_changes(&cw.update, 1));
                         cw.update.reset();
                         try!(Self::update_indexes_delete(&mut cw.indexes, rowid));
-                        try!(Self::update_indexes_insert(&mut cw.indexes, rowid, &v));
+                        try!(Self::update_indexes_insert(&self.myconn, &mut cw.indexes, rowid, &v));
                         Ok(())
                     },
                 }
@@ -1038,6 +1337,9 @@ impl elmo::StorageWriter for MyWriter {
     fn delete(&mut self, db: &str, coll: &str, id: &bson::Value) -> Result<bool> {
         try!(self.prep_collection_writer(db, coll));
         let mut cw = self.cw.as_mut().unwrap();
+        if cw.capped.is_some() {
+            return Err(elmo::capped_cannot_delete());
+        }
         match try!(cw.find_rowid(&id).map_err(elmo::wrap_err)) {
             None => Ok(false),
             Some(rowid) => {
@@ -1064,6 +1366,13 @@ impl elmo::StorageWriter for MyWriter {
         try!(self.prep_collection_writer(db, coll));
         let mut cw = self.cw.as_mut().unwrap();
         let ba = v.to_bson_array();
+        if let Some(ref capped) = cw.capped {
+            try!(capped.check_doc_size(ba.len()));
+        }
+        if cw.capped.is_some() {
+            // before the doc goes in
+            try!(Self::load_capped_stats(&self.myconn, cw));
+        }
         cw.insert.clear_bindings();
         try!(cw.insert.bind_blob(1,&ba).map_err(elmo::wrap_err));
         try!(step_done(&mut cw.insert));
@@ -1074,8 +1383,16 @@ impl elmo::StorageWriter for MyWriter {
         // a rowid won't get reused until a 64 bit integer wraps,
         // at which time we will have other, more severe problems.
         // try!(Self::update_indexes_delete(&mut cw.indexes, rowid));
-        try!(Self::update_indexes_insert(&mut cw.indexes, rowid, &v));
-        Ok(())
+        // if this fails, probably a duplicate key, the doc and whatever
+        // index entries made it in are still there.  the caller has a
+        // savepoint around each insert, and rolling back to it takes them
+        // out.
+        try!(Self::update_indexes_insert(&self.myconn, &mut cw.indexes, rowid, &v));
+        let capped = cw.capped;
+        match capped {
+            Some(capped) => Self::evict_capped(&self.myconn, cw, &capped, ba.len()),
+            None => Ok(()),
+        }
     }
 
     fn commit(mut self: Box<Self>) -> Result<()> {
@@ -1090,12 +1407,161 @@ impl elmo::StorageWriter for MyWriter {
         Ok(())
     }
 
+    // sqlite does the real work here.  we just keep track of how deep we
+    // are so that we can name them and catch bad ones.
+    fn savepoint(&mut self) -> Result<elmo::Savepoint> {
+        let n = self.savepoints + 1;
+        try!(self.myconn.conn.exec(&format!("SAVEPOINT sp{}", n)).map_err(elmo::wrap_err));
+        self.savepoints = n;
+        Ok(elmo::Savepoint(n))
+    }
+
+    fn rollback_to(&mut self, sp: elmo::Savepoint) -> Result<()> {
+        let elmo::Savepoint(n) = sp;
+        if n == 0 || n > self.savepoints {
+            return Err(elmo::Error::Misc(String::from("no such savepoint")));
+        }
+        // the cached statements may refer to tables that are about to go
+        // away
+        self.cw = None;
+        try!(self.myconn.conn.exec(&format!("ROLLBACK TO SAVEPOINT sp{}", n)).map_err(elmo::wrap_err));
+        self.savepoints = n;
+        Ok(())
+    }
+
+    fn release(&mut self, sp: elmo::Savepoint) -> Result<()> {
+        let elmo::Savepoint(n) = sp;
+        if n == 0 || n > self.savepoints {
+            return Err(elmo::Error::Misc(String::from("no such savepoint")));
+        }
+        try!(self.myconn.conn.exec(&format!("RELEASE SAVEPOINT sp{}", n)).map_err(elmo::wrap_err));
+        self.savepoints = n - 1;
+        Ok(())
+    }
+
+    fn begin_index_build(&mut self, info: elmo::IndexInfo) -> Result<bool> {
+        let created = try!(self.create_index(info, true));
+        // the cached collection writer doesn't know about this index
+        self.cw = None;
+        Ok(created)
+    }
+
+    fn index_build_add(&mut self, ndx: &elmo::IndexInfo, ids: &[bson::Value]) -> Result<()> {
+        try!(self.prep_collection_writer(&ndx.db, &ndx.coll));
+        let unique =
+            match ndx.options.get("unique") {
+                Some(&bson::Value::BBoolean(b)) => b,
+                _ => false,
+            };
+        let (normspec, weights) = try!(elmo::get_normalized_spec(&ndx.spec, &ndx.options));
+        let collation = try!(ndx.collation_spec());
+        let tbl_coll = get_table_name_for_collection(&ndx.db, &ndx.coll);
+        let tbl_ndx = get_table_name_for_index(&ndx.db, &ndx.coll, &ndx.name);
+        let mut stmt_doc = try!(self.myconn.conn.prepare(&format!("SELECT bson FROM \"{}\" WHERE did=?", tbl_coll)).map_err(elmo::wrap_err));
+        // a writer may have already put an entry in when it changed the doc.
+        // putting it in again is harmless.
+        let mut stmt_insert = try!(self.myconn.conn.prepare(&format!("INSERT OR IGNORE INTO \"{}\" (k,doc_rowid) VALUES (?,?)", tbl_ndx)).map_err(elmo::wrap_err));
+        let mut stmt_owner = try!(self.myconn.conn.prepare(&format!("SELECT doc_rowid FROM \"{}\" WHERE k=?", tbl_ndx)).map_err(elmo::wrap_err));
+        let cw = self.cw.as_mut().unwrap();
+        let mut multikey = false;
+        for id in ids {
+            let rowid =
+                match try!(cw.find_rowid(id)) {
+                    Some(rowid) => rowid,
+                    None => {
+                        // deleted since the snapshot
+                        continue;
+                    },
+                };
+            stmt_doc.clear_bindings();
+            try!(stmt_doc.bind_int64(1, rowid).map_err(elmo::wrap_err));
+            let doc =
+                match try!(stmt_doc.step().map_err(elmo::wrap_err)) {
+                    None => None,
+                    Some(row) => Some(try!(bson::Document::from_bson(&row.column_slice(0).expect("NOT NULL")))),
+                };
+            stmt_doc.reset();
+            let doc =
+                match doc {
+                    Some(doc) => doc,
+                    None => continue,
+                };
+            let entries = try!(elmo::get_index_entries(&doc, &normspec, &weights, &ndx.options));
+            multikey = multikey || elmo::is_multikey_doc(&doc, &normspec);
+            for vals in entries {
+                let vref = vals.iter().map(|&(ref v,neg)| (v,neg)).collect::<Vec<_>>();
+                let k = bson::Value::encode_multi_for_index_collated(&vref, None, collation.as_ref());
+                stmt_insert.clear_bindings();
+                try!(stmt_insert.bind_blob(1, &k).map_err(elmo::wrap_err));
+                try!(stmt_insert.bind_int64(2, rowid).map_err(elmo::wrap_err));
+                try!(step_done(&mut stmt_insert));
+                stmt_insert.reset();
+                if unique {
+                    // OR IGNORE means a collision doesn't fail, so see who
+                    // owns the key now.
+                    stmt_owner.clear_bindings();
+                    try!(stmt_owner.bind_blob(1, &k).map_err(elmo::wrap_err));
+                    let owner =
+                        match try!(stmt_owner.step().map_err(elmo::wrap_err)) {
+                            None => None,
+                            Some(row) => Some(row.column_int64(0)),
+                        };
+                    stmt_owner.reset();
+                    if owner != Some(rowid) {
+                        return Err(elmo::Error::MongoCode(11000, String::from("duplicate key error building unique index")));
+                    }
+                }
+            }
+        }
+        if multikey && !ndx.is_multikey() {
+            let mut info = ndx.clone();
+            try!(self.myconn.set_index_multikey(&mut info));
+        }
+        Ok(())
+    }
+
+    fn list_index_builds(&mut self) -> Result<Vec<elmo::IndexInfo>> {
+        let mut stmt = try!(self.myconn.conn.prepare("SELECT i.ndxName, i.spec, i.options, i.dbName, i.collName FROM \"indexes\" i INNER JOIN \"building\" b ON b.dbName=i.dbName AND b.collName=i.collName AND b.ndxName=i.ndxName").map_err(elmo::wrap_err));
+        let mut v = Vec::new();
+        loop {
+            match try!(stmt.step().map_err(elmo::wrap_err)) {
+                None => break,
+                Some(row) => {
+                    let info = try!(get_index_info_from_row(&row));
+                    v.push(info);
+                },
+            }
+        }
+        Ok(v)
+    }
+
+    fn finish_index_build(&mut self, ndx: &elmo::IndexInfo) -> Result<()> {
+        let mut stmt = try!(self.myconn.conn.prepare("DELETE FROM \"building\" WHERE dbName=? AND collName=? AND ndxName=?").map_err(elmo::wrap_err));
+        try!(stmt.bind_text(1, &ndx.db).map_err(elmo::wrap_err));
+        try!(stmt.bind_text(2, &ndx.coll).map_err(elmo::wrap_err));
+        try!(stmt.bind_text(3, &ndx.name).map_err(elmo::wrap_err));
+        try!(step_done(&mut stmt));
+        try!(verify_changes(&stmt, 1));
+        Ok(())
+    }
+
     // TODO maybe just move all the stuff below from the private section into here?
 
     fn create_collection(&mut self, db: &str, coll: &str, options: bson::Document) -> Result<bool> {
         self.base_create_collection(db, coll, options)
     }
 
+    fn set_collection_options(&mut self, db: &str, coll: &str, options: bson::Document) -> Result<bool> {
+        let v_options = options.to_bson_array();
+        let mut stmt = try!(self.myconn.conn.prepare("UPDATE \"collections\" SET options=? WHERE dbName=? AND collName=?").map_err(elmo::wrap_err));
+        try!(stmt.bind_blob(1, &v_options).map_err(elmo::wrap_err));
+        try!(stmt.bind_text(2, db).map_err(elmo::wrap_err));
+        try!(stmt.bind_text(3, coll).map_err(elmo::wrap_err));
+        try!(step_done(&mut stmt));
+        let changed = stmt.changes() > 0;
+        Ok(changed)
+    }
+
     fn drop_collection(&mut self, db: &str, coll: &str) -> Result<bool> {
         self.base_drop_collection(db, coll)
     }
@@ -1108,6 +1574,18 @@ impl elmo::StorageWriter for MyWriter {
         self.base_rename_collection(old_name, new_name, drop_target)
     }
 
+    fn set_index_options(&mut self, db: &str, coll: &str, name: &str, options: bson::Document) -> Result<bool> {
+        let ba_options = options.to_bson_array();
+        let mut stmt = try!(self.myconn.conn.prepare("UPDATE \"indexes\" SET options=? WHERE dbName=? AND collName=? AND ndxName=?").map_err(elmo::wrap_err));
+        try!(stmt.bind_blob(1, &ba_options).map_err(elmo::wrap_err));
+        try!(stmt.bind_text(2, db).map_err(elmo::wrap_err));
+        try!(stmt.bind_text(3, coll).map_err(elmo::wrap_err));
+        try!(stmt.bind_text(4, name).map_err(elmo::wrap_err));
+        try!(step_done(&mut stmt));
+        let changed = stmt.changes() > 0;
+        Ok(changed)
+    }
+
     fn drop_index(&mut self, db: &str, coll: &str, name: &str) -> Result<bool> {
         self.base_drop_index(db, coll, name)
     }
@@ -1117,6 +1595,8 @@ impl elmo::StorageWriter for MyWriter {
     }
 
     fn clear_collection(&mut self, db: &str, coll: &str) -> Result<bool> {
+        // the cached writer may be counting docs for a capped collection
+        self.cw = None;
         self.base_clear_collection(db, coll)
     }
 
@@ -1181,7 +1661,21 @@ impl elmo::StorageBase for MyReader {
     }
 
     fn get_reader_regular_index_scan(&self, ndx: &elmo::IndexInfo, bounds: elmo::QueryBounds) -> Result<Box<Iterator<Item=Result<elmo::Row>> + 'static>> {
-        let rdr = try!(self.myconn.get_reader_regular_index_scan(self.myconn.clone(), false, ndx, bounds));
+        let rdr = try!(self.myconn.get_reader_regular_index_scan(self.myconn.clone(), false, ndx, bounds, false));
+        Ok(box rdr)
+    }
+
+    fn get_reader_reversed_index_scan(&self, ndx: &elmo::IndexInfo, bounds: elmo::QueryBounds) -> Result<Box<Iterator<Item=Result<elmo::Row>> + 'static>> {
+        let rdr = try!(self.myconn.get_reader_regular_index_scan(self.myconn.clone(), false, ndx, bounds, true));
+        Ok(box rdr)
+    }
+
+    fn get_record_ids_regular_index_scan(&self, ndx: &elmo::IndexInfo, bounds: elmo::QueryBounds) -> Result<Box<Iterator<Item=Result<Box<[u8]>>> + 'static>> {
+        self.myconn.get_record_ids_regular_index_scan(ndx, bounds)
+    }
+
+    fn get_reader_records(&self, db: &str, coll: &str, ids: Vec<Box<[u8]>>) -> Result<Box<Iterator<Item=Result<elmo::Row>> + 'static>> {
+        let rdr = try!(self.myconn.get_reader_records(self.myconn.clone(), false, db, coll, ids));
         Ok(box rdr)
     }
 
@@ -1190,7 +1684,7 @@ impl elmo::StorageBase for MyReader {
     }
 
     fn list_indexes(&self, ns: Option<(&str, &str)>) -> Result<Vec<elmo::IndexInfo>> {
-        self.myconn.base_list_indexes(ns)
+        self.myconn.base_list_indexes(ns, true)
     }
 
 }
@@ -1208,10 +1702,26 @@ impl elmo::StorageReader for MyReader {
     }
 
     fn into_reader_regular_index_scan(&self, ndx: &elmo::IndexInfo, bounds: elmo::QueryBounds) -> Result<Box<Iterator<Item=Result<elmo::Row>> + 'static>> {
-        let rdr = try!(self.myconn.get_reader_regular_index_scan(self.myconn.clone(), true, ndx, bounds));
+        let rdr = try!(self.myconn.get_reader_regular_index_scan(self.myconn.clone(), true, ndx, bounds, false));
+        Ok(box rdr)
+    }
+
+    fn into_reader_reversed_index_scan(&self, ndx: &elmo::IndexInfo, bounds: elmo::QueryBounds) -> Result<Box<Iterator<Item=Result<elmo::Row>> + 'static>> {
+        let rdr = try!(self.myconn.get_reader_regular_index_scan(self.myconn.clone(), true, ndx, bounds, true));
         Ok(box rdr)
     }
 
+    fn into_reader_collection_scan_after(mut self: Box<Self>, db: &str, coll: &str, id: &bson::Value) -> Result<Option<Box<Iterator<Item=Result<elmo::Row>> + 'static>>> {
+        match try!(MyConn::get_table_scan_reader_after(self.myconn.clone(), true, db, coll, id)) {
+            Some(rdr) => {
+                // the collection reader ends the tx now
+                self.in_tx = false;
+                Ok(Some(box rdr))
+            },
+            None => Ok(None),
+        }
+    }
+
 }
 
 impl elmo::StorageBase for MyWriter {
@@ -1226,7 +1736,21 @@ impl elmo::StorageBase for MyWriter {
     }
 
     fn get_reader_regular_index_scan(&self, ndx: &elmo::IndexInfo, bounds: elmo::QueryBounds) -> Result<Box<Iterator<Item=Result<elmo::Row>> + 'static>> {
-        let rdr = try!(self.myconn.get_reader_regular_index_scan(self.myconn.clone(), false, ndx, bounds));
+        let rdr = try!(self.myconn.get_reader_regular_index_scan(self.myconn.clone(), false, ndx, bounds, false));
+        Ok(box rdr)
+    }
+
+    fn get_reader_reversed_index_scan(&self, ndx: &elmo::IndexInfo, bounds: elmo::QueryBounds) -> Result<Box<Iterator<Item=Result<elmo::Row>> + 'static>> {
+        let rdr = try!(self.myconn.get_reader_regular_index_scan(self.myconn.clone(), false, ndx, bounds, true));
+        Ok(box rdr)
+    }
+
+    fn get_record_ids_regular_index_scan(&self, ndx: &elmo::IndexInfo, bounds: elmo::QueryBounds) -> Result<Box<Iterator<Item=Result<Box<[u8]>>> + 'static>> {
+        self.myconn.get_record_ids_regular_index_scan(ndx, bounds)
+    }
+
+    fn get_reader_records(&self, db: &str, coll: &str, ids: Vec<Box<[u8]>>) -> Result<Box<Iterator<Item=Result<elmo::Row>> + 'static>> {
+        let rdr = try!(self.myconn.get_reader_records(self.myconn.clone(), false, db, coll, ids));
         Ok(box rdr)
     }
 
@@ -1235,7 +1759,7 @@ impl elmo::StorageBase for MyWriter {
     }
 
     fn list_indexes(&self, ns: Option<(&str, &str)>) -> Result<Vec<elmo::IndexInfo>> {
-        self.myconn.base_list_indexes(ns)
+        self.myconn.base_list_indexes(ns, true)
     }
 
 }
@@ -1248,6 +1772,7 @@ impl elmo::StorageConnection for MyPublicConn {
             myconn: self.myconn.clone(),
             in_tx: true,
             cw: None,
+            savepoints: 0,
         };
         Ok(box w)
     }
@@ -1274,6 +1799,8 @@ fn base_connect(name: &str) -> sqlite3::SqliteResult<sqlite3::DatabaseConnection
             Ok(_) => {
                 try!(conn.exec("CREATE TABLE IF NOT EXISTS \"collections\" (dbName TEXT NOT NULL, collName TEXT NOT NULL, options BLOB NOT NULL, PRIMARY KEY (dbName,collName))"));
                 try!(conn.exec("CREATE TABLE IF NOT EXISTS \"indexes\" (dbName TEXT NOT NULL, collName TEXT NOT NULL, ndxName TEXT NOT NULL, spec BLOB NOT NULL, options BLOB NOT NULL, PRIMARY KEY (dbName, collName, ndxName), FOREIGN KEY (dbName,collName) REFERENCES \"collections\" ON DELETE CASCADE ON UPDATE CASCADE, UNIQUE (spec,dbName,collName))"));
+                try!(conn.exec("CREATE TABLE IF NOT EXISTS \"capped\" (dbName TEXT NOT NULL, collName TEXT NOT NULL, count INTEGER NOT NULL, bytes INTEGER NOT NULL, PRIMARY KEY (dbName, collName), FOREIGN KEY (dbName,collName) REFERENCES \"collections\" ON DELETE CASCADE ON UPDATE CASCADE)"));
+                try!(conn.exec("CREATE TABLE IF NOT EXISTS \"building\" (dbName TEXT NOT NULL, collName TEXT NOT NULL, ndxName TEXT NOT NULL, PRIMARY KEY (dbName, collName, ndxName), FOREIGN KEY (dbName,collName,ndxName) REFERENCES \"indexes\" ON DELETE CASCADE ON UPDATE CASCADE)"));
                 try!(conn.exec("COMMIT TRANSACTION"));
                 break;
             },
@@ -1316,12 +1843,14 @@ pub fn connect(name: &str) -> Result<Box<elmo::StorageConnection>> {
 #[derive(Clone)]
 pub struct MyFactory {
     filename: String,
+    planner: std::sync::Arc<elmo::PlannerState>,
 }
 
 impl MyFactory {
     pub fn new(filename: String) -> MyFactory {
         MyFactory {
             filename: filename,
+            planner: std::sync::Arc::new(elmo::PlannerState::new()),
         }
     }
 }
@@ -1329,7 +1858,7 @@ impl MyFactory {
 impl elmo::ConnectionFactory for MyFactory {
     fn open(&self) -> elmo::Result<elmo::Connection> {
         let conn = try!(connect(&self.filename));
-        let conn = elmo::Connection::new(conn);
+        let conn = elmo::Connection::new(conn, self.planner.clone());
         Ok(conn)
     }
 
@@ -1,6 +1,7 @@
 
 extern crate misc;
 extern crate bson;
+#[macro_use]
 extern crate elmo;
 extern crate elmo_sqlite3;
 
@@ -58,3 +59,6 @@ fn insert() {
     assert!(r.is_ok());
 }
 
+// every engine runs the same conformance tests from elmo.  each one gets
+// its own #[test] and its own fresh database file.
+conformance_tests!(name => elmo_sqlite3::MyFactory::new(misc::tempfile(name)));